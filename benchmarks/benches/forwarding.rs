@@ -1,15 +1,14 @@
 use bytes::Bytes;
 use criterion::{black_box, criterion_group, criterion_main, Criterion};
-use futures_util::TryFutureExt;
 use rand::{thread_rng, Rng};
 use std::mem::transmute;
 use test_utils::{find_unused_ports, read_assert_payload, write_payload, TransportKind};
-use transport::{ErasedReadWrite, Transport};
+use transport::ErasedReadWrite;
 
-struct Context {
+struct Context<N = ErasedReadWrite> {
     client_to_node: ErasedReadWrite,
-    node_to_client: ErasedReadWrite,
-    node_to_server: ErasedReadWrite,
+    node_to_client: N,
+    node_to_server: N,
     server_to_node: ErasedReadWrite,
 }
 
@@ -18,10 +17,10 @@ async fn setup(kind: TransportKind) -> Context {
     let port_node = ports[0];
     let port_server = ports[1];
 
-    let client_fut = kind.connect(port_node).map_ok(Transport::into_erased);
-    let node_to_client_fut = kind.accept(port_node).map_ok(Transport::into_erased);
-    let node_to_server_fut = kind.connect(port_server).map_ok(Transport::into_erased);
-    let server_fut = kind.accept(port_server).map_ok(Transport::into_erased);
+    let client_fut = kind.connect(port_node);
+    let node_to_client_fut = kind.accept(port_node);
+    let node_to_server_fut = kind.connect(port_server);
+    let server_fut = kind.accept(port_server);
 
     let (node_to_client, server_to_node, client_to_node, node_to_server) =
         tokio::try_join!(node_to_client_fut, server_fut, client_fut, node_to_server_fut).unwrap();
@@ -34,6 +33,49 @@ async fn setup(kind: TransportKind) -> Context {
     }
 }
 
+/// Same as `setup`, but the node holds the raw TCP streams, as required for `splice(2)`.
+#[cfg(target_os = "linux")]
+async fn setup_tcp_splice() -> Context<tokio::net::TcpStream> {
+    use tokio::net::{TcpListener, TcpStream};
+
+    let ports = find_unused_ports(2);
+    let port_node = ports[0];
+    let port_server = ports[1];
+
+    let client_fut = test_utils::tcp_connect(port_node);
+    let node_to_client_fut = async {
+        let listener = TcpListener::bind(("127.0.0.1", port_node)).await?;
+        let (stream, _) = listener.accept().await?;
+        anyhow::Ok(stream)
+    };
+    let node_to_server_fut = async { anyhow::Ok(TcpStream::connect(("127.0.0.1", port_server)).await?) };
+    let server_fut = test_utils::tcp_accept(port_server);
+
+    let (node_to_client, server_to_node, client_to_node, node_to_server) =
+        tokio::try_join!(node_to_client_fut, server_fut, client_fut, node_to_server_fut).unwrap();
+
+    Context {
+        client_to_node,
+        node_to_client,
+        node_to_server,
+        server_to_node,
+    }
+}
+
+async fn copy_forward(mut a: ErasedReadWrite, mut b: ErasedReadWrite) {
+    tokio::io::copy_bidirectional(&mut a, &mut b).await.unwrap();
+}
+
+#[cfg(target_os = "linux")]
+async fn splice_forward(a: tokio::net::TcpStream, b: tokio::net::TcpStream) {
+    use std::sync::atomic::AtomicU64;
+
+    let a_to_b = AtomicU64::new(0);
+    let b_to_a = AtomicU64::new(0);
+
+    transport::splice_bidirectional(&a, &b, &a_to_b, &b_to_a).await.unwrap();
+}
+
 async fn endpoint(transport: &'static mut ErasedReadWrite, payload: Bytes) {
     let (reader, writer) = tokio::io::split(transport);
 
@@ -66,7 +108,7 @@ async fn transfer(client_to_node: &mut ErasedReadWrite, server_to_node: &mut Era
 }
 
 macro_rules! harness {
-    ($benchmark_ident:ident, $name:literal, $setup:block) => {
+    ($benchmark_ident:ident, $name:literal, $setup:block, $forward:path) => {
         fn $benchmark_ident(c: &mut Criterion) {
             use tokio::io::AsyncWriteExt;
 
@@ -81,11 +123,7 @@ macro_rules! harness {
 
             let mut ctx = rt.block_on(async { $setup });
 
-            let handle = rt.spawn(async move {
-                transport::forward_bidirectional(ctx.node_to_client, ctx.node_to_server)
-                    .await
-                    .unwrap();
-            });
+            let handle = rt.spawn($forward(ctx.node_to_client, ctx.node_to_server));
 
             c.bench_function(concat!($name, " forwarding 100KiB"), |b| {
                 b.iter(|| {
@@ -126,20 +164,46 @@ macro_rules! harness {
     };
 }
 
-harness!(duplex_benchmark, "Duplex", {
-    let (client_to_node, node_to_client) = tokio::io::duplex(5012);
-    let (node_to_server, server_to_node) = tokio::io::duplex(5012);
-    Context {
-        client_to_node: Box::new(client_to_node) as ErasedReadWrite,
-        node_to_client: Box::new(node_to_client) as ErasedReadWrite,
-        node_to_server: Box::new(node_to_server) as ErasedReadWrite,
-        server_to_node: Box::new(server_to_node) as ErasedReadWrite,
-    }
-});
-harness!(tcp_benchmark, "TCP", { setup(TransportKind::Tcp).await });
-harness!(ws_benchmark, "WebSocket", { setup(TransportKind::Ws).await });
+harness!(
+    duplex_benchmark,
+    "Duplex",
+    {
+        let (client_to_node, node_to_client) = tokio::io::duplex(5012);
+        let (node_to_server, server_to_node) = tokio::io::duplex(5012);
+        Context {
+            client_to_node: Box::new(client_to_node) as ErasedReadWrite,
+            node_to_client: Box::new(node_to_client) as ErasedReadWrite,
+            node_to_server: Box::new(node_to_server) as ErasedReadWrite,
+            server_to_node: Box::new(server_to_node) as ErasedReadWrite,
+        }
+    },
+    copy_forward
+);
+harness!(tcp_benchmark, "TCP", { setup(TransportKind::Tcp).await }, copy_forward);
+harness!(
+    ws_benchmark,
+    "WebSocket",
+    { setup(TransportKind::Ws).await },
+    copy_forward
+);
+#[cfg(target_os = "linux")]
+harness!(
+    tcp_splice_benchmark,
+    "TCP splice",
+    { setup_tcp_splice().await },
+    splice_forward
+);
 
 // TODO: multiple streams in parallel
 
+#[cfg(target_os = "linux")]
+criterion_group!(
+    benches,
+    duplex_benchmark,
+    tcp_benchmark,
+    ws_benchmark,
+    tcp_splice_benchmark
+);
+#[cfg(not(target_os = "linux"))]
 criterion_group!(benches, duplex_benchmark, tcp_benchmark, ws_benchmark);
 criterion_main!(benches);
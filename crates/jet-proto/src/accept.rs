@@ -1,7 +1,7 @@
 use crate::utils::{RequestHelper, ResponseHelper};
 use crate::{
-    get_uuid_in_path, Error, JET_HEADER_ASSOCIATION, JET_HEADER_HOST, JET_HEADER_INSTANCE, JET_HEADER_METHOD,
-    JET_HEADER_TIMEOUT, JET_HEADER_VERSION,
+    get_bearer_token, get_uuid_in_path, Error, JET_HEADER_ASSOCIATION, JET_HEADER_AUTHORIZATION, JET_HEADER_HOST,
+    JET_HEADER_INSTANCE, JET_HEADER_METHOD, JET_HEADER_TIMEOUT, JET_HEADER_VERSION,
 };
 use http::StatusCode;
 use std::io;
@@ -14,6 +14,8 @@ pub struct JetAcceptReq {
     pub host: String,
     pub association: Uuid,
     pub candidate: Uuid,
+    /// Association token, only sent with version 2
+    pub token: Option<String>,
 }
 
 impl JetAcceptReq {
@@ -37,6 +39,9 @@ impl JetAcceptReq {
                 stream.write_fmt(format_args!("Host: {}\r\n", &self.host))?;
                 stream.write_fmt(format_args!("Connection: Keep-Alive\r\n"))?;
                 stream.write_fmt(format_args!("Jet-Version: {}\r\n", &self.version.to_string()))?;
+                if let Some(token) = &self.token {
+                    stream.write_fmt(format_args!("{JET_HEADER_AUTHORIZATION}: Bearer {token}\r\n"))?;
+                }
                 stream.write_fmt(format_args!("\r\n"))?;
             }
         }
@@ -62,6 +67,7 @@ impl JetAcceptReq {
                                 host: host.to_string(),
                                 association: association_id,
                                 candidate: candidate_id,
+                                token: get_bearer_token(request),
                             });
                         }
                     } else if path.eq("/") {
@@ -72,6 +78,7 @@ impl JetAcceptReq {
                                     host: host.to_string(),
                                     association: Uuid::nil(),
                                     candidate: Uuid::nil(),
+                                    token: None,
                                 });
                             }
                         }
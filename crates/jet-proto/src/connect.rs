@@ -1,7 +1,7 @@
 use crate::utils::{RequestHelper, ResponseHelper};
 use crate::{
-    get_uuid_in_path, Error, JET_HEADER_ASSOCIATION, JET_HEADER_CONNECTION, JET_HEADER_HOST, JET_HEADER_METHOD,
-    JET_HEADER_VERSION,
+    get_bearer_token, get_uuid_in_path, Error, JET_HEADER_ASSOCIATION, JET_HEADER_AUTHORIZATION, JET_HEADER_CONNECTION,
+    JET_HEADER_HOST, JET_HEADER_METHOD, JET_HEADER_VERSION,
 };
 use http::StatusCode;
 use std::io;
@@ -14,6 +14,8 @@ pub struct JetConnectReq {
     pub host: String,
     pub association: Uuid,
     pub candidate: Uuid,
+    /// Association token, only sent with version 2
+    pub token: Option<String>,
 }
 
 impl JetConnectReq {
@@ -50,6 +52,9 @@ impl JetConnectReq {
                     JET_HEADER_VERSION,
                     &self.version.to_string()
                 ))?;
+                if let Some(token) = &self.token {
+                    stream.write_fmt(format_args!("{JET_HEADER_AUTHORIZATION}: Bearer {token}\r\n"))?;
+                }
                 stream.write_fmt(format_args!("\r\n"))?;
             }
         }
@@ -84,6 +89,7 @@ impl JetConnectReq {
                                 host: host.to_string(),
                                 association: association_id,
                                 candidate: candidate_id,
+                                token: get_bearer_token(request),
                             });
                         }
                     } else if path.eq("/") {
@@ -96,6 +102,7 @@ impl JetConnectReq {
                                             host: host.to_string(),
                                             association,
                                             candidate: Uuid::nil(),
+                                            token: None,
                                         });
                                     }
                                 }
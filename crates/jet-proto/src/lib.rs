@@ -29,6 +29,7 @@ const JET_HEADER_TIMEOUT: &str = "Jet-Timeout";
 const JET_HEADER_INSTANCE: &str = "Jet-Instance";
 const JET_HEADER_HOST: &str = "Host";
 const JET_HEADER_CONNECTION: &str = "Connection";
+const JET_HEADER_AUTHORIZATION: &str = "Authorization";
 
 static mut JET_MSG_MASK: u8 = 0x73;
 static JET_MASK_INIT: Once = Once::new();
@@ -192,6 +193,13 @@ fn get_uuid_in_path(path: &str, index: usize) -> Option<Uuid> {
     }
 }
 
+fn get_bearer_token(request: &httparse::Request) -> Option<String> {
+    request
+        .get_header_value(JET_HEADER_AUTHORIZATION)
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| token.trim().to_owned())
+}
+
 fn apply_mask(mask: u8, payload: &mut [u8]) {
     for byte in payload {
         *byte ^= mask;
@@ -339,8 +347,41 @@ mod tests {
                     association: Uuid::from_str("300f1c82-d33b-11e9-bb65-2a2ae2dbcce5").unwrap(),
                     candidate: Uuid::from_str("4c8f409a-c1a2-4cae-bda2-84c590fed618").unwrap(),
                     version: 2,
-                    host: "jet101.wayk.net".to_string()
+                    host: "jet101.wayk.net".to_string(),
+                    token: None,
                 })
         );
     }
+
+    #[test]
+    fn accept_and_connect_v2_carry_token() {
+        use std::str::FromStr;
+
+        let association = Uuid::from_str("300f1c82-d33b-11e9-bb65-2a2ae2dbcce5").unwrap();
+        let candidate = Uuid::from_str("4c8f409a-c1a2-4cae-bda2-84c590fed618").unwrap();
+
+        let messages = [
+            JetMessage::JetAcceptReq(JetAcceptReq {
+                version: 2,
+                host: "jet101.wayk.net".to_owned(),
+                association,
+                candidate,
+                token: Some("header.payload.signature".to_owned()),
+            }),
+            JetMessage::JetConnectReq(JetConnectReq {
+                version: 2,
+                host: "jet101.wayk.net".to_owned(),
+                association,
+                candidate,
+                token: Some("header.payload.signature".to_owned()),
+            }),
+        ];
+
+        for message in messages {
+            let mut buf = Vec::new();
+            message.write_to(&mut buf).unwrap();
+            let decoded = JetMessage::read_request(&mut buf.as_slice()).unwrap();
+            assert_eq!(decoded, message);
+        }
+    }
 }
@@ -387,6 +387,7 @@ impl From<std::io::ErrorKind> for ReasonCode {
     fn from(kind: std::io::ErrorKind) -> ReasonCode {
         match kind {
             std::io::ErrorKind::ConnectionRefused => ReasonCode::CONNECTION_REFUSED,
+            std::io::ErrorKind::PermissionDenied => ReasonCode::CONNECTION_NOT_ALLOWED_BY_RULESET,
             std::io::ErrorKind::TimedOut => ReasonCode::TTL_EXPIRED,
             #[cfg(feature = "nightly")] // https://github.com/rust-lang/rust/issues/86442
             std::io::ErrorKind::HostUnreachable => ReasonCode::HOST_UNREACHABLE,
@@ -23,7 +23,6 @@ use std::io;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::net::TcpStream;
 use tokio::sync::{mpsc, oneshot, Notify};
 use tokio::task::JoinHandle;
@@ -49,6 +48,38 @@ pub enum JmuxApiRequest {
     },
 }
 
+/// Notable events of the JMUX session, reported through [`JmuxProxy::with_event_sender`].
+#[derive(Debug, Clone)]
+pub enum JmuxEvent {
+    ChannelOpened {
+        id: LocalChannelId,
+        destination_url: DestinationUrl,
+    },
+    ChannelClosed {
+        id: LocalChannelId,
+    },
+}
+
+pub type EventSender = mpsc::UnboundedSender<JmuxEvent>;
+
+/// Stream of a channel established by a [`Connector`] (e.g.: a `TcpStream`, possibly wrapped)
+pub trait ChannelStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T> ChannelStream for T where T: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl std::fmt::Debug for dyn ChannelStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ChannelStream").finish_non_exhaustive()
+    }
+}
+
+pub type ConnectFuture = futures_util::future::BoxFuture<'static, io::Result<Box<dyn ChannelStream>>>;
+
+/// Establishes the streams of the channels requested by the peer, in place of a direct `TcpStream::connect`.
+///
+/// An error of kind `PermissionDenied` is reported to the peer as "connection not allowed by ruleset".
+pub type Connector = Arc<dyn Fn(&DestinationUrl) -> ConnectFuture + Send + Sync>;
+
 #[derive(Debug)]
 pub enum JmuxApiResponse {
     Success {
@@ -63,6 +94,8 @@ pub enum JmuxApiResponse {
 pub struct JmuxProxy {
     cfg: JmuxConfig,
     api_request_rx: Option<ApiRequestReceiver>,
+    event_tx: Option<EventSender>,
+    connector: Option<Connector>,
     jmux_reader: Box<dyn AsyncRead + Unpin + Send>,
     jmux_writer: Box<dyn AsyncWrite + Unpin + Send>,
 }
@@ -75,6 +108,8 @@ impl JmuxProxy {
         Self {
             cfg: JmuxConfig::default(),
             api_request_rx: None,
+            event_tx: None,
+            connector: None,
             jmux_reader,
             jmux_writer,
         }
@@ -90,6 +125,16 @@ impl JmuxProxy {
         self
     }
 
+    pub fn with_event_sender(mut self, event_tx: EventSender) -> Self {
+        self.event_tx = Some(event_tx);
+        self
+    }
+
+    pub fn with_connector(mut self, connector: Connector) -> Self {
+        self.connector = Some(connector);
+        self
+    }
+
     // TODO: consider using something like ChildTask<T> more widely in Devolutions Gateway
     pub fn spawn(self) -> JoinHandle<anyhow::Result<()>> {
         let fut = self.run();
@@ -106,6 +151,8 @@ async fn run_proxy_impl(proxy: JmuxProxy, span: Span) -> anyhow::Result<()> {
     let JmuxProxy {
         cfg,
         api_request_rx,
+        event_tx,
+        connector,
         jmux_reader,
         jmux_writer,
     } = proxy;
@@ -128,6 +175,8 @@ async fn run_proxy_impl(proxy: JmuxProxy, span: Span) -> anyhow::Result<()> {
         jmux_stream,
         msg_to_send_tx,
         api_request_rx,
+        event_tx,
+        connector,
         parent_span: span,
     }
     .spawn();
@@ -220,8 +269,14 @@ type InternalMessageSender = mpsc::UnboundedSender<InternalMessage>;
 
 #[derive(Debug)]
 enum InternalMessage {
-    Eof { id: LocalChannelId },
-    StreamResolved { channel: JmuxChannelCtx, stream: TcpStream },
+    Eof {
+        id: LocalChannelId,
+    },
+    StreamResolved {
+        channel: JmuxChannelCtx,
+        stream: Box<dyn ChannelStream>,
+        destination_url: DestinationUrl,
+    },
 }
 
 // === internal tasks === //
@@ -265,6 +320,8 @@ struct JmuxSchedulerTask<T: AsyncRead + Unpin + Send + 'static> {
     jmux_stream: FramedRead<T, JmuxCodec>,
     msg_to_send_tx: MessageSender,
     api_request_rx: ApiRequestReceiver,
+    event_tx: Option<EventSender>,
+    connector: Option<Connector>,
     parent_span: Span,
 }
 
@@ -283,9 +340,18 @@ async fn scheduler_task_impl<T: AsyncRead + Unpin + Send + 'static>(task: JmuxSc
         mut jmux_stream,
         msg_to_send_tx,
         mut api_request_rx,
+        event_tx,
+        connector,
         parent_span,
     } = task;
 
+    // Events are informational: a dropped receiver is not an error.
+    let send_event = |event: JmuxEvent| {
+        if let Some(event_tx) = &event_tx {
+            let _ = event_tx.send(event);
+        }
+    };
+
     let mut jmux_ctx = JmuxCtx::new();
     let mut data_senders: HashMap<LocalChannelId, DataSender> = HashMap::new();
     let mut pending_channels: HashMap<LocalChannelId, (DestinationUrl, ApiResponseSender)> = HashMap::new();
@@ -336,6 +402,7 @@ async fn scheduler_task_impl<T: AsyncRead + Unpin + Send + 'static>(task: JmuxSc
                         }
 
                         let (reader, writer) = stream.into_split();
+                        let (reader, writer) = (Box::new(reader), Box::new(writer));
 
                         DataWriterTask {
                             writer,
@@ -382,6 +449,7 @@ async fn scheduler_task_impl<T: AsyncRead + Unpin + Send + 'static>(task: JmuxSc
                             },
                             JmuxChannelState::Closed => {
                                 jmux_ctx.unregister(local_id);
+                                send_event(JmuxEvent::ChannelClosed { id: local_id });
                                 msg_to_send_tx
                                     .send(Message::close(distant_id))
                                     .context("couldn’t send CLOSE message")?;
@@ -392,7 +460,7 @@ async fn scheduler_task_impl<T: AsyncRead + Unpin + Send + 'static>(task: JmuxSc
                         }
                     }
                     InternalMessage::StreamResolved {
-                        channel, stream
+                        channel, stream, destination_url
                     } => {
                         let local_id = channel.local_id;
                         let distant_id = channel.distant_id;
@@ -418,7 +486,10 @@ async fn scheduler_task_impl<T: AsyncRead + Unpin + Send + 'static>(task: JmuxSc
                             debug!("Channel accepted");
                         });
 
-                        let (reader, writer) = stream.into_split();
+                        send_event(JmuxEvent::ChannelOpened { id: local_id, destination_url });
+
+                        let (reader, writer) = tokio::io::split(stream);
+                        let (reader, writer) = (Box::new(reader), Box::new(writer));
 
                         DataWriterTask {
                             writer,
@@ -532,6 +603,7 @@ async fn scheduler_task_impl<T: AsyncRead + Unpin + Send + 'static>(task: JmuxSc
                         StreamResolverTask {
                             channel,
                             destination_url: msg.destination_url,
+                            connector: connector.clone(),
                             internal_msg_tx: internal_msg_tx.clone(),
                             msg_to_send_tx: msg_to_send_tx.clone(),
                         }
@@ -574,6 +646,8 @@ async fn scheduler_task_impl<T: AsyncRead + Unpin + Send + 'static>(task: JmuxSc
 
                             span: channel_span.exit(),
                         })?;
+
+                        send_event(JmuxEvent::ChannelOpened { id: local_id, destination_url });
                     }
                     Message::WindowAdjust(msg) => {
                         if let Some(ctx) = jmux_ctx.get_channel_mut(LocalChannelId::from(msg.recipient_channel_id)) {
@@ -685,6 +759,7 @@ async fn scheduler_task_impl<T: AsyncRead + Unpin + Send + 'static>(task: JmuxSc
 
                         if channel.local_state == JmuxChannelState::Closed {
                             jmux_ctx.unregister(local_id);
+                            send_event(JmuxEvent::ChannelClosed { id: local_id });
                             trace!("Channel closed");
                         }
                     }
@@ -701,7 +776,7 @@ async fn scheduler_task_impl<T: AsyncRead + Unpin + Send + 'static>(task: JmuxSc
 // ---------------------- //
 
 struct DataReaderTask {
-    reader: OwnedReadHalf,
+    reader: Box<dyn AsyncRead + Unpin + Send>,
     local_id: LocalChannelId,
     distant_id: DistantChannelId,
     window_size_updated: Arc<Notify>,
@@ -802,7 +877,7 @@ impl DataReaderTask {
 // ---------------------- //
 
 struct DataWriterTask {
-    writer: OwnedWriteHalf,
+    writer: Box<dyn AsyncWrite + Unpin + Send>,
     data_rx: DataReceiver,
 }
 
@@ -834,6 +909,7 @@ impl DataWriterTask {
 struct StreamResolverTask {
     channel: JmuxChannelCtx,
     destination_url: DestinationUrl,
+    connector: Option<Connector>,
     internal_msg_tx: InternalMessageSender,
     msg_to_send_tx: MessageSender,
 }
@@ -858,6 +934,7 @@ impl StreamResolverTask {
         let Self {
             channel,
             destination_url,
+            connector,
             internal_msg_tx,
             msg_to_send_tx,
         } = self;
@@ -867,11 +944,18 @@ impl StreamResolverTask {
         let port = destination_url.port();
 
         match scheme {
-            "tcp" => match TcpStream::connect((host, port)).await {
+            "tcp" => match connect(connector.as_ref(), &destination_url).await {
                 Ok(stream) => {
                     internal_msg_tx
-                        .send(InternalMessage::StreamResolved { channel, stream })
-                        .context("could't send back resolved stream through internal mpsc channel")?;
+                        .send(InternalMessage::StreamResolved {
+                            channel,
+                            stream,
+                            destination_url: destination_url.clone(),
+                        })
+                        // The stream is not required to be `Sync`, so the send error can't be used as a source.
+                        .map_err(|_| {
+                            anyhow::anyhow!("could't send back resolved stream through internal mpsc channel")
+                        })?;
                 }
                 Err(error) => {
                     debug!(?error, "TcpStream::connect failed");
@@ -892,6 +976,19 @@ impl StreamResolverTask {
     }
 }
 
+async fn connect(
+    connector: Option<&Connector>,
+    destination_url: &DestinationUrl,
+) -> io::Result<Box<dyn ChannelStream>> {
+    match connector {
+        Some(connector) => connector(destination_url).await,
+        None => {
+            let stream = TcpStream::connect((destination_url.host(), destination_url.port())).await?;
+            Ok(Box::new(stream))
+        }
+    }
+}
+
 /// Aborts the running task when dropped.
 /// Also see https://github.com/tokio-rs/tokio/issues/1830 for some background.
 #[must_use]
@@ -1,9 +1,13 @@
 mod copy_bidirectional;
 mod forward;
+#[cfg(target_os = "linux")]
+mod splice;
 mod ws;
 
 pub use self::copy_bidirectional::*;
 pub use self::forward::*;
+#[cfg(target_os = "linux")]
+pub use self::splice::*;
 pub use self::ws::*;
 
 use tokio::io::{AsyncRead, AsyncWrite};
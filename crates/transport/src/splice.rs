@@ -0,0 +1,249 @@
+//! Zero-copy forwarding between two TCP sockets using `splice(2)`.
+//!
+//! Data is moved from one socket to the other through a pipe, without being copied into userspace buffers.
+//! When `splice(2)` is not supported by the kernel or for these sockets, data is copied as usual instead.
+
+use std::io;
+use std::os::fd::{AsRawFd as _, RawFd};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use tokio::io::Interest;
+use tokio::net::TcpStream;
+
+/// Maximum number of bytes moved by a single `splice` call (default capacity of a pipe).
+const PIPE_SIZE: usize = 64 * 1024;
+
+/// Size of the buffer used when falling back to copying the data.
+const COPY_BUFFER_SIZE: usize = 16 * 1024;
+
+/// Forwards data in both directions between `a` and `b` until both directions reach EOF.
+///
+/// When one side reaches EOF, the write half of the other side is shut down, as `tokio::io::copy_bidirectional` does.
+/// The number of bytes forwarded so far is added to the counters as the data flows, and the totals are returned
+/// when done (`a` to `b`, then `b` to `a`).
+pub async fn splice_bidirectional(
+    a: &TcpStream,
+    b: &TcpStream,
+    a_to_b_counter: &AtomicU64,
+    b_to_a_counter: &AtomicU64,
+) -> io::Result<(u64, u64)> {
+    tokio::try_join!(
+        splice_one_direction(a, b, a_to_b_counter),
+        splice_one_direction(b, a, b_to_a_counter),
+    )
+}
+
+async fn splice_one_direction(src: &TcpStream, dst: &TcpStream, counter: &AtomicU64) -> io::Result<u64> {
+    let pipe = match Pipe::new() {
+        Ok(pipe) => pipe,
+        Err(error) if is_unsupported(&error) => return copy_one_direction(src, dst, counter, 0).await,
+        Err(error) => return Err(error),
+    };
+
+    let mut total = 0;
+
+    loop {
+        let n = match src
+            .async_io(Interest::READABLE, || splice(src.as_raw_fd(), pipe.write_fd, PIPE_SIZE))
+            .await
+        {
+            Ok(n) => n,
+            // Once data was forwarded, splice(2) is known to work with these sockets and the error is genuine.
+            Err(error) if total == 0 && is_unsupported(&error) => {
+                return copy_one_direction(src, dst, counter, total).await
+            }
+            Err(error) => return Err(error),
+        };
+
+        if n == 0 {
+            shutdown_write(dst)?;
+            return Ok(total);
+        }
+
+        // The pipe must be drained before reading more from the source.
+        let mut remaining = n;
+
+        while remaining > 0 {
+            match dst
+                .async_io(Interest::WRITABLE, || splice(pipe.read_fd, dst.as_raw_fd(), remaining))
+                .await
+            {
+                Ok(written) => remaining -= written,
+                Err(error) if total == 0 && is_unsupported(&error) => {
+                    drain_pipe(&pipe, dst, remaining).await?;
+
+                    total += n as u64;
+                    counter.fetch_add(n as u64, Ordering::Relaxed);
+
+                    return copy_one_direction(src, dst, counter, total).await;
+                }
+                Err(error) => return Err(error),
+            }
+        }
+
+        total += n as u64;
+        counter.fetch_add(n as u64, Ordering::Relaxed);
+    }
+}
+
+/// Forwards data from `src` to `dst` by copying it through a userspace buffer, until EOF.
+///
+/// `total` is the number of bytes already forwarded in this direction.
+async fn copy_one_direction(src: &TcpStream, dst: &TcpStream, counter: &AtomicU64, mut total: u64) -> io::Result<u64> {
+    let mut buf = vec![0; COPY_BUFFER_SIZE];
+
+    loop {
+        let n = src.async_io(Interest::READABLE, || src.try_read(&mut buf)).await?;
+
+        if n == 0 {
+            shutdown_write(dst)?;
+            return Ok(total);
+        }
+
+        write_all(dst, &buf[..n]).await?;
+
+        total += n as u64;
+        counter.fetch_add(n as u64, Ordering::Relaxed);
+    }
+}
+
+/// Writes the `len` bytes held by the pipe to `dst`.
+async fn drain_pipe(pipe: &Pipe, dst: &TcpStream, len: usize) -> io::Result<()> {
+    let mut buf = vec![0; len];
+    let mut filled = 0;
+
+    while filled < len {
+        // SAFETY: FFI call with a valid file descriptor, and a buffer valid for `len - filled` bytes.
+        let ret = unsafe { libc::read(pipe.read_fd, buf[filled..].as_mut_ptr().cast(), len - filled) };
+
+        match ret {
+            // The bytes are already in the pipe, the read can't block.
+            0 => return Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+            n if n > 0 => filled += usize::try_from(n).expect("positive"),
+            _ => return Err(io::Error::last_os_error()),
+        }
+    }
+
+    write_all(dst, &buf).await
+}
+
+async fn write_all(dst: &TcpStream, mut bytes: &[u8]) -> io::Result<()> {
+    while !bytes.is_empty() {
+        let n = dst.async_io(Interest::WRITABLE, || dst.try_write(bytes)).await?;
+        bytes = &bytes[n..];
+    }
+
+    Ok(())
+}
+
+fn shutdown_write(dst: &TcpStream) -> io::Result<()> {
+    // SAFETY: FFI call with a valid file descriptor, owned by `dst` for the duration of the call.
+    let ret = unsafe { libc::shutdown(dst.as_raw_fd(), libc::SHUT_WR) };
+
+    match ret {
+        0 => Ok(()),
+        _ => match io::Error::last_os_error() {
+            // The peer may already be gone.
+            error if error.raw_os_error() == Some(libc::ENOTCONN) => Ok(()),
+            error => Err(error),
+        },
+    }
+}
+
+/// Whether `splice(2)` can't be used at all, as opposed to an actual I/O error.
+fn is_unsupported(error: &io::Error) -> bool {
+    matches!(error.raw_os_error(), Some(libc::EINVAL | libc::ENOSYS))
+}
+
+fn splice(fd_in: RawFd, fd_out: RawFd, len: usize) -> io::Result<usize> {
+    // SAFETY: FFI call with valid file descriptors, and null offsets (both ends are not seekable).
+    let ret = unsafe {
+        libc::splice(
+            fd_in,
+            std::ptr::null_mut(),
+            fd_out,
+            std::ptr::null_mut(),
+            len,
+            libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK,
+        )
+    };
+
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(usize::try_from(ret).expect("non-negative"))
+    }
+}
+
+struct Pipe {
+    read_fd: RawFd,
+    write_fd: RawFd,
+}
+
+impl Pipe {
+    fn new() -> io::Result<Self> {
+        let mut fds = [0; 2];
+
+        // SAFETY: FFI call with a valid pointer to an array of two file descriptors.
+        let ret = unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) };
+
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Self {
+            read_fd: fds[0],
+            write_fd: fds[1],
+        })
+    }
+}
+
+impl Drop for Pipe {
+    fn drop(&mut self) {
+        // SAFETY: both file descriptors are owned by this struct, and are not used after this point.
+        unsafe {
+            libc::close(self.read_fd);
+            libc::close(self.write_fd);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    async fn tcp_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (connected, accepted) = tokio::join!(TcpStream::connect(addr), listener.accept());
+        (connected.unwrap(), accepted.unwrap().0)
+    }
+
+    #[tokio::test]
+    async fn bytes_in_the_pipe_are_forwarded_before_copying() {
+        let (mut client, src) = tcp_pair().await;
+        let (dst, mut server) = tcp_pair().await;
+
+        let pipe = Pipe::new().unwrap();
+        // SAFETY: FFI call with a valid file descriptor, and a buffer valid for its length.
+        let ret = unsafe { libc::write(pipe.write_fd, b"spliced ".as_ptr().cast(), 8) };
+        assert_eq!(ret, 8);
+
+        drain_pipe(&pipe, &dst, 8).await.unwrap();
+
+        client.write_all(b"then copied").await.unwrap();
+        client.shutdown().await.unwrap();
+
+        let counter = AtomicU64::new(8);
+        let total = copy_one_direction(&src, &dst, &counter, 8).await.unwrap();
+        assert_eq!(total, 19);
+        assert_eq!(counter.load(Ordering::Relaxed), 19);
+
+        let mut received = Vec::new();
+        server.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"spliced then copied");
+    }
+}
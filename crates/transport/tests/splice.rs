@@ -0,0 +1,75 @@
+#![cfg(target_os = "linux")]
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use anyhow::Context as _;
+use futures_util::FutureExt;
+use proptest::prelude::*;
+use test_utils::{find_unused_ports, payload, read_assert_payload, tcp_accept, tcp_connect, write_payload};
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+
+async fn endpoint(payload: &[u8], stream: transport::ErasedReadWrite) -> anyhow::Result<()> {
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let write_fut = write_payload(&mut writer, payload).map(|res| res.context("write payload"));
+    let read_fut = read_assert_payload(&mut reader, payload).map(|res| res.context("assert payload"));
+    tokio::try_join!(write_fut, read_fut)?;
+    writer.shutdown().await.context("shutdown operation")?;
+    Ok(())
+}
+
+async fn node(port_node: u16, port_server: u16) -> anyhow::Result<(u64, u64, u64, u64)> {
+    let listener = TcpListener::bind(("127.0.0.1", port_node)).await.context("bind")?;
+    let (client_stream, _) = listener.accept().await.context("accept")?;
+    let server_stream = TcpStream::connect(("127.0.0.1", port_server))
+        .await
+        .context("connect")?;
+
+    let client_to_server = AtomicU64::new(0);
+    let server_to_client = AtomicU64::new(0);
+
+    let (a_to_b, b_to_a) =
+        transport::splice_bidirectional(&client_stream, &server_stream, &client_to_server, &server_to_client)
+            .await
+            .context("splice")?;
+
+    Ok((
+        a_to_b,
+        b_to_a,
+        client_to_server.load(Ordering::Relaxed),
+        server_to_client.load(Ordering::Relaxed),
+    ))
+}
+
+#[test]
+fn splice_three_points() {
+    let ports = find_unused_ports(2);
+    let port_node = ports[0];
+    let port_server = ports[1];
+
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+
+    proptest!(ProptestConfig::with_cases(10), |(
+        payload in payload().no_shrink(),
+    )| {
+        rt.block_on(async {
+            let server_fut = async {
+                let stream = tcp_accept(port_server).await.context("accept")?;
+                endpoint(&payload.0, stream).await
+            };
+            let node_fut = node(port_node, port_server).map(|res| res.context("node"));
+            let client_fut = async {
+                let stream = tcp_connect(port_node).await.context("connect")?;
+                endpoint(&payload.0, stream).await
+            };
+
+            let (_, counters, _) = tokio::try_join!(server_fut, node_fut, client_fut).unwrap();
+
+            let expected = payload.0.len() as u64;
+            assert_eq!(counters, (expected, expected, expected, expected));
+        });
+    })
+}
@@ -1,4 +1,4 @@
-use axum::extract::State;
+use axum::extract::{Query, State};
 use axum::response::{IntoResponse as _, Response};
 use axum::routing::get;
 use axum::{Json, Router};
@@ -10,6 +10,9 @@ use crate::extract::DiagnosticsReadScope;
 use crate::http::HttpError;
 use crate::listener::ListenerUrls;
 use crate::log::GatewayLog;
+use crate::target_addr::TargetAddr;
+use crate::target_pool::{self, SelectionStrategy, TargetPoolState};
+use crate::token::ApplicationProtocol;
 use crate::DgwState;
 
 pub fn make_router<S>(state: DgwState) -> Router<S> {
@@ -17,6 +20,8 @@ pub fn make_router<S>(state: DgwState) -> Router<S> {
         .route("/logs", get(get_logs))
         .route("/clock", get(get_clock))
         .route("/configuration", get(get_configuration))
+        .route("/resolve", get(get_resolution))
+        .route("/target-pools", get(get_target_pools))
         .with_state(state)
 }
 
@@ -102,6 +107,132 @@ impl ClockDiagnostic {
     }
 }
 
+/// Outcome of a destination name resolution
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[derive(Serialize)]
+pub(crate) struct ResolutionDiagnostic {
+    /// Resolved addresses, in order of preference
+    #[cfg_attr(feature = "openapi", schema(value_type = Vec<String>))]
+    addresses: Vec<std::net::SocketAddr>,
+    /// SRV records from which the addresses were resolved, in order of preference
+    srv_records: Vec<SrvRecordDiagnostic>,
+    /// Nameservers which were queried, empty when the system resolver was used
+    #[cfg_attr(feature = "openapi", schema(value_type = Vec<String>))]
+    nameservers: Vec<std::net::SocketAddr>,
+    /// Remaining time before the answer expires from the cache, in seconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ttl_secs: Option<u64>,
+}
+
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[derive(Serialize)]
+pub(crate) struct SrvRecordDiagnostic {
+    target: String,
+    port: u16,
+    priority: u16,
+    weight: u16,
+}
+
+impl From<crate::resolver::Resolution> for ResolutionDiagnostic {
+    fn from(resolution: crate::resolver::Resolution) -> Self {
+        Self {
+            addresses: resolution.addrs,
+            srv_records: resolution
+                .srv_records
+                .into_iter()
+                .map(|record| SrvRecordDiagnostic {
+                    target: record.target,
+                    port: record.port,
+                    priority: record.priority,
+                    weight: record.weight,
+                })
+                .collect(),
+            nameservers: resolution.nameservers,
+            ttl_secs: resolution.ttl.map(|ttl| ttl.as_secs()),
+        }
+    }
+}
+
+/// State of a target pool
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[derive(Serialize)]
+pub(crate) struct TargetPoolDiagnostic {
+    /// Name of the pool, referenced by the tokens as `pool://<NAME>`
+    name: String,
+    /// Selection strategy used when the token doesn’t specify one
+    strategy: SelectionStrategy,
+    /// Interval between two health checks, in seconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    health_check_interval_secs: Option<u64>,
+    /// Duration during which a failed target is taken out of rotation, in seconds
+    cooldown_secs: u64,
+    /// Targets of the pool
+    targets: Vec<PoolTargetDiagnostic>,
+}
+
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[derive(Serialize)]
+pub(crate) struct PoolTargetDiagnostic {
+    target: String,
+    /// Whether the target is in rotation
+    healthy: bool,
+    /// Remaining time before the target is put back in rotation, in seconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cooldown_remaining_secs: Option<u64>,
+    /// Error of the last failed connection or health check
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_error: Option<String>,
+    /// Time of the last successful connection or health check, in seconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_success_timestamp_secs: Option<i64>,
+    /// Number of running sessions to this target
+    active_sessions: usize,
+}
+
+impl TargetPoolDiagnostic {
+    fn new(
+        pool: &target_pool::TargetPool,
+        state: &TargetPoolState,
+        session_counts: &std::collections::HashMap<String, usize>,
+    ) -> Self {
+        let now = tokio::time::Instant::now();
+
+        let targets = pool
+            .targets
+            .iter()
+            .map(|target| {
+                let health = state.health(target);
+
+                PoolTargetDiagnostic {
+                    target: target.to_string(),
+                    healthy: health.is_healthy(),
+                    cooldown_remaining_secs: health
+                        .unhealthy_until
+                        .filter(|until| *until > now)
+                        .map(|until| (until - now).as_secs()),
+                    last_error: health.last_error,
+                    last_success_timestamp_secs: health.last_success.map(|time| time.unix_timestamp()),
+                    active_sessions: session_counts.get(target.as_str()).copied().unwrap_or(0),
+                }
+            })
+            .collect();
+
+        Self {
+            name: pool.name.clone(),
+            strategy: pool.strategy,
+            health_check_interval_secs: pool.health_check_interval.map(|interval| interval.as_secs()),
+            cooldown_secs: pool.cooldown.as_secs(),
+            targets,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ResolveQueryParam {
+    target: String,
+    protocol: Option<ApplicationProtocol>,
+}
+
 /// Retrieves latest logs.
 #[cfg_attr(feature = "openapi", utoipa::path(
     get,
@@ -180,3 +311,91 @@ async fn get_configuration(
 async fn get_clock() -> Json<ClockDiagnostic> {
     Json(ClockDiagnostic::now())
 }
+
+/// Resolves a destination the same way the Gateway does when connecting to a target.
+///
+/// The default port of the application protocol is used when the target is specified without a port, in which case
+/// SRV records are looked up if enabled.
+#[cfg_attr(feature = "openapi", utoipa::path(
+    get,
+    operation_id = "GetResolutionDiagnostic",
+    tag = "Diagnostics",
+    path = "/jet/diagnostics/resolve",
+    params(
+        ("target" = String, Query, description = "Destination to resolve (e.g.: rdp.corp.local, tcp://rdp.corp.local:3389)"),
+        ("protocol" = Option<String>, Query, description = "Application protocol (e.g.: rdp, ssh), defining the default port"),
+    ),
+    responses(
+        (status = 200, description = "Outcome of the name resolution", body = ResolutionDiagnostic),
+        (status = 400, description = "Bad request"),
+        (status = 401, description = "Invalid or missing authorization token"),
+        (status = 403, description = "Insufficient permissions"),
+        (status = 502, description = "Name resolution failed"),
+    ),
+    security(("scope_token" = ["gateway.diagnostics.read"])),
+))]
+async fn get_resolution(
+    State(DgwState { conf_handle, .. }): State<DgwState>,
+    _scope: DiagnosticsReadScope,
+    Query(query): Query<ResolveQueryParam>,
+) -> Result<Json<ResolutionDiagnostic>, HttpError> {
+    let conf = conf_handle.get_conf();
+
+    let default_port = query
+        .protocol
+        .as_ref()
+        .and_then(ApplicationProtocol::known_default_port);
+
+    let target = TargetAddr::parse(&query.target, default_port)
+        .map_err(HttpError::bad_request().with_msg("invalid target").err())?;
+
+    let resolution = conf
+        .outbound
+        .resolver
+        .resolve_detailed(&target)
+        .await
+        .map_err(HttpError::bad_gateway().with_msg("name resolution failed").err())?;
+
+    Ok(Json(ResolutionDiagnostic::from(resolution)))
+}
+
+/// Retrieves the state of the target pools.
+///
+/// Targets which recently failed are taken out of rotation until their cool-down ends.
+#[cfg_attr(feature = "openapi", utoipa::path(
+    get,
+    operation_id = "GetTargetPoolsDiagnostic",
+    tag = "Diagnostics",
+    path = "/jet/diagnostics/target-pools",
+    responses(
+        (status = 200, description = "State of the target pools", body = [TargetPoolDiagnostic]),
+        (status = 400, description = "Bad request"),
+        (status = 401, description = "Invalid or missing authorization token"),
+        (status = 403, description = "Insufficient permissions"),
+        (status = 500, description = "Failed to retrieve the running sessions"),
+    ),
+    security(("scope_token" = ["gateway.diagnostics.read"])),
+))]
+async fn get_target_pools(
+    State(DgwState {
+        conf_handle,
+        sessions,
+        target_pools,
+        ..
+    }): State<DgwState>,
+    _scope: DiagnosticsReadScope,
+) -> Result<Json<Vec<TargetPoolDiagnostic>>, HttpError> {
+    let conf = conf_handle.get_conf();
+
+    let session_counts = target_pool::count_sessions_by_target(&sessions)
+        .await
+        .map_err(HttpError::internal().err())?;
+
+    let pools = conf
+        .target_pools
+        .iter()
+        .map(|pool| TargetPoolDiagnostic::new(pool, &target_pools, &session_counts))
+        .collect();
+
+    Ok(Json(pools))
+}
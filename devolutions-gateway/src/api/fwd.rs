@@ -1,30 +1,37 @@
+use std::borrow::Cow;
+use std::io;
 use std::net::SocketAddr;
 use std::sync::Arc;
 
 use anyhow::Context as _;
-use axum::extract::ws::WebSocket;
+use axum::extract::ws::{self, WebSocket};
 use axum::extract::{self, ConnectInfo, State, WebSocketUpgrade};
 use axum::response::Response;
 use axum::routing::get;
 use axum::Router;
-use tokio::io::{AsyncRead, AsyncWrite};
+use futures::StreamExt as _;
+use tokio::sync::Notify;
 use tracing::{field, Instrument as _};
 use typed_builder::TypedBuilder;
 use uuid::Uuid;
 
 use crate::config::Conf;
+use crate::egress::EgressDenied;
 use crate::extract::AssociationToken;
 use crate::http::HttpError;
 use crate::proxy::Proxy;
+use crate::recording::events::SessionEvent;
 use crate::session::{ConnectionModeDetails, SessionInfo, SessionMessageSender};
 use crate::subscriber::SubscriberSender;
+use crate::target_pool::{self, TargetPoolState};
 use crate::token::{ApplicationProtocol, AssociationTokenClaims, ConnectionMode, Protocol};
-use crate::{utils, DgwState};
+use crate::{proxy_protocol, DgwState};
 
 pub fn make_router<S>(state: DgwState) -> Router<S> {
     Router::new()
         .route("/tcp/:id", get(fwd_tcp))
         .route("/tls/:id", get(fwd_tls))
+        .route("/udp/:id", get(fwd_udp))
         .with_state(state)
 }
 
@@ -33,6 +40,7 @@ async fn fwd_tcp(
         conf_handle,
         sessions,
         subscriber_tx,
+        target_pools,
         ..
     }): State<DgwState>,
     AssociationToken(claims): AssociationToken,
@@ -48,7 +56,17 @@ async fn fwd_tcp(
     let span = tracing::Span::current();
 
     let response = ws.on_upgrade(move |ws| {
-        handle_fwd(ws, conf, sessions, subscriber_tx, claims, source_addr, false).instrument(span)
+        handle_fwd(
+            ws,
+            conf,
+            sessions,
+            subscriber_tx,
+            target_pools,
+            claims,
+            source_addr,
+            false,
+        )
+        .instrument(span)
     });
 
     Ok(response)
@@ -59,6 +77,7 @@ async fn fwd_tls(
         conf_handle,
         sessions,
         subscriber_tx,
+        target_pools,
         ..
     }): State<DgwState>,
     AssociationToken(claims): AssociationToken,
@@ -74,37 +93,86 @@ async fn fwd_tls(
     let span = tracing::Span::current();
 
     let response = ws.on_upgrade(move |ws| {
-        handle_fwd(ws, conf, sessions, subscriber_tx, claims, source_addr, true).instrument(span)
+        handle_fwd(
+            ws,
+            conf,
+            sessions,
+            subscriber_tx,
+            target_pools,
+            claims,
+            source_addr,
+            true,
+        )
+        .instrument(span)
     });
 
     Ok(response)
 }
 
+async fn fwd_udp(
+    State(DgwState {
+        conf_handle,
+        sessions,
+        subscriber_tx,
+        ..
+    }): State<DgwState>,
+    AssociationToken(claims): AssociationToken,
+    extract::Path(session_id): extract::Path<Uuid>,
+    ConnectInfo(source_addr): ConnectInfo<SocketAddr>,
+    ws: WebSocketUpgrade,
+) -> Result<Response, HttpError> {
+    if session_id != claims.jet_aid {
+        return Err(HttpError::forbidden().msg("wrong session ID"));
+    }
+
+    let ConnectionMode::Fwd { targets, .. } = &claims.jet_cm else {
+        return Err(HttpError::bad_request().msg("invalid connection mode"));
+    };
+
+    if !targets
+        .iter()
+        .all(|target| target.scheme().eq_ignore_ascii_case(crate::udp::UDP_SCHEME))
+    {
+        return Err(HttpError::bad_request().msg("targets must use the udp scheme"));
+    }
+
+    let conf = conf_handle.get_conf();
+    let span = tracing::Span::current();
+
+    let response = ws
+        .on_upgrade(move |ws| handle_fwd_udp(ws, conf, sessions, subscriber_tx, claims, source_addr).instrument(span));
+
+    Ok(response)
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn handle_fwd(
     ws: WebSocket,
     conf: Arc<Conf>,
     sessions: SessionMessageSender,
     subscriber_tx: SubscriberSender,
+    target_pools: Arc<TargetPoolState>,
     claims: AssociationTokenClaims,
     source_addr: SocketAddr,
     with_tls: bool,
 ) {
-    let stream = crate::ws::websocket_compat(ws);
-
     let span = info_span!(
         "fwd",
         session_id = claims.jet_aid.to_string(),
         protocol = claims.jet_ap.to_string(),
-        target = field::Empty
+        target = field::Empty,
+        resolved = field::Empty,
+        nb_failed_attempts = field::Empty
     );
 
     let result = Forward::builder()
         .client_addr(source_addr)
-        .client_stream(stream)
+        .client_ws(ws)
         .conf(conf)
         .claims(claims)
         .sessions(sessions)
         .subscriber_tx(subscriber_tx)
+        .target_pools(target_pools)
         .with_tls(with_tls)
         .build()
         .run()
@@ -113,34 +181,75 @@ async fn handle_fwd(
 
     if let Err(error) = result {
         span.in_scope(|| {
-            error!(error = format!("{error:#}"), "WebSocket forwarding failure");
+            if EgressDenied::is_cause_of(&error) {
+                warn!(error = format!("{error:#}"), "Destination denied by the egress policy");
+            } else if is_certificate_rejection(&error) {
+                warn!(error = format!("{error:#}"), "Target certificate rejected");
+            } else {
+                error!(error = format!("{error:#}"), "WebSocket forwarding failure");
+            }
         });
     }
 }
 
+/// Close code sent when the target couldn’t be reached (registered as "Bad Gateway")
+const CLOSE_CODE_TARGET_UNREACHABLE: ws::CloseCode = 1014;
+
+/// Close code sent when the certificate presented by the target was rejected
+const CLOSE_CODE_TARGET_CERTIFICATE_REJECTED: ws::CloseCode = 4001;
+
+fn is_certificate_rejection(error: &anyhow::Error) -> bool {
+    error
+        .chain()
+        .filter_map(|cause| cause.downcast_ref::<io::Error>())
+        .any(|error| crate::tls::rejected_certificate_error(error).is_some())
+}
+
+/// Returns the close code and reason telling the client why the target couldn’t be reached.
+fn close_reason(error: &anyhow::Error) -> (ws::CloseCode, &'static str) {
+    if EgressDenied::is_cause_of(error) {
+        (ws::close_code::POLICY, "destination denied by the egress policy")
+    } else if is_certificate_rejection(error) {
+        (CLOSE_CODE_TARGET_CERTIFICATE_REJECTED, "target certificate rejected")
+    } else {
+        (CLOSE_CODE_TARGET_UNREACHABLE, "couldn’t connect to the target")
+    }
+}
+
+/// Closes the WebSocket with a code telling the client why the target couldn’t be reached.
+async fn close_with_error(mut ws: WebSocket, error: &anyhow::Error) {
+    let (code, reason) = close_reason(error);
+
+    let _ = ws
+        .send(ws::Message::Close(Some(ws::CloseFrame {
+            code,
+            reason: Cow::from(reason),
+        })))
+        .await;
+}
+
 #[derive(TypedBuilder)]
-struct Forward<S> {
+struct Forward {
     conf: Arc<Conf>,
     claims: AssociationTokenClaims,
-    client_stream: S,
+    client_ws: WebSocket,
     client_addr: SocketAddr,
     sessions: SessionMessageSender,
     subscriber_tx: SubscriberSender,
+    target_pools: Arc<TargetPoolState>,
     with_tls: bool,
 }
 
-impl<S> Forward<S>
-where
-    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
-{
+impl Forward {
     async fn run(self) -> anyhow::Result<()> {
         let Self {
             conf,
             claims,
-            client_stream,
+            client_ws,
             client_addr,
             sessions,
             subscriber_tx,
+            target_pools,
             with_tls,
         } = self;
 
@@ -148,7 +257,7 @@ where
             anyhow::bail!("can't meet recording policy");
         }
 
-        let ConnectionMode::Fwd { targets, .. } = claims.jet_cm else {
+        let ConnectionMode::Fwd { targets, .. } = &claims.jet_cm else {
             anyhow::bail!("invalid connection mode")
         };
 
@@ -156,12 +265,32 @@ where
 
         trace!("Select and connect to target");
 
-        let ((server_stream, server_addr), selected_target) =
-            utils::successive_try(&targets, utils::tcp_connect).await?;
+        let ((mut server_stream, server_addr), selected_target) =
+            match target_pool::connect_to_target(targets, claims.jet_lb, &conf, &target_pools, &sessions).await {
+                Ok(connection) => connection,
+                Err(error) => {
+                    close_with_error(client_ws, &error).await;
+                    return Err(error);
+                }
+            };
 
         trace!(%selected_target, "Connected");
         span.record("target", selected_target.to_string());
 
+        sessions.record_event(
+            claims.jet_aid,
+            claims.jet_rec,
+            SessionEvent::DestinationSelected {
+                destination: selected_target.to_string(),
+                address: server_addr,
+            },
+        );
+
+        // Sent before anything else, including the TLS handshake.
+        if claims.jet_pp || conf.outbound.sends_proxy_protocol(&selected_target, server_addr) {
+            proxy_protocol::send_header(&mut server_stream, client_addr, server_addr, claims.jet_aid).await?;
+        }
+
         // ARD uses MVS codec which doesn't like buffering.
         let buffer_size = if claims.jet_ap == ApplicationProtocol::Known(Protocol::Ard) {
             Some(1024)
@@ -174,9 +303,21 @@ where
 
             // Establish TLS connection with server
 
-            let server_stream = crate::tls::connect(selected_target.host(), server_stream)
-                .await
-                .context("TLS connect")?;
+            let tls_connect = async {
+                let connector = conf.outbound.tls.connector(&claims, &selected_target, server_addr)?;
+
+                crate::tls::connect(selected_target.host(), server_stream, &connector)
+                    .await
+                    .context("TLS connect")
+            };
+
+            let server_stream = match tls_connect.await {
+                Ok(server_stream) => server_stream,
+                Err(error) => {
+                    close_with_error(client_ws, &error).await;
+                    return Err(error);
+                }
+            };
 
             info!("WebSocket-TLS forwarding");
 
@@ -189,13 +330,16 @@ where
             )
             .with_ttl(claims.jet_ttl)
             .with_recording_policy(claims.jet_rec)
-            .with_filtering_policy(claims.jet_flt);
+            .with_filtering_policy(claims.jet_flt)
+            .with_capture_policy(claims.jet_cap)
+            .with_subject(claims.sub.clone())
+            .with_client_addr(client_addr);
 
             Proxy::builder()
                 .conf(conf)
                 .session_info(info)
                 .address_a(client_addr)
-                .transport_a(client_stream)
+                .transport_a(crate::ws::websocket_compat(client_ws))
                 .address_b(server_addr)
                 .transport_b(server_stream)
                 .sessions(sessions)
@@ -217,13 +361,16 @@ where
             )
             .with_ttl(claims.jet_ttl)
             .with_recording_policy(claims.jet_rec)
-            .with_filtering_policy(claims.jet_flt);
+            .with_filtering_policy(claims.jet_flt)
+            .with_capture_policy(claims.jet_cap)
+            .with_subject(claims.sub.clone())
+            .with_client_addr(client_addr);
 
             Proxy::builder()
                 .conf(conf)
                 .session_info(info)
                 .address_a(client_addr)
-                .transport_a(client_stream)
+                .transport_a(crate::ws::websocket_compat(client_ws))
                 .address_b(server_addr)
                 .transport_b(server_stream)
                 .sessions(sessions)
@@ -236,3 +383,148 @@ where
         }
     }
 }
+
+async fn handle_fwd_udp(
+    ws: WebSocket,
+    conf: Arc<Conf>,
+    sessions: SessionMessageSender,
+    subscriber_tx: SubscriberSender,
+    claims: AssociationTokenClaims,
+    source_addr: SocketAddr,
+) {
+    let span = info_span!(
+        "fwd_udp",
+        session_id = claims.jet_aid.to_string(),
+        protocol = claims.jet_ap.to_string(),
+        target = field::Empty
+    );
+
+    let result = forward_udp(ws, conf, sessions, subscriber_tx, claims, source_addr)
+        .instrument(span.clone())
+        .await;
+
+    if let Err(error) = result {
+        span.in_scope(|| {
+            if EgressDenied::is_cause_of(&error) {
+                warn!(error = format!("{error:#}"), "Destination denied by the egress policy");
+            } else {
+                error!(error = format!("{error:#}"), "UDP forwarding failure");
+            }
+        });
+    }
+}
+
+async fn forward_udp(
+    client_ws: WebSocket,
+    conf: Arc<Conf>,
+    sessions: SessionMessageSender,
+    subscriber_tx: SubscriberSender,
+    claims: AssociationTokenClaims,
+    client_addr: SocketAddr,
+) -> anyhow::Result<()> {
+    if claims.jet_rec {
+        anyhow::bail!("can't meet recording policy");
+    }
+
+    // Captures hold TCP segments only.
+    if claims.jet_cap {
+        anyhow::bail!("can't meet capture policy");
+    }
+
+    let ConnectionMode::Fwd { targets, .. } = &claims.jet_cm else {
+        anyhow::bail!("invalid connection mode")
+    };
+
+    let (socket, target_addr, selected_target) = match crate::udp::bind_for_target(targets, &conf.outbound).await {
+        Ok(binding) => binding,
+        Err(error) => {
+            close_with_error(client_ws, &error).await;
+            return Err(error);
+        }
+    };
+
+    tracing::Span::current().record("target", selected_target.to_string());
+
+    sessions.record_event(
+        claims.jet_aid,
+        claims.jet_rec,
+        SessionEvent::DestinationSelected {
+            destination: selected_target.to_string(),
+            address: target_addr,
+        },
+    );
+
+    let info = SessionInfo::new(
+        claims.jet_aid,
+        claims.jet_ap.clone(),
+        ConnectionModeDetails::Fwd {
+            destination_host: selected_target.clone(),
+        },
+    )
+    .with_ttl(claims.jet_ttl)
+    .with_recording_policy(claims.jet_rec)
+    .with_filtering_policy(claims.jet_flt)
+    .with_subject(claims.sub.clone())
+    .with_client_addr(client_addr);
+
+    let notify_kill = Arc::new(Notify::new());
+
+    // Datagrams are not captured, not even on demand.
+    crate::session::add_session_in_progress(&sessions, &subscriber_tx, info, Arc::clone(&notify_kill), None).await?;
+
+    info!(%target_addr, "WebSocket-UDP forwarding");
+
+    let (client_tx, client_rx) = client_ws.split();
+
+    let res = tokio::select! {
+        res = crate::udp::relay(client_rx, client_tx, &socket, target_addr, conf.outbound.udp_idle_timeout) => res,
+        () = notify_kill.notified() => Ok(()),
+    };
+
+    crate::session::remove_session_in_progress(&sessions, &subscriber_tx, claims.jet_aid).await?;
+
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio_rustls::rustls;
+
+    use super::*;
+
+    #[test]
+    fn rejected_certificate_close_code() {
+        let error = io::Error::new(
+            io::ErrorKind::InvalidData,
+            rustls::Error::InvalidCertificate(rustls::CertificateError::UnknownIssuer),
+        );
+        let error = anyhow::Error::new(error).context("TLS connect");
+
+        assert_eq!(close_reason(&error).0, CLOSE_CODE_TARGET_CERTIFICATE_REJECTED);
+    }
+
+    #[test]
+    fn unreachable_target_close_code() {
+        let error = anyhow::Error::new(io::Error::from(io::ErrorKind::ConnectionRefused)).context("connect");
+        assert_eq!(close_reason(&error).0, CLOSE_CODE_TARGET_UNREACHABLE);
+
+        // A TLS alert sent by the target is not a rejection of its certificate.
+        let error = io::Error::new(
+            io::ErrorKind::InvalidData,
+            rustls::Error::AlertReceived(rustls::AlertDescription::HandshakeFailure),
+        );
+        let error = anyhow::Error::new(error).context("TLS connect");
+        assert_eq!(close_reason(&error).0, CLOSE_CODE_TARGET_UNREACHABLE);
+    }
+
+    #[test]
+    fn denied_destination_close_code() {
+        let error = anyhow::Error::new(EgressDenied {
+            host: "169.254.169.254".to_owned(),
+            port: 80,
+            ip: None,
+        });
+
+        assert_eq!(close_reason(&error).0, ws::close_code::POLICY);
+    }
+}
@@ -1,12 +1,16 @@
 use std::net::SocketAddr;
+use std::sync::Arc;
 
 use axum::extract::ws::WebSocket;
 use axum::extract::{ConnectInfo, State, WebSocketUpgrade};
 use axum::response::Response;
+use axum::Extension;
 use tracing::Instrument as _;
 
+use crate::config::Conf;
 use crate::extract::JmuxToken;
 use crate::http::HttpError;
+use crate::listener::LocalAddr;
 use crate::session::SessionMessageSender;
 use crate::subscriber::SubscriberSender;
 use crate::token::JmuxTokenClaims;
@@ -14,29 +18,36 @@ use crate::DgwState;
 
 pub async fn handler(
     State(DgwState {
+        conf_handle,
         sessions,
         subscriber_tx,
         ..
     }): State<DgwState>,
     JmuxToken(claims): JmuxToken,
     ConnectInfo(source_addr): ConnectInfo<SocketAddr>,
+    Extension(LocalAddr(local_addr)): Extension<LocalAddr>,
     ws: WebSocketUpgrade,
 ) -> Result<Response, HttpError> {
-    let response = ws.on_upgrade(move |ws| handle_socket(ws, sessions, subscriber_tx, claims, source_addr));
+    let conf = conf_handle.get_conf();
+
+    let response =
+        ws.on_upgrade(move |ws| handle_socket(ws, conf, sessions, subscriber_tx, claims, source_addr, local_addr));
 
     Ok(response)
 }
 
 async fn handle_socket(
     ws: WebSocket,
+    conf: Arc<Conf>,
     sessions: SessionMessageSender,
     subscriber_tx: SubscriberSender,
     claims: JmuxTokenClaims,
     source_addr: SocketAddr,
+    local_addr: SocketAddr,
 ) {
     let stream = crate::ws::websocket_compat(ws);
 
-    let result = crate::jmux::handle(stream, claims, sessions, subscriber_tx)
+    let result = crate::jmux::handle(stream, claims, source_addr, local_addr, conf, sessions, subscriber_tx)
         .instrument(info_span!("jmux", client = %source_addr))
         .await;
 
@@ -1,29 +1,37 @@
-use std::fs;
-use std::net::SocketAddr;
-use std::path::Path;
+use std::cmp;
+use std::collections::HashSet;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::{Bound, Range};
 
-use anyhow::Context as _;
+use axum::body::Body;
 use axum::extract::ws::WebSocket;
 use axum::extract::{self, ConnectInfo, Query, State, WebSocketUpgrade};
-use axum::response::Response;
+use axum::http::{HeaderMap, HeaderName, HeaderValue, Method};
+use axum::response::{IntoResponse as _, Response};
 use axum::routing::{delete, get};
 use axum::{Json, Router};
+use axum_extra::body::AsyncReadBody;
+use axum_extra::headers::{self, HeaderMapExt as _};
 use devolutions_gateway_task::ShutdownSignal;
-use hyper::StatusCode;
+use hyper::{header, StatusCode};
+use tokio::sync::watch;
 use tracing::Instrument as _;
 use uuid::Uuid;
 
 use crate::extract::{JrecToken, RecordingDeleteScope, RecordingsReadScope};
 use crate::http::{HttpError, HttpErrorBuilder};
-use crate::recording::RecordingMessageSender;
+use crate::recording::index::{RecordingQuery, RecordingSortKey, SortOrder};
+use crate::recording::{LiveRecording, RecordingMessageSender};
 use crate::token::{JrecTokenClaims, RecordingFileType, RecordingOperation};
 use crate::DgwState;
 
 pub fn make_router<S>(state: DgwState) -> Router<S> {
     Router::new()
         .route("/push/:id", get(jrec_push))
+        .route("/shadow/:id", get(jrec_shadow))
         .route("/delete/:id", delete(jrec_delete))
         .route("/list", get(list_recordings))
+        .route("/export", get(export_recordings))
         .route("/pull/:id/:filename", get(pull_recording_file))
         .route("/play", get(get_player))
         .route("/play/*path", get(get_player))
@@ -34,6 +42,11 @@ pub fn make_router<S>(state: DgwState) -> Router<S> {
 #[serde(rename_all = "camelCase")]
 struct JrecPushQueryParam {
     file_type: RecordingFileType,
+    /// Name of the pushed stream, when several streams are pushed in parallel for the same session
+    stream: Option<String>,
+    /// Resume the last file of the recording if the client is reconnecting
+    #[serde(default)]
+    resume: bool,
 }
 
 async fn jrec_push(
@@ -52,16 +65,14 @@ async fn jrec_push(
         return Err(HttpError::forbidden().msg("expected push operation"));
     }
 
+    if let Some(stream) = &query.stream {
+        if !crate::recording::is_valid_stream_name(stream) {
+            return Err(HttpError::bad_request().msg("invalid stream name"));
+        }
+    }
+
     let response = ws.on_upgrade(move |ws| {
-        handle_jrec_push(
-            ws,
-            recordings,
-            shutdown_signal,
-            claims,
-            query.file_type,
-            session_id,
-            source_addr,
-        )
+        handle_jrec_push(ws, recordings, shutdown_signal, claims, query, session_id, source_addr)
     });
 
     Ok(response)
@@ -72,7 +83,7 @@ async fn handle_jrec_push(
     recordings: RecordingMessageSender,
     shutdown_signal: ShutdownSignal,
     claims: JrecTokenClaims,
-    file_type: RecordingFileType,
+    query: JrecPushQueryParam,
     session_id: Uuid,
     source_addr: SocketAddr,
 ) {
@@ -82,7 +93,9 @@ async fn handle_jrec_push(
         .client_stream(stream)
         .recordings(recordings)
         .claims(claims)
-        .file_type(file_type)
+        .file_type(query.file_type)
+        .stream(query.stream)
+        .resume(query.resume)
         .session_id(session_id)
         .shutdown_signal(shutdown_signal)
         .build()
@@ -95,6 +108,66 @@ async fn handle_jrec_push(
     }
 }
 
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct JrecShadowQueryParam {
+    /// Name of the stream to shadow, the default stream if absent
+    stream: Option<String>,
+}
+
+async fn jrec_shadow(
+    State(DgwState {
+        shutdown_signal,
+        recordings,
+        ..
+    }): State<DgwState>,
+    JrecToken(claims): JrecToken,
+    Query(query): Query<JrecShadowQueryParam>,
+    extract::Path(session_id): extract::Path<Uuid>,
+    ConnectInfo(source_addr): ConnectInfo<SocketAddr>,
+    ws: WebSocketUpgrade,
+) -> Result<Response, HttpError> {
+    if claims.jet_rop != RecordingOperation::Pull {
+        return Err(HttpError::forbidden().msg("expected pull operation"));
+    }
+
+    if session_id != claims.jet_aid {
+        return Err(HttpError::forbidden().msg("not allowed to shadow this recording"));
+    }
+
+    let live = recordings
+        .subscribe_live(session_id, query.stream)
+        .await
+        .map_err(HttpError::internal().err())?
+        .ok_or_else(|| HttpError::not_found().msg("no on-going recording for this session"))?;
+
+    let response = ws.on_upgrade(move |ws| handle_jrec_shadow(ws, live, shutdown_signal, source_addr));
+
+    Ok(response)
+}
+
+async fn handle_jrec_shadow(
+    ws: WebSocket,
+    live: watch::Receiver<LiveRecording>,
+    shutdown_signal: ShutdownSignal,
+    source_addr: SocketAddr,
+) {
+    let stream = crate::ws::websocket_compat(ws);
+
+    let result = crate::recording::ClientShadow::builder()
+        .client_stream(stream)
+        .live(live)
+        .shutdown_signal(shutdown_signal)
+        .build()
+        .run()
+        .instrument(info_span!("jrec-shadow", client = %source_addr))
+        .await;
+
+    if let Err(error) = result {
+        error!(client = %source_addr, error = format!("{error:#}"), "WebSocket-JREC shadowing failure");
+    }
+}
+
 /// Lists all recordings stored on this instance
 #[cfg_attr(feature = "openapi", utoipa::path(
     delete,
@@ -112,8 +185,8 @@ async fn handle_jrec_push(
 ))]
 async fn jrec_delete(
     State(DgwState {
-        conf_handle,
         recordings,
+        recording_storage,
         ..
     }): State<DgwState>,
     _scope: RecordingDeleteScope,
@@ -130,23 +203,62 @@ async fn jrec_delete(
         );
     }
 
-    let recording_path = conf_handle.get_conf().recording_path.join(session_id.to_string());
-
-    debug!(%recording_path, "Delete recording");
+    debug!(%session_id, "Delete recording");
 
-    tokio::fs::remove_dir_all(recording_path)
+    recording_storage
+        .delete_recording(session_id)
         .await
         .map_err(HttpError::internal().with_msg("failed to delete recording").err())?;
 
+    recordings.index.remove(session_id);
+
     Ok(())
 }
 
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ListRecordingsQueryParam {
+    from: Option<i64>,
+    to: Option<i64>,
+    protocol: Option<String>,
+    destination: Option<String>,
+    subject: Option<String>,
+    source_ip: Option<IpAddr>,
+    gateway_id: Option<Uuid>,
+    #[serde(default)]
+    sort: RecordingSortKey,
+    #[serde(default)]
+    order: SortOrder,
+    #[serde(default)]
+    offset: usize,
+    limit: Option<usize>,
+    /// Returns the manifests instead of the IDs only
+    #[serde(default)]
+    detailed: bool,
+}
+
 /// Lists all recordings stored on this instance
+///
+/// The total number of matching recordings, before pagination, is returned in the `X-Total-Count` header.
 #[cfg_attr(feature = "openapi", utoipa::path(
     get,
     operation_id = "ListRecordings",
     tag = "Jrec",
     path = "/jet/jrec/list",
+    params(
+        ("from" = Option<i64>, Query, description = "Only recordings still running at or after this Unix timestamp"),
+        ("to" = Option<i64>, Query, description = "Only recordings started before this Unix timestamp"),
+        ("protocol" = Option<String>, Query, description = "Application protocol of the recorded session"),
+        ("destination" = Option<String>, Query, description = "Destination, or destination host, of the recorded session"),
+        ("subject" = Option<String>, Query, description = "Subject of the token used to establish the recorded session"),
+        ("sourceIp" = Option<String>, Query, description = "IP address of the client which established the recorded session"),
+        ("gatewayId" = Option<Uuid>, Query, description = "ID of the Gateway instance which handled the recorded session"),
+        ("sort" = Option<String>, Query, description = "Sort key: startTime (default) or duration"),
+        ("order" = Option<String>, Query, description = "Sort order: asc or desc (default)"),
+        ("offset" = Option<usize>, Query, description = "Number of recordings to skip"),
+        ("limit" = Option<usize>, Query, description = "Maximum number of recordings to return"),
+        ("detailed" = Option<bool>, Query, description = "Return the recording manifests instead of the IDs only"),
+    ),
     responses(
         (status = 200, description = "List of recordings on this Gateway instance", body = [Uuid]),
         (status = 400, description = "Bad request"),
@@ -156,42 +268,127 @@ async fn jrec_delete(
     security(("scope_token" = ["gateway.recordings.read"])),
 ))]
 pub(crate) async fn list_recordings(
-    State(DgwState { conf_handle, .. }): State<DgwState>,
+    State(DgwState { recordings, .. }): State<DgwState>,
     _scope: RecordingsReadScope,
-) -> Result<Json<Vec<Uuid>>, HttpError> {
-    let conf = conf_handle.get_conf();
-    let recording_path = conf.recording_path.as_std_path();
+    Query(query): Query<ListRecordingsQueryParam>,
+) -> Result<Response, HttpError> {
+    let detailed = query.detailed;
+
+    let query = RecordingQuery {
+        from: query.from,
+        to: query.to,
+        protocol: query.protocol,
+        destination: query.destination,
+        subject: query.subject,
+        source_ip: query.source_ip,
+        gateway_id: query.gateway_id,
+        sort: query.sort,
+        order: query.order,
+        offset: query.offset,
+        limit: query.limit,
+    };
+
+    let (total, manifests) = recordings.index.query(&query);
 
-    let dirs = if recording_path.exists() {
-        list_uuid_dirs(recording_path).map_err(HttpError::internal().with_msg("failed recording listing").err())?
+    let mut response = if detailed {
+        Json(manifests).into_response()
     } else {
-        // If the recording directory does not exist, it means that there is no recording yet
-        Vec::new()
+        Json(
+            manifests
+                .into_iter()
+                .map(|manifest| manifest.session_id)
+                .collect::<Vec<_>>(),
+        )
+        .into_response()
     };
 
-    return Ok(Json(dirs));
-
-    fn list_uuid_dirs(dir_path: &Path) -> anyhow::Result<Vec<Uuid>> {
-        let read_dir = fs::read_dir(dir_path).context("couldn’t read directory")?;
-
-        let list = read_dir
-            .filter_map(|entry| {
-                let path = entry.ok()?.path();
-                if path.is_dir() {
-                    let file_name = path.file_name()?.to_str()?;
-                    let uuid = Uuid::parse_str(file_name).ok()?;
-                    Some(uuid)
-                } else {
-                    None
-                }
-            })
-            .collect();
-
-        Ok(list)
+    response
+        .headers_mut()
+        .insert(HeaderName::from_static("x-total-count"), HeaderValue::from(total));
+
+    Ok(response)
+}
+
+#[derive(Deserialize)]
+pub(crate) struct ExportRecordingsQueryParam {
+    /// IDs of the recordings to export, the parameter being repeated for each recording
+    #[serde(rename = "id")]
+    ids: Vec<Uuid>,
+}
+
+/// Exports one or several recordings as a TAR archive
+///
+/// The archive is generated on the fly. Each recording is stored in a folder named after its ID, containing
+/// the manifest, the recording files and a `SHA256SUMS` file.
+#[cfg_attr(feature = "openapi", utoipa::path(
+    get,
+    operation_id = "ExportRecordings",
+    tag = "Jrec",
+    path = "/jet/jrec/export",
+    params(
+        ("id" = Vec<Uuid>, Query, description = "ID of a recording to export (repeat the parameter to export several recordings)"),
+    ),
+    responses(
+        (status = 200, description = "TAR archive of the recordings", body = Vec<u8>, content_type = "application/x-tar"),
+        (status = 400, description = "Bad request"),
+        (status = 401, description = "Invalid or missing authorization token"),
+        (status = 403, description = "Insufficient permissions"),
+        (status = 404, description = "Recording not found"),
+    ),
+    security(("scope_token" = ["gateway.recordings.read"])),
+))]
+pub(crate) async fn export_recordings(
+    State(DgwState { recording_storage, .. }): State<DgwState>,
+    _scope: RecordingsReadScope,
+    axum_extra::extract::Query(query): axum_extra::extract::Query<ExportRecordingsQueryParam>,
+) -> Result<Response, HttpError> {
+    let mut ids = query.ids;
+
+    let mut seen = HashSet::new();
+    ids.retain(|id| seen.insert(*id));
+
+    if ids.is_empty() {
+        return Err(HttpError::bad_request().msg("no recording to export"));
+    }
+
+    let mut manifests = Vec::with_capacity(ids.len());
+
+    for id in ids {
+        let manifest = recording_storage
+            .read_manifest(id)
+            .await
+            .map_err(
+                HttpError::internal()
+                    .with_msg("failed to read recording manifest")
+                    .err(),
+            )?
+            .ok_or_else(|| HttpError::not_found().msg("recording not found"))?;
+
+        manifests.push(manifest);
     }
+
+    let archive_name = match manifests.as_slice() {
+        [manifest] => format!("recording-{}.tar", manifest.session_id),
+        _ => "recordings.tar".to_owned(),
+    };
+
+    let reader = crate::recording::export::bundle_reader(recording_storage, manifests);
+
+    let mut response = AsyncReadBody::new(reader).into_response();
+
+    let content_disposition = HeaderValue::from_str(&format!("attachment; filename=\"{archive_name}\""))
+        .map_err(HttpError::internal().err())?;
+
+    let response_headers = response.headers_mut();
+    response_headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("application/x-tar"));
+    response_headers.insert(header::CONTENT_DISPOSITION, content_disposition);
+
+    Ok(response)
 }
 
 /// Retrieves a recording file for a given session
+///
+/// Supports single byte range requests (`Range`, `If-Range`) and conditional requests (`If-None-Match`).
 #[cfg_attr(feature = "openapi", utoipa::path(
     get,
     operation_id = "PullRecordingFile",
@@ -203,24 +400,23 @@ pub(crate) async fn list_recordings(
     ),
     responses(
         (status = 200, description = "Recording file", body = Vec<u8>),
+        (status = 206, description = "Requested range of the recording file", body = Vec<u8>),
+        (status = 304, description = "Recording file not modified"),
         (status = 400, description = "Bad request"),
         (status = 401, description = "Invalid or missing authorization token"),
         (status = 403, description = "Insufficient permissions"),
         (status = 404, description = "File not found"),
+        (status = 416, description = "Requested range not satisfiable"),
     ),
     security(("jrec_token" = ["pull"])),
 ))]
-pub(crate) async fn pull_recording_file<ReqBody>(
-    State(DgwState { conf_handle, .. }): State<DgwState>,
+pub(crate) async fn pull_recording_file(
+    State(DgwState { recording_storage, .. }): State<DgwState>,
     extract::Path((id, filename)): extract::Path<(Uuid, String)>,
     JrecToken(claims): JrecToken,
-    request: axum::http::Request<ReqBody>,
-) -> Result<Response<tower_http::services::fs::ServeFileSystemResponseBody>, HttpError>
-where
-    ReqBody: Send + 'static,
-{
-    use tower::ServiceExt as _;
-
+    method: Method,
+    headers: HeaderMap,
+) -> Result<Response, HttpError> {
     if filename.contains("..") || filename.contains('/') || filename.contains('\\') {
         return Err(HttpError::bad_request().msg("invalid file name"));
     }
@@ -229,22 +425,135 @@ where
         return Err(HttpError::forbidden().msg("not allowed to read this recording"));
     }
 
-    let path = conf_handle
-        .get_conf()
-        .recording_path
-        .join(id.to_string())
-        .join(filename);
+    let info = recording_storage
+        .file_info(id, &filename)
+        .await
+        .map_err(
+            HttpError::internal()
+                .with_msg("failed to read recording file metadata")
+                .err(),
+        )?
+        .ok_or_else(|| HttpError::not_found().msg("requested file does not exist"))?;
+
+    let etag = info
+        .etag
+        .parse::<headers::ETag>()
+        .map_err(|_| HttpError::internal().msg("invalid entity tag"))?;
+    let last_modified = info.last_modified.map(headers::LastModified::from);
+
+    if let Some(if_none_match) = headers.typed_get::<headers::IfNoneMatch>() {
+        if !if_none_match.precondition_passes(&etag) {
+            let mut response = StatusCode::NOT_MODIFIED.into_response();
+            response.headers_mut().typed_insert(etag);
+            return Ok(response);
+        }
+    }
+
+    let range = match requested_range(&headers, info.size, &etag, last_modified.as_ref()) {
+        Ok(range) => range,
+        Err(RangeNotSatisfiable) => {
+            let mut response = StatusCode::RANGE_NOT_SATISFIABLE.into_response();
+            response
+                .headers_mut()
+                .typed_insert(headers::ContentRange::unsatisfied_bytes(info.size));
+            return Ok(response);
+        }
+    };
+
+    let (status, body_range) = match range {
+        Some(range) => (StatusCode::PARTIAL_CONTENT, range),
+        None => (StatusCode::OK, 0..info.size),
+    };
+
+    let mut response = if method == Method::HEAD {
+        Response::new(Body::empty())
+    } else {
+        let reader = recording_storage
+            .open_file(id, &filename, body_range.clone())
+            .await
+            .map_err(HttpError::internal().with_msg("failed to open recording file").err())?;
+
+        AsyncReadBody::new(reader).into_response()
+    };
+
+    *response.status_mut() = status;
+
+    let response_headers = response.headers_mut();
+
+    if status == StatusCode::PARTIAL_CONTENT {
+        let content_range = headers::ContentRange::bytes(body_range.clone(), info.size)
+            .map_err(|_| HttpError::internal().msg("invalid content range"))?;
+        response_headers.typed_insert(content_range);
+    }
+
+    response_headers.typed_insert(headers::ContentLength(body_range.end - body_range.start));
+    response_headers.typed_insert(headers::AcceptRanges::bytes());
+    response_headers.typed_insert(etag);
 
-    if !path.exists() || !path.is_file() {
-        return Err(HttpError::not_found().msg("requested file does not exist"));
+    if let Some(last_modified) = last_modified {
+        response_headers.typed_insert(last_modified);
     }
 
-    let response = tower_http::services::ServeFile::new(path)
-        .oneshot(request)
-        .await
-        .map_err(HttpError::internal().err())?;
+    response_headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type(&filename)));
+
+    return Ok(response);
+
+    struct RangeNotSatisfiable;
+
+    /// Returns the single byte range to serve, if any.
+    ///
+    /// Multiple ranges are not supported, in which case the whole file is served, as permitted by RFC 9110.
+    fn requested_range(
+        headers: &HeaderMap,
+        size: u64,
+        etag: &headers::ETag,
+        last_modified: Option<&headers::LastModified>,
+    ) -> Result<Option<Range<u64>>, RangeNotSatisfiable> {
+        let Some(range) = headers.typed_get::<headers::Range>() else {
+            return Ok(None);
+        };
+
+        // The range only applies if the representation is unchanged since the client got it.
+        if let Some(if_range) = headers.typed_get::<headers::IfRange>() {
+            if if_range.is_modified(Some(etag), last_modified) {
+                return Ok(None);
+            }
+        }
+
+        let mut ranges = range.satisfiable_ranges(size);
+
+        let (Some((start, end)), None) = (ranges.next(), ranges.next()) else {
+            return Ok(None);
+        };
+
+        let start = match start {
+            Bound::Included(start) => start,
+            Bound::Excluded(start) => start.saturating_add(1),
+            Bound::Unbounded => 0,
+        };
+
+        let end = match end {
+            Bound::Included(end) => end.saturating_add(1),
+            Bound::Excluded(end) => end,
+            Bound::Unbounded => size,
+        };
+
+        let end = cmp::min(end, size);
+
+        if start >= end {
+            return Err(RangeNotSatisfiable);
+        }
+
+        Ok(Some(start..end))
+    }
 
-    Ok(response)
+    fn content_type(filename: &str) -> &'static str {
+        match filename.rsplit_once('.').map(|(_, extension)| extension) {
+            Some("webm") => "video/webm",
+            Some("json") => "application/json",
+            _ => "application/octet-stream",
+        }
+    }
 }
 
 async fn get_player<ReqBody>(
@@ -9,9 +9,10 @@ use picky_krb::messages::KdcProxyMessage;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpStream, UdpSocket};
 
+use crate::egress::EgressDenied;
 use crate::http::{HttpError, HttpErrorBuilder};
 use crate::token::AccessTokenClaims;
-use crate::DgwState;
+use crate::{utils, DgwState};
 
 pub fn make_router<S>(state: DgwState) -> Router<S> {
     Router::new().route("/:token", post(kdc_proxy)).with_state(state)
@@ -84,9 +85,9 @@ async fn kdc_proxy(
 
     let kdc_reply_message = if protocol == "tcp" {
         #[allow(clippy::redundant_closure)] // We get a better caller location for the error by using a closure.
-        let mut connection = TcpStream::connect(kdc_addr.as_addr())
+        let (mut connection, _) = utils::tcp_connect(kdc_addr, &conf.outbound)
             .await
-            .map_err(|e| unable_to_reach_kdc_server_err(e))?;
+            .map_err(|e| unable_to_connect_kdc_server_err(e))?;
 
         trace!("Connected! Forwarding KDC message...");
 
@@ -118,12 +119,20 @@ async fn kdc_proxy(
             .await
             .map_err(HttpError::internal().with_msg("unable to bind UDP socket").err())?;
 
+        #[allow(clippy::redundant_closure)] // We get a better caller location for the error by using a closure.
+        let kdc_socket_addr = utils::resolve_allowed(kdc_addr, &conf.outbound)
+            .await
+            .map_err(|e| unable_to_connect_kdc_server_err(e))?
+            .into_iter()
+            .next()
+            .ok_or_else(|| HttpError::bad_gateway().msg("KDC server address could not be resolved"))?;
+
         trace!("Binded! Forwarding KDC message...");
 
         // first 4 bytes contains message length. we don't need it for UDP
         #[allow(clippy::redundant_closure)] // We get a better caller location for the error by using a closure.
         udp_socket
-            .send_to(&kdc_proxy_message.kerb_message.0 .0[4..], kdc_addr.as_addr())
+            .send_to(&kdc_proxy_message.kerb_message.0 .0[4..], kdc_socket_addr)
             .await
             .map_err(|e| unable_to_reach_kdc_server_err(e))?;
 
@@ -157,6 +166,29 @@ async fn read_kdc_reply_message(connection: &mut TcpStream) -> std::io::Result<V
     Ok(buf)
 }
 
+/// Destinations denied by the egress policy are distinguished from unreachable ones.
+#[track_caller]
+fn unable_to_connect_kdc_server_err(error: anyhow::Error) -> HttpError {
+    if EgressDenied::is_cause_of(&error) {
+        return HttpError::forbidden()
+            .with_msg("KDC server denied by the egress policy")
+            .build(error);
+    }
+
+    if error.is::<tokio::time::error::Elapsed>() {
+        return HttpErrorBuilder::new(StatusCode::GATEWAY_TIMEOUT)
+            .with_msg("unable to reach KDC server")
+            .build(error);
+    }
+
+    match error.downcast::<io::Error>() {
+        Ok(error) => unable_to_reach_kdc_server_err(error),
+        Err(error) => HttpError::bad_gateway()
+            .with_msg("unable to reach KDC server")
+            .build(error),
+    }
+}
+
 #[track_caller]
 fn unable_to_reach_kdc_server_err(error: io::Error) -> HttpError {
     use io::ErrorKind;
@@ -11,6 +11,7 @@ use crate::http::HttpError;
 use crate::recording::ActiveRecordings;
 use crate::session::SessionMessageSender;
 use crate::subscriber::SubscriberSender;
+use crate::target_pool::TargetPoolState;
 use crate::token::{CurrentJrl, TokenCache};
 use crate::DgwState;
 
@@ -22,6 +23,7 @@ pub async fn handler(
         sessions,
         subscriber_tx,
         recordings,
+        target_pools,
         ..
     }): State<DgwState>,
     ConnectInfo(source_addr): ConnectInfo<SocketAddr>,
@@ -39,6 +41,7 @@ pub async fn handler(
             sessions,
             subscriber_tx,
             recordings.active_recordings,
+            target_pools,
             source_addr,
         )
         .instrument(span)
@@ -56,6 +59,7 @@ async fn handle_socket(
     sessions: SessionMessageSender,
     subscriber_tx: SubscriberSender,
     active_recordings: Arc<ActiveRecordings>,
+    target_pools: Arc<TargetPoolState>,
     source_addr: SocketAddr,
 ) {
     let stream = crate::ws::websocket_compat(ws);
@@ -69,6 +73,7 @@ async fn handle_socket(
         sessions,
         subscriber_tx,
         &active_recordings,
+        &target_pools,
     )
     .await;
 
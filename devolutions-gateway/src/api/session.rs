@@ -3,14 +3,15 @@ use axum::routing::post;
 use axum::Router;
 use uuid::Uuid;
 
-use crate::extract::SessionTerminateScope;
+use crate::extract::{SessionCaptureScope, SessionTerminateScope};
 use crate::http::HttpError;
-use crate::session::KillResult;
+use crate::session::{CaptureResult, KillResult};
 use crate::DgwState;
 
 pub fn make_router<S>(state: DgwState) -> Router<S> {
     Router::new()
         .route("/:id/terminate", post(terminate_session))
+        .route("/:id/capture", post(start_capture))
         .with_state(state)
 }
 
@@ -47,3 +48,43 @@ pub(crate) async fn terminate_session(
         KillResult::NotFound => Err(HttpError::not_found().msg("session not found")),
     }
 }
+
+/// Start capturing the traffic of a running session
+///
+/// The traffic is written in a PCAPNG file of the capture folder until the session ends.
+/// Nothing is done if the traffic of this session is already captured.
+///
+/// Plain TCP sessions forwarded using splice(2) (see `Outbound.ZeroCopy`) can't be captured.
+#[cfg_attr(feature = "openapi", utoipa::path(
+    post,
+    operation_id = "StartSessionCapture",
+    tag = "Sessions",
+    path = "/jet/session/{id}/capture",
+    params(
+        ("id" = Uuid, Path, description = "Session / association ID of the session to capture")
+    ),
+    responses(
+        (status = 200, description = "Traffic capture started"),
+        (status = 400, description = "Bad request, or the traffic of this session can't be captured"),
+        (status = 401, description = "Invalid or missing authorization token"),
+        (status = 403, description = "Insufficient permissions"),
+        (status = 404, description = "No running session found with provided ID"),
+        (status = 500, description = "Unexpected server error"),
+    ),
+    security(("scope_token" = ["gateway.session.capture"])),
+))]
+pub(crate) async fn start_capture(
+    State(DgwState { sessions, .. }): State<DgwState>,
+    axum::extract::Path(session_id): axum::extract::Path<Uuid>,
+    _scope: SessionCaptureScope,
+) -> Result<(), HttpError> {
+    match sessions
+        .start_capture(session_id)
+        .await
+        .map_err(HttpError::internal().err())?
+    {
+        CaptureResult::Success => Ok(()),
+        CaptureResult::NotFound => Err(HttpError::not_found().msg("session not found")),
+        CaptureResult::NotSupported => Err(HttpError::bad_request().msg("traffic of this session can't be captured")),
+    }
+}
@@ -18,6 +18,7 @@ use crate::config::{WebAppAuth, WebAppConf, WebAppUser};
 use crate::extract::WebAppToken;
 use crate::http::HttpError;
 use crate::target_addr::TargetAddr;
+use crate::tls::CertThumbprint;
 use crate::token::ApplicationProtocol;
 use crate::DgwState;
 
@@ -230,6 +231,12 @@ pub(crate) enum SessionTokenContentType {
         destination: TargetAddr,
         /// Unique ID for this session
         session_id: Uuid,
+        /// SHA-256 thumbprints of the certificates accepted for the destination, instead of verifying the chain
+        #[serde(default)]
+        tls_pin: Vec<CertThumbprint>,
+        /// Do not verify the certificate of the destination
+        #[serde(default)]
+        tls_insecure: bool,
     },
     Jmux {
         /// Protocol for the session (e.g.: "tunnel")
@@ -326,6 +333,8 @@ pub(crate) async fn sign_session_token(
             protocol,
             destination,
             session_id,
+            tls_pin,
+            tls_insecure,
         } => (
             AssociationTokenClaims {
                 jet_aid: session_id,
@@ -336,7 +345,14 @@ pub(crate) async fn sign_session_token(
                 },
                 jet_rec: false,
                 jet_flt: false,
+                jet_cap: false,
+                jet_pp: false,
+                jet_lb: None,
+                jet_tls_pin: tls_pin,
+                jet_tls_insecure: tls_insecure,
+                jet_tls_identity: None,
                 jet_ttl: crate::token::SessionTtl::Unlimited,
+                sub: Some(web_app_token.sub.clone()),
                 exp,
                 jti: Some(jti),
             }
@@ -363,6 +379,8 @@ pub(crate) async fn sign_session_token(
                 jet_ap: protocol,
                 hosts: nonempty::NonEmpty::new(destination.clone()),
                 jet_ttl: crate::token::SessionTtl::Unlimited,
+                jet_cap: false,
+                sub: Some(web_app_token.sub.clone()),
                 exp,
                 jti,
             }
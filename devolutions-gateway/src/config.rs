@@ -1,6 +1,11 @@
+use crate::egress::{DestinationRule, EgressPolicy};
 use crate::listener::ListenerUrls;
+use crate::resolver::Resolver;
 use crate::target_addr::TargetAddr;
+use crate::target_pool::TargetPool;
+use crate::tls::TargetTls;
 use crate::token::Subkey;
+use crate::upstream_proxy::UpstreamProxyRules;
 use anyhow::Context;
 use camino::{Utf8Path, Utf8PathBuf};
 use cfg_if::cfg_if;
@@ -23,6 +28,10 @@ const CERTIFICATE_LABELS: &[&str] = &["CERTIFICATE", "X509 CERTIFICATE", "TRUSTE
 const PRIVATE_KEY_LABELS: &[&str] = &["PRIVATE KEY", "RSA PRIVATE KEY", "EC PRIVATE KEY"];
 const WEB_APP_TOKEN_DEFAULT_LIFETIME_SECS: u64 = 28800; // 8 hours
 const WEB_APP_DEFAULT_LOGIN_LIMIT_RATE: u8 = 10;
+const OUTBOUND_DEFAULT_CONNECT_TIMEOUT_SECS: u64 = 10;
+const OUTBOUND_DEFAULT_ATTEMPT_TIMEOUT_SECS: u64 = 5;
+const OUTBOUND_DEFAULT_ATTEMPT_DELAY_MS: u64 = 250; // Recommended by RFC 8305
+const OUTBOUND_DEFAULT_UDP_IDLE_TIMEOUT_SECS: u64 = 60;
 const ENV_VAR_DGATEWAY_WEBAPP_PATH: &str = "DGATEWAY_WEBAPP_PATH";
 
 cfg_if! {
@@ -46,7 +55,11 @@ pub struct TlsPublicKey(pub Vec<u8>);
 
 #[derive(Clone)]
 pub struct Tls {
+    /// Acceptor for raw JMUX sessions, without ALPN
     pub acceptor: tokio_rustls::TlsAcceptor,
+    /// Acceptor for the HTTPS listeners, negotiating HTTP/2 or HTTP/1.1
+    pub http_acceptor: tokio_rustls::TlsAcceptor,
+    pub server_config: Arc<rustls::ServerConfig>,
 }
 
 impl fmt::Debug for Tls {
@@ -59,9 +72,19 @@ impl Tls {
     fn init(cert_source: crate::tls::CertificateSource) -> anyhow::Result<Self> {
         let tls_server_config = crate::tls::build_server_config(cert_source).context("failed build TLS config")?;
 
-        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(tls_server_config));
+        let server_config = Arc::new(tls_server_config);
 
-        Ok(Self { acceptor })
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::clone(&server_config));
+
+        let mut http_server_config = rustls::ServerConfig::clone(&server_config);
+        http_server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        let http_acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(http_server_config));
+
+        Ok(Self {
+            acceptor,
+            http_acceptor,
+            server_config,
+        })
     }
 }
 
@@ -79,14 +102,51 @@ pub struct Conf {
     pub delegation_private_key: Option<PrivateKey>,
     pub plugins: Option<Vec<Utf8PathBuf>>,
     pub recording_path: Utf8PathBuf,
+    pub capture_path: Utf8PathBuf,
+    pub recording_storage: dto::RecordingStorageConf,
     pub sogar: dto::SogarConf,
     pub jrl_file: Utf8PathBuf,
     pub ngrok: Option<dto::NgrokConf>,
     pub verbosity_profile: dto::VerbosityProfile,
     pub web_app: WebAppConf,
+    pub outbound: OutboundConf,
+    pub proxy_protocol: ProxyProtocolConf,
+    pub target_pools: Vec<TargetPool>,
     pub debug: dto::DebugConf,
 }
 
+/// Options for the PROXY protocol headers received on the listeners
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+pub struct ProxyProtocolConf {
+    /// Sources from which a PROXY protocol header is expected (PROXY protocol is disabled when empty)
+    pub trusted_sources: Vec<ipnet::IpNet>,
+}
+
+/// Options for the connections established toward the targets
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct OutboundConf {
+    /// Maximum duration for connecting to a target, including the name resolution
+    pub connect_timeout: std::time::Duration,
+    /// Maximum duration of a single connection attempt to one of the resolved addresses
+    pub attempt_timeout: std::time::Duration,
+    /// Delay before starting the next connection attempt while the previous ones are still pending
+    pub attempt_delay: std::time::Duration,
+    /// Duration without any datagram after which a UDP forwarding session ends
+    pub udp_idle_timeout: std::time::Duration,
+    /// Destinations the Gateway is allowed to connect to
+    pub egress: EgressPolicy,
+    /// Upstream proxies through which some destinations are reached
+    pub proxies: UpstreamProxyRules,
+    /// Destinations to which a PROXY protocol header is sent
+    pub send_proxy_protocol: Vec<DestinationRule>,
+    /// Resolver for the destination host names
+    pub resolver: Resolver,
+    /// Trust anchors for the certificates of the targets
+    pub tls: TargetTls,
+    /// Whether plain TCP sessions are forwarded using splice(2), on Linux
+    pub zero_copy: bool,
+}
+
 #[derive(PartialEq, Debug, Clone)]
 pub struct WebAppConf {
     pub enabled: bool,
@@ -146,7 +206,7 @@ impl Conf {
 
         let requires_tls = listeners
             .iter()
-            .any(|l| matches!(l.internal_url.scheme(), "https" | "wss"));
+            .any(|l| matches!(l.internal_url.scheme(), "https" | "wss" | "quic"));
 
         let tls = match conf_file.tls_certificate_source.unwrap_or_default() {
             _ if !requires_tls => {
@@ -237,6 +297,12 @@ impl Conf {
             .unwrap_or_else(|| Utf8PathBuf::from("recordings"))
             .pipe_ref(|path| normalize_data_path(path, &data_dir));
 
+        let capture_path = conf_file
+            .capture_path
+            .clone()
+            .unwrap_or_else(|| Utf8PathBuf::from("captures"))
+            .pipe_ref(|path| normalize_data_path(path, &data_dir));
+
         let provisioner_public_key = read_pub_key(
             conf_file.provisioner_public_key_file.as_deref(),
             conf_file.provisioner_public_key_data.as_ref(),
@@ -275,6 +341,22 @@ impl Conf {
             }
         }
 
+        let target_pools = conf_file
+            .target_pools
+            .iter()
+            .map(|pool| TargetPool::from_dto(pool).with_context(|| format!("target pool {}", pool.name)))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        for (idx, pool) in target_pools.iter().enumerate() {
+            anyhow::ensure!(
+                !target_pools[..idx]
+                    .iter()
+                    .any(|other| other.name.eq_ignore_ascii_case(&pool.name)),
+                "duplicated target pool name: {}",
+                pool.name,
+            );
+        }
+
         Ok(Conf {
             id: conf_file.id,
             hostname,
@@ -288,6 +370,8 @@ impl Conf {
             delegation_private_key,
             plugins: conf_file.plugins.clone(),
             recording_path,
+            capture_path,
+            recording_storage: conf_file.recording_storage.clone().unwrap_or_default(),
             sogar: conf_file.sogar.clone().unwrap_or_default(),
             jrl_file,
             ngrok: conf_file.ngrok.clone(),
@@ -298,11 +382,121 @@ impl Conf {
                 .map(WebAppConf::from_dto)
                 .unwrap_or_else(WebAppConf::from_env)
                 .context("webapp config")?,
+            outbound: conf_file
+                .outbound
+                .as_ref()
+                .map(OutboundConf::from_dto)
+                .transpose()
+                .context("outbound config")?
+                .unwrap_or_default(),
+            proxy_protocol: conf_file
+                .proxy_protocol
+                .as_ref()
+                .map(ProxyProtocolConf::from_dto)
+                .transpose()
+                .context("PROXY protocol config")?
+                .unwrap_or_default(),
+            target_pools,
             debug: conf_file.debug.clone().unwrap_or_default(),
         })
     }
 }
 
+impl OutboundConf {
+    fn from_dto(value: &dto::OutboundConf) -> anyhow::Result<Self> {
+        use std::time::Duration;
+
+        let egress = value
+            .egress
+            .as_ref()
+            .map(EgressPolicy::from_dto)
+            .transpose()
+            .context("invalid egress policy")?
+            .unwrap_or_default();
+
+        let proxies = UpstreamProxyRules::from_dto(&value.proxies).context("invalid upstream proxy rule")?;
+
+        let send_proxy_protocol = value
+            .send_proxy_protocol
+            .iter()
+            .enumerate()
+            .map(|(idx, rule)| DestinationRule::from_dto(rule).with_context(|| format!("rule #{idx}")))
+            .collect::<anyhow::Result<Vec<_>>>()
+            .context("invalid SendProxyProtocol rule")?;
+
+        let resolver = value
+            .dns
+            .as_ref()
+            .map(Resolver::from_dto)
+            .transpose()
+            .context("invalid DNS configuration")?
+            .unwrap_or_default();
+
+        let tls = value
+            .tls
+            .as_ref()
+            .map(TargetTls::from_dto)
+            .transpose()
+            .context("invalid target TLS configuration")?
+            .unwrap_or_default();
+
+        Ok(Self {
+            connect_timeout: Duration::from_secs(
+                value.connect_timeout.unwrap_or(OUTBOUND_DEFAULT_CONNECT_TIMEOUT_SECS),
+            ),
+            attempt_timeout: Duration::from_secs(
+                value.attempt_timeout.unwrap_or(OUTBOUND_DEFAULT_ATTEMPT_TIMEOUT_SECS),
+            ),
+            attempt_delay: Duration::from_millis(value.attempt_delay.unwrap_or(OUTBOUND_DEFAULT_ATTEMPT_DELAY_MS)),
+            udp_idle_timeout: Duration::from_secs(
+                value.udp_idle_timeout.unwrap_or(OUTBOUND_DEFAULT_UDP_IDLE_TIMEOUT_SECS),
+            ),
+            egress,
+            proxies,
+            send_proxy_protocol,
+            resolver,
+            tls,
+            zero_copy: value.zero_copy.unwrap_or(false),
+        })
+    }
+
+    /// Returns true when a PROXY protocol header must be sent to this destination.
+    pub fn sends_proxy_protocol(&self, dest: &TargetAddr, addr: std::net::SocketAddr) -> bool {
+        self.send_proxy_protocol
+            .iter()
+            .any(|rule| rule.matches(dest.host(), Some(addr.ip()), dest.port()))
+    }
+}
+
+impl ProxyProtocolConf {
+    fn from_dto(value: &dto::ProxyProtocolConf) -> anyhow::Result<Self> {
+        let trusted_sources = value
+            .trusted_sources
+            .iter()
+            .map(|source| {
+                source
+                    .parse::<ipnet::IpNet>()
+                    .or_else(|_| source.parse::<std::net::IpAddr>().map(ipnet::IpNet::from))
+                    .with_context(|| format!("invalid trusted source: {source}"))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        Ok(Self { trusted_sources })
+    }
+
+    /// Returns true when a PROXY protocol header is expected from this peer.
+    pub fn is_trusted(&self, peer_ip: std::net::IpAddr) -> bool {
+        let peer_ip = crate::egress::canonical_ip(peer_ip);
+        self.trusted_sources.iter().any(|source| source.contains(&peer_ip))
+    }
+}
+
+impl Default for OutboundConf {
+    fn default() -> Self {
+        Self::from_dto(&dto::OutboundConf::default()).expect("default outbound configuration is valid")
+    }
+}
+
 impl WebAppConf {
     fn from_dto(value: &dto::WebAppConf) -> anyhow::Result<Self> {
         let authentication = match value.authentication {
@@ -546,6 +740,29 @@ fn default_hostname() -> Option<String> {
     hostname::get().ok()?.into_string().ok()
 }
 
+pub(crate) fn read_client_identity(
+    conf: &dto::ClientIdentityConf,
+) -> anyhow::Result<(Vec<rustls::Certificate>, rustls::PrivateKey)> {
+    match conf.certificate_file.extension() {
+        Some("pfx" | "p12") => {
+            read_pfx_file(&conf.certificate_file, conf.private_key_password.as_ref()).context("read PFX/PKCS12 file")
+        }
+        None | Some(_) => {
+            let certificates =
+                read_rustls_certificate_file(&conf.certificate_file).context("read client certificate")?;
+
+            let private_key = conf
+                .private_key_file
+                .as_ref()
+                .context("client private key file is missing")?
+                .pipe_deref(read_rustls_priv_key_file)
+                .context("read client private key")?;
+
+            Ok((certificates, private_key))
+        }
+    }
+}
+
 fn read_pfx_file(
     path: &Utf8Path,
     password: Option<&dto::Password>,
@@ -657,7 +874,7 @@ fn read_pfx_file(
     Ok((certificates, private_key))
 }
 
-fn read_rustls_certificate_file(path: &Utf8Path) -> anyhow::Result<Vec<rustls::Certificate>> {
+pub(crate) fn read_rustls_certificate_file(path: &Utf8Path) -> anyhow::Result<Vec<rustls::Certificate>> {
     read_rustls_certificate(Some(path), None).transpose().unwrap()
 }
 
@@ -936,6 +1153,14 @@ pub mod dto {
         #[serde(skip_serializing_if = "Option::is_none")]
         pub recording_path: Option<Utf8PathBuf>,
 
+        /// Where recordings are persisted (defaults to the recordings folder)
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub recording_storage: Option<RecordingStorageConf>,
+
+        /// Path to the folder where the traffic captures are stored
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub capture_path: Option<Utf8PathBuf>,
+
         /// Ngrok config (closely maps https://ngrok.com/docs/ngrok-agent/config/)
         #[serde(skip_serializing_if = "Option::is_none")]
         pub ngrok: Option<NgrokConf>,
@@ -948,6 +1173,18 @@ pub mod dto {
         #[serde(skip_serializing_if = "Option::is_none")]
         pub web_app: Option<WebAppConf>,
 
+        /// Options for the connections established toward the targets
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub outbound: Option<OutboundConf>,
+
+        /// PROXY protocol headers expected from the load balancers in front of the Gateway
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub proxy_protocol: Option<ProxyProtocolConf>,
+
+        /// Named pools of targets, referenced by the tokens as `pool://<NAME>`
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        pub target_pools: Vec<TargetPoolConf>,
+
         /// (Unstable) Folder and prefix for log files
         #[serde(skip_serializing_if = "Option::is_none")]
         pub log_file: Option<Utf8PathBuf>,
@@ -1011,7 +1248,12 @@ pub mod dto {
                 jrl_file: None,
                 plugins: None,
                 recording_path: None,
+                recording_storage: None,
+                capture_path: None,
                 web_app: None,
+                outbound: None,
+                proxy_protocol: None,
+                target_pools: Vec::new(),
                 sogar: None,
                 debug: None,
                 rest: serde_json::Map::new(),
@@ -1077,7 +1319,7 @@ pub mod dto {
 
         /// Folder where pcap recordings should be stored
         ///
-        /// Providing this option will cause the PCAP interceptor to be attached to each stream.
+        /// Providing this option will cause the traffic of every session to be captured in this folder.
         pub capture_path: Option<Utf8PathBuf>,
 
         /// Enable unstable API which may break at any point
@@ -1296,6 +1538,43 @@ pub mod dto {
         pub deny_cidrs: Vec<String>,
     }
 
+    #[derive(PartialEq, Eq, Debug, Clone, Default, Serialize, Deserialize)]
+    #[serde(tag = "Kind")]
+    pub enum RecordingStorageConf {
+        /// Recordings are kept in the recordings folder
+        #[default]
+        Local,
+        /// Recordings are uploaded to an S3-compatible object storage
+        ///
+        /// The recordings folder is still used to hold the files of on-going recordings.
+        S3(S3StorageConf),
+    }
+
+    #[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+    #[serde(rename_all = "PascalCase")]
+    pub struct S3StorageConf {
+        /// Name of the bucket
+        pub bucket: String,
+        /// Region of the bucket (e.g.: us-east-1)
+        pub region: String,
+        /// Custom endpoint for S3-compatible services such as MinIO (e.g.: http://localhost:9000)
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub endpoint: Option<String>,
+        /// Access key ID
+        pub access_key_id: String,
+        /// Secret access key
+        pub secret_access_key: Password,
+        /// Prefix prepended to all object keys (e.g.: recordings/)
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub key_prefix: Option<String>,
+        /// Use path-style addressing (required by most S3-compatible services)
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub force_path_style: Option<bool>,
+        /// Size of the parts used for multipart uploads, in MiB (minimum is 5)
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub part_size: Option<u64>,
+    }
+
     #[derive(PartialEq, Eq, Debug, Clone, Copy, Default, Serialize, Deserialize)]
     pub enum CertSource {
         /// Provided by filesystem
@@ -1406,4 +1685,198 @@ pub mod dto {
         Custom,
         None,
     }
+
+    #[derive(PartialEq, Eq, Debug, Clone, Default, Serialize, Deserialize)]
+    #[serde(rename_all = "PascalCase")]
+    pub struct OutboundConf {
+        /// Maximum duration for connecting to a target, including the name resolution, in seconds
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub connect_timeout: Option<u64>,
+        /// Maximum duration of a single connection attempt to one of the resolved addresses, in seconds
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub attempt_timeout: Option<u64>,
+        /// Delay before starting the next connection attempt while the previous ones are still pending, in milliseconds
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub attempt_delay: Option<u64>,
+        /// Duration without any datagram after which a UDP forwarding session ends, in seconds
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub udp_idle_timeout: Option<u64>,
+        /// Policy restricting the destinations the Gateway is allowed to connect to
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub egress: Option<EgressPolicyConf>,
+        /// Rules selecting the upstream proxy through which a destination is reached
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        pub proxies: Vec<UpstreamProxyConf>,
+        /// Destinations to which a PROXY protocol (v2) header is sent, advertising the address of the client
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        pub send_proxy_protocol: Vec<DestinationRuleConf>,
+        /// Name resolution of the destinations
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub dns: Option<DnsConf>,
+        /// Verification of the certificates presented by the targets
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub tls: Option<TargetTlsConf>,
+        /// Forward plain TCP sessions using splice(2) on Linux; their traffic can't be captured on demand
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub zero_copy: Option<bool>,
+    }
+
+    #[derive(PartialEq, Eq, Debug, Clone, Default, Serialize, Deserialize)]
+    #[serde(rename_all = "PascalCase")]
+    pub struct TargetTlsConf {
+        /// Source of the certificates trusted when verifying the targets (defaults to System)
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub ca_source: Option<TargetCaSource>,
+        /// Path to a PEM bundle of trusted CA certificates, used when CaSource is Custom
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub ca_bundle_file: Option<Utf8PathBuf>,
+        /// Client certificates presented to the targets requiring mutual TLS
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        pub client_identities: Vec<ClientIdentityConf>,
+        /// Verification of the certificates when the token doesn’t pin them (defaults to Verify)
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub default_policy: Option<TargetTlsPolicy>,
+    }
+
+    #[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+    #[serde(rename_all = "PascalCase")]
+    pub struct ClientIdentityConf {
+        /// Name of the identity, used by the tokens to select it
+        pub name: String,
+        /// Path to the certificate chain (PEM), or to a PFX/PKCS12 file holding both the chain and the key
+        pub certificate_file: Utf8PathBuf,
+        /// Path to the private key (PEM), unused for PFX/PKCS12 files
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub private_key_file: Option<Utf8PathBuf>,
+        /// Password of the PFX/PKCS12 file
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub private_key_password: Option<Password>,
+        /// Destinations to which this identity is presented when the token doesn’t select one
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        pub destinations: Vec<DestinationRuleConf>,
+    }
+
+    #[derive(PartialEq, Eq, Debug, Clone, Copy, Default, Serialize, Deserialize)]
+    pub enum TargetTlsPolicy {
+        /// The certificate chain and the name of the targets are verified
+        #[default]
+        Verify,
+        /// The certificates of the targets are not verified, as if all the tokens held the `jet_tls_insecure` claim
+        Insecure,
+    }
+
+    #[derive(PartialEq, Eq, Debug, Clone, Copy, Default, Serialize, Deserialize)]
+    pub enum TargetCaSource {
+        /// Certificates trusted by the operating system
+        #[default]
+        System,
+        /// Certificates of the CA bundle file
+        Custom,
+    }
+
+    #[derive(PartialEq, Eq, Debug, Clone, Default, Serialize, Deserialize)]
+    #[serde(rename_all = "PascalCase")]
+    pub struct DnsConf {
+        /// Nameservers used to resolve the destinations (e.g.: 10.0.0.53, 10.0.0.54:5353), instead of the system resolver
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        pub nameservers: Vec<String>,
+        /// Nameservers used to resolve the destinations under a given domain suffix
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        pub domains: Vec<DnsDomainConf>,
+        /// Maximum number of answers kept in cache
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub cache_size: Option<usize>,
+        /// Look up SRV records (e.g.: _rdp._tcp.<HOST>) for the destinations specified without a port
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub srv_lookup: Option<bool>,
+    }
+
+    #[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+    #[serde(rename_all = "PascalCase")]
+    pub struct DnsDomainConf {
+        /// Domain suffix (e.g.: corp.local), matching the domain itself and all its subdomains
+        pub suffix: String,
+        /// Nameservers used to resolve the domains under this suffix
+        pub nameservers: Vec<String>,
+    }
+
+    #[derive(PartialEq, Eq, Debug, Clone, Default, Serialize, Deserialize)]
+    #[serde(rename_all = "PascalCase")]
+    pub struct ProxyProtocolConf {
+        /// Sources from which a PROXY protocol header is expected (e.g.: 10.0.0.0/8, 192.168.1.10)
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        pub trusted_sources: Vec<String>,
+    }
+
+    #[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+    #[serde(rename_all = "PascalCase")]
+    pub struct TargetPoolConf {
+        /// Name of the pool, referenced by the tokens as `pool://<NAME>`
+        pub name: String,
+        /// Targets of the pool (e.g.: rdp-01.example.com:3389)
+        pub targets: Vec<String>,
+        /// Selection strategy used when the token doesn’t specify one (defaults to RoundRobin)
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub strategy: Option<TargetSelectionStrategy>,
+        /// Interval in seconds between two health checks of the targets (no health check when unset)
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub health_check_interval: Option<u64>,
+        /// Duration in seconds during which a failed target is taken out of rotation (defaults to 30)
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub cooldown: Option<u64>,
+    }
+
+    #[derive(PartialEq, Eq, Debug, Clone, Copy, Serialize, Deserialize)]
+    pub enum TargetSelectionStrategy {
+        /// Each new session starts with the next target
+        RoundRobin,
+        /// Targets with the fewest running sessions first
+        LeastConnections,
+        /// Targets in random order
+        Random,
+    }
+
+    #[derive(PartialEq, Eq, Debug, Clone, Default, Serialize, Deserialize)]
+    #[serde(rename_all = "PascalCase")]
+    pub struct EgressPolicyConf {
+        /// When not empty, only the destinations matching at least one of these rules are allowed
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        pub allow: Vec<DestinationRuleConf>,
+        /// Destinations matching any of these rules are denied, even if they match an allow rule
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        pub deny: Vec<DestinationRuleConf>,
+    }
+
+    #[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+    #[serde(rename_all = "PascalCase")]
+    pub struct DestinationRuleConf {
+        /// Network of the resolved address (e.g.: 10.0.0.0/8, 169.254.169.254)
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub cidr: Option<String>,
+        /// Host name of the destination, possibly with wildcards (e.g.: *.example.com)
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub host: Option<String>,
+        /// Port or inclusive range of ports (e.g.: 22, 1-1024)
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub ports: Option<String>,
+    }
+
+    #[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+    #[serde(rename_all = "PascalCase")]
+    pub struct UpstreamProxyConf {
+        /// Network of the destination address (e.g.: 10.0.0.0/8)
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub cidr: Option<String>,
+        /// Host name of the destination, possibly with wildcards (e.g.: *.example.com)
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub host: Option<String>,
+        /// URL of the proxy (e.g.: socks5://proxy.example.com:1080, http://proxy.example.com:3128)
+        pub url: String,
+        /// Username to authenticate with
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub username: Option<String>,
+        /// Password to authenticate with
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub password: Option<Password>,
+    }
 }
@@ -0,0 +1,306 @@
+//! Gateway-side egress policy, restricting the destinations the Gateway is allowed to connect to.
+//!
+//! Tokens only describe where a session is supposed to go. This policy protects the networks reachable by the
+//! Gateway (loopback, link-local metadata services, management network…) regardless of the tokens presented.
+//!
+//! The policy is evaluated after name resolution, so that a host name can’t be used to reach a denied network.
+//! Destinations reached through an upstream proxy by host name are not resolved by the Gateway. Their address is
+//! unknown, so the policy fails closed: network deny rules matching the port deny them, and they are only allowed
+//! when the allow list is empty or contains a matching host rule.
+
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::RangeInclusive;
+
+use anyhow::Context as _;
+use ipnet::IpNet;
+use tap::prelude::*;
+
+use crate::config::dto;
+
+/// Error returned when a destination is denied by the egress policy.
+#[derive(Debug, Clone, thiserror::Error)]
+pub struct EgressDenied {
+    pub host: String,
+    pub port: u16,
+    /// Resolved address, when the destination was resolved by the Gateway
+    pub ip: Option<IpAddr>,
+}
+
+impl fmt::Display for EgressDenied {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.ip {
+            Some(ip) => write!(
+                f,
+                "destination {}:{} ({}) is denied by the egress policy",
+                self.host,
+                self.port,
+                SocketAddr::new(ip, self.port)
+            ),
+            None => write!(
+                f,
+                "destination {}:{} is denied by the egress policy",
+                self.host, self.port
+            ),
+        }
+    }
+}
+
+impl EgressDenied {
+    /// Returns true when the error is caused by a destination denied by the egress policy.
+    pub fn is_cause_of(error: &anyhow::Error) -> bool {
+        error.downcast_ref::<EgressDenied>().is_some()
+    }
+}
+
+/// Allow and deny rules applied to all outbound connections.
+///
+/// A destination is denied when it matches any deny rule. Otherwise, it is allowed when there is no allow rule,
+/// or when it matches at least one of them.
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+pub struct EgressPolicy {
+    allow: Vec<DestinationRule>,
+    deny: Vec<DestinationRule>,
+}
+
+impl EgressPolicy {
+    pub fn from_dto(value: &dto::EgressPolicyConf) -> anyhow::Result<Self> {
+        let parse_all = |rules: &[dto::DestinationRuleConf]| {
+            rules
+                .iter()
+                .enumerate()
+                .map(|(idx, rule)| DestinationRule::from_dto(rule).with_context(|| format!("rule #{idx}")))
+                .collect::<anyhow::Result<Vec<_>>>()
+        };
+
+        Ok(Self {
+            allow: parse_all(&value.allow).context("invalid allow rule")?,
+            deny: parse_all(&value.deny).context("invalid deny rule")?,
+        })
+    }
+
+    /// Checks whether connecting to `addr`, resolved from `host`, is allowed.
+    pub fn check(&self, host: &str, addr: SocketAddr) -> Result<(), EgressDenied> {
+        self.check_impl(host, Some(addr.ip()), addr.port())
+    }
+
+    /// Checks whether connecting to `host` without resolving it first (e.g.: through an upstream proxy) is allowed.
+    pub fn check_unresolved(&self, host: &str, port: u16) -> Result<(), EgressDenied> {
+        self.check_impl(host, None, port)
+    }
+
+    fn check_impl(&self, host: &str, ip: Option<IpAddr>, port: u16) -> Result<(), EgressDenied> {
+        // The unresolved destinations may end up in any network.
+        let is_denied = self
+            .deny
+            .iter()
+            .any(|rule| rule.matches(host, ip, port) || (ip.is_none() && rule.may_match_unresolved(port)));
+        let is_allowed = self.allow.is_empty() || self.allow.iter().any(|rule| rule.matches(host, ip, port));
+
+        if is_denied || !is_allowed {
+            Err(EgressDenied {
+                host: host.to_owned(),
+                port,
+                ip,
+            })
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Rule matching destinations by network or host name, and by port.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct DestinationRule {
+    destination: DestinationMatcher,
+    ports: Option<RangeInclusive<u16>>,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+enum DestinationMatcher {
+    Any,
+    Network(IpNet),
+    /// Host name, possibly containing wildcards (e.g.: `*.example.com`)
+    Host(String),
+}
+
+impl DestinationRule {
+    pub fn from_dto(value: &dto::DestinationRuleConf) -> anyhow::Result<Self> {
+        let destination = match (&value.cidr, &value.host) {
+            (Some(_), Some(_)) => anyhow::bail!("Cidr and Host can’t be both specified"),
+            (Some(cidr), None) => cidr
+                .parse::<IpNet>()
+                .or_else(|_| cidr.parse::<IpAddr>().map(IpNet::from))
+                .with_context(|| format!("invalid CIDR: {cidr}"))?
+                .pipe(DestinationMatcher::Network),
+            (None, Some(host)) => DestinationMatcher::Host(host.clone()),
+            (None, None) => DestinationMatcher::Any,
+        };
+
+        let ports = value
+            .ports
+            .as_deref()
+            .map(parse_port_range)
+            .transpose()
+            .context("invalid port range")?;
+
+        Ok(Self { destination, ports })
+    }
+
+    /// Network rules never match when `ip` is unknown (i.e.: the destination was not resolved).
+    ///
+    /// Use [`Self::may_match_unresolved`] to fail closed on such destinations.
+    pub fn matches(&self, host: &str, ip: Option<IpAddr>, port: u16) -> bool {
+        let destination_matches = match &self.destination {
+            DestinationMatcher::Any => true,
+            DestinationMatcher::Network(network) => ip.map_or(false, |ip| network.contains(&canonical_ip(ip))),
+            DestinationMatcher::Host(pattern) => crate::utils::wildcard_host_match(pattern, host),
+        };
+
+        destination_matches && self.port_matches(port)
+    }
+
+    /// Returns true when the rule could match an unresolved destination, depending on the address it resolves to.
+    pub fn may_match_unresolved(&self, port: u16) -> bool {
+        matches!(self.destination, DestinationMatcher::Network(_)) && self.port_matches(port)
+    }
+
+    fn port_matches(&self, port: u16) -> bool {
+        self.ports.as_ref().map_or(true, |ports| ports.contains(&port))
+    }
+}
+
+/// IPv4-mapped IPv6 addresses are matched as the IPv4 address they represent.
+pub(crate) fn canonical_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        IpAddr::V4(_) => ip,
+    }
+}
+
+/// Parses a single port (e.g.: `22`) or an inclusive range of ports (e.g.: `1-1024`).
+fn parse_port_range(value: &str) -> anyhow::Result<RangeInclusive<u16>> {
+    let (start, end) = value.split_once('-').unwrap_or((value, value));
+
+    let start = start
+        .trim()
+        .parse::<u16>()
+        .with_context(|| format!("invalid port: {start}"))?;
+    let end = end
+        .trim()
+        .parse::<u16>()
+        .with_context(|| format!("invalid port: {end}"))?;
+
+    anyhow::ensure!(start <= end, "empty port range: {value}");
+
+    Ok(start..=end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(cidr: Option<&str>, host: Option<&str>, ports: Option<&str>) -> dto::DestinationRuleConf {
+        dto::DestinationRuleConf {
+            cidr: cidr.map(str::to_owned),
+            host: host.map(str::to_owned),
+            ports: ports.map(str::to_owned),
+        }
+    }
+
+    #[test]
+    fn deny_rules_take_precedence() {
+        let policy = EgressPolicy::from_dto(&dto::EgressPolicyConf {
+            allow: vec![rule(Some("10.0.0.0/8"), None, None)],
+            deny: vec![rule(Some("10.0.0.0/24"), None, Some("22"))],
+        })
+        .unwrap();
+
+        assert!(policy.check("server", "10.1.0.1:3389".parse().unwrap()).is_ok());
+        assert!(policy.check("server", "10.0.0.1:3389".parse().unwrap()).is_ok());
+        assert!(policy.check("server", "10.0.0.1:22".parse().unwrap()).is_err());
+        assert!(policy.check("server", "192.168.0.1:3389".parse().unwrap()).is_err());
+    }
+
+    #[test]
+    fn network_rules_apply_to_resolved_addresses() {
+        let policy = EgressPolicy::from_dto(&dto::EgressPolicyConf {
+            allow: Vec::new(),
+            deny: vec![
+                rule(Some("127.0.0.0/8"), None, None),
+                rule(Some("169.254.169.254"), None, None),
+            ],
+        })
+        .unwrap();
+
+        assert!(policy
+            .check("innocent.example.com", "127.0.0.1:80".parse().unwrap())
+            .is_err());
+        assert!(policy
+            .check("innocent.example.com", "[::ffff:127.0.0.1]:80".parse().unwrap())
+            .is_err());
+        assert!(policy.check("metadata", "169.254.169.254:80".parse().unwrap()).is_err());
+        assert!(policy
+            .check("innocent.example.com", "93.184.216.34:80".parse().unwrap())
+            .is_ok());
+    }
+
+    #[test]
+    fn host_rules_support_wildcards_and_port_ranges() {
+        let policy = EgressPolicy::from_dto(&dto::EgressPolicyConf {
+            allow: vec![rule(None, Some("*.corp.local"), Some("1000-2000"))],
+            deny: Vec::new(),
+        })
+        .unwrap();
+
+        assert!(policy.check("rdp.corp.local", "10.0.0.1:1500".parse().unwrap()).is_ok());
+        assert!(policy
+            .check("rdp.corp.local", "10.0.0.1:3389".parse().unwrap())
+            .is_err());
+        assert!(policy
+            .check("rdp.other.local", "10.0.0.1:1500".parse().unwrap())
+            .is_err());
+    }
+
+    #[test]
+    fn unresolved_destinations_fail_closed() {
+        let policy = EgressPolicy::from_dto(&dto::EgressPolicyConf {
+            allow: vec![
+                rule(Some("10.0.0.0/8"), None, None),
+                rule(None, Some("*.corp.local"), None),
+            ],
+            deny: vec![rule(Some("0.0.0.0/0"), None, Some("22"))],
+        })
+        .unwrap();
+
+        // Network allow rules can't vouch for an unresolved destination, but network deny rules block it.
+        assert!(policy.check_unresolved("rdp.corp.local", 3389).is_ok());
+        assert!(policy.check_unresolved("rdp.corp.local", 22).is_err());
+        assert!(policy.check_unresolved("rdp.other.local", 3389).is_err());
+    }
+
+    #[test]
+    fn deny_cidr_blocks_unresolved_destinations() {
+        let policy = EgressPolicy::from_dto(&dto::EgressPolicyConf {
+            allow: Vec::new(),
+            deny: vec![rule(Some("169.254.169.254"), None, None)],
+        })
+        .unwrap();
+
+        let error = policy.check_unresolved("metadata.internal", 80).unwrap_err();
+        assert_eq!(error.host, "metadata.internal");
+        assert_eq!(error.ip, None);
+
+        // Without any network deny rule, the same destination is allowed.
+        assert!(EgressPolicy::default()
+            .check_unresolved("metadata.internal", 80)
+            .is_ok());
+    }
+
+    #[test]
+    fn invalid_rules_are_rejected() {
+        assert!(DestinationRule::from_dto(&rule(Some("10.0.0.0/33"), None, None)).is_err());
+        assert!(DestinationRule::from_dto(&rule(Some("10.0.0.0/8"), Some("host"), None)).is_err());
+        assert!(DestinationRule::from_dto(&rule(None, None, Some("2000-1000"))).is_err());
+    }
+}
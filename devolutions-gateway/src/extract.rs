@@ -161,6 +161,25 @@ where
     }
 }
 
+#[derive(Clone, Copy)]
+pub struct SessionCaptureScope;
+
+#[async_trait]
+impl<S> FromRequestParts<S> for SessionCaptureScope
+where
+    S: Send + Sync,
+{
+    type Rejection = HttpError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        match ScopeToken::from_request_parts(parts, state).await?.0.scope {
+            AccessScope::Wildcard => Ok(Self),
+            AccessScope::SessionCapture => Ok(Self),
+            _ => Err(HttpError::forbidden().msg("invalid scope for route")),
+        }
+    }
+}
+
 #[derive(Clone, Copy)]
 pub struct AssociationsReadScope;
 
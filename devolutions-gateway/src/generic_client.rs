@@ -3,17 +3,39 @@ use std::sync::Arc;
 
 use anyhow::Context as _;
 use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt as _};
+use tokio::net::TcpStream;
 use tracing::field;
 use typed_builder::TypedBuilder;
 
 use crate::config::Conf;
 use crate::proxy::Proxy;
+use crate::proxy_protocol;
 use crate::rdp_pcb::{extract_association_claims, read_pcb};
+use crate::recording::events::SessionEvent;
 use crate::recording::ActiveRecordings;
+use crate::rendezvous::{Connector, RendezvousState};
 use crate::session::{ConnectionModeDetails, SessionInfo, SessionMessageSender};
 use crate::subscriber::SubscriberSender;
+use crate::target_pool::{self, TargetPoolState};
 use crate::token::{ConnectionMode, CurrentJrl, TokenCache};
-use crate::utils;
+
+/// Stream of a client connected to a generic listener.
+pub trait ClientStream: AsyncWrite + AsyncRead + Unpin + Send + Sized + 'static {
+    /// Returns the plain TCP stream of the client, if the client is directly connected over TCP.
+    fn into_tcp_stream(self) -> Result<TcpStream, Self>;
+}
+
+impl ClientStream for TcpStream {
+    fn into_tcp_stream(self) -> Result<TcpStream, Self> {
+        Ok(self)
+    }
+}
+
+impl ClientStream for transport::ErasedReadWrite {
+    fn into_tcp_stream(self) -> Result<TcpStream, Self> {
+        Err(self)
+    }
+}
 
 #[derive(TypedBuilder)]
 pub struct GenericClient<S> {
@@ -25,16 +47,24 @@ pub struct GenericClient<S> {
     sessions: SessionMessageSender,
     subscriber_tx: SubscriberSender,
     active_recordings: Arc<ActiveRecordings>,
+    target_pools: Arc<TargetPoolState>,
+    rendezvous: Arc<RendezvousState>,
 }
 
 impl<S> GenericClient<S>
 where
-    S: AsyncWrite + AsyncRead + Unpin,
+    S: ClientStream,
 {
     #[instrument(
         "generic_client",
         skip_all,
-        fields(session_id = field::Empty, protocol = field::Empty, target = field::Empty),
+        fields(
+            session_id = field::Empty,
+            protocol = field::Empty,
+            target = field::Empty,
+            resolved = field::Empty,
+            nb_failed_attempts = field::Empty
+        ),
     )]
     pub async fn serve(self) -> anyhow::Result<()> {
         let Self {
@@ -46,6 +76,8 @@ where
             sessions,
             subscriber_tx,
             active_recordings,
+            target_pools,
+            rendezvous,
         } = self;
 
         let span = tracing::Span::current();
@@ -70,7 +102,25 @@ where
 
         match claims.jet_cm {
             ConnectionMode::Rdv => {
-                anyhow::bail!("TCP rendezvous not supported");
+                if claims.jet_rec {
+                    anyhow::bail!("can't meet recording policy");
+                }
+
+                let connector = Connector {
+                    stream: Box::new(client_stream),
+                    addr: client_addr,
+                    leftover: leftover_bytes.freeze(),
+                    claims,
+                    jet_version: None,
+                };
+
+                if rendezvous.pair(None, connector).is_err() {
+                    anyhow::bail!("no acceptor is waiting for this association");
+                }
+
+                debug!("Paired with acceptor");
+
+                Ok(())
             }
             ConnectionMode::Fwd { targets, creds: None } => {
                 if claims.jet_rec {
@@ -80,11 +130,24 @@ where
                 trace!("Select and connect to target");
 
                 let ((mut server_stream, server_addr), selected_target) =
-                    utils::successive_try(&targets, utils::tcp_connect).await?;
+                    target_pool::connect_to_target(&targets, claims.jet_lb, &conf, &target_pools, &sessions).await?;
 
                 trace!(%selected_target, "Connected");
                 span.record("target", selected_target.to_string());
 
+                sessions.record_event(
+                    claims.jet_aid,
+                    claims.jet_rec,
+                    SessionEvent::DestinationSelected {
+                        destination: selected_target.to_string(),
+                        address: server_addr,
+                    },
+                );
+
+                if claims.jet_pp || conf.outbound.sends_proxy_protocol(&selected_target, server_addr) {
+                    proxy_protocol::send_header(&mut server_stream, client_addr, server_addr, claims.jet_aid).await?;
+                }
+
                 info!("TCP forwarding");
 
                 server_stream
@@ -101,7 +164,37 @@ where
                 )
                 .with_ttl(claims.jet_ttl)
                 .with_recording_policy(claims.jet_rec)
-                .with_filtering_policy(claims.jet_flt);
+                .with_filtering_policy(claims.jet_flt)
+                .with_capture_policy(claims.jet_cap)
+                .with_subject(claims.sub.clone())
+                .with_client_addr(client_addr);
+
+                #[cfg(target_os = "linux")]
+                let client_stream =
+                    if conf.outbound.zero_copy && !info.capture_policy && conf.debug.capture_path.is_none() {
+                        match client_stream.into_tcp_stream() {
+                            Ok(client_stream) => {
+                                trace!("Both ends are plain TCP streams, splice(2) will be used to forward data.");
+
+                                return Proxy::builder()
+                                    .conf(conf)
+                                    .session_info(info)
+                                    .address_a(client_addr)
+                                    .transport_a(client_stream)
+                                    .address_b(server_addr)
+                                    .transport_b(server_stream)
+                                    .sessions(sessions)
+                                    .subscriber_tx(subscriber_tx)
+                                    .build()
+                                    .forward_spliced()
+                                    .await
+                                    .context("encountered a failure during plain tcp traffic proxying");
+                            }
+                            Err(client_stream) => client_stream,
+                        }
+                    } else {
+                        client_stream
+                    };
 
                 Proxy::builder()
                     .conf(conf)
@@ -10,10 +10,14 @@ pub mod plugin_recording;
 // pub mod rdp;
 
 pin_project! {
+    /// Stream passing the bytes going through it to inspectors
+    ///
+    /// `inspectors` are given the bytes read from the inner stream, and `write_inspectors` the bytes written to it.
     pub struct Interceptor<S> {
         #[pin]
         pub inner: S,
         pub inspectors: Vec<Box<dyn Inspector + Send>>,
+        pub write_inspectors: Vec<Box<dyn Inspector + Send>>,
     }
 }
 
@@ -22,6 +26,7 @@ impl<S> Interceptor<S> {
         Self {
             inner: stream,
             inspectors: Vec::new(),
+            write_inspectors: Vec::new(),
         }
     }
 }
@@ -38,12 +43,15 @@ where
     ) -> task::Poll<io::Result<()>> {
         let this = self.project();
 
+        // The buffer may already hold bytes from a previous read (e.g.: not yet written by the copy routine).
+        let filled_before = buf.filled().len();
+
         match futures::ready!(this.inner.poll_read(cx, buf)) {
             Ok(()) => {}
             Err(e) => return task::Poll::Ready(Err(e)),
         }
 
-        let filled = buf.filled();
+        let filled = &buf.filled()[filled_before..];
 
         for inspector in this.inspectors {
             if let Err(e) = inspector.inspect_bytes(filled) {
@@ -61,7 +69,20 @@ where
 {
     #[inline]
     fn poll_write(self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &[u8]) -> task::Poll<io::Result<usize>> {
-        self.project().inner.poll_write(cx, buf)
+        let this = self.project();
+
+        let written = match futures::ready!(this.inner.poll_write(cx, buf)) {
+            Ok(written) => written,
+            Err(e) => return task::Poll::Ready(Err(e)),
+        };
+
+        for inspector in this.write_inspectors {
+            if let Err(e) = inspector.inspect_bytes(&buf[..written]) {
+                debug!("inspector error: {}", e);
+            }
+        }
+
+        task::Poll::Ready(Ok(written))
     }
 
     #[inline]
@@ -106,7 +127,12 @@ pub struct DummyDissector;
 
 impl Dissector for DummyDissector {
     fn dissect_one(&mut self, _: PeerSide, bytes: &mut BytesMut) -> Option<BytesMut> {
-        Some(bytes.split_to(bytes.len()))
+        // An empty message would be returned forever by `dissect_all`.
+        if bytes.is_empty() {
+            None
+        } else {
+            Some(bytes.split_to(bytes.len()))
+        }
     }
 }
 
@@ -129,3 +155,52 @@ impl Dissector for WaykDissector {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use tokio::io::ReadBuf;
+
+    use super::*;
+
+    struct Collect(Arc<Mutex<Vec<u8>>>);
+
+    impl Inspector for Collect {
+        fn inspect_bytes(&mut self, bytes: &[u8]) -> anyhow::Result<()> {
+            self.0.lock().unwrap().extend_from_slice(bytes);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn bytes_already_in_the_buffer_are_not_inspected_again() {
+        let inspected = Arc::new(Mutex::new(Vec::new()));
+
+        let mut interceptor = Interceptor::new(&b"second"[..]);
+        interceptor.inspectors.push(Box::new(Collect(Arc::clone(&inspected))));
+
+        // Like the copy routines, keep the bytes of the previous read at the start of the buffer.
+        let mut storage = [0; 16];
+        let mut buf = ReadBuf::new(&mut storage);
+        buf.put_slice(b"first ");
+
+        std::future::poll_fn(|cx| Pin::new(&mut interceptor).poll_read(cx, &mut buf))
+            .await
+            .unwrap();
+
+        assert_eq!(buf.filled(), b"first second");
+        assert_eq!(*inspected.lock().unwrap(), b"second");
+    }
+
+    #[test]
+    fn dummy_dissector_returns_all_pending_bytes_once() {
+        let mut bytes = BytesMut::from(&b"hello"[..]);
+
+        let messages = DummyDissector.dissect_all(PeerSide::Client, &mut bytes);
+
+        assert_eq!(messages, [&b"hello"[..]]);
+        assert!(bytes.is_empty());
+        assert!(DummyDissector.dissect_all(PeerSide::Client, &mut bytes).is_empty());
+    }
+}
@@ -1,157 +1,893 @@
-use crate::interceptor::{Dissector, Inspector, PeerSide};
+//! Traffic capture in PCAPNG files.
+//!
+//! The bytes exchanged by the peers are wrapped into TCP/IP packets between their actual addresses, so that usual
+//! tools (e.g.: Wireshark) can follow both directions of the stream. The TCP handshake and teardown are synthetic.
+//! The section header holds comments describing the session.
+//!
+//! The streams opened by the Gateway on behalf of the session (e.g.: JMUX channels) are captured in the same file,
+//! each one as its own TCP flow between the Gateway and the target. For JMUX sessions, the session flow holds the
+//! multiplexed JMUX framing.
+
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Write};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
 use anyhow::Context as _;
 use bytes::BytesMut;
-use devolutions_gateway_task::ChildTask;
-use packet::builder::Builder;
-use packet::ether::{Builder as BuildEthernet, Protocol};
-use packet::ip::v6::Builder as BuildV6;
-use packet::tcp::flag::Flags;
-use pcap_file::pcap::{PcapPacket, PcapWriter};
-use std::fs::File;
-use std::net::SocketAddr;
-use std::path::Path;
+use camino::Utf8PathBuf;
+use parking_lot::Mutex;
 use tokio::sync::mpsc;
+use uuid::Uuid;
+
+use crate::config::Conf;
+use crate::interceptor::{Dissector, DummyDissector, Inspector, PeerSide};
+use crate::session::SessionInfo;
 
 const TCP_IP_PACKET_MAX_SIZE: usize = 16384;
 
+/// Number of intercepted chunks waiting to be written, beyond which the traffic is dropped from the capture
+const CAPTURE_QUEUE_SIZE: usize = 1024;
+
+const SECTION_HEADER_BLOCK: u32 = 0x0A0D_0D0A;
+const INTERFACE_DESCRIPTION_BLOCK: u32 = 0x0000_0001;
+const ENHANCED_PACKET_BLOCK: u32 = 0x0000_0006;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+
+const OPT_ENDOFOPT: u16 = 0;
+const OPT_COMMENT: u16 = 1;
+const SHB_USERAPPL: u16 = 4;
+
+/// Raw IP packets, without link-layer header
+const LINKTYPE_RAW: u16 = 101;
+
+const IPPROTO_TCP: u8 = 6;
+
+const TCP_FIN: u8 = 0x01;
+const TCP_SYN: u8 = 0x02;
+const TCP_PSH: u8 = 0x08;
+const TCP_ACK: u8 = 0x10;
+
+/// Traffic capture of a session, which may be started at any time while the session is running
+pub struct SessionCapture {
+    directory: Utf8PathBuf,
+    session_id: Uuid,
+    comments: Vec<String>,
+    client_addr: SocketAddr,
+    server_addr: SocketAddr,
+    dissector: Mutex<Option<Box<dyn Dissector + Send>>>,
+    sender: OnceLock<mpsc::Sender<Captured>>,
+    dropping: AtomicBool,
+}
+
+/// Stream opened by the Gateway on behalf of the session, captured as a flow of its own
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+struct ChannelFlow {
+    /// Local address of the socket opened by the Gateway
+    gateway_addr: SocketAddr,
+    target_addr: SocketAddr,
+}
+
+enum Captured {
+    Data {
+        /// `None` for the traffic between the client and the server of the session
+        channel: Option<ChannelFlow>,
+        side: PeerSide,
+        timestamp: SystemTime,
+        bytes: Vec<u8>,
+    },
+    /// One side of a channel is not captured anymore
+    Closed {
+        channel: ChannelFlow,
+        side: PeerSide,
+        timestamp: SystemTime,
+    },
+}
+
+impl SessionCapture {
+    /// Prepares the capture of the traffic between the client and the server of a session.
+    ///
+    /// The capture is started right away when required by the session policy, or when a capture folder is configured
+    /// in the debug options. In the latter case, the capture is written in this folder.
+    pub fn new(
+        conf: &Conf,
+        info: &SessionInfo,
+        client_addr: SocketAddr,
+        server_addr: SocketAddr,
+        dissector: impl Dissector + Send + 'static,
+    ) -> anyhow::Result<Arc<Self>> {
+        let directory = conf.debug.capture_path.as_ref().unwrap_or(&conf.capture_path).clone();
+
+        let mut comments = vec![
+            format!("Session ID: {}", info.id()),
+            format!("Application protocol: {}", info.application_protocol),
+        ];
+
+        if let Some(subject) = &info.subject {
+            comments.push(format!("Subject: {subject}"));
+        }
+
+        comments.push(format!("Client: {client_addr}"));
+        comments.push(format!("Server: {server_addr}"));
+
+        let capture = Arc::new(Self {
+            directory,
+            session_id: info.id(),
+            comments,
+            client_addr,
+            server_addr,
+            dissector: Mutex::new(Some(Box::new(dissector))),
+            sender: OnceLock::new(),
+            dropping: AtomicBool::new(false),
+        });
+
+        if info.capture_policy || conf.debug.capture_path.is_some() {
+            capture.start()?;
+        }
+
+        Ok(capture)
+    }
+
+    /// Starts writing the traffic in a new capture file, unless already started.
+    ///
+    /// The file is created in the background, and the failures are only logged.
+    pub fn start(&self) -> anyhow::Result<()> {
+        let mut dissector = self.dissector.lock();
+
+        if self.sender.get().is_some() {
+            return Ok(());
+        }
+
+        let format = time::format_description::parse("[year]-[month]-[day]_[hour]-[minute]-[second]")
+            .expect("valid hardcoded format");
+
+        let filename = format!(
+            "{}-at-{}.pcapng",
+            self.session_id,
+            time::OffsetDateTime::now_utc().format(&format)?
+        );
+
+        let dissector = dissector.take().unwrap_or_else(|| Box::new(DummyDissector));
+        let (sender, receiver) = mpsc::channel(CAPTURE_QUEUE_SIZE);
+
+        let writer = CaptureWriter {
+            directory: self.directory.clone(),
+            path: self.directory.join(filename),
+            comments: self.comments.clone(),
+            client_addr: self.client_addr,
+            server_addr: self.server_addr,
+            dissector,
+        };
+
+        let session_id = self.session_id;
+
+        // File operations are blocking: the capture file is created and written outside of the runtime workers.
+        tokio::task::spawn_blocking(move || {
+            if let Err(error) = writer.run(receiver) {
+                error!(%session_id, error = format!("{error:#}"), "Traffic capture failed");
+            }
+        });
+
+        let _ = self.sender.set(sender);
+
+        Ok(())
+    }
+
+    /// Returns an inspector capturing the bytes sent by the given side.
+    pub fn inspector(self: &Arc<Self>, side: PeerSide) -> PcapInspector {
+        PcapInspector {
+            side,
+            channel: None,
+            capture: Arc::clone(self),
+        }
+    }
+
+    /// Returns an inspector capturing the bytes sent by the given side of a stream opened by the Gateway on behalf
+    /// of the session (e.g.: a JMUX channel), the client side being the Gateway.
+    ///
+    /// The side is closed in the capture when the inspector is dropped.
+    pub fn channel_inspector(
+        self: &Arc<Self>,
+        side: PeerSide,
+        gateway_addr: SocketAddr,
+        target_addr: SocketAddr,
+    ) -> PcapInspector {
+        PcapInspector {
+            side,
+            channel: Some(ChannelFlow {
+                gateway_addr,
+                target_addr,
+            }),
+            capture: Arc::clone(self),
+        }
+    }
+}
+
 pub struct PcapInspector {
     side: PeerSide,
-    sender: mpsc::UnboundedSender<(PeerSide, Vec<u8>)>,
+    channel: Option<ChannelFlow>,
+    capture: Arc<SessionCapture>,
 }
 
 impl Inspector for PcapInspector {
     fn inspect_bytes(&mut self, bytes: &[u8]) -> anyhow::Result<()> {
-        self.sender
-            .send((self.side, bytes.to_vec()))
-            .context("PCAP inspector task is terminated")?;
+        let Some(sender) = self.capture.sender.get() else {
+            return Ok(());
+        };
+
+        if bytes.is_empty() {
+            return Ok(());
+        }
+
+        let captured = Captured::Data {
+            channel: self.channel,
+            side: self.side,
+            timestamp: SystemTime::now(),
+            bytes: bytes.to_vec(),
+        };
+
+        match sender.try_send(captured) {
+            Ok(()) => {}
+            // The capture file is written slower than the session is going, the capture will be incomplete.
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                if !self.capture.dropping.swap(true, Ordering::Relaxed) {
+                    warn!(
+                        session_id = %self.capture.session_id,
+                        "Capture queue is full, traffic is dropped from the capture"
+                    );
+                }
+            }
+            // The writer task failed and already reported it, the capture is stopped.
+            Err(mpsc::error::TrySendError::Closed(_)) => {}
+        }
+
         Ok(())
     }
 }
 
-impl PcapInspector {
-    /// Returns client side and server side inspector
-    pub fn init(
-        client_addr: SocketAddr,
-        server_addr: SocketAddr,
-        pcap_filename: impl AsRef<Path>,
-        dissector: impl Dissector + Send + 'static,
-    ) -> anyhow::Result<(Self, Self)> {
-        let file = File::create(pcap_filename).context("failed to crate pcap file")?;
-        let pcap_writer = PcapWriter::new(file).context("failed to create pcap writer")?;
+impl Drop for PcapInspector {
+    fn drop(&mut self) {
+        if let (Some(channel), Some(sender)) = (self.channel, self.capture.sender.get()) {
+            // When the message is lost, the channel is closed at the end of the capture instead.
+            let _ = sender.try_send(Captured::Closed {
+                channel,
+                side: self.side,
+                timestamp: SystemTime::now(),
+            });
+        }
+    }
+}
+
+/// Writes the captured traffic in a new file, until all the inspectors are dropped.
+struct CaptureWriter {
+    directory: Utf8PathBuf,
+    path: Utf8PathBuf,
+    comments: Vec<String>,
+    client_addr: SocketAddr,
+    server_addr: SocketAddr,
+    dissector: Box<dyn Dissector + Send>,
+}
+
+impl CaptureWriter {
+    /// Blocks the current thread until the capture is complete, or a write fails.
+    fn run(mut self, mut receiver: mpsc::Receiver<Captured>) -> anyhow::Result<()> {
+        std::fs::create_dir_all(&self.directory)
+            .with_context(|| format!("failed to create capture folder {}", self.directory))?;
+
+        let file = File::create(&self.path).with_context(|| format!("failed to create capture file {}", self.path))?;
 
-        let (sender, receiver) = mpsc::unbounded_channel();
+        let mut writer = PcapNgWriter::new(file);
+        let mut session_flow = TcpFlow::new(self.client_addr, self.server_addr);
 
-        ChildTask::spawn(writer_task(receiver, pcap_writer, client_addr, server_addr, dissector)).detach();
+        writer
+            .write_header(&self.comments)
+            .and_then(|()| writer.write_handshake(&mut session_flow, SystemTime::now()))
+            .context("failed to write capture header")?;
 
-        Ok((
-            Self {
-                side: PeerSide::Client,
-                sender: sender.clone(),
-            },
-            Self {
-                side: PeerSide::Server,
-                sender,
-            },
-        ))
+        info!(path = %self.path, "Traffic capture started");
+
+        let mut client_acc = BytesMut::new();
+        let mut server_acc = BytesMut::new();
+
+        // Channels are added to the capture on their first bytes, and removed once both sides are closed.
+        let mut channels: HashMap<ChannelFlow, ChannelState> = HashMap::new();
+
+        while let Some(captured) = receiver.blocking_recv() {
+            match captured {
+                Captured::Data {
+                    channel: None,
+                    side,
+                    timestamp,
+                    bytes,
+                } => {
+                    let acc = match side {
+                        PeerSide::Client => &mut client_acc,
+                        PeerSide::Server => &mut server_acc,
+                    };
+
+                    acc.extend_from_slice(&bytes);
+
+                    for message in self.dissector.dissect_all(side, acc) {
+                        writer
+                            .write_data(&mut session_flow, side, timestamp, &message)
+                            .context("failed to write capture file, traffic capture stopped")?;
+                    }
+                }
+                Captured::Data {
+                    channel: Some(channel),
+                    side,
+                    timestamp,
+                    bytes,
+                } => {
+                    let state = match channels.entry(channel) {
+                        Entry::Occupied(entry) => entry.into_mut(),
+                        Entry::Vacant(entry) => {
+                            let mut flow = TcpFlow::new(channel.gateway_addr, channel.target_addr);
+                            writer
+                                .write_handshake(&mut flow, timestamp)
+                                .context("failed to write capture file, traffic capture stopped")?;
+                            entry.insert(ChannelState { flow, closed: None })
+                        }
+                    };
+
+                    writer
+                        .write_data(&mut state.flow, side, timestamp, &bytes)
+                        .context("failed to write capture file, traffic capture stopped")?;
+                }
+                Captured::Closed {
+                    channel,
+                    side,
+                    timestamp,
+                } => {
+                    // Channels without any traffic are not in the capture.
+                    let Some(state) = channels.get_mut(&channel) else {
+                        continue;
+                    };
+
+                    writer
+                        .write_segment(&mut state.flow, side, timestamp, TCP_FIN | TCP_ACK, &[])
+                        .context("failed to write capture file, traffic capture stopped")?;
+
+                    match state.closed {
+                        Some(closed_side) => {
+                            writer
+                                .write_segment(&mut state.flow, closed_side, timestamp, TCP_ACK, &[])
+                                .context("failed to write capture file, traffic capture stopped")?;
+                            channels.remove(&channel);
+                        }
+                        None => state.closed = Some(side),
+                    }
+                }
+            }
+        }
+
+        let timestamp = SystemTime::now();
+
+        for state in channels.values_mut() {
+            match state.closed {
+                Some(closed_side) => {
+                    let open_side = match closed_side {
+                        PeerSide::Client => PeerSide::Server,
+                        PeerSide::Server => PeerSide::Client,
+                    };
+                    writer
+                        .write_segment(&mut state.flow, open_side, timestamp, TCP_FIN | TCP_ACK, &[])
+                        .and_then(|()| writer.write_segment(&mut state.flow, closed_side, timestamp, TCP_ACK, &[]))
+                        .context("failed to write capture file")?;
+                }
+                None => writer
+                    .write_teardown(&mut state.flow, timestamp)
+                    .context("failed to write capture file")?,
+            }
+        }
+
+        writer
+            .write_teardown(&mut session_flow, timestamp)
+            .and_then(|()| writer.out.flush())
+            .context("failed to write capture file")
     }
 }
 
-async fn writer_task(
-    mut receiver: mpsc::UnboundedReceiver<(PeerSide, Vec<u8>)>,
-    mut pcap_writer: PcapWriter<File>,
-    server_addr: SocketAddr,
+struct ChannelState {
+    flow: TcpFlow,
+    /// Side closed first
+    closed: Option<PeerSide>,
+}
+
+/// Addresses and sequence numbers of a TCP flow held by the capture
+struct TcpFlow {
     client_addr: SocketAddr,
-    mut dissector: impl Dissector,
-) {
-    let mut server_seq_number = 0;
-    let mut server_acc = BytesMut::new();
+    server_addr: SocketAddr,
+    client_seq: u32,
+    server_seq: u32,
+}
+
+impl TcpFlow {
+    fn new(client_addr: SocketAddr, server_addr: SocketAddr) -> Self {
+        Self {
+            client_addr,
+            server_addr,
+            client_seq: 0,
+            server_seq: 0,
+        }
+    }
+}
+
+struct PcapNgWriter<W> {
+    out: W,
+}
+
+impl<W: Write> PcapNgWriter<W> {
+    fn new(out: W) -> Self {
+        Self { out }
+    }
 
-    let mut client_seq_number = 0;
-    let mut client_acc = BytesMut::new();
+    /// Writes the section header and the interface description.
+    fn write_header(&mut self, comments: &[String]) -> io::Result<()> {
+        let mut body = Vec::new();
+        body.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+        body.extend_from_slice(&1u16.to_le_bytes()); // Major version
+        body.extend_from_slice(&0u16.to_le_bytes()); // Minor version
+        body.extend_from_slice(&(-1i64).to_le_bytes()); // Section length is not specified
+        for comment in comments {
+            push_option(&mut body, OPT_COMMENT, comment.as_bytes());
+        }
+        push_option(
+            &mut body,
+            SHB_USERAPPL,
+            concat!("Devolutions Gateway ", env!("CARGO_PKG_VERSION")).as_bytes(),
+        );
+        push_option(&mut body, OPT_ENDOFOPT, &[]);
+        self.write_block(SECTION_HEADER_BLOCK, &body)?;
+
+        let mut body = Vec::new();
+        body.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+        body.extend_from_slice(&0u16.to_le_bytes()); // Reserved
+        body.extend_from_slice(&0u32.to_le_bytes()); // No snapshot length limit
+        self.write_block(INTERFACE_DESCRIPTION_BLOCK, &body)
+    }
+
+    fn write_handshake(&mut self, flow: &mut TcpFlow, timestamp: SystemTime) -> io::Result<()> {
+        self.write_segment(flow, PeerSide::Client, timestamp, TCP_SYN, &[])?;
+        self.write_segment(flow, PeerSide::Server, timestamp, TCP_SYN | TCP_ACK, &[])?;
+        self.write_segment(flow, PeerSide::Client, timestamp, TCP_ACK, &[])
+    }
+
+    fn write_data(&mut self, flow: &mut TcpFlow, side: PeerSide, timestamp: SystemTime, data: &[u8]) -> io::Result<()> {
+        for chunk in data.chunks(TCP_IP_PACKET_MAX_SIZE) {
+            self.write_segment(flow, side, timestamp, TCP_PSH | TCP_ACK, chunk)?;
+        }
 
-    while let Some((side, bytes)) = receiver.recv().await {
-        debug!("New packet intercepted. Packet size = {}", bytes.len());
+        Ok(())
+    }
+
+    fn write_teardown(&mut self, flow: &mut TcpFlow, timestamp: SystemTime) -> io::Result<()> {
+        self.write_segment(flow, PeerSide::Client, timestamp, TCP_FIN | TCP_ACK, &[])?;
+        self.write_segment(flow, PeerSide::Server, timestamp, TCP_FIN | TCP_ACK, &[])?;
+        self.write_segment(flow, PeerSide::Client, timestamp, TCP_ACK, &[])
+    }
 
-        let (acc, source_addr, dest_addr, seq_number, ack_number) = match side {
+    fn write_segment(
+        &mut self,
+        flow: &mut TcpFlow,
+        side: PeerSide,
+        timestamp: SystemTime,
+        flags: u8,
+        payload: &[u8],
+    ) -> io::Result<()> {
+        let (source, dest, seq, ack) = match side {
             PeerSide::Client => (
-                &mut client_acc,
-                client_addr,
-                server_addr,
-                &mut client_seq_number,
-                server_seq_number,
+                flow.client_addr,
+                flow.server_addr,
+                &mut flow.client_seq,
+                flow.server_seq,
             ),
             PeerSide::Server => (
-                &mut server_acc,
-                server_addr,
-                client_addr,
-                &mut server_seq_number,
-                client_seq_number,
+                flow.server_addr,
+                flow.client_addr,
+                &mut flow.server_seq,
+                flow.client_seq,
             ),
         };
 
-        acc.extend_from_slice(&bytes);
-
-        let messages = dissector.dissect_all(side, acc);
-
-        for data in messages {
-            for data_chunk in data.chunks(TCP_IP_PACKET_MAX_SIZE) {
-                // Build tcpip packet
-                let tcpip_packet = match (source_addr, dest_addr) {
-                    (SocketAddr::V4(source), SocketAddr::V4(dest)) => {
-                        BuildEthernet::default()
-                            .destination([0x00, 0x15, 0x5D, 0x01, 0x64, 0x04].into())
-                            .unwrap() // 00:15:5D:01:64:04
-                            .source([0x00, 0x15, 0x5D, 0x01, 0x64, 0x01].into())
-                            .unwrap() // 00:15:5D:01:64:01
-                            .protocol(Protocol::Ipv4)
-                            .unwrap()
-                            .ip()
-                            .unwrap()
-                            .v4()
-                            .unwrap()
-                            .source(*source.ip())
-                            .unwrap()
-                            .destination(*dest.ip())
-                            .unwrap()
-                            .ttl(128)
-                            .unwrap()
-                            .tcp()
-                            .unwrap()
-                            .window(0x7fff)
-                            .unwrap()
-                            .source(source_addr.port())
-                            .unwrap()
-                            .destination(dest_addr.port())
-                            .unwrap()
-                            .acknowledgment(ack_number)
-                            .unwrap()
-                            .sequence(*seq_number)
-                            .unwrap()
-                            .flags(Flags::from_bits_truncate(0x0018))
-                            .unwrap()
-                            .payload(data_chunk)
-                            .unwrap()
-                            .build()
-                            .unwrap()
-                    }
-                    (SocketAddr::V6(_source), SocketAddr::V6(_dest)) => BuildV6::default().build().unwrap(),
-                    (_, _) => unreachable!(),
-                };
+        let packet = tcp_ip_packet(source, dest, *seq, ack, flags, payload);
 
-                // Write packet in pcap file
-                let since_epoch = std::time::SystemTime::now()
-                    .duration_since(std::time::UNIX_EPOCH)
-                    .expect("Time went backwards");
+        // SYN and FIN flags consume one sequence number.
+        let consumed = payload.len() + usize::from(flags & (TCP_SYN | TCP_FIN) != 0);
+        *seq = seq.wrapping_add(u32::try_from(consumed).expect("segment size is bounded"));
 
-                let packet = PcapPacket::new(since_epoch, tcpip_packet.len() as u32, &tcpip_packet);
+        self.write_packet(timestamp, &packet)
+    }
 
-                if let Err(e) = pcap_writer.write_packet(&packet) {
-                    error!("Error writing pcap file: {}", e);
-                }
+    fn write_packet(&mut self, timestamp: SystemTime, packet: &[u8]) -> io::Result<()> {
+        // Timestamps are in microseconds, the default resolution.
+        let micros =
+            u64::try_from(timestamp.duration_since(UNIX_EPOCH).unwrap_or_default().as_micros()).unwrap_or(u64::MAX);
+        let packet_len = u32::try_from(packet.len()).expect("packet size is bounded");
 
-                // Update the seq_number
-                *seq_number += data_chunk.len() as u32;
-            }
+        let mut body = Vec::with_capacity(20 + packet.len() + 3);
+        body.extend_from_slice(&0u32.to_le_bytes()); // Interface ID
+        body.extend_from_slice(&((micros >> 32) as u32).to_le_bytes());
+        body.extend_from_slice(&(micros as u32).to_le_bytes());
+        body.extend_from_slice(&packet_len.to_le_bytes()); // Captured length
+        body.extend_from_slice(&packet_len.to_le_bytes()); // Original length
+        body.extend_from_slice(packet);
+        pad(&mut body);
+
+        self.write_block(ENHANCED_PACKET_BLOCK, &body)
+    }
+
+    fn write_block(&mut self, block_type: u32, body: &[u8]) -> io::Result<()> {
+        let total_len = u32::try_from(body.len() + 12).expect("block size is bounded");
+
+        let mut block = Vec::with_capacity(body.len() + 12);
+        block.extend_from_slice(&block_type.to_le_bytes());
+        block.extend_from_slice(&total_len.to_le_bytes());
+        block.extend_from_slice(body);
+        block.extend_from_slice(&total_len.to_le_bytes());
+
+        self.out.write_all(&block)
+    }
+}
+
+fn push_option(body: &mut Vec<u8>, code: u16, value: &[u8]) {
+    let value = &value[..value.len().min(usize::from(u16::MAX))];
+    let len = u16::try_from(value.len()).expect("truncated above");
+
+    body.extend_from_slice(&code.to_le_bytes());
+    body.extend_from_slice(&len.to_le_bytes());
+    body.extend_from_slice(value);
+    pad(body);
+}
+
+/// Pads to 32 bits, as required for the block bodies and the option values.
+fn pad(buf: &mut Vec<u8>) {
+    buf.resize(buf.len() + (4 - buf.len() % 4) % 4, 0);
+}
+
+/// Builds a TCP/IP packet.
+///
+/// When the addresses do not belong to the same family, IPv4 addresses are mapped to IPv6.
+fn tcp_ip_packet(source: SocketAddr, dest: SocketAddr, seq: u32, ack: u32, flags: u8, payload: &[u8]) -> Vec<u8> {
+    let mut segment = Vec::with_capacity(20 + payload.len());
+    segment.extend_from_slice(&source.port().to_be_bytes());
+    segment.extend_from_slice(&dest.port().to_be_bytes());
+    segment.extend_from_slice(&seq.to_be_bytes());
+    segment.extend_from_slice(&ack.to_be_bytes());
+    segment.push(5 << 4); // Data offset: 5 words, no option
+    segment.push(flags);
+    segment.extend_from_slice(&u16::MAX.to_be_bytes()); // Window size
+    segment.extend_from_slice(&[0, 0]); // Checksum, computed below
+    segment.extend_from_slice(&[0, 0]); // Urgent pointer
+    segment.extend_from_slice(payload);
+
+    let segment_len = u16::try_from(segment.len()).expect("segment size is bounded");
+
+    let (mut packet, pseudo_header) = match (source.ip(), dest.ip()) {
+        (IpAddr::V4(source_ip), IpAddr::V4(dest_ip)) => {
+            let mut header = Vec::with_capacity(20 + segment.len());
+            header.push(0x45); // Version 4, header length: 5 words
+            header.push(0); // DSCP and ECN
+            header.extend_from_slice(&(20 + segment_len).to_be_bytes());
+            header.extend_from_slice(&[0, 0]); // Identification
+            header.extend_from_slice(&[0x40, 0]); // Don't fragment
+            header.push(64); // Time to live
+            header.push(IPPROTO_TCP);
+            header.extend_from_slice(&[0, 0]); // Checksum, computed below
+            header.extend_from_slice(&source_ip.octets());
+            header.extend_from_slice(&dest_ip.octets());
+
+            let checksum = checksum(&[&header]);
+            header[10..12].copy_from_slice(&checksum.to_be_bytes());
+
+            let mut pseudo_header = Vec::with_capacity(12);
+            pseudo_header.extend_from_slice(&source_ip.octets());
+            pseudo_header.extend_from_slice(&dest_ip.octets());
+            pseudo_header.extend_from_slice(&[0, IPPROTO_TCP]);
+            pseudo_header.extend_from_slice(&segment_len.to_be_bytes());
+
+            (header, pseudo_header)
+        }
+        (source_ip, dest_ip) => {
+            let (source_ip, dest_ip) = (to_ipv6(source_ip), to_ipv6(dest_ip));
+
+            let mut header = Vec::with_capacity(40 + segment.len());
+            header.extend_from_slice(&[0x60, 0, 0, 0]); // Version 6, no traffic class nor flow label
+            header.extend_from_slice(&segment_len.to_be_bytes());
+            header.push(IPPROTO_TCP);
+            header.push(64); // Hop limit
+            header.extend_from_slice(&source_ip.octets());
+            header.extend_from_slice(&dest_ip.octets());
+
+            let mut pseudo_header = Vec::with_capacity(40);
+            pseudo_header.extend_from_slice(&source_ip.octets());
+            pseudo_header.extend_from_slice(&dest_ip.octets());
+            pseudo_header.extend_from_slice(&u32::from(segment_len).to_be_bytes());
+            pseudo_header.extend_from_slice(&[0, 0, 0, IPPROTO_TCP]);
+
+            (header, pseudo_header)
+        }
+    };
+
+    let checksum = checksum(&[&pseudo_header, &segment]);
+    segment[16..18].copy_from_slice(&checksum.to_be_bytes());
+
+    packet.extend_from_slice(&segment);
+
+    packet
+}
+
+fn to_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+/// Internet checksum (RFC 1071) of the concatenated parts, each part but the last being of even length.
+fn checksum(parts: &[&[u8]]) -> u16 {
+    let mut sum = 0u64;
+
+    for part in parts {
+        for word in part.chunks(2) {
+            let word = match *word {
+                [high, low] => u16::from_be_bytes([high, low]),
+                [high] => u16::from_be_bytes([high, 0]),
+                _ => unreachable!("chunks of at most two bytes"),
+            };
+
+            sum += u64::from(word);
+        }
+    }
+
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the type and the body of each block.
+    fn blocks(mut file: &[u8]) -> Vec<(u32, &[u8])> {
+        let mut blocks = Vec::new();
+
+        while !file.is_empty() {
+            let block_type = u32::from_le_bytes(file[0..4].try_into().unwrap());
+            let total_len = u32::from_le_bytes(file[4..8].try_into().unwrap()) as usize;
+            assert_eq!(total_len % 4, 0);
+            assert_eq!(file[total_len - 4..total_len], file[4..8]);
+
+            blocks.push((block_type, &file[8..total_len - 4]));
+            file = &file[total_len..];
+        }
+
+        blocks
+    }
+
+    /// Returns the IP packet held by an enhanced packet block.
+    fn packet(body: &[u8]) -> &[u8] {
+        let len = u32::from_le_bytes(body[12..16].try_into().unwrap()) as usize;
+        &body[20..20 + len]
+    }
+
+    fn seq_and_ack(packet: &[u8], ip_header_len: usize) -> (u32, u32) {
+        let segment = &packet[ip_header_len..];
+        (
+            u32::from_be_bytes(segment[4..8].try_into().unwrap()),
+            u32::from_be_bytes(segment[8..12].try_into().unwrap()),
+        )
+    }
+
+    #[test]
+    fn both_directions_are_sequenced() {
+        let client_addr = "10.0.0.1:50000".parse().unwrap();
+        let server_addr = "10.0.0.2:3389".parse().unwrap();
+
+        let mut writer = PcapNgWriter::new(Vec::new());
+        let mut flow = TcpFlow::new(client_addr, server_addr);
+        writer.write_header(&["Session ID: test".to_owned()]).unwrap();
+        writer.write_handshake(&mut flow, UNIX_EPOCH).unwrap();
+        writer
+            .write_data(&mut flow, PeerSide::Client, UNIX_EPOCH, b"hello")
+            .unwrap();
+        writer
+            .write_data(&mut flow, PeerSide::Server, UNIX_EPOCH, b"world!")
+            .unwrap();
+        writer
+            .write_data(&mut flow, PeerSide::Client, UNIX_EPOCH, b"bye")
+            .unwrap();
+        writer.write_teardown(&mut flow, UNIX_EPOCH).unwrap();
+
+        let blocks = blocks(&writer.out);
+
+        assert_eq!(blocks[0].0, SECTION_HEADER_BLOCK);
+        assert!(blocks[0].1.windows(16).any(|window| window == b"Session ID: test"));
+        assert_eq!(blocks[1].0, INTERFACE_DESCRIPTION_BLOCK);
+
+        let packets: Vec<&[u8]> = blocks[2..]
+            .iter()
+            .inspect(|(block_type, _)| assert_eq!(*block_type, ENHANCED_PACKET_BLOCK))
+            .map(|(_, body)| packet(body))
+            .collect();
+
+        // Handshake, three data segments, and teardown.
+        assert_eq!(packets.len(), 9);
+
+        let expected = [(0, 0), (0, 1), (1, 1), (1, 1), (1, 6), (6, 7), (9, 7), (7, 10), (10, 8)];
+
+        for (packet, expected) in packets.iter().zip(expected) {
+            assert_eq!(packet[0] >> 4, 4);
+            assert_eq!(checksum(&[&packet[..20]]), 0);
+            assert_eq!(seq_and_ack(packet, 20), expected);
         }
+
+        assert_eq!(&packets[4][..20][12..16], [10, 0, 0, 2]);
+        assert_eq!(&packets[4][40..], b"world!");
+    }
+
+    fn test_capture() -> Arc<SessionCapture> {
+        Arc::new(SessionCapture {
+            directory: Utf8PathBuf::new(),
+            session_id: Uuid::new_v4(),
+            comments: Vec::new(),
+            client_addr: "10.0.0.1:50000".parse().unwrap(),
+            server_addr: "10.0.0.2:3389".parse().unwrap(),
+            dissector: Mutex::new(None),
+            sender: OnceLock::new(),
+            dropping: AtomicBool::new(false),
+        })
+    }
+
+    /// Returns the source and destination ports of an IPv4 packet.
+    fn ports(packet: &[u8]) -> (u16, u16) {
+        (
+            u16::from_be_bytes([packet[20], packet[21]]),
+            u16::from_be_bytes([packet[22], packet[23]]),
+        )
+    }
+
+    #[test]
+    fn inspection_never_fails_once_started() {
+        let capture = test_capture();
+
+        let (sender, mut receiver) = mpsc::channel(1);
+        capture.sender.set(sender).unwrap();
+
+        let mut inspector = capture.inspector(PeerSide::Client);
+
+        // Once the queue is full, the traffic is dropped.
+        inspector.inspect_bytes(b"hello").unwrap();
+        inspector.inspect_bytes(b"world").unwrap();
+        assert!(capture.dropping.load(Ordering::Relaxed));
+        assert!(matches!(receiver.try_recv().unwrap(), Captured::Data { bytes, .. } if bytes == b"hello"));
+        assert!(receiver.try_recv().is_err());
+
+        // Once the writer task is terminated, the traffic is ignored.
+        drop(receiver);
+        inspector.inspect_bytes(b"bye").unwrap();
+    }
+
+    #[tokio::test]
+    async fn capture_is_written_until_inspectors_are_dropped() {
+        let folder = tempfile::tempdir().unwrap();
+        let directory = Utf8PathBuf::from_path_buf(folder.path().join("captures")).unwrap();
+        let path = directory.join("capture.pcapng");
+
+        let writer = CaptureWriter {
+            directory,
+            path: path.clone(),
+            comments: vec!["Session ID: test".to_owned()],
+            client_addr: "10.0.0.1:50000".parse().unwrap(),
+            server_addr: "10.0.0.2:3389".parse().unwrap(),
+            dissector: Box::new(DummyDissector),
+        };
+
+        let (sender, receiver) = mpsc::channel(CAPTURE_QUEUE_SIZE);
+        let writer_handle = tokio::task::spawn_blocking(move || writer.run(receiver));
+
+        for (side, bytes) in [(PeerSide::Client, b"hello"), (PeerSide::Server, b"world")] {
+            let captured = Captured::Data {
+                channel: None,
+                side,
+                timestamp: UNIX_EPOCH,
+                bytes: bytes.to_vec(),
+            };
+            sender.send(captured).await.unwrap();
+        }
+
+        drop(sender);
+        writer_handle.await.unwrap().unwrap();
+
+        let file = std::fs::read(&path).unwrap();
+        let blocks = blocks(&file);
+
+        // Header blocks, handshake, two data segments, and teardown.
+        assert_eq!(blocks.len(), 2 + 3 + 2 + 3);
+        assert_eq!(&packet(blocks[5].1)[40..], b"hello");
+        assert_eq!(&packet(blocks[6].1)[40..], b"world");
+    }
+
+    #[test]
+    fn channels_are_captured_as_their_own_flow() {
+        let folder = tempfile::tempdir().unwrap();
+        let path = Utf8PathBuf::from_path_buf(folder.path().join("capture.pcapng")).unwrap();
+
+        let capture = test_capture();
+        let (sender, receiver) = mpsc::channel(CAPTURE_QUEUE_SIZE);
+        capture.sender.set(sender).unwrap();
+
+        let gateway_addr = "10.0.0.2:40000".parse().unwrap();
+        let target_addr = "10.0.0.3:22".parse().unwrap();
+
+        let mut session_inspector = capture.inspector(PeerSide::Client);
+        let mut to_target = capture.channel_inspector(PeerSide::Client, gateway_addr, target_addr);
+        let mut from_target = capture.channel_inspector(PeerSide::Server, gateway_addr, target_addr);
+
+        session_inspector.inspect_bytes(b"jmux").unwrap();
+        to_target.inspect_bytes(b"hello").unwrap();
+        from_target.inspect_bytes(b"world").unwrap();
+        drop((to_target, from_target, session_inspector, capture));
+
+        let writer = CaptureWriter {
+            directory: Utf8PathBuf::from_path_buf(folder.path().to_owned()).unwrap(),
+            path: path.clone(),
+            comments: Vec::new(),
+            client_addr: "10.0.0.1:50000".parse().unwrap(),
+            server_addr: "10.0.0.2:3389".parse().unwrap(),
+            dissector: Box::new(DummyDissector),
+        };
+        writer.run(receiver).unwrap();
+
+        let file = std::fs::read(&path).unwrap();
+        let packets: Vec<&[u8]> = blocks(&file)[2..].iter().map(|(_, body)| packet(body)).collect();
+
+        // Session handshake and data, channel handshake, data and teardown, and session teardown.
+        assert_eq!(packets.len(), 3 + 1 + 3 + 2 + 3 + 3);
+        assert_eq!(ports(packets[3]), (50000, 3389));
+        assert_eq!(&packets[3][40..], b"jmux");
+
+        let channel_packets = &packets[4..12];
+        assert_eq!(ports(channel_packets[0]), (40000, 22));
+        assert_eq!(&channel_packets[3][40..], b"hello");
+        assert_eq!(&channel_packets[4][12..20], [10, 0, 0, 3, 10, 0, 0, 2]);
+        assert_eq!(&channel_packets[4][40..], b"world");
+        assert!(channel_packets.iter().all(|packet| {
+            let ports = ports(packet);
+            ports == (40000, 22) || ports == (22, 40000)
+        }));
+        assert_eq!(channel_packets[5][33], TCP_FIN | TCP_ACK);
+        assert_eq!(channel_packets[6][33], TCP_FIN | TCP_ACK);
+        assert_eq!(channel_packets[7][33], TCP_ACK);
+    }
+
+    #[test]
+    fn mixed_families_are_mapped_to_ipv6() {
+        let source: SocketAddr = "192.168.1.10:4000".parse().unwrap();
+        let dest: SocketAddr = "[2001:db8::1]:22".parse().unwrap();
+
+        let packet = tcp_ip_packet(source, dest, 1, 1, TCP_PSH | TCP_ACK, b"ssh");
+
+        assert_eq!(packet[0] >> 4, 6);
+        assert_eq!(u16::from_be_bytes([packet[4], packet[5]]), 23);
+        assert_eq!(
+            packet[8..24],
+            "::ffff:192.168.1.10".parse::<Ipv6Addr>().unwrap().octets()
+        );
+        assert_eq!(&packet[60..], b"ssh");
+
+        // The checksum of a valid segment, including its pseudo-header, is zero.
+        let mut pseudo_header = Vec::new();
+        pseudo_header.extend_from_slice(&packet[8..40]);
+        pseudo_header.extend_from_slice(&23u32.to_be_bytes());
+        pseudo_header.extend_from_slice(&[0, 0, 0, IPPROTO_TCP]);
+        assert_eq!(checksum(&[&pseudo_header, &packet[40..]]), 0);
     }
 }
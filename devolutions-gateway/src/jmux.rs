@@ -1,29 +1,60 @@
+use std::io;
+use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
 
+use crate::config::Conf;
+use crate::egress::EgressDenied;
+use crate::interceptor::pcap::SessionCapture;
+use crate::interceptor::{DummyDissector, Interceptor, PeerSide};
+use crate::recording::events::SessionEvent;
 use crate::session::{ConnectionModeDetails, SessionInfo, SessionMessageSender};
 use crate::subscriber::SubscriberSender;
+use crate::target_addr::TargetAddr;
 use crate::token::JmuxTokenClaims;
 
 use anyhow::Context as _;
 use devolutions_gateway_task::ChildTask;
-use jmux_proxy::JmuxProxy;
+use jmux_proxy::{ChannelStream, ConnectFuture, Connector, DestinationUrl, JmuxEvent, JmuxProxy};
 use tap::prelude::*;
-use tokio::io::{AsyncRead, AsyncWrite};
-use tokio::sync::Notify;
+use tokio::io::{AsyncRead, AsyncReadExt as _, AsyncWrite};
+use tokio::sync::{mpsc, Notify};
 use transport::{ErasedRead, ErasedWrite};
 
+/// Magic bytes starting a raw JMUX session (without WebSocket)
+pub const RAW_PREAMBLE_MAGIC: [u8; 4] = *b"JMUX";
+
+pub const RAW_PREAMBLE_READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Reads the preamble of a raw JMUX session, and returns the token it holds.
+///
+/// The preamble is made of the `JMUX` magic bytes, the length of the token (big endian u16) and the token itself.
+/// Nothing past the preamble is read.
+pub async fn read_raw_preamble(stream: &mut (impl AsyncRead + Unpin)) -> anyhow::Result<String> {
+    let mut magic = [0; 4];
+    stream.read_exact(&mut magic).await.context("read magic bytes")?;
+    anyhow::ensure!(magic == RAW_PREAMBLE_MAGIC, "invalid magic bytes");
+
+    let token_len = stream.read_u16().await.context("read token length")?;
+
+    let mut token = vec![0; usize::from(token_len)];
+    stream.read_exact(&mut token).await.context("read token")?;
+
+    String::from_utf8(token).context("token is not valid UTF-8")
+}
+
+/// Serves a JMUX session, `gateway_addr` being the local address of the listener the client is connected to.
 pub async fn handle(
     stream: impl AsyncRead + AsyncWrite + Send + 'static,
     claims: JmuxTokenClaims,
+    client_addr: SocketAddr,
+    gateway_addr: SocketAddr,
+    conf: Arc<Conf>,
     sessions: SessionMessageSender,
     subscriber_tx: SubscriberSender,
 ) -> anyhow::Result<()> {
     use jmux_proxy::{FilteringRule, JmuxConfig};
 
-    let (reader, writer) = tokio::io::split(stream);
-    let reader = Box::new(reader) as ErasedRead;
-    let writer = Box::new(writer) as ErasedWrite;
-
     let main_destination_host = claims.hosts.first().clone();
 
     let config = JmuxConfig {
@@ -51,13 +82,65 @@ pub async fn handle(
             destination_host: main_destination_host,
         },
     )
-    .with_ttl(claims.jet_ttl);
+    .with_ttl(claims.jet_ttl)
+    .with_capture_policy(claims.jet_cap)
+    .with_subject(claims.sub)
+    .with_client_addr(client_addr);
+
+    // The JMUX connection is terminated by the Gateway itself, and each channel is captured as a flow of its own.
+    let capture =
+        SessionCapture::new(&conf, &info, client_addr, gateway_addr, DummyDissector).context("traffic capture")?;
+
+    let mut stream = Interceptor::new(stream);
+    stream.inspectors.push(Box::new(capture.inspector(PeerSide::Client)));
+    stream
+        .write_inspectors
+        .push(Box::new(capture.inspector(PeerSide::Server)));
 
+    let (reader, writer) = tokio::io::split(stream);
+    let reader = Box::new(reader) as ErasedRead;
+    let writer = Box::new(writer) as ErasedWrite;
+
+    let recording_policy = info.recording_policy;
     let notify_kill = Arc::new(Notify::new());
 
-    crate::session::add_session_in_progress(&sessions, &subscriber_tx, info, notify_kill.clone()).await?;
+    crate::session::add_session_in_progress(
+        &sessions,
+        &subscriber_tx,
+        info,
+        notify_kill.clone(),
+        Some(Arc::clone(&capture)),
+    )
+    .await?;
+
+    let (event_tx, mut event_rx) = mpsc::unbounded_channel();
 
-    let proxy_fut = JmuxProxy::new(reader, writer).with_config(config).run();
+    // Channels opened and closed are added to the timeline of the session.
+    let _events_handle = ChildTask::spawn({
+        let sessions = sessions.clone();
+
+        async move {
+            while let Some(event) = event_rx.recv().await {
+                let event = match event {
+                    JmuxEvent::ChannelOpened { id, destination_url } => SessionEvent::JmuxChannelOpened {
+                        channel_id: u32::from(id),
+                        destination: destination_url.to_string(),
+                    },
+                    JmuxEvent::ChannelClosed { id } => SessionEvent::JmuxChannelClosed {
+                        channel_id: u32::from(id),
+                    },
+                };
+
+                sessions.record_event(session_id, recording_policy, event);
+            }
+        }
+    });
+
+    let proxy_fut = JmuxProxy::new(reader, writer)
+        .with_config(config)
+        .with_event_sender(event_tx)
+        .with_connector(make_connector(conf, Arc::clone(&capture)))
+        .run();
     let proxy_handle = ChildTask::spawn(proxy_fut);
     let join_fut = proxy_handle.join();
     tokio::pin!(join_fut);
@@ -77,3 +160,76 @@ pub async fn handle(
 
     res
 }
+
+/// Channels are connected like any other target, so that the outbound options (e.g.: egress policy) apply.
+///
+/// The traffic of each channel is captured between the local address of its socket and the target address.
+fn make_connector(conf: Arc<Conf>, capture: Arc<SessionCapture>) -> Connector {
+    Arc::new(move |destination_url: &DestinationUrl| -> ConnectFuture {
+        let conf = Arc::clone(&conf);
+        let capture = Arc::clone(&capture);
+        let destination =
+            TargetAddr::from_components(destination_url.scheme(), destination_url.host(), destination_url.port());
+
+        Box::pin(async move {
+            let destination = destination.map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+            match crate::utils::tcp_connect(&destination, &conf.outbound).await {
+                Ok((stream, target_addr)) => {
+                    let gateway_addr = stream.local_addr()?;
+
+                    let mut stream = Interceptor::new(stream);
+                    stream.inspectors.push(Box::new(capture.channel_inspector(
+                        PeerSide::Server,
+                        gateway_addr,
+                        target_addr,
+                    )));
+                    stream.write_inspectors.push(Box::new(capture.channel_inspector(
+                        PeerSide::Client,
+                        gateway_addr,
+                        target_addr,
+                    )));
+
+                    Ok(Box::new(stream) as Box<dyn ChannelStream>)
+                }
+                Err(error) if EgressDenied::is_cause_of(&error) => {
+                    Err(io::Error::new(io::ErrorKind::PermissionDenied, format!("{error:#}")))
+                }
+                Err(error) => {
+                    // Preserve the kind of error, so that the peer is given the most accurate reason code.
+                    let kind = if error.is::<tokio::time::error::Elapsed>() {
+                        io::ErrorKind::TimedOut
+                    } else {
+                        error
+                            .downcast_ref::<io::Error>()
+                            .map_or(io::ErrorKind::Other, io::Error::kind)
+                    };
+
+                    Err(io::Error::new(kind, format!("{error:#}")))
+                }
+            }
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn raw_preamble() {
+        let mut input: &[u8] = b"JMUX\x00\x05tokenleftover";
+
+        let token = read_raw_preamble(&mut input).await.unwrap();
+
+        assert_eq!(token, "token");
+        assert_eq!(input, b"leftover");
+    }
+
+    #[tokio::test]
+    async fn raw_preamble_invalid_magic() {
+        let mut input: &[u8] = b"JET\x00\x00\x05token";
+
+        assert!(read_raw_preamble(&mut input).await.is_err());
+    }
+}
@@ -10,6 +10,7 @@ pub mod openapi;
 
 pub mod api;
 pub mod config;
+pub mod egress;
 pub mod extract;
 pub mod generic_client;
 pub mod http;
@@ -21,14 +22,21 @@ pub mod middleware;
 pub mod ngrok;
 pub mod plugin_manager;
 pub mod proxy;
+pub mod proxy_protocol;
+pub mod quic;
 pub mod rdp_extension;
 pub mod rdp_pcb;
 pub mod recording;
+pub mod rendezvous;
+pub mod resolver;
 pub mod session;
 pub mod subscriber;
 pub mod target_addr;
+pub mod target_pool;
 pub mod tls;
 pub mod token;
+pub mod udp;
+pub mod upstream_proxy;
 pub mod utils;
 pub mod ws;
 
@@ -41,6 +49,9 @@ pub struct DgwState {
     pub subscriber_tx: subscriber::SubscriberSender,
     pub shutdown_signal: devolutions_gateway_task::ShutdownSignal,
     pub recordings: recording::RecordingMessageSender,
+    pub recording_storage: recording::storage::DynRecordingStorage,
+    pub target_pools: Arc<target_pool::TargetPoolState>,
+    pub rendezvous: Arc<rendezvous::RendezvousState>,
 }
 
 #[doc(hidden)]
@@ -57,10 +68,14 @@ impl DgwState {
         let conf_handle = config::ConfHandle::mock(json_config)?;
         let token_cache = Arc::new(token::new_token_cache());
         let jrl = Arc::new(parking_lot::Mutex::new(token::JrlTokenClaims::default()));
-        let (session_manager_handle, session_manager_rx) = session::session_manager_channel();
         let (recording_manager_handle, recording_manager_rx) = recording::recording_message_channel();
+        let (session_manager_handle, session_manager_rx) = session::session_manager_channel(&recording_manager_handle);
         let (subscriber_tx, subscriber_rx) = subscriber::subscriber_channel();
         let (shutdown_handle, shutdown_signal) = devolutions_gateway_task::ShutdownHandle::new();
+        let recording_storage = {
+            let conf = conf_handle.get_conf();
+            recording::storage::from_conf(&conf.recording_storage, &conf.recording_path)?
+        };
 
         let state = Self {
             conf_handle,
@@ -70,6 +85,9 @@ impl DgwState {
             subscriber_tx,
             shutdown_signal,
             recordings: recording_manager_handle,
+            recording_storage,
+            target_pools: Arc::new(target_pool::TargetPoolState::default()),
+            rendezvous: Arc::new(rendezvous::RendezvousState::default()),
         };
 
         let handles = MockHandles {
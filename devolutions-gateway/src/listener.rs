@@ -10,11 +10,15 @@ use tracing::Instrument as _;
 use url::Url;
 
 use crate::generic_client::GenericClient;
+use crate::proxy_protocol;
+use crate::token::AccessTokenClaims;
 use crate::utils::url_to_socket_addr;
 use crate::DgwState;
 
 const HTTP_CONNECTION_MAX_DURATION: tokio::time::Duration = tokio::time::Duration::from_secs(10 * 60);
 
+const TLS_HANDSHAKE_TIMEOUT: tokio::time::Duration = tokio::time::Duration::from_secs(10);
+
 #[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
 #[derive(Debug, Clone, Serialize)]
 pub struct ListenerUrls {
@@ -32,13 +36,19 @@ pub enum ListenerKind {
     Tcp,
     Http,
     Https,
+    Quic,
+}
+
+enum ListenerSocket {
+    Tcp(TcpListener),
+    Quic(quinn::Endpoint),
 }
 
 pub struct GatewayListener {
     addr: SocketAddr,
     listener_url: Url,
     kind: ListenerKind,
-    listener: TcpListener,
+    socket: ListenerSocket,
     state: DgwState,
 }
 
@@ -50,31 +60,40 @@ impl GatewayListener {
 
         let socket_addr = url_to_socket_addr(&url).context("invalid url")?;
 
-        let socket = if socket_addr.is_ipv4() {
-            TcpSocket::new_v4().context("failed to create IPv4 TCP socket")?
-        } else {
-            TcpSocket::new_v6().context("failed to created IPv6 TCP socket")?
-        };
-        socket.bind(socket_addr).context("failed to bind TCP socket")?;
-
-        let listener = socket
-            .listen(64)
-            .context("failed to listen with the binded TCP socket")?;
-
         let kind = match url.scheme() {
             "tcp" => ListenerKind::Tcp,
             "http" => ListenerKind::Http,
             "https" => ListenerKind::Https,
+            "quic" => ListenerKind::Quic,
             unsupported => anyhow::bail!("unsupported listener scheme: {}", unsupported),
         };
 
+        let socket = if kind == ListenerKind::Quic {
+            let conf = state.conf_handle.get_conf();
+            let tls_conf = conf.tls.as_ref().context("TLS configuration is missing")?;
+
+            crate::quic::bind(socket_addr, tls_conf).map(ListenerSocket::Quic)?
+        } else {
+            let socket = if socket_addr.is_ipv4() {
+                TcpSocket::new_v4().context("failed to create IPv4 TCP socket")?
+            } else {
+                TcpSocket::new_v6().context("failed to created IPv6 TCP socket")?
+            };
+            socket.bind(socket_addr).context("failed to bind TCP socket")?;
+
+            socket
+                .listen(64)
+                .context("failed to listen with the binded TCP socket")
+                .map(ListenerSocket::Tcp)?
+        };
+
         info!(?kind, addr = %socket_addr, "Listener started successfully");
 
         Ok(Self {
             addr: socket_addr,
             listener_url: url,
             kind,
-            listener,
+            socket,
             state,
         })
     }
@@ -89,10 +108,14 @@ impl GatewayListener {
 
     #[instrument("listener", skip(self), fields(port = self.listener_url.port().expect("port")))]
     pub async fn run(self) -> anyhow::Result<()> {
-        match self.kind() {
-            ListenerKind::Tcp => run_tcp_listener(self.listener, self.state).await,
-            ListenerKind::Http => run_http_listener(self.listener, self.state).await,
-            ListenerKind::Https => run_https_listener(self.listener, self.state).await,
+        match (self.kind, self.socket) {
+            (ListenerKind::Tcp, ListenerSocket::Tcp(listener)) => run_tcp_listener(listener, self.state).await,
+            (ListenerKind::Http, ListenerSocket::Tcp(listener)) => run_http_listener(listener, self.state).await,
+            (ListenerKind::Https, ListenerSocket::Tcp(listener)) => run_https_listener(listener, self.state).await,
+            (ListenerKind::Quic, ListenerSocket::Quic(endpoint)) => {
+                crate::quic::run_listener(endpoint, self.state).await
+            }
+            (kind, _) => anyhow::bail!("unexpected socket for {kind:?} listener"),
         }
     }
 }
@@ -114,11 +137,15 @@ impl Task for GatewayListener {
 async fn run_tcp_listener(listener: TcpListener, state: DgwState) -> anyhow::Result<()> {
     loop {
         match listener.accept().await.context("failed to accept connection") {
-            Ok((stream, peer_addr)) => {
+            Ok((mut stream, peer_addr)) => {
                 let state = state.clone();
 
                 ChildTask::spawn(async move {
-                    if let Err(e) = handle_tcp_peer(stream, state, peer_addr).await {
+                    let Some(client_addr) = recover_client_addr(&mut stream, peer_addr, &state).await else {
+                        return;
+                    };
+
+                    if let Err(e) = handle_tcp_peer(stream, state, client_addr).await {
                         error!(error = format!("{e:#}"), "Peer failure");
                     }
                 })
@@ -135,6 +162,8 @@ async fn handle_tcp_peer(stream: TcpStream, state: DgwState, peer_addr: SocketAd
         error!("set_nodelay on TcpStream failed: {}", e);
     }
 
+    let local_addr = stream.local_addr().context("couldn't retrieve local address")?;
+
     let mut peeked = [0; 4];
     let n_read = stream
         .peek(&mut peeked)
@@ -143,8 +172,21 @@ async fn handle_tcp_peer(stream: TcpStream, state: DgwState, peer_addr: SocketAd
 
     // Check if first four bytes contains some protocol magic bytes
     match &peeked[..n_read] {
-        [b'J', b'E', b'T', b'\0'] => anyhow::bail!("not yet supported"),
-        [b'J', b'M', b'U', b'X'] => anyhow::bail!("not yet supported"),
+        [b'J', b'E', b'T', b'\0'] => crate::rendezvous::handle_jet_peer(stream, peer_addr, state).await?,
+        [b'J', b'M', b'U', b'X'] => handle_raw_jmux_peer(stream, state, peer_addr, local_addr).await?,
+        // TLS handshake record: only raw JMUX sessions are accepted over TLS.
+        [0x16, 0x03, 0x01..=0x03, _] => {
+            let conf = state.conf_handle.get_conf();
+
+            let tls_conf = conf.tls.as_ref().context("TLS configuration is missing")?;
+
+            let tls_stream = tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, tls_conf.acceptor.accept(stream))
+                .await
+                .context("timed out at TLS handshake")?
+                .context("TLS handshake failed")?;
+
+            handle_raw_jmux_peer(tls_stream, state, peer_addr, local_addr).await?;
+        }
         _ => {
             GenericClient::builder()
                 .conf(state.conf_handle.get_conf())
@@ -155,6 +197,8 @@ async fn handle_tcp_peer(stream: TcpStream, state: DgwState, peer_addr: SocketAd
                 .sessions(state.sessions)
                 .subscriber_tx(state.subscriber_tx)
                 .active_recordings(state.recordings.active_recordings)
+                .target_pools(state.target_pools)
+                .rendezvous(state.rendezvous)
                 .build()
                 .serve()
                 .await?;
@@ -164,21 +208,75 @@ async fn handle_tcp_peer(stream: TcpStream, state: DgwState, peer_addr: SocketAd
     Ok(())
 }
 
+/// Serves a JMUX session established without WebSocket, the JMUX token being sent in a preamble.
+pub(crate) async fn handle_raw_jmux_peer<S>(
+    mut stream: S,
+    state: DgwState,
+    client_addr: SocketAddr,
+    local_addr: SocketAddr,
+) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let token = tokio::time::timeout(
+        crate::jmux::RAW_PREAMBLE_READ_TIMEOUT,
+        crate::jmux::read_raw_preamble(&mut stream),
+    )
+    .await
+    .context("timed out at JMUX preamble reception")?
+    .context("invalid JMUX preamble")?;
+
+    let conf = state.conf_handle.get_conf();
+
+    let claims = crate::middleware::auth::authenticate(
+        client_addr,
+        &token,
+        &conf,
+        &state.token_cache,
+        &state.jrl,
+        &state.recordings.active_recordings,
+    )
+    .context("token validation")?;
+
+    let AccessTokenClaims::Jmux(claims) = claims else {
+        anyhow::bail!("unexpected token type");
+    };
+
+    info!(session_id = %claims.jet_aid, "Raw JMUX session");
+
+    crate::jmux::handle(
+        stream,
+        claims,
+        client_addr,
+        local_addr,
+        conf,
+        state.sessions,
+        state.subscriber_tx,
+    )
+    .await
+}
+
 async fn run_http_listener(listener: TcpListener, state: DgwState) -> anyhow::Result<()> {
     loop {
         match listener.accept().await {
-            Ok((stream, peer_addr)) => {
+            Ok((mut stream, peer_addr)) => {
                 let state = state.clone();
 
-                let fut = tokio::time::timeout(HTTP_CONNECTION_MAX_DURATION, async move {
-                    if let Err(e) = handle_http_peer(stream, state, peer_addr).await {
-                        error!(error = format!("{e:#}"), "handle_http_peer failed");
-                    }
+                ChildTask::spawn(async move {
+                    let Some((client_addr, local_addr)) = recover_addrs(&mut stream, peer_addr, &state).await else {
+                        return;
+                    };
+
+                    let _ = tokio::time::timeout(HTTP_CONNECTION_MAX_DURATION, async move {
+                        if let Err(e) = handle_http_peer(stream, state, client_addr, local_addr).await {
+                            error!(error = format!("{e:#}"), "handle_http_peer failed");
+                        }
+                    })
+                    .inspect_err(|error| debug!(%error, "Drop long-lived HTTP connection"))
+                    .instrument(info_span!("http", client = %client_addr))
+                    .await;
                 })
-                .inspect_err(|error| debug!(%error, "Drop long-lived HTTP connection"))
-                .instrument(info_span!("http", client = %peer_addr));
-
-                ChildTask::spawn(fut).detach();
+                .detach();
             }
             Err(error) => {
                 error!(%error, "failed to accept connection");
@@ -194,19 +292,26 @@ async fn run_https_listener(listener: TcpListener, state: DgwState) -> anyhow::R
 
     loop {
         match listener.accept().await {
-            Ok((stream, peer_addr)) => {
-                let tls_acceptor = tls_conf.acceptor.clone();
+            Ok((mut stream, peer_addr)) => {
+                let tls_acceptor = tls_conf.http_acceptor.clone();
                 let state = state.clone();
 
-                let fut = tokio::time::timeout(HTTP_CONNECTION_MAX_DURATION, async move {
-                    if let Err(e) = handle_https_peer(stream, tls_acceptor, state, peer_addr).await {
-                        error!(error = format!("{e:#}"), "handle_https_peer failed");
-                    }
+                ChildTask::spawn(async move {
+                    // The PROXY protocol header is sent in clear, before the TLS handshake.
+                    let Some((client_addr, local_addr)) = recover_addrs(&mut stream, peer_addr, &state).await else {
+                        return;
+                    };
+
+                    let _ = tokio::time::timeout(HTTP_CONNECTION_MAX_DURATION, async move {
+                        if let Err(e) = handle_https_peer(stream, tls_acceptor, state, client_addr, local_addr).await {
+                            error!(error = format!("{e:#}"), "handle_https_peer failed");
+                        }
+                    })
+                    .inspect_err(|error| debug!(%error, "Drop long-lived HTTP connection"))
+                    .instrument(info_span!("https", client = %client_addr))
+                    .await;
                 })
-                .inspect_err(|error| debug!(%error, "Drop long-lived HTTP connection"))
-                .instrument(info_span!("https", client = %peer_addr));
-
-                ChildTask::spawn(fut).detach();
+                .detach();
             }
             Err(error) => {
                 error!(%error, "failed to accept connection");
@@ -215,11 +320,47 @@ async fn run_https_listener(listener: TcpListener, state: DgwState) -> anyhow::R
     }
 }
 
+/// Returns the address of the actual client, or `None` when the connection must be dropped.
+///
+/// Behind a load balancer, the client address is advertised in a PROXY protocol header.
+async fn recover_client_addr(stream: &mut TcpStream, peer_addr: SocketAddr, state: &DgwState) -> Option<SocketAddr> {
+    let conf = state.conf_handle.get_conf();
+
+    match proxy_protocol::recover_client_addr(stream, peer_addr, &conf.proxy_protocol).await {
+        Ok(client_addr) => Some(client_addr),
+        Err(e) => {
+            warn!(error = format!("{e:#}"), %peer_addr, "Rejected connection");
+            None
+        }
+    }
+}
+
+/// Returns the addresses of the actual client and of the local end of the connection, or `None` when the connection
+/// must be dropped.
+async fn recover_addrs(
+    stream: &mut TcpStream,
+    peer_addr: SocketAddr,
+    state: &DgwState,
+) -> Option<(SocketAddr, SocketAddr)> {
+    let local_addr = match stream.local_addr() {
+        Ok(local_addr) => local_addr,
+        Err(error) => {
+            warn!(%error, %peer_addr, "Couldn't retrieve local address");
+            return None;
+        }
+    };
+
+    let client_addr = recover_client_addr(stream, peer_addr, state).await?;
+
+    Some((client_addr, local_addr))
+}
+
 async fn handle_https_peer(
     stream: TcpStream,
     tls_acceptor: tokio_rustls::TlsAcceptor,
     state: DgwState,
     peer_addr: SocketAddr,
+    local_addr: SocketAddr,
 ) -> anyhow::Result<()> {
     let tls_stream = tls_acceptor
         .accept(stream)
@@ -227,28 +368,50 @@ async fn handle_https_peer(
         .context("TLS handshake failed")?
         .pipe(tokio_rustls::TlsStream::Server);
 
-    handle_http_peer(tls_stream, state, peer_addr).await
+    handle_http_peer(tls_stream, state, peer_addr, local_addr).await
 }
 
-pub(crate) async fn handle_http_peer<I>(io: I, state: DgwState, peer_addr: SocketAddr) -> anyhow::Result<()>
+/// Local address of the listener an HTTP connection was accepted on
+#[derive(Debug, Clone, Copy)]
+pub struct LocalAddr(pub SocketAddr);
+
+pub async fn handle_http_peer<I>(
+    io: I,
+    state: DgwState,
+    peer_addr: SocketAddr,
+    local_addr: SocketAddr,
+) -> anyhow::Result<()>
 where
     I: AsyncRead + AsyncWrite + Send + Unpin + 'static,
 {
     use axum::extract::connect_info::ConnectInfo;
     use hyper::service::service_fn;
-    use tower::Service as _;
+    use tower::{Service as _, ServiceBuilder};
 
     let service = service_fn(move |request: hyper::Request<hyper::body::Incoming>| {
         // We have to clone `tower_service` because hyper's `Service` uses `&self` whereas
         // tower's `Service` requires `&mut self`.
         //
         // We don't need to call `poll_ready` since `Router` is always ready.
-        crate::make_http_service(state.clone())
+        let router = crate::make_http_service(state.clone())
             .layer(axum::Extension(ConnectInfo(peer_addr)))
-            .call(request)
+            .layer(axum::Extension(LocalAddr(local_addr)));
+
+        // The extended CONNECT requests must be rewritten before routing.
+        ServiceBuilder::new()
+            .layer(axum::middleware::from_fn(
+                crate::middleware::websocket::extended_connect_middleware,
+            ))
+            .service(router)
+            .call(request.map(axum::body::Body::new))
     });
 
-    let result = hyper_util::server::conn::auto::Builder::new(hyper_util::rt::TokioExecutor::new())
+    let mut builder = hyper_util::server::conn::auto::Builder::new(hyper_util::rt::TokioExecutor::new());
+
+    // WebSocket over HTTP/2 (RFC 8441).
+    builder.http2().enable_connect_protocol();
+
+    let result = builder
         .serve_connection_with_upgrades(hyper_util::rt::TokioIo::new(io), service)
         .await;
 
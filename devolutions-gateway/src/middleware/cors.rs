@@ -1,10 +1,30 @@
-use axum::http::{header, Method};
+use axum::http::{header, HeaderName, Method};
 use tower_http::cors::CorsLayer;
 
 pub fn make_middleware() -> CorsLayer {
     CorsLayer::new()
-        .allow_methods([Method::GET, Method::POST, Method::PUT, Method::DELETE, Method::PATCH])
-        .allow_headers([header::AUTHORIZATION, header::CONTENT_TYPE])
+        .allow_methods([
+            Method::GET,
+            Method::HEAD,
+            Method::POST,
+            Method::PUT,
+            Method::DELETE,
+            Method::PATCH,
+        ])
+        .allow_headers([
+            header::AUTHORIZATION,
+            header::CONTENT_TYPE,
+            header::RANGE,
+            header::IF_RANGE,
+            header::IF_NONE_MATCH,
+        ])
+        .expose_headers([
+            header::CONTENT_RANGE,
+            header::ACCEPT_RANGES,
+            header::ETAG,
+            header::CONTENT_DISPOSITION,
+            HeaderName::from_static("x-total-count"),
+        ])
         .allow_origin(tower_http::cors::Any)
         .max_age(std::time::Duration::from_secs(7200))
         .allow_credentials(false)
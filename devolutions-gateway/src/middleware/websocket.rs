@@ -0,0 +1,53 @@
+//! WebSocket over HTTP/2 (RFC 8441)
+//!
+//! An HTTP/2 client opens a WebSocket with an extended CONNECT request (`:protocol = websocket`) instead of an
+//! HTTP/1.1 upgrade. The request is rewritten into the HTTP/1.1 form expected by `WebSocketUpgrade`, and the
+//! `101 Switching Protocols` response is turned into the `200 OK` expected by the HTTP/2 client. The stream itself
+//! is upgraded by hyper on a successful response.
+//!
+//! Since the method is rewritten, this middleware must wrap the router instead of being added with `Router::layer`,
+//! which runs after routing.
+
+use axum::body::Body;
+use axum::http::{header, HeaderValue, Method, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::Response;
+
+pub async fn extended_connect_middleware(mut request: Request<Body>, next: Next) -> Response {
+    let is_websocket_connect = request.method() == Method::CONNECT
+        && request
+            .extensions()
+            .get::<hyper::ext::Protocol>()
+            .is_some_and(|protocol| protocol.as_str().eq_ignore_ascii_case("websocket"));
+
+    if !is_websocket_connect {
+        return next.run(request).await;
+    }
+
+    *request.method_mut() = Method::GET;
+
+    let headers = request.headers_mut();
+    headers.insert(header::CONNECTION, HeaderValue::from_static("upgrade"));
+    headers.insert(header::UPGRADE, HeaderValue::from_static("websocket"));
+    // The key is only used to compute `Sec-WebSocket-Accept`, which is removed from the response below.
+    headers.insert(
+        header::SEC_WEBSOCKET_KEY,
+        HeaderValue::from_static("dGhlIHNhbXBsZSBub25jZQ=="),
+    );
+
+    let mut response = next.run(request).await;
+
+    if response.status() == StatusCode::SWITCHING_PROTOCOLS {
+        *response.status_mut() = StatusCode::OK;
+
+        // Connection-specific headers are not allowed in HTTP/2, and a content length would make the client
+        // reject the tunneled data.
+        let headers = response.headers_mut();
+        headers.remove(header::CONNECTION);
+        headers.remove(header::UPGRADE);
+        headers.remove(header::SEC_WEBSOCKET_ACCEPT);
+        headers.remove(header::CONTENT_LENGTH);
+    }
+
+    response
+}
@@ -1,3 +1,4 @@
+use std::net::{Ipv4Addr, SocketAddr};
 use std::time::Duration;
 
 use anyhow::Context as _;
@@ -227,12 +228,14 @@ async fn run_tcp_tunnel(mut tunnel: ngrok::tunnel::TcpTunnel, state: DgwState) {
                     if let Err(e) = GenericClient::builder()
                         .conf(state.conf_handle.get_conf())
                         .client_addr(peer_addr)
-                        .client_stream(conn)
+                        .client_stream(Box::new(conn) as transport::ErasedReadWrite)
                         .token_cache(state.token_cache)
                         .jrl(state.jrl)
                         .sessions(state.sessions)
                         .subscriber_tx(state.subscriber_tx)
                         .active_recordings(state.recordings.active_recordings)
+                        .target_pools(state.target_pools)
+                        .rendezvous(state.rendezvous)
                         .build()
                         .serve()
                         .await
@@ -265,7 +268,10 @@ async fn run_http_tunnel(mut tunnel: ngrok::tunnel::HttpTunnel, state: DgwState)
                 let peer_addr = conn.remote_addr();
 
                 let fut = async move {
-                    if let Err(e) = crate::listener::handle_http_peer(conn, state, peer_addr).await {
+                    // Tunneled connections are not accepted on a local listener.
+                    let local_addr = SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0));
+
+                    if let Err(e) = crate::listener::handle_http_peer(conn, state, peer_addr, local_addr).await {
                         error!(error = format!("{e:#}"), "handle_http_peer failed");
                     }
                 }
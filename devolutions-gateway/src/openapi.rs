@@ -10,13 +10,17 @@ use uuid::Uuid;
         crate::api::heartbeat::get_heartbeat,
         crate::api::sessions::get_sessions,
         crate::api::session::terminate_session,
+        crate::api::session::start_capture,
         crate::api::diagnostics::get_logs,
         crate::api::diagnostics::get_configuration,
         crate::api::diagnostics::get_clock,
+        crate::api::diagnostics::get_resolution,
+        crate::api::diagnostics::get_target_pools,
         crate::api::config::patch_config,
         crate::api::jrl::update_jrl,
         crate::api::jrl::get_jrl_info,
         crate::api::jrec::list_recordings,
+        crate::api::jrec::export_recordings,
         crate::api::jrec::pull_recording_file,
         crate::api::webapp::sign_app_token,
         crate::api::webapp::sign_session_token,
@@ -33,6 +37,11 @@ use uuid::Uuid;
         crate::config::dto::Subscriber,
         crate::api::diagnostics::ConfigDiagnostic,
         crate::api::diagnostics::ClockDiagnostic,
+        crate::api::diagnostics::ResolutionDiagnostic,
+        crate::api::diagnostics::SrvRecordDiagnostic,
+        crate::api::diagnostics::TargetPoolDiagnostic,
+        crate::api::diagnostics::PoolTargetDiagnostic,
+        crate::target_pool::SelectionStrategy,
         crate::api::config::SubProvisionerKey,
         crate::api::config::ConfigPatch,
         crate::api::jrl::JrlInfo,
@@ -59,6 +68,9 @@ struct SessionInfo {
     recording_policy: bool,
     /// Filtering Policy
     filtering_policy: bool,
+    /// Capture Policy
+    // NOTE: Optional purely for client code generation (this field didn't always exist)
+    capture_policy: Option<bool>,
     /// Date this session was started
     start_timestamp: OffsetDateTime,
     /// Maximum session duration in minutes (0 is used for the infinite duration)
@@ -68,6 +80,10 @@ struct SessionInfo {
     connection_mode: ConnectionMode,
     /// Destination Host
     destination_host: Option<String>,
+    /// Subject of the token used to establish the session
+    subject: Option<String>,
+    /// Address of the client which established the session
+    client_addr: Option<String>,
 }
 
 #[allow(unused)]
@@ -228,6 +244,10 @@ struct SessionTokenSignRequest {
     destination: Option<String>,
     /// Unique ID for this session
     session_id: Option<Uuid>,
+    /// SHA-256 thumbprints of the certificates accepted for the destination, instead of verifying the chain
+    tls_pin: Option<Vec<String>>,
+    /// Do not verify the certificate of the destination
+    tls_insecure: Option<bool>,
     /// Kerberos realm.
     ///
     /// E.g.: `ad.it-help.ninja`.
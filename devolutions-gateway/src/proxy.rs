@@ -1,18 +1,25 @@
 use crate::config::Conf;
-use crate::interceptor::pcap::PcapInspector;
-use crate::interceptor::{Dissector, DummyDissector, Interceptor, WaykDissector};
+use crate::interceptor::pcap::SessionCapture;
+use crate::interceptor::{Dissector, DummyDissector, Inspector, Interceptor, PeerSide, WaykDissector};
+use crate::recording::events::SessionEvent;
 use crate::session::{SessionInfo, SessionMessageSender};
 use crate::subscriber::SubscriberSender;
 use crate::token::{ApplicationProtocol, Protocol};
-use camino::Utf8PathBuf;
+use anyhow::Context as _;
+use devolutions_gateway_task::ChildTask;
 use futures::future::Either;
 use std::io;
 use std::net::SocketAddr;
 use std::pin::pin;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt as _};
+#[cfg(target_os = "linux")]
+use tokio::net::TcpStream;
 use tokio::sync::Notify;
 use typed_builder::TypedBuilder;
+use uuid::Uuid;
 
 #[derive(TypedBuilder)]
 pub struct Proxy<A, B> {
@@ -64,67 +71,50 @@ where
     where
         D: Dissector + Send + 'static,
     {
-        if let Some(capture_path) = self.conf.debug.capture_path.as_ref() {
-            let format = time::format_description::parse("[year]-[month]-[day]_[hour]-[minute]-[second]")
-                .expect("valid hardcoded format");
-
-            let filename = format!(
-                "{}({})-to-{}({})-at-{}.pcap",
-                self.address_a.ip(),
-                self.address_a.port(),
-                self.address_b.ip(),
-                self.address_b.port(),
-                time::OffsetDateTime::now_utc().format(&format)?
-            );
-            let mut path = Utf8PathBuf::from(capture_path);
-            path.push(filename);
-
-            let (client_inspector, server_inspector) =
-                PcapInspector::init(self.address_a, self.address_b, path, dissector)?;
-
-            let mut a = Interceptor::new(self.transport_a);
-            a.inspectors.push(Box::new(client_inspector));
-
-            let mut b = Interceptor::new(self.transport_b);
-            b.inspectors.push(Box::new(server_inspector));
-
-            Proxy {
-                transport_a: a,
-                transport_b: b,
-                conf: self.conf,
-                session_info: self.session_info,
-                address_a: self.address_a,
-                address_b: self.address_b,
-                sessions: self.sessions,
-                subscriber_tx: self.subscriber_tx,
-                buffer_size: self.buffer_size,
-            }
-            .forward()
-            .await
-        } else {
-            self.forward().await
-        }
+        let capture = SessionCapture::new(
+            &self.conf,
+            &self.session_info,
+            self.address_a,
+            self.address_b,
+            dissector,
+        )
+        .context("traffic capture")?;
+
+        self.forward_with_capture(capture).await
     }
 
+    /// Forwards the data without any dissector; the traffic is captured like for any other protocol.
     pub async fn forward(self) -> anyhow::Result<()> {
-        let mut transport_a = self.transport_a;
-        let mut transport_b = self.transport_b;
-
-        let session_id = self.session_info.id();
-        let notify_kill = Arc::new(Notify::new());
+        self.forward_using_dissector(DummyDissector).await
+    }
 
-        crate::session::add_session_in_progress(
-            &self.sessions,
-            &self.subscriber_tx,
+    async fn forward_with_capture(self, capture: Arc<SessionCapture>) -> anyhow::Result<()> {
+        let session = ForwardingSession::start(
             self.session_info,
-            notify_kill.clone(),
+            Some(Arc::clone(&capture)),
+            self.sessions,
+            self.subscriber_tx,
         )
         .await?;
 
-        // NOTE(DGW-86): when recording is required, should we wait for it to start before we forward, or simply spawn
-        // a timer to check if the recording is started within a few seconds?
+        let mut transport_a = Interceptor::new(self.transport_a);
+        transport_a
+            .inspectors
+            .push(Box::new(ByteCounter(Arc::clone(&session.from_client))));
 
-        let kill_notified = notify_kill.notified();
+        let mut transport_b = Interceptor::new(self.transport_b);
+        transport_b
+            .inspectors
+            .push(Box::new(ByteCounter(Arc::clone(&session.from_server))));
+
+        transport_a
+            .inspectors
+            .push(Box::new(capture.inspector(PeerSide::Client)));
+        transport_b
+            .inspectors
+            .push(Box::new(capture.inspector(PeerSide::Server)));
+
+        let kill_notified = session.notify_kill.notified();
 
         let res = if let Some(buffer_size) = self.buffer_size {
             // Use our for of copy_bidirectional because tokio doesn't have an API to set the buffer size.
@@ -146,7 +136,111 @@ where
         // Ensure we close the transports cleanly at the end (ignore errors at this point)
         let _ = tokio::join!(transport_a.shutdown(), transport_b.shutdown());
 
-        crate::session::remove_session_in_progress(&self.sessions, &self.subscriber_tx, session_id).await?;
+        session.end(res).await
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Proxy<TcpStream, TcpStream> {
+    /// Forwards the data without copying it into userspace buffers, using splice(2).
+    ///
+    /// No dissector is used, and the traffic of a session forwarded this way can't be captured, not even on demand.
+    pub async fn forward_spliced(mut self) -> anyhow::Result<()> {
+        let session = ForwardingSession::start(self.session_info, None, self.sessions, self.subscriber_tx).await?;
+
+        let kill_notified = session.notify_kill.notified();
+
+        let forward_fut = transport::splice_bidirectional(
+            &self.transport_a,
+            &self.transport_b,
+            &session.from_client,
+            &session.from_server,
+        );
+
+        let res = match futures::future::select(pin!(forward_fut), pin!(kill_notified)).await {
+            Either::Left((res, _)) => res.map(|_| ()),
+            Either::Right(_) => Ok(()),
+        };
+
+        // Ensure we close the transports cleanly at the end (ignore errors at this point)
+        let _ = tokio::join!(self.transport_a.shutdown(), self.transport_b.shutdown());
+
+        session.end(res).await
+    }
+}
+
+/// Bookkeeping of a session for the duration of the forwarding.
+struct ForwardingSession {
+    session_id: Uuid,
+    recording_policy: bool,
+    sessions: SessionMessageSender,
+    subscriber_tx: SubscriberSender,
+    notify_kill: Arc<Notify>,
+    from_client: Arc<AtomicU64>,
+    from_server: Arc<AtomicU64>,
+    checkpoint_task: ChildTask<()>,
+}
+
+impl ForwardingSession {
+    async fn start(
+        session_info: SessionInfo,
+        capture: Option<Arc<SessionCapture>>,
+        sessions: SessionMessageSender,
+        subscriber_tx: SubscriberSender,
+    ) -> anyhow::Result<Self> {
+        let from_client = Arc::new(AtomicU64::new(0));
+        let from_server = Arc::new(AtomicU64::new(0));
+
+        let session_id = session_info.id();
+        let recording_policy = session_info.recording_policy;
+        let notify_kill = Arc::new(Notify::new());
+
+        crate::session::add_session_in_progress(&sessions, &subscriber_tx, session_info, notify_kill.clone(), capture)
+            .await?;
+
+        // NOTE(DGW-86): when recording is required, should we wait for it to start before we forward, or simply spawn
+        // a timer to check if the recording is started within a few seconds?
+
+        // Periodically add the number of bytes forwarded so far to the timeline of the session.
+        let checkpoint_task = ChildTask::spawn({
+            let sessions = sessions.clone();
+            let from_client = Arc::clone(&from_client);
+            let from_server = Arc::clone(&from_server);
+
+            async move {
+                let mut interval = tokio::time::interval(BYTES_CHECKPOINT_INTERVAL);
+                interval.tick().await;
+
+                loop {
+                    interval.tick().await;
+                    record_bytes_checkpoint(&sessions, session_id, recording_policy, &from_client, &from_server);
+                }
+            }
+        });
+
+        Ok(Self {
+            session_id,
+            recording_policy,
+            sessions,
+            subscriber_tx,
+            notify_kill,
+            from_client,
+            from_server,
+            checkpoint_task,
+        })
+    }
+
+    async fn end(self, res: io::Result<()>) -> anyhow::Result<()> {
+        drop(self.checkpoint_task);
+        record_bytes_checkpoint(
+            &self.sessions,
+            self.session_id,
+            self.recording_policy,
+            &self.from_client,
+            &self.from_server,
+        );
+
+        crate::session::remove_session_in_progress(&self.sessions, &self.subscriber_tx, self.session_id).await?;
 
         match res {
             Ok(()) => {
@@ -169,6 +263,34 @@ where
     }
 }
 
+fn record_bytes_checkpoint(
+    sessions: &SessionMessageSender,
+    session_id: Uuid,
+    recording_policy: bool,
+    from_client: &AtomicU64,
+    from_server: &AtomicU64,
+) {
+    sessions.record_event(
+        session_id,
+        recording_policy,
+        SessionEvent::BytesCheckpoint {
+            from_client: from_client.load(Ordering::Relaxed),
+            from_server: from_server.load(Ordering::Relaxed),
+        },
+    )
+}
+
+const BYTES_CHECKPOINT_INTERVAL: Duration = Duration::from_secs(60);
+
+struct ByteCounter(Arc<AtomicU64>);
+
+impl Inspector for ByteCounter {
+    fn inspect_bytes(&mut self, bytes: &[u8]) -> anyhow::Result<()> {
+        self.0.fetch_add(bytes.len() as u64, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
 /// Walks source chain and check for status codes like ECONNRESET or ECONNABORTED that we don’t consider to be actual errors
 fn is_really_an_error(original_error: &io::Error) -> bool {
     use std::error::Error as _;
@@ -0,0 +1,330 @@
+//! [PROXY protocol] (versions 1 and 2) support.
+//!
+//! Load balancers such as HAProxy or AWS NLB prepend a header to the TCP stream, advertising the address of the
+//! actual client. This header is only honored for the connections coming from a trusted source, and it is then
+//! required: as recommended by the specification, the receiver never tries to guess whether it is present.
+//!
+//! The Gateway may itself send a version 2 header to the targets, so that they see the address of the client
+//! instead of the one of the Gateway.
+//!
+//! [PROXY protocol]: https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use anyhow::Context as _;
+use tokio::io::{AsyncRead, AsyncReadExt as _, AsyncWrite, AsyncWriteExt as _};
+use uuid::Uuid;
+
+use crate::config::ProxyProtocolConf;
+
+const V2_SIGNATURE: [u8; 12] = [0x0D, 0x0A, 0x0D, 0x0A, 0x00, 0x0D, 0x0A, 0x51, 0x55, 0x49, 0x54, 0x0A];
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+
+/// Maximum length of a version 1 header, including the CRLF.
+const V1_MAX_LEN: usize = 107;
+
+const HEADER_READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+const V2_VERSION_COMMAND_PROXY: u8 = 0x21;
+const V2_FAMILY_INET_STREAM: u8 = 0x11;
+const V2_FAMILY_INET6_STREAM: u8 = 0x21;
+
+/// TLV type for an opaque identifier of the connection (up to 128 bytes).
+const PP2_TYPE_UNIQUE_ID: u8 = 0x05;
+
+/// Returns the address of the actual client, reading the PROXY protocol header if the peer is a trusted source.
+///
+/// The header is consumed from the stream, and nothing past it is read.
+pub async fn recover_client_addr<S>(
+    stream: &mut S,
+    peer_addr: SocketAddr,
+    conf: &ProxyProtocolConf,
+) -> anyhow::Result<SocketAddr>
+where
+    S: AsyncRead + Unpin,
+{
+    if !conf.is_trusted(peer_addr.ip()) {
+        return Ok(peer_addr);
+    }
+
+    let source_addr = tokio::time::timeout(HEADER_READ_TIMEOUT, read_header(stream))
+        .await
+        .context("timed out while reading the PROXY protocol header")?
+        .with_context(|| format!("invalid PROXY protocol header from {peer_addr}"))?;
+
+    match source_addr {
+        Some(client_addr) => {
+            debug!(proxy = %peer_addr, client = %client_addr, "Recovered client address from PROXY protocol header");
+            Ok(client_addr)
+        }
+        // e.g.: health checks performed by the proxy itself
+        None => Ok(peer_addr),
+    }
+}
+
+/// Sends a version 2 header advertising the client address to the target.
+///
+/// The session ID is sent as the unique ID of the connection. This must be the very first thing sent to the target.
+pub async fn send_header<S>(
+    stream: &mut S,
+    client_addr: SocketAddr,
+    server_addr: SocketAddr,
+    session_id: Uuid,
+) -> anyhow::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    let header = encode_v2(client_addr, server_addr, session_id);
+
+    stream
+        .write_all(&header)
+        .await
+        .context("failed to send PROXY protocol header")?;
+
+    debug!(client = %client_addr, "PROXY protocol header sent");
+
+    Ok(())
+}
+
+fn encode_v2(source: SocketAddr, destination: SocketAddr, session_id: Uuid) -> Vec<u8> {
+    let session_id = session_id.hyphenated().to_string();
+
+    let mut addresses = Vec::with_capacity(36 + 3 + session_id.len());
+
+    let family = match (source, destination) {
+        (SocketAddr::V4(source), SocketAddr::V4(destination)) => {
+            addresses.extend_from_slice(&source.ip().octets());
+            addresses.extend_from_slice(&destination.ip().octets());
+            V2_FAMILY_INET_STREAM
+        }
+        // Both addresses must belong to the same family: IPv4 addresses are mapped if required.
+        (source, destination) => {
+            addresses.extend_from_slice(&to_ipv6(source.ip()).octets());
+            addresses.extend_from_slice(&to_ipv6(destination.ip()).octets());
+            V2_FAMILY_INET6_STREAM
+        }
+    };
+
+    addresses.extend_from_slice(&source.port().to_be_bytes());
+    addresses.extend_from_slice(&destination.port().to_be_bytes());
+
+    addresses.push(PP2_TYPE_UNIQUE_ID);
+    addresses.extend_from_slice(&u16::try_from(session_id.len()).expect("UUID length").to_be_bytes());
+    addresses.extend_from_slice(session_id.as_bytes());
+
+    let mut header = Vec::with_capacity(V2_SIGNATURE.len() + 4 + addresses.len());
+    header.extend_from_slice(&V2_SIGNATURE);
+    header.push(V2_VERSION_COMMAND_PROXY);
+    header.push(family);
+    header.extend_from_slice(&u16::try_from(addresses.len()).expect("small header").to_be_bytes());
+    header.extend_from_slice(&addresses);
+
+    header
+}
+
+fn to_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+/// Reads a PROXY protocol header, returning the advertised source address if any.
+async fn read_header<S>(stream: &mut S) -> anyhow::Result<Option<SocketAddr>>
+where
+    S: AsyncRead + Unpin,
+{
+    // Both headers are at least 12-byte long.
+    let mut prefix = [0; 12];
+    stream.read_exact(&mut prefix).await.context("failed to read header")?;
+
+    if prefix == V2_SIGNATURE {
+        let mut fixed = [0; 4];
+        stream.read_exact(&mut fixed).await.context("failed to read header")?;
+
+        let [version_command, family_protocol, len_hi, len_lo] = fixed;
+        let len = usize::from(u16::from_be_bytes([len_hi, len_lo]));
+
+        let mut addresses = vec![0; len];
+        stream
+            .read_exact(&mut addresses)
+            .await
+            .context("failed to read addresses")?;
+
+        decode_v2(version_command, family_protocol, &addresses)
+    } else if prefix.starts_with(V1_PREFIX) {
+        let mut line = prefix.to_vec();
+
+        // Read byte by byte, so that nothing past the header is consumed.
+        while !line.ends_with(b"\r\n") {
+            anyhow::ensure!(line.len() < V1_MAX_LEN, "header is too long");
+            line.push(stream.read_u8().await.context("failed to read header")?);
+        }
+
+        decode_v1(&line)
+    } else {
+        anyhow::bail!("PROXY protocol header is missing")
+    }
+}
+
+fn decode_v1(line: &[u8]) -> anyhow::Result<Option<SocketAddr>> {
+    let line = std::str::from_utf8(line).context("header is not valid ASCII")?;
+    let line = line.strip_suffix("\r\n").context("header is not terminated by CRLF")?;
+
+    let mut fields = line.split(' ').skip(1);
+
+    match fields.next() {
+        Some("UNKNOWN") => Ok(None),
+        Some(family @ ("TCP4" | "TCP6")) => {
+            let (Some(source), Some(_destination), Some(source_port), Some(_destination_port), None) = (
+                fields.next(),
+                fields.next(),
+                fields.next(),
+                fields.next(),
+                fields.next(),
+            ) else {
+                anyhow::bail!("unexpected number of fields");
+            };
+
+            let source: IpAddr = match family {
+                "TCP4" => source.parse::<Ipv4Addr>().context("invalid source address")?.into(),
+                _ => source.parse::<Ipv6Addr>().context("invalid source address")?.into(),
+            };
+
+            let source_port = source_port.parse::<u16>().context("invalid source port")?;
+
+            Ok(Some(SocketAddr::new(source, source_port)))
+        }
+        _ => anyhow::bail!("unsupported protocol family"),
+    }
+}
+
+fn decode_v2(version_command: u8, family_protocol: u8, addresses: &[u8]) -> anyhow::Result<Option<SocketAddr>> {
+    const COMMAND_LOCAL: u8 = 0x0;
+    const COMMAND_PROXY: u8 = 0x1;
+
+    const FAMILY_INET: u8 = 0x1;
+    const FAMILY_INET6: u8 = 0x2;
+
+    anyhow::ensure!(version_command >> 4 == 2, "unsupported version");
+
+    match version_command & 0x0F {
+        COMMAND_LOCAL => return Ok(None),
+        COMMAND_PROXY => {}
+        _ => anyhow::bail!("unsupported command"),
+    }
+
+    // The addresses block may be followed by TLVs, which are ignored.
+    match family_protocol >> 4 {
+        FAMILY_INET => {
+            let block = addresses.get(..12).context("addresses block is truncated")?;
+            let source = Ipv4Addr::new(block[0], block[1], block[2], block[3]);
+            let source_port = u16::from_be_bytes([block[8], block[9]]);
+            Ok(Some(SocketAddr::new(source.into(), source_port)))
+        }
+        FAMILY_INET6 => {
+            let block = addresses.get(..36).context("addresses block is truncated")?;
+            let source = Ipv6Addr::from(<[u8; 16]>::try_from(&block[..16]).expect("16-byte slice"));
+            let source_port = u16::from_be_bytes([block[32], block[33]]);
+            Ok(Some(SocketAddr::new(source.into(), source_port)))
+        }
+        // AF_UNSPEC or AF_UNIX: the source address is not meaningful for us.
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn read_all(mut input: &[u8]) -> (anyhow::Result<Option<SocketAddr>>, Vec<u8>) {
+        let result = read_header(&mut input).await;
+        (result, input.to_vec())
+    }
+
+    #[tokio::test]
+    async fn v1_header() {
+        let (result, rest) = read_all(b"PROXY TCP4 192.0.2.10 198.51.100.1 56324 443\r\nGET / HTTP/1.1\r\n").await;
+        assert_eq!(result.unwrap(), Some("192.0.2.10:56324".parse().unwrap()));
+        assert_eq!(rest, b"GET / HTTP/1.1\r\n");
+
+        let (result, _) = read_all(b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n").await;
+        assert_eq!(result.unwrap(), Some("[2001:db8::1]:56324".parse().unwrap()));
+
+        let (result, rest) = read_all(b"PROXY UNKNOWN\r\n\x03\x00").await;
+        assert_eq!(result.unwrap(), None);
+        assert_eq!(rest, b"\x03\x00");
+    }
+
+    #[tokio::test]
+    async fn v2_header() {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x21, 0x11, 0x00, 0x0F]);
+        header.extend_from_slice(&[192, 0, 2, 10, 198, 51, 100, 1, 0xDC, 0x04, 0x01, 0xBB]);
+        header.extend_from_slice(&[0xEE, 0x00, 0x00]); // Empty TLV
+        header.extend_from_slice(b"\x03\x00");
+
+        let (result, rest) = read_all(&header).await;
+        assert_eq!(result.unwrap(), Some("192.0.2.10:56324".parse().unwrap()));
+        assert_eq!(rest, b"\x03\x00");
+    }
+
+    #[tokio::test]
+    async fn v2_header_roundtrip() {
+        let session_id = Uuid::new_v4();
+
+        let header = encode_v2(
+            "192.0.2.10:56324".parse().unwrap(),
+            "198.51.100.1:22".parse().unwrap(),
+            session_id,
+        );
+        assert_eq!(&header[12..14], &[V2_VERSION_COMMAND_PROXY, V2_FAMILY_INET_STREAM]);
+        assert!(header.ends_with(session_id.hyphenated().to_string().as_bytes()));
+
+        let (result, rest) = read_all(&header).await;
+        assert_eq!(result.unwrap(), Some("192.0.2.10:56324".parse().unwrap()));
+        assert!(rest.is_empty());
+
+        // Mixed families are sent as IPv6 addresses.
+        let header = encode_v2(
+            "192.0.2.10:56324".parse().unwrap(),
+            "[2001:db8::2]:22".parse().unwrap(),
+            session_id,
+        );
+        assert_eq!(header[13], V2_FAMILY_INET6_STREAM);
+
+        let (result, _) = read_all(&header).await;
+        assert_eq!(result.unwrap(), Some("[::ffff:192.0.2.10]:56324".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn v2_local_command() {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x20, 0x00, 0x00, 0x00]);
+
+        let (result, rest) = read_all(&header).await;
+        assert_eq!(result.unwrap(), None);
+        assert!(rest.is_empty());
+    }
+
+    #[tokio::test]
+    async fn missing_or_invalid_header() {
+        let (result, _) = read_all(b"GET / HTTP/1.1\r\nHost: gateway\r\n\r\n").await;
+        assert!(result.is_err());
+
+        let (result, _) = read_all(b"PROXY TCP4 192.0.2.10 198.51.100.1 56324\r\n").await;
+        assert!(result.is_err());
+
+        let mut too_long = b"PROXY TCP4 ".to_vec();
+        too_long.extend_from_slice(&[b'1'; 200]);
+        let (result, _) = read_all(&too_long).await;
+        assert!(result.is_err());
+
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x21, 0x11, 0x00, 0x04, 192, 0, 2, 10]);
+        let (result, _) = read_all(&header).await;
+        assert!(result.is_err());
+    }
+}
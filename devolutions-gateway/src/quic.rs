@@ -0,0 +1,194 @@
+//! QUIC listener.
+//!
+//! Each bidirectional stream of a QUIC connection carries a session of its own: either a raw JMUX session, starting
+//! with the JMUX preamble, or a generic session, starting with a preconnection PDU. Since packet loss only stalls
+//! the stream it affects, several sessions can share a connection without blocking each other.
+//!
+//! The TLS configuration of the HTTPS listeners is reused, with the `devolutions-gateway` ALPN protocol.
+
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use anyhow::Context as _;
+use devolutions_gateway_task::ChildTask;
+use tokio::io::{AsyncRead, AsyncReadExt as _, AsyncWrite, Chain, ReadBuf};
+use tokio_rustls::rustls;
+use tracing::Instrument as _;
+
+use crate::config::Tls;
+use crate::generic_client::{ClientStream, GenericClient};
+use crate::DgwState;
+
+/// ALPN protocol negotiated by the clients of the QUIC listener
+pub const ALPN_PROTOCOL: &[u8] = b"devolutions-gateway";
+
+const MAGIC_READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub fn bind(addr: SocketAddr, tls: &Tls) -> anyhow::Result<quinn::Endpoint> {
+    let mut crypto = rustls::ServerConfig::clone(&tls.server_config);
+    crypto.alpn_protocols = vec![ALPN_PROTOCOL.to_vec()];
+
+    let server_config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+
+    quinn::Endpoint::server(server_config, addr).context("failed to bind QUIC endpoint")
+}
+
+pub async fn run_listener(endpoint: quinn::Endpoint, state: DgwState) -> anyhow::Result<()> {
+    let endpoint_addr = endpoint.local_addr().context("couldn't retrieve endpoint address")?;
+
+    while let Some(connecting) = endpoint.accept().await {
+        let state = state.clone();
+        let peer_addr = connecting.remote_address();
+
+        ChildTask::spawn(
+            async move {
+                if let Err(e) = handle_connection(connecting, state, endpoint_addr).await {
+                    error!(error = format!("{e:#}"), "Peer failure");
+                }
+            }
+            .instrument(info_span!("quic", client = %peer_addr)),
+        )
+        .detach();
+    }
+
+    anyhow::bail!("QUIC endpoint closed")
+}
+
+async fn handle_connection(
+    connecting: quinn::Connecting,
+    state: DgwState,
+    endpoint_addr: SocketAddr,
+) -> anyhow::Result<()> {
+    let connection = connecting.await.context("QUIC handshake failed")?;
+    let peer_addr = connection.remote_address();
+
+    // The endpoint may be bound to the wildcard address, the connection knows which local address it uses.
+    let local_addr = SocketAddr::new(
+        connection.local_ip().unwrap_or(endpoint_addr.ip()),
+        endpoint_addr.port(),
+    );
+
+    loop {
+        let (send, recv) = match connection.accept_bi().await {
+            Ok(stream) => stream,
+            Err(quinn::ConnectionError::ApplicationClosed(_) | quinn::ConnectionError::TimedOut) => {
+                debug!("Connection closed");
+                return Ok(());
+            }
+            Err(e) => return Err(anyhow::Error::new(e).context("failed to accept stream")),
+        };
+
+        let state = state.clone();
+        let stream_id = send.id();
+
+        ChildTask::spawn(
+            async move {
+                if let Err(e) = handle_stream(send, recv, state, peer_addr, local_addr).await {
+                    error!(error = format!("{e:#}"), "Stream failure");
+                }
+            }
+            .instrument(info_span!("stream", id = %stream_id)),
+        )
+        .detach();
+    }
+}
+
+async fn handle_stream(
+    send: quinn::SendStream,
+    recv: quinn::RecvStream,
+    state: DgwState,
+    peer_addr: SocketAddr,
+    local_addr: SocketAddr,
+) -> anyhow::Result<()> {
+    let (magic, recv) = tokio::time::timeout(MAGIC_READ_TIMEOUT, read_magic(recv))
+        .await
+        .context("timed out at first bytes reception")?
+        .context("couldn't read four first bytes")?;
+
+    let stream = QuicStream { send, recv };
+
+    if magic == crate::jmux::RAW_PREAMBLE_MAGIC {
+        crate::listener::handle_raw_jmux_peer(stream, state, peer_addr, local_addr).await
+    } else {
+        GenericClient::builder()
+            .conf(state.conf_handle.get_conf())
+            .client_addr(peer_addr)
+            .client_stream(stream)
+            .token_cache(state.token_cache)
+            .jrl(state.jrl)
+            .sessions(state.sessions)
+            .subscriber_tx(state.subscriber_tx)
+            .active_recordings(state.recordings.active_recordings)
+            .target_pools(state.target_pools)
+            .rendezvous(state.rendezvous)
+            .build()
+            .serve()
+            .await
+    }
+}
+
+/// Reads the four first bytes, and returns a reader yielding them again before the rest of the stream.
+async fn read_magic<R>(mut reader: R) -> io::Result<([u8; 4], Chain<io::Cursor<[u8; 4]>, R>)>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut magic = [0; 4];
+    reader.read_exact(&mut magic).await?;
+    Ok((magic, io::Cursor::new(magic).chain(reader)))
+}
+
+/// Bidirectional QUIC stream
+struct QuicStream {
+    send: quinn::SendStream,
+    recv: Chain<io::Cursor<[u8; 4]>, quinn::RecvStream>,
+}
+
+impl AsyncRead for QuicStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        AsyncRead::poll_read(Pin::new(&mut self.recv), cx, buf)
+    }
+}
+
+impl AsyncWrite for QuicStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        AsyncWrite::poll_write(Pin::new(&mut self.send), cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        AsyncWrite::poll_flush(Pin::new(&mut self.send), cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        AsyncWrite::poll_shutdown(Pin::new(&mut self.send), cx)
+    }
+}
+
+impl ClientStream for QuicStream {
+    fn into_tcp_stream(self) -> Result<tokio::net::TcpStream, Self> {
+        Err(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt as _;
+
+    #[tokio::test]
+    async fn magic_is_read_again() {
+        let (mut client, server) = tokio::io::duplex(64);
+        client.write_all(b"JMUX\x00\x05token").await.unwrap();
+        drop(client);
+
+        let (magic, mut reader) = read_magic(server).await.unwrap();
+        assert_eq!(&magic, b"JMUX");
+
+        let mut received = Vec::new();
+        reader.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"JMUX\x00\x05token");
+    }
+}
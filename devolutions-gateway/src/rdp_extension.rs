@@ -3,11 +3,14 @@ use std::net::SocketAddr;
 use std::sync::Arc;
 
 use crate::config::Conf;
+use crate::egress::EgressDenied;
 use crate::proxy::Proxy;
+use crate::recording::events::SessionEvent;
 use crate::recording::ActiveRecordings;
 use crate::session::{ConnectionModeDetails, SessionInfo, SessionMessageSender};
 use crate::subscriber::SubscriberSender;
 use crate::target_addr::TargetAddr;
+use crate::target_pool::TargetPoolState;
 use crate::token::{AssociationTokenClaims, CurrentJrl, TokenCache, TokenError};
 
 use anyhow::Context as _;
@@ -143,10 +146,14 @@ enum CleanPathError {
     Internal(#[from] anyhow::Error),
     #[error("Couldn’t perform TLS handshake")]
     TlsHandshake(#[source] io::Error),
+    #[error("server certificate rejected")]
+    CertificateRejected(#[source] io::Error),
     #[error("authorization error")]
     Authorization(#[from] AuthorizationError),
     #[error("Generic IO error")]
     Io(#[from] io::Error),
+    #[error("destination denied by the egress policy")]
+    EgressDenied(#[source] anyhow::Error),
 }
 
 struct CleanPathResult {
@@ -157,6 +164,7 @@ struct CleanPathResult {
     x224_rsp: Vec<u8>,
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn process_cleanpath(
     cleanpath_pdu: RDCleanPathPdu,
     client_addr: SocketAddr,
@@ -164,9 +172,9 @@ async fn process_cleanpath(
     token_cache: &TokenCache,
     jrl: &CurrentJrl,
     active_recordings: &ActiveRecordings,
+    sessions: &SessionMessageSender,
+    target_pools: &TargetPoolState,
 ) -> Result<CleanPathResult, CleanPathError> {
-    use crate::utils;
-
     let token = cleanpath_pdu
         .proxy_auth
         .as_deref()
@@ -202,13 +210,25 @@ async fn process_cleanpath(
 
     trace!(?targets, "Connecting to destination server");
 
-    let ((mut server_stream, server_addr), selected_target) = utils::successive_try(targets, utils::tcp_connect)
-        .await
-        .context("couldn’t connect to RDP server")?;
+    let ((mut server_stream, server_addr), selected_target) =
+        crate::target_pool::connect_to_target(targets, claims.jet_lb, conf, target_pools, sessions)
+            .await
+            .context("couldn’t connect to RDP server")
+            .map_err(|e| {
+                if EgressDenied::is_cause_of(&e) {
+                    CleanPathError::EgressDenied(e)
+                } else {
+                    CleanPathError::Internal(e)
+                }
+            })?;
 
     debug!(%selected_target, "Connected to destination server");
     span.record("target", selected_target.to_string());
 
+    if claims.jet_pp || conf.outbound.sends_proxy_protocol(&selected_target, server_addr) {
+        crate::proxy_protocol::send_header(&mut server_stream, client_addr, server_addr, claims.jet_aid).await?;
+    }
+
     // Send preconnection blob if applicable
     if let Some(pcb) = cleanpath_pdu.preconnection_blob {
         server_stream.write_all(pcb.as_bytes()).await?;
@@ -234,12 +254,24 @@ async fn process_cleanpath(
 
     // Establish TLS connection with server
 
-    let server_stream = crate::tls::connect(selected_target.host(), server_stream)
+    let connector = conf
+        .outbound
+        .tls
+        .connector(&claims, &selected_target, server_addr)
+        .map_err(CleanPathError::BadRequest)?;
+
+    let server_stream = crate::tls::connect(selected_target.host(), server_stream, &connector)
         .await
-        .map_err(CleanPathError::TlsHandshake)?;
+        .map_err(|e| {
+            if crate::tls::rejected_certificate_error(&e).is_some() {
+                CleanPathError::CertificateRejected(e)
+            } else {
+                CleanPathError::TlsHandshake(e)
+            }
+        })?;
 
     Ok(CleanPathResult {
-        destination: selected_target.to_owned(),
+        destination: selected_target,
         claims,
         server_addr,
         server_stream,
@@ -248,7 +280,16 @@ async fn process_cleanpath(
 }
 
 #[allow(clippy::too_many_arguments)]
-#[instrument("fwd", skip_all, fields(session_id = field::Empty, target = field::Empty))]
+#[instrument(
+    "fwd",
+    skip_all,
+    fields(
+        session_id = field::Empty,
+        target = field::Empty,
+        resolved = field::Empty,
+        nb_failed_attempts = field::Empty
+    )
+)]
 pub async fn handle(
     mut client_stream: impl AsyncRead + AsyncWrite + Unpin + Send,
     client_addr: SocketAddr,
@@ -258,6 +299,7 @@ pub async fn handle(
     sessions: SessionMessageSender,
     subscriber_tx: SubscriberSender,
     active_recordings: &ActiveRecordings,
+    target_pools: &TargetPoolState,
 ) -> anyhow::Result<()> {
     // Special handshake of our RDP extension
 
@@ -275,7 +317,18 @@ pub async fn handle(
         server_addr,
         server_stream,
         x224_rsp,
-    } = match process_cleanpath(cleanpath_pdu, client_addr, &conf, token_cache, jrl, active_recordings).await {
+    } = match process_cleanpath(
+        cleanpath_pdu,
+        client_addr,
+        &conf,
+        token_cache,
+        jrl,
+        active_recordings,
+        &sessions,
+        target_pools,
+    )
+    .await
+    {
         Ok(result) => result,
         Err(error) => {
             let response = RDCleanPathPdu::from(&error);
@@ -286,6 +339,15 @@ pub async fn handle(
         }
     };
 
+    sessions.record_event(
+        claims.jet_aid,
+        claims.jet_rec,
+        SessionEvent::DestinationSelected {
+            destination: destination.to_string(),
+            address: server_addr,
+        },
+    );
+
     // Send success RDCleanPathPdu response
 
     let x509_chain = server_stream
@@ -312,7 +374,11 @@ pub async fn handle(
             destination_host: destination.clone(),
         },
     )
-    .with_ttl(claims.jet_ttl);
+    .with_ttl(claims.jet_ttl)
+    .with_recording_policy(claims.jet_rec)
+    .with_capture_policy(claims.jet_cap)
+    .with_subject(claims.sub.clone())
+    .with_client_addr(client_addr);
 
     info!("RDP-TLS forwarding");
 
@@ -339,7 +405,16 @@ impl From<&CleanPathError> for RDCleanPathPdu {
             CleanPathError::BadRequest(_) => Self::new_http_error(400),
             CleanPathError::Internal(_) => Self::new_http_error(500),
             CleanPathError::TlsHandshake(e) => io_to_rdcleanpath_err(e),
+            CleanPathError::CertificateRejected(e) => match crate::tls::rejected_certificate_error(e) {
+                // Same alert as the one sent to the server when rejecting its certificate.
+                Some(certificate_error) => {
+                    let alert = tokio_rustls::rustls::AlertDescription::from(certificate_error.clone());
+                    Self::new_tls_error(alert.get_u8())
+                }
+                None => io_to_rdcleanpath_err(e),
+            },
             CleanPathError::Io(e) => io_to_rdcleanpath_err(e),
+            CleanPathError::EgressDenied(_) => Self::new_wsa_error(WsaError::WSAEACCES.as_u16()),
             CleanPathError::Authorization(AuthorizationError::Forbidden) => Self::new_http_error(403),
             CleanPathError::Authorization(AuthorizationError::Unauthorized) => Self::new_http_error(401),
             CleanPathError::Authorization(AuthorizationError::BadToken(_)) => Self::new_http_error(401), // NOTE: this could be refined
@@ -497,3 +572,50 @@ impl From<&io::Error> for WsaError {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use tokio_rustls::rustls;
+
+    use super::*;
+
+    fn tls_error(error: rustls::Error) -> io::Error {
+        io::Error::new(ErrorKind::InvalidData, error)
+    }
+
+    #[test]
+    fn rejected_certificate_is_reported_as_a_tls_alert() {
+        let error = CleanPathError::CertificateRejected(tls_error(rustls::Error::InvalidCertificate(
+            rustls::CertificateError::UnknownIssuer,
+        )));
+
+        let error = RDCleanPathPdu::from(&error).error.expect("error response");
+
+        assert_eq!(error.tls_alert_code, Some(rustls::AlertDescription::UnknownCA.get_u8()));
+        assert_eq!(error.wsa_last_error, None);
+    }
+
+    #[test]
+    fn pin_mismatch_is_reported_as_a_tls_alert() {
+        let error = CleanPathError::CertificateRejected(tls_error(rustls::Error::InvalidCertificate(
+            rustls::CertificateError::ApplicationVerificationFailure,
+        )));
+
+        let error = RDCleanPathPdu::from(&error).error.expect("error response");
+
+        assert_eq!(
+            error.tls_alert_code,
+            Some(rustls::AlertDescription::AccessDenied.get_u8())
+        );
+    }
+
+    #[test]
+    fn handshake_failure_is_reported_as_a_socket_error() {
+        let error = CleanPathError::TlsHandshake(io::Error::from(ErrorKind::ConnectionReset));
+
+        let error = RDCleanPathPdu::from(&error).error.expect("error response");
+
+        assert_eq!(error.tls_alert_code, None);
+        assert_eq!(error.wsa_last_error, Some(WsaError::WSAECONNRESET.as_u16()));
+    }
+}
@@ -0,0 +1,265 @@
+//! Timeline of the events observed by the gateway during a session, written alongside its recording.
+//!
+//! The events are stored in the recording folder, one JSON object per line, so that a player can overlay them
+//! on the recording. A recording reopened after being terminated gets a new events file, the files listed in the
+//! manifest forming the timeline once concatenated.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use anyhow::Context as _;
+use camino::Utf8Path;
+use tokio::fs;
+use tokio::io::AsyncWriteExt as _;
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+use crate::token::ApplicationProtocol;
+
+/// Returns the name of the `index`-th events file of a recording.
+pub fn events_file_name(index: usize) -> String {
+    format!("events-{index}.jsonl")
+}
+
+/// Maximum number of events kept for a session which is not recorded (yet).
+const MAX_PENDING_EVENTS_PER_SESSION: usize = 256;
+
+/// Maximum number of sessions for which events are kept until a recording starts.
+const MAX_PENDING_SESSIONS: usize = 1024;
+
+/// Number of events which can be queued for the recording manager before new events are dropped.
+const EVENT_CHANNEL_CAPACITY: usize = 1024;
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum SessionEvent {
+    #[serde(rename_all = "camelCase")]
+    SessionStarted {
+        protocol: ApplicationProtocol,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        destination: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        subject: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        client_addr: Option<SocketAddr>,
+    },
+    /// Destination selected among the targets of the token
+    #[serde(rename_all = "camelCase")]
+    DestinationSelected {
+        destination: String,
+        address: SocketAddr,
+    },
+    #[serde(rename_all = "camelCase")]
+    JmuxChannelOpened {
+        channel_id: u32,
+        destination: String,
+    },
+    #[serde(rename_all = "camelCase")]
+    JmuxChannelClosed {
+        channel_id: u32,
+    },
+    /// Number of bytes forwarded in each direction since the start of the session
+    #[serde(rename_all = "camelCase")]
+    BytesCheckpoint {
+        from_client: u64,
+        from_server: u64,
+    },
+    #[serde(rename_all = "camelCase")]
+    SessionKilled {
+        reason: KillReason,
+    },
+    SessionEnded,
+    #[serde(rename_all = "camelCase")]
+    RecordingConnected {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        stream: Option<String>,
+        file_name: String,
+        resumed: bool,
+    },
+    #[serde(rename_all = "camelCase")]
+    RecordingDisconnected {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        stream: Option<String>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum KillReason {
+    /// Killed using the session termination endpoint
+    Api,
+    /// Killed because it reached its maximum duration
+    Ttl,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EventRecord {
+    /// Unix timestamp, in milliseconds
+    pub timestamp_ms: i64,
+    #[serde(flatten)]
+    pub event: SessionEvent,
+}
+
+impl EventRecord {
+    pub fn now(event: SessionEvent) -> Self {
+        let timestamp_ms = time::OffsetDateTime::now_utc().unix_timestamp_nanos() / 1_000_000;
+
+        Self {
+            timestamp_ms: i64::try_from(timestamp_ms).unwrap_or(i64::MAX),
+            event,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct EventMessage {
+    pub id: Uuid,
+    /// Whether the session is expected to be recorded, in which case its events are kept until the recording starts
+    pub recording_policy: bool,
+    pub record: EventRecord,
+}
+
+/// Sending half of the channel carrying the session events to the recording manager.
+///
+/// The events have a channel of their own so that they never hold up the other messages of the recording manager.
+#[derive(Clone, Debug)]
+pub struct EventSender {
+    channel: mpsc::Sender<EventMessage>,
+    /// Set while events are dropped, so that the overload is reported once instead of for each event
+    dropping: Arc<AtomicBool>,
+}
+
+impl EventSender {
+    /// Sends an event without ever blocking the caller: the event is dropped if the channel is full.
+    pub fn send(&self, id: Uuid, recording_policy: bool, event: SessionEvent) {
+        let message = EventMessage {
+            id,
+            recording_policy,
+            record: EventRecord::now(event),
+        };
+
+        match self.channel.try_send(message) {
+            Ok(()) => {
+                if self.dropping.swap(false, Ordering::Relaxed) {
+                    info!("Session events are recorded again");
+                }
+            }
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                if !self.dropping.swap(true, Ordering::Relaxed) {
+                    warn!("The recording manager is overloaded; session events are dropped");
+                }
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => {
+                debug!("Recording manager is stopped; session event dropped");
+            }
+        }
+    }
+}
+
+pub(super) fn event_channel() -> (EventSender, mpsc::Receiver<EventMessage>) {
+    let (tx, rx) = mpsc::channel(EVENT_CHANNEL_CAPACITY);
+
+    let sender = EventSender {
+        channel: tx,
+        dropping: Arc::new(AtomicBool::new(false)),
+    };
+
+    (sender, rx)
+}
+
+/// Appends the records at the end of the events file.
+pub async fn append(path: &Utf8Path, records: &[EventRecord]) -> anyhow::Result<()> {
+    let mut lines = Vec::new();
+
+    for record in records {
+        serde_json::to_writer(&mut lines, record).context("serialize event")?;
+        lines.push(b'\n');
+    }
+
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await
+        .with_context(|| format!("failed to open {path}"))?;
+
+    file.write_all(&lines).await.context("write events")?;
+    file.flush().await.context("flush events")?;
+
+    Ok(())
+}
+
+/// Events of the sessions expected to be recorded, kept until the recording starts.
+#[derive(Debug, Default)]
+pub struct PendingEvents(HashMap<Uuid, Vec<EventRecord>>);
+
+impl PendingEvents {
+    pub fn push(&mut self, id: Uuid, record: EventRecord) {
+        if !self.0.contains_key(&id) && self.0.len() >= MAX_PENDING_SESSIONS {
+            // Sessions which are never recorded would otherwise accumulate here.
+            let oldest = self
+                .0
+                .iter()
+                .min_by_key(|(_, records)| records.first().map_or(i64::MIN, |record| record.timestamp_ms))
+                .map(|(id, _)| *id);
+
+            if let Some(oldest) = oldest {
+                trace!(session.id = %oldest, "Discard pending events");
+                self.0.remove(&oldest);
+            }
+        }
+
+        let records = self.0.entry(id).or_default();
+
+        if records.len() < MAX_PENDING_EVENTS_PER_SESSION {
+            records.push(record);
+        } else {
+            trace!(session.id = %id, "Too many pending events; event discarded");
+        }
+    }
+
+    pub fn take(&mut self, id: Uuid) -> Vec<EventRecord> {
+        self.0.remove(&id).unwrap_or_default()
+    }
+
+    pub fn discard(&mut self, id: Uuid) {
+        self.0.remove(&id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_serialization() {
+        let record = EventRecord {
+            timestamp_ms: 1000,
+            event: SessionEvent::JmuxChannelOpened {
+                channel_id: 3,
+                destination: "tcp://server:22".to_owned(),
+            },
+        };
+
+        assert_eq!(
+            serde_json::to_string(&record).unwrap(),
+            r#"{"timestampMs":1000,"type":"jmuxChannelOpened","channelId":3,"destination":"tcp://server:22"}"#
+        );
+    }
+
+    #[test]
+    fn pending_events_are_bounded() {
+        let mut pending = PendingEvents::default();
+        let id = Uuid::new_v4();
+
+        for _ in 0..MAX_PENDING_EVENTS_PER_SESSION + 10 {
+            pending.push(id, EventRecord::now(SessionEvent::SessionEnded));
+        }
+
+        assert_eq!(pending.take(id).len(), MAX_PENDING_EVENTS_PER_SESSION);
+        assert!(pending.take(id).is_empty());
+    }
+}
@@ -0,0 +1,299 @@
+//! Export bundles: TAR archives of one or several recordings, generated on the fly.
+//!
+//! Each recording is stored under a folder named after its session ID, and contains the manifest, all the
+//! files listed in the manifest (including the events files) and a `SHA256SUMS` file (in the `sha256sum`
+//! format) computed while streaming.
+
+use std::fmt::Write as _;
+use std::future::Future as _;
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Context as _;
+use sha2::{Digest as _, Sha256};
+use tokio::io::{AsyncRead, AsyncReadExt as _, AsyncWrite, AsyncWriteExt as _, DuplexStream, ReadBuf};
+use tokio::task::JoinHandle;
+use uuid::Uuid;
+
+use super::storage::{DynRecordingStorage, RecordingStorage, MANIFEST_FILE_NAME};
+use super::JrecManifest;
+
+pub const CHECKSUMS_FILE_NAME: &str = "SHA256SUMS";
+
+const BLOCK_SIZE: usize = 512;
+
+/// Reader over a TAR archive of the given recordings, written by a background task as it is read.
+pub fn bundle_reader(storage: DynRecordingStorage, manifests: Vec<JrecManifest>) -> BundleReader {
+    const PIPE_CAPACITY: usize = 64 * 1024;
+
+    let (pipe, writer) = tokio::io::duplex(PIPE_CAPACITY);
+
+    let task = tokio::spawn(async move { write_bundle(storage.as_ref(), &manifests, writer).await });
+
+    BundleReader { pipe, task: Some(task) }
+}
+
+/// See [`bundle_reader`].
+///
+/// Once the archive is fully read, an error is returned if it couldn’t be written entirely, so that the
+/// consumer never mistakes a truncated archive for a complete one.
+pub struct BundleReader {
+    pipe: DuplexStream,
+    task: Option<JoinHandle<anyhow::Result<()>>>,
+}
+
+impl AsyncRead for BundleReader {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        if buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+
+        let filled_before = buf.filled().len();
+
+        ready!(Pin::new(&mut this.pipe).poll_read(cx, buf))?;
+
+        if buf.filled().len() != filled_before {
+            return Poll::Ready(Ok(()));
+        }
+
+        // End of the pipe: the writing task is terminating.
+        let Some(task) = this.task.as_mut() else {
+            return Poll::Ready(Ok(()));
+        };
+
+        let result = ready!(Pin::new(task).poll(cx));
+        this.task = None;
+
+        match result {
+            Ok(Ok(())) => Poll::Ready(Ok(())),
+            Ok(Err(e)) => {
+                error!(error = format!("{e:#}"), "Failed to export recordings");
+                Poll::Ready(Err(io::Error::other(format!("{e:#}"))))
+            }
+            Err(e) => Poll::Ready(Err(io::Error::other(e))),
+        }
+    }
+}
+
+/// Writes a TAR archive of the given recordings into `out`.
+///
+/// Files missing from the storage (e.g.: a file listed in the manifest, but never pushed) are skipped.
+/// The size of each file is captured before it is archived, so files of on-going recordings are exported
+/// as they were at this point.
+async fn write_bundle<W>(storage: &dyn RecordingStorage, manifests: &[JrecManifest], mut out: W) -> anyhow::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    for manifest in manifests {
+        let id = manifest.session_id;
+
+        let file_names = std::iter::once(MANIFEST_FILE_NAME)
+            .chain(manifest.files.iter().map(|file| file.file_name.as_str()))
+            .chain(manifest.events_files.iter().map(String::as_str));
+
+        let mut checksums = String::new();
+
+        for file_name in file_names {
+            let Some(info) = storage
+                .file_info(id, file_name)
+                .await
+                .with_context(|| format!("failed to retrieve info for {id}/{file_name}"))?
+            else {
+                warn!(%id, %file_name, "Recording file not found; skipped from the export");
+                continue;
+            };
+
+            let reader = storage
+                .open_file(id, file_name, 0..info.size)
+                .await
+                .with_context(|| format!("failed to open {id}/{file_name}"))?;
+
+            let header = TarHeader {
+                folder: id,
+                file_name,
+                size: info.size,
+                mtime: unix_time(info.last_modified.unwrap_or_else(SystemTime::now)),
+            };
+
+            let digest = append_file(&mut out, &header, reader)
+                .await
+                .with_context(|| format!("failed to archive {id}/{file_name}"))?;
+
+            let _ = writeln!(checksums, "{digest}  {file_name}");
+        }
+
+        let header = TarHeader {
+            folder: id,
+            file_name: CHECKSUMS_FILE_NAME,
+            size: checksums.len() as u64,
+            mtime: unix_time(SystemTime::now()),
+        };
+
+        append_file(&mut out, &header, checksums.as_bytes())
+            .await
+            .with_context(|| format!("failed to archive {id}/{CHECKSUMS_FILE_NAME}"))?;
+    }
+
+    // The end of the archive is marked by two zero-filled blocks.
+    out.write_all(&[0; BLOCK_SIZE * 2]).await?;
+    out.flush().await?;
+
+    Ok(())
+}
+
+struct TarHeader<'a> {
+    folder: Uuid,
+    file_name: &'a str,
+    size: u64,
+    mtime: u64,
+}
+
+impl TarHeader<'_> {
+    /// Encodes the header in the ustar format.
+    fn encode(&self) -> anyhow::Result<[u8; BLOCK_SIZE]> {
+        let mut block = [0; BLOCK_SIZE];
+
+        // The session ID goes in the prefix field, so that long file names still fit in the name field.
+        let file_name = self.file_name.as_bytes();
+        anyhow::ensure!(file_name.len() <= 100, "file name too long: {}", self.file_name);
+        block[..file_name.len()].copy_from_slice(file_name);
+
+        block[100..108].copy_from_slice(b"0000644\0");
+        block[108..116].copy_from_slice(b"0000000\0");
+        block[116..124].copy_from_slice(b"0000000\0");
+        encode_numeric(&mut block[124..136], self.size);
+        encode_numeric(&mut block[136..148], self.mtime);
+        block[156] = b'0';
+        block[257..263].copy_from_slice(b"ustar\0");
+        block[263..265].copy_from_slice(b"00");
+
+        let prefix = self.folder.to_string();
+        block[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+
+        // The checksum is computed with the checksum field filled with spaces.
+        block[148..156].copy_from_slice(b"        ");
+        let checksum: u32 = block.iter().map(|b| u32::from(*b)).sum();
+        block[148..156].copy_from_slice(format!("{checksum:06o}\0 ").as_bytes());
+
+        Ok(block)
+    }
+}
+
+/// Encodes a numeric field as NUL-terminated octal, or in base-256 (GNU extension) when too large.
+fn encode_numeric(field: &mut [u8], value: u64) {
+    let digits = field.len() - 1;
+
+    if value < 1 << (3 * digits) {
+        let octal = format!("{value:0digits$o}");
+        field[..digits].copy_from_slice(octal.as_bytes());
+        field[digits] = 0;
+    } else {
+        field.fill(0);
+        let bytes = value.to_be_bytes();
+        let len = field.len();
+        field[len - bytes.len()..].copy_from_slice(&bytes);
+        field[0] |= 0x80;
+    }
+}
+
+/// Appends a file entry, returning the SHA-256 digest of its content as a lowercase hex string.
+async fn append_file<W, R>(out: &mut W, header: &TarHeader<'_>, mut reader: R) -> anyhow::Result<String>
+where
+    W: AsyncWrite + Unpin,
+    R: AsyncRead + Unpin,
+{
+    out.write_all(&header.encode()?).await?;
+
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 64 * 1024];
+    let mut remaining = header.size;
+
+    while remaining > 0 {
+        let to_read = usize::try_from(remaining).map_or(buf.len(), |remaining| remaining.min(buf.len()));
+        let n = reader.read(&mut buf[..to_read]).await?;
+
+        anyhow::ensure!(n != 0, "file is shorter than expected ({remaining} bytes missing)");
+
+        hasher.update(&buf[..n]);
+        out.write_all(&buf[..n]).await?;
+
+        remaining -= n as u64;
+    }
+
+    let padding = (BLOCK_SIZE - (header.size % BLOCK_SIZE as u64) as usize) % BLOCK_SIZE;
+    out.write_all(&[0; BLOCK_SIZE][..padding]).await?;
+
+    let digest = hasher.finalize().iter().fold(String::new(), |mut acc, b| {
+        let _ = write!(acc, "{b:02x}");
+        acc
+    });
+
+    Ok(digest)
+}
+
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |duration| duration.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_checksum_and_fields() {
+        let header = TarHeader {
+            folder: Uuid::nil(),
+            file_name: "recording-0.webm",
+            size: 1000,
+            mtime: 0,
+        };
+
+        let block = header.encode().unwrap();
+
+        assert_eq!(&block[..16], b"recording-0.webm");
+        assert_eq!(&block[124..136], b"00000001750\0");
+        assert_eq!(&block[345..381], b"00000000-0000-0000-0000-000000000000");
+
+        let stored = std::str::from_utf8(&block[148..154]).unwrap();
+        let stored = u32::from_str_radix(stored, 8).unwrap();
+
+        let mut blank = block;
+        blank[148..156].copy_from_slice(b"        ");
+        let expected: u32 = blank.iter().map(|b| u32::from(*b)).sum();
+
+        assert_eq!(stored, expected);
+    }
+
+    #[test]
+    fn large_size_uses_base_256() {
+        let mut field = [0; 12];
+        encode_numeric(&mut field, 1 << 40);
+
+        assert_eq!(field[0], 0x80);
+        assert_eq!(&field[4..], &(1u64 << 40).to_be_bytes());
+    }
+
+    #[tokio::test]
+    async fn file_is_padded_to_block_size() {
+        let header = TarHeader {
+            folder: Uuid::nil(),
+            file_name: "recording.json",
+            size: 3,
+            mtime: 0,
+        };
+
+        let mut out = Vec::new();
+        let digest = append_file(&mut out, &header, &b"abc"[..]).await.unwrap();
+
+        assert_eq!(out.len(), BLOCK_SIZE * 2);
+        assert_eq!(&out[BLOCK_SIZE..BLOCK_SIZE + 3], b"abc");
+        assert_eq!(
+            digest,
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+}
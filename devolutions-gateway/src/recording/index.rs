@@ -0,0 +1,234 @@
+//! In-memory catalogue of the recordings, rebuilt from the manifests at startup.
+
+use std::cmp;
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+use parking_lot::RwLock;
+use uuid::Uuid;
+
+use super::JrecManifest;
+use crate::target_addr::TargetAddr;
+
+#[derive(Debug, Default)]
+pub struct RecordingIndex(RwLock<HashMap<Uuid, JrecManifest>>);
+
+impl RecordingIndex {
+    pub fn insert(&self, manifest: JrecManifest) {
+        self.0.write().insert(manifest.session_id, manifest);
+    }
+
+    pub fn remove(&self, id: Uuid) {
+        self.0.write().remove(&id);
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.read().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.read().is_empty()
+    }
+
+    /// Returns the recordings matching the query, along with the total number of matches before pagination.
+    pub fn query(&self, query: &RecordingQuery) -> (usize, Vec<JrecManifest>) {
+        let mut matches: Vec<JrecManifest> = self
+            .0
+            .read()
+            .values()
+            .filter(|manifest| query.matches(manifest))
+            .cloned()
+            .collect();
+
+        matches.sort_by(|a, b| {
+            let ordering = match query.sort {
+                RecordingSortKey::StartTime => a.start_time.cmp(&b.start_time),
+                RecordingSortKey::Duration => a.duration.cmp(&b.duration),
+            }
+            .then_with(|| a.session_id.cmp(&b.session_id));
+
+            match query.order {
+                SortOrder::Asc => ordering,
+                SortOrder::Desc => ordering.reverse(),
+            }
+        });
+
+        let total = matches.len();
+
+        let page = matches
+            .into_iter()
+            .skip(query.offset)
+            .take(query.limit.unwrap_or(usize::MAX))
+            .collect();
+
+        (total, page)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum RecordingSortKey {
+    #[default]
+    StartTime,
+    Duration,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum SortOrder {
+    Asc,
+    #[default]
+    Desc,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RecordingQuery {
+    /// Only recordings still running at or after this time (Unix timestamp, in seconds)
+    pub from: Option<i64>,
+    /// Only recordings started before this time (Unix timestamp, in seconds)
+    pub to: Option<i64>,
+    pub protocol: Option<String>,
+    /// Either a full destination (e.g.: tcp://hostname:3389) or only the host
+    pub destination: Option<String>,
+    pub subject: Option<String>,
+    pub source_ip: Option<IpAddr>,
+    pub gateway_id: Option<Uuid>,
+    pub sort: RecordingSortKey,
+    pub order: SortOrder,
+    pub offset: usize,
+    pub limit: Option<usize>,
+}
+
+impl RecordingQuery {
+    fn matches(&self, manifest: &JrecManifest) -> bool {
+        if let Some(from) = self.from {
+            if manifest.start_time + cmp::max(manifest.duration, 0) < from {
+                return false;
+            }
+        }
+
+        if let Some(to) = self.to {
+            if manifest.start_time >= to {
+                return false;
+            }
+        }
+
+        if let Some(protocol) = &self.protocol {
+            let Some(manifest_protocol) = &manifest.protocol else {
+                return false;
+            };
+
+            if !manifest_protocol.as_str().eq_ignore_ascii_case(protocol) {
+                return false;
+            }
+        }
+
+        if let Some(destination) = &self.destination {
+            let Some(manifest_destination) = &manifest.destination else {
+                return false;
+            };
+
+            if !destination_matches(manifest_destination, destination) {
+                return false;
+            }
+        }
+
+        if self.subject.is_some() && manifest.subject != self.subject {
+            return false;
+        }
+
+        if self.source_ip.is_some() && manifest.source_ip != self.source_ip {
+            return false;
+        }
+
+        if self.gateway_id.is_some() && manifest.gateway_id != self.gateway_id {
+            return false;
+        }
+
+        true
+    }
+}
+
+fn destination_matches(manifest_destination: &str, query: &str) -> bool {
+    if manifest_destination.eq_ignore_ascii_case(query) {
+        return true;
+    }
+
+    TargetAddr::parse(manifest_destination, None)
+        .map(|target| target.host().eq_ignore_ascii_case(query))
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest(start_time: i64, duration: i64, destination: &str, subject: &str) -> JrecManifest {
+        JrecManifest {
+            session_id: Uuid::new_v4(),
+            start_time,
+            duration,
+            files: Vec::new(),
+            protocol: None,
+            destination: Some(destination.to_owned()),
+            subject: Some(subject.to_owned()),
+            source_ip: None,
+            gateway_id: None,
+            events_files: Vec::new(),
+        }
+    }
+
+    fn index() -> RecordingIndex {
+        let index = RecordingIndex::default();
+        index.insert(manifest(100, 50, "tcp://server-a:3389", "alice"));
+        index.insert(manifest(200, 10, "tcp://server-b:3389", "bob"));
+        index.insert(manifest(300, 500, "tcp://server-a:22", "alice"));
+        index
+    }
+
+    #[test]
+    fn filter_by_subject_and_destination_host() {
+        let query = RecordingQuery {
+            subject: Some("alice".to_owned()),
+            destination: Some("SERVER-A".to_owned()),
+            ..Default::default()
+        };
+
+        let (total, page) = index().query(&query);
+
+        assert_eq!(total, 2);
+        assert_eq!(page[0].start_time, 300);
+        assert_eq!(page[1].start_time, 100);
+    }
+
+    #[test]
+    fn filter_by_time_range() {
+        let query = RecordingQuery {
+            from: Some(160),
+            to: Some(300),
+            ..Default::default()
+        };
+
+        let (total, page) = index().query(&query);
+
+        assert_eq!(total, 1);
+        assert_eq!(page[0].start_time, 200);
+    }
+
+    #[test]
+    fn sort_and_paginate() {
+        let query = RecordingQuery {
+            sort: RecordingSortKey::Duration,
+            order: SortOrder::Asc,
+            offset: 1,
+            limit: Some(1),
+            ..Default::default()
+        };
+
+        let (total, page) = index().query(&query);
+
+        assert_eq!(total, 3);
+        assert_eq!(page.len(), 1);
+        assert_eq!(page[0].duration, 50);
+    }
+}
@@ -0,0 +1,1870 @@
+pub mod events;
+pub mod export;
+pub mod index;
+pub mod storage;
+mod webm;
+
+use core::fmt;
+use std::cmp;
+use std::collections::hash_map::Entry;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use anyhow::Context as _;
+use async_trait::async_trait;
+use camino::{Utf8Path, Utf8PathBuf};
+use devolutions_gateway_task::{ShutdownSignal, Task};
+use parking_lot::Mutex;
+use serde::Serialize;
+use tokio::io::{AsyncRead, AsyncReadExt as _, AsyncSeekExt as _, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::{fs, io};
+use typed_builder::TypedBuilder;
+use uuid::Uuid;
+
+use self::events::{EventMessage, EventRecord, EventSender, PendingEvents, SessionEvent};
+use self::index::RecordingIndex;
+use self::storage::DynRecordingStorage;
+use crate::session::{ConnectionModeDetails, SessionMessageSender};
+use crate::token::{ApplicationProtocol, JrecTokenClaims, RecordingFileType};
+
+const DISCONNECTED_TTL_SECS: i64 = 10;
+const DISCONNECTED_TTL_DURATION: tokio::time::Duration = tokio::time::Duration::from_secs(DISCONNECTED_TTL_SECS as u64);
+
+const STORE_FILE_MAX_ATTEMPTS: u32 = 5;
+/// Delay before the second attempt at storing a file, doubled after each failure
+const STORE_FILE_INITIAL_RETRY_DELAY: tokio::time::Duration = tokio::time::Duration::from_secs(2);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JrecFile {
+    pub file_name: String,
+    pub start_time: i64,
+    pub duration: i64,
+    /// Name of the stream this file belongs to, absent for the default stream
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stream: Option<String>,
+    /// Interruptions of the push stream, when the client resumed into this file after reconnecting
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub gaps: Vec<JrecGap>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JrecGap {
+    pub start_time: i64,
+    pub duration: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JrecManifest {
+    pub session_id: Uuid,
+    pub start_time: i64,
+    pub duration: i64,
+    pub files: Vec<JrecFile>,
+    /// Application protocol of the recorded session
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub protocol: Option<ApplicationProtocol>,
+    /// Destination of the recorded session
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub destination: Option<String>,
+    /// Subject of the token used to establish the recorded session
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subject: Option<String>,
+    /// IP address of the client which established the recorded session
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_ip: Option<IpAddr>,
+    /// ID of the Gateway instance which handled the recorded session
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gateway_id: Option<Uuid>,
+    /// Names of the files holding the timeline of the session events, in chronological order
+    ///
+    /// A new file is started each time the recording is reopened after being terminated.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub events_files: Vec<String>,
+}
+
+impl JrecManifest {
+    /// Returns the file currently (or last) written for the given stream.
+    fn last_file_mut(&mut self, stream: Option<&str>) -> Option<&mut JrecFile> {
+        self.files
+            .iter_mut()
+            .rev()
+            .find(|file| file.stream.as_deref() == stream)
+    }
+
+    fn last_file(&self, stream: Option<&str>) -> Option<&JrecFile> {
+        self.files.iter().rev().find(|file| file.stream.as_deref() == stream)
+    }
+
+    /// Adds a new file for the given stream, returning its name.
+    fn push_file(&mut self, stream: Option<&str>, file_type: RecordingFileType, start_time: i64) -> String {
+        let next_file_idx = self
+            .files
+            .iter()
+            .filter(|file| file.stream.as_deref() == stream)
+            .count();
+
+        let file_name = match stream {
+            Some(stream) => format!("recording-{stream}-{next_file_idx}.{file_type}"),
+            None => format!("recording-{next_file_idx}.{file_type}"),
+        };
+
+        self.files.push(JrecFile {
+            file_name: file_name.clone(),
+            start_time,
+            duration: 0,
+            stream: stream.map(str::to_owned),
+            gaps: Vec::new(),
+        });
+
+        file_name
+    }
+
+    /// Adds a new events file, returning its name.
+    fn push_events_file(&mut self) -> String {
+        let file_name = events::events_file_name(self.events_files.len());
+        self.events_files.push(file_name.clone());
+        file_name
+    }
+}
+
+/// Returns whether `name` is acceptable as a stream name.
+///
+/// Stream names end up in file names, so only ASCII alphanumerics, `-` and `_` are allowed.
+pub fn is_valid_stream_name(name: &str) -> bool {
+    const MAX_LENGTH: usize = 64;
+
+    !name.is_empty()
+        && name.len() <= MAX_LENGTH
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+#[derive(TypedBuilder)]
+pub struct ClientPush<S> {
+    recordings: RecordingMessageSender,
+    claims: JrecTokenClaims,
+    client_stream: S,
+    file_type: RecordingFileType,
+    session_id: Uuid,
+    /// Name of the pushed stream, `None` for the default stream
+    stream: Option<String>,
+    /// When set, the client expects the resume handshake: the gateway first sends the number of bytes already
+    /// persisted for the current file (as a big-endian u64), and the client continues from this offset.
+    ///
+    /// An offset of 0 means that a new file was started.
+    resume: bool,
+    shutdown_signal: ShutdownSignal,
+}
+
+impl<S> ClientPush<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    pub async fn run(self) -> anyhow::Result<()> {
+        let Self {
+            recordings,
+            claims,
+            mut client_stream,
+            file_type,
+            session_id,
+            stream,
+            resume,
+            mut shutdown_signal,
+        } = self;
+
+        if session_id != claims.jet_aid {
+            anyhow::bail!("inconsistent session ID (ID in token: {})", claims.jet_aid);
+        }
+
+        let ConnectedRecording {
+            path: recording_file,
+            offset,
+            live,
+        } = match recordings.connect(session_id, stream.clone(), file_type, resume).await {
+            Ok(connected) => connected,
+            Err(e) => {
+                warn!(error = format!("{e:#}"), "Unable to start recording");
+                client_stream.shutdown().await.context("shutdown")?;
+                return Ok(());
+            }
+        };
+
+        debug!(path = %recording_file, offset, "Opening file");
+
+        let res = match open_recording_file(&recording_file, offset).await {
+            Ok(file) => {
+                let mut file = io::BufWriter::new(file);
+
+                let shutdown_signal = shutdown_signal.wait();
+                let copy_fut = async {
+                    if resume {
+                        client_stream.write_all(&offset.to_be_bytes()).await?;
+                        client_stream.flush().await?;
+                    }
+
+                    copy_to_file(&mut client_stream, &mut file, &live, offset).await
+                };
+
+                let res = tokio::select! {
+                    res = copy_fut => {
+                        res.context("JREC streaming to file").map(|_| ())
+                    },
+                    _ = shutdown_signal => {
+                        trace!("Received shutdown signal");
+                        client_stream.shutdown().await.context("shutdown")
+                    },
+                };
+
+                // Whatever happened, the bytes received so far are persisted before disconnecting, as the resume
+                // offset is the size of the file.
+                match file.flush().await {
+                    Ok(()) => res,
+                    Err(e) => res.and(Err(anyhow::Error::new(e).context("flush recording file"))),
+                }
+            }
+            Err(e) => Err(anyhow::Error::new(e).context(format!("failed to open file at {recording_file}"))),
+        };
+
+        recordings.disconnect(session_id, stream).await.context("disconnect")?;
+
+        res
+    }
+}
+
+/// Opens the recording file for writing at `offset`.
+///
+/// For a new file, `offset` is 0 and the file is truncated. When resuming, anything past `offset` is discarded
+/// so that the file matches what was reported to the client.
+async fn open_recording_file(path: &Utf8Path, offset: u64) -> io::Result<fs::File> {
+    let mut file = fs::OpenOptions::new()
+        .read(false)
+        .write(true)
+        .truncate(false)
+        .create(true)
+        .open(path)
+        .await?;
+
+    file.set_len(offset).await?;
+    file.seek(io::SeekFrom::Start(offset)).await?;
+
+    Ok(file)
+}
+
+/// Copies the client stream into the recording file, notifying the live viewers after each flush.
+///
+/// `total_written` is the number of bytes already in the file, when resuming.
+///
+/// Writes are buffered, and the buffer is flushed every [`LIVE_FLUSH_INTERVAL`] while data is received. Live
+/// viewers are only notified after a flush so that they never observe a partial write.
+async fn copy_to_file<R>(
+    client_stream: &mut R,
+    file: &mut io::BufWriter<fs::File>,
+    live: &watch::Sender<LiveRecording>,
+    mut total_written: u64,
+) -> io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut buf = vec![0; 16 * 1024];
+    let mut buffered = 0u64;
+
+    let mut flush_interval = tokio::time::interval(LIVE_FLUSH_INTERVAL);
+    flush_interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        tokio::select! {
+            res = client_stream.read(&mut buf) => {
+                let n = res?;
+
+                if n == 0 {
+                    file.flush().await?;
+                    total_written += buffered;
+                    notify_written(live, total_written);
+                    return Ok(total_written);
+                }
+
+                file.write_all(&buf[..n]).await?;
+                buffered += n as u64;
+            }
+            _ = flush_interval.tick(), if buffered > 0 => {
+                file.flush().await?;
+                total_written += buffered;
+                buffered = 0;
+                notify_written(live, total_written);
+            }
+        }
+    }
+}
+
+/// Maximum delay before the bytes received for a recording are visible to live viewers.
+const LIVE_FLUSH_INTERVAL: tokio::time::Duration = tokio::time::Duration::from_millis(500);
+
+fn notify_written(live: &watch::Sender<LiveRecording>, total_written: u64) {
+    live.send_modify(|state| {
+        if let LiveRecording::File { written, .. } = state {
+            *written = total_written;
+        }
+    });
+}
+
+#[derive(TypedBuilder)]
+pub struct ClientShadow<S> {
+    live: watch::Receiver<LiveRecording>,
+    client_stream: S,
+    shutdown_signal: ShutdownSignal,
+}
+
+impl<S> ClientShadow<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    pub async fn run(self) -> anyhow::Result<()> {
+        let Self {
+            mut live,
+            client_stream,
+            mut shutdown_signal,
+        } = self;
+
+        let (mut client_reader, mut client_writer) = io::split(client_stream);
+
+        // The shadowing client is not expected to send anything; reading is only used to detect when it goes away.
+        let client_gone = async move {
+            let mut sink = [0; 1024];
+            loop {
+                match client_reader.read(&mut sink).await {
+                    Ok(0) | Err(_) => break,
+                    Ok(_) => {}
+                }
+            }
+        };
+        tokio::pin!(client_gone);
+
+        // The file being streamed, and the offset of the next byte to send.
+        let mut current: Option<(Utf8PathBuf, fs::File, u64)> = None;
+
+        loop {
+            let state = live.borrow_and_update().clone();
+
+            match state {
+                LiveRecording::File {
+                    path,
+                    file_type,
+                    written,
+                } => {
+                    let is_new_file = current
+                        .as_ref()
+                        .map_or(true, |(current_path, _, _)| *current_path != path);
+
+                    if is_new_file {
+                        let is_first_file = current.is_none();
+
+                        debug!(%path, "Start shadowing recording file");
+
+                        let mut file = match fs::File::open(&path).await {
+                            Ok(file) => file,
+                            // The file was already handed over to the storage, and removed from the staging folder.
+                            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                                debug!(%path, "Recording file is not in the staging folder anymore");
+                                break;
+                            }
+                            Err(e) => {
+                                return Err(anyhow::Error::new(e).context(format!("failed to open file at {path}")));
+                            }
+                        };
+
+                        // Only the first file replayed to the client is trimmed: the following ones are
+                        // streamed from their very beginning, as they are produced.
+                        if is_first_file && file_type == RecordingFileType::WebM {
+                            seek_to_last_webm_cluster(&mut file, &mut client_writer)
+                                .await
+                                .context("replay WebM header")?;
+                        }
+
+                        let sent = file.stream_position().await.context("failed to read file position")?;
+
+                        current = Some((path, file, sent));
+                    }
+
+                    let (_, file, sent) = current.as_mut().expect("set above");
+
+                    // Only the bytes flushed by the writer are sent, so that a partial write is never streamed.
+                    let remaining = written.saturating_sub(*sent);
+                    *sent += io::copy(&mut (&mut *file).take(remaining), &mut client_writer)
+                        .await
+                        .context("stream recording to client")?;
+                    client_writer.flush().await.context("flush")?;
+                }
+                LiveRecording::Terminated => {
+                    debug!("Recording is terminated");
+                    break;
+                }
+            }
+
+            tokio::select! {
+                res = live.changed() => {
+                    if res.is_err() {
+                        debug!("Recording is not tracked anymore");
+                        break;
+                    }
+                }
+                () = &mut client_gone => {
+                    debug!("Shadowing client is gone");
+                    return Ok(());
+                }
+                _ = shutdown_signal.wait() => {
+                    trace!("Received shutdown signal");
+                    break;
+                }
+            }
+        }
+
+        client_writer.shutdown().await.context("shutdown")?;
+
+        Ok(())
+    }
+}
+
+/// Writes the WebM header to `out` and positions `file` at the beginning of the last complete cluster.
+///
+/// A WebM file produced by a browser is made of a header (EBML header, segment info and tracks) followed
+/// by clusters, each cluster starting at a keyframe. Replaying the header followed by the last cluster
+/// gives a playable stream without sending the whole recording.
+///
+/// If no cluster is found, `file` is positioned at the beginning and nothing is written.
+async fn seek_to_last_webm_cluster<W>(file: &mut fs::File, out: &mut W) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let std_file = file.try_clone().await?.into_std().await;
+
+    let clusters = tokio::task::spawn_blocking(move || webm::find_clusters(std::io::BufReader::new(std_file)))
+        .await
+        .map_err(io::Error::other)??;
+
+    let Some((first_cluster, last_cluster)) = clusters else {
+        file.seek(io::SeekFrom::Start(0)).await?;
+        return Ok(());
+    };
+
+    file.seek(io::SeekFrom::Start(0)).await?;
+    io::copy(&mut (&mut *file).take(first_cluster), out).await?;
+
+    file.seek(io::SeekFrom::Start(last_cluster)).await?;
+
+    Ok(())
+}
+
+/// A set containing IDs of currently active recordings.
+///
+/// The ID is inserted at the initial recording
+///
+/// The purpose of this set is to provide a quick way of checking if a recording
+/// is on-going for a given session ID in non-async context.
+/// If you are looking for the the detailled recording state, you can use the
+/// the `get_state` method provided by `RecordingMessageSender`.
+#[derive(Debug)]
+pub struct ActiveRecordings(Mutex<HashSet<Uuid>>);
+
+impl ActiveRecordings {
+    pub fn contains(&self, id: Uuid) -> bool {
+        self.0.lock().contains(&id)
+    }
+
+    fn insert(&self, id: Uuid) -> usize {
+        let mut guard = self.0.lock();
+        guard.insert(id);
+        guard.len()
+    }
+
+    fn remove(&self, id: Uuid) {
+        self.0.lock().remove(&id);
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum OnGoingRecordingState {
+    Connected,
+    LastSeen { timestamp: i64 },
+}
+
+/// State of an on-going recording, as seen by live viewers.
+#[derive(Debug, Clone)]
+pub enum LiveRecording {
+    /// Bytes are appended to this file as long as the recording is connected.
+    File {
+        path: Utf8PathBuf,
+        file_type: RecordingFileType,
+        /// Number of bytes written to the file so far.
+        written: u64,
+    },
+    /// The recording is terminated: no more bytes will be appended.
+    Terminated,
+}
+
+#[derive(Debug)]
+struct ConnectedRecording {
+    path: Utf8PathBuf,
+    /// Number of bytes already persisted in the file, non-zero when resuming.
+    offset: u64,
+    live: Arc<watch::Sender<LiveRecording>>,
+}
+
+#[derive(Debug, Clone)]
+struct OnGoingStream {
+    state: OnGoingRecordingState,
+    live: Arc<watch::Sender<LiveRecording>>,
+}
+
+#[derive(Debug, Clone)]
+struct OnGoingRecording {
+    manifest: JrecManifest,
+    /// Streams pushed for this recording, keyed by name (`None` for the default stream)
+    streams: HashMap<Option<String>, OnGoingStream>,
+    /// Name of the file where the events are written
+    events_file: String,
+}
+
+impl OnGoingRecording {
+    /// The recording is connected as long as one of its streams is connected.
+    fn state(&self) -> OnGoingRecordingState {
+        let mut last_seen = None;
+
+        for stream in self.streams.values() {
+            match stream.state {
+                OnGoingRecordingState::Connected => return OnGoingRecordingState::Connected,
+                OnGoingRecordingState::LastSeen { timestamp } => last_seen = cmp::max(last_seen, Some(timestamp)),
+            }
+        }
+
+        OnGoingRecordingState::LastSeen {
+            timestamp: last_seen.unwrap_or_default(),
+        }
+    }
+
+    fn terminate(&self) {
+        for stream in self.streams.values() {
+            stream.live.send_replace(LiveRecording::Terminated);
+        }
+    }
+}
+
+enum RecordingManagerMessage {
+    Connect {
+        id: Uuid,
+        stream: Option<String>,
+        file_type: RecordingFileType,
+        resume: bool,
+        channel: oneshot::Sender<ConnectedRecording>,
+    },
+    Disconnect {
+        id: Uuid,
+        stream: Option<String>,
+    },
+    GetState {
+        id: Uuid,
+        channel: oneshot::Sender<Option<OnGoingRecordingState>>,
+    },
+    GetCount {
+        channel: oneshot::Sender<usize>,
+    },
+    SubscribeLive {
+        id: Uuid,
+        stream: Option<String>,
+        channel: oneshot::Sender<Option<watch::Receiver<LiveRecording>>>,
+    },
+}
+
+impl fmt::Debug for RecordingManagerMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecordingManagerMessage::Connect {
+                id,
+                stream,
+                file_type,
+                resume,
+                channel: _,
+            } => f
+                .debug_struct("Connect")
+                .field("id", id)
+                .field("stream", stream)
+                .field("file_type", file_type)
+                .field("resume", resume)
+                .finish_non_exhaustive(),
+            RecordingManagerMessage::Disconnect { id, stream } => f
+                .debug_struct("Disconnect")
+                .field("id", id)
+                .field("stream", stream)
+                .finish(),
+            RecordingManagerMessage::GetState { id, channel: _ } => {
+                f.debug_struct("GetState").field("id", id).finish_non_exhaustive()
+            }
+            RecordingManagerMessage::GetCount { channel: _ } => f.debug_struct("GetCount").finish_non_exhaustive(),
+            RecordingManagerMessage::SubscribeLive { id, stream, channel: _ } => f
+                .debug_struct("SubscribeLive")
+                .field("id", id)
+                .field("stream", stream)
+                .finish_non_exhaustive(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RecordingMessageSender {
+    channel: mpsc::Sender<RecordingManagerMessage>,
+    events: EventSender,
+    pub active_recordings: Arc<ActiveRecordings>,
+    pub index: Arc<RecordingIndex>,
+}
+
+impl RecordingMessageSender {
+    async fn connect(
+        &self,
+        id: Uuid,
+        stream: Option<String>,
+        file_type: RecordingFileType,
+        resume: bool,
+    ) -> anyhow::Result<ConnectedRecording> {
+        let (tx, rx) = oneshot::channel();
+        self.channel
+            .send(RecordingManagerMessage::Connect {
+                id,
+                stream,
+                file_type,
+                resume,
+                channel: tx,
+            })
+            .await
+            .ok()
+            .context("couldn't send New message")?;
+        rx.await
+            .context("couldn't receive recording file path for this recording")
+    }
+
+    async fn disconnect(&self, id: Uuid, stream: Option<String>) -> anyhow::Result<()> {
+        self.channel
+            .send(RecordingManagerMessage::Disconnect { id, stream })
+            .await
+            .ok()
+            .context("couldn't send Remove message")
+    }
+
+    pub async fn get_state(&self, id: Uuid) -> anyhow::Result<Option<OnGoingRecordingState>> {
+        let (tx, rx) = oneshot::channel();
+        self.channel
+            .send(RecordingManagerMessage::GetState { id, channel: tx })
+            .await
+            .ok()
+            .context("couldn't send GetState message")?;
+        rx.await.context("couldn't receive recording state")
+    }
+
+    pub async fn get_count(&self) -> anyhow::Result<usize> {
+        let (tx, rx) = oneshot::channel();
+        self.channel
+            .send(RecordingManagerMessage::GetCount { channel: tx })
+            .await
+            .ok()
+            .context("couldn't send GetCount message")?;
+        rx.await.context("couldn't receive ongoing recording count")
+    }
+
+    /// Subscribes to the live state of a stream of an on-going recording.
+    ///
+    /// Returns `None` if there is no such stream on-going for this ID.
+    pub async fn subscribe_live(
+        &self,
+        id: Uuid,
+        stream: Option<String>,
+    ) -> anyhow::Result<Option<watch::Receiver<LiveRecording>>> {
+        let (tx, rx) = oneshot::channel();
+        self.channel
+            .send(RecordingManagerMessage::SubscribeLive {
+                id,
+                stream,
+                channel: tx,
+            })
+            .await
+            .ok()
+            .context("couldn't send SubscribeLive message")?;
+        rx.await.context("couldn't receive live recording subscription")
+    }
+
+    /// Adds an event to the timeline of a session.
+    ///
+    /// The events of a session with a recording policy are kept until its recording starts. Otherwise, they are only
+    /// written if a recording is on-going.
+    ///
+    /// The event is dropped if the recording manager is overloaded, so that the caller is never blocked.
+    pub fn record_event(&self, id: Uuid, recording_policy: bool, event: SessionEvent) {
+        self.events.send(id, recording_policy, event);
+    }
+
+    /// Returns the sending half of the session events channel.
+    pub fn event_sender(&self) -> EventSender {
+        self.events.clone()
+    }
+}
+
+pub struct RecordingMessageReceiver {
+    channel: mpsc::Receiver<RecordingManagerMessage>,
+    events: mpsc::Receiver<EventMessage>,
+    active_recordings: Arc<ActiveRecordings>,
+    index: Arc<RecordingIndex>,
+}
+
+pub fn recording_message_channel() -> (RecordingMessageSender, RecordingMessageReceiver) {
+    let ongoing_recordings = Arc::new(ActiveRecordings(Mutex::new(HashSet::new())));
+    let index = Arc::new(RecordingIndex::default());
+
+    let (tx, rx) = mpsc::channel(64);
+    let (events_tx, events_rx) = events::event_channel();
+
+    let handle = RecordingMessageSender {
+        channel: tx,
+        events: events_tx,
+        active_recordings: ongoing_recordings.clone(),
+        index: Arc::clone(&index),
+    };
+
+    let receiver = RecordingMessageReceiver {
+        channel: rx,
+        events: events_rx,
+        active_recordings: ongoing_recordings,
+        index,
+    };
+
+    (handle, receiver)
+}
+
+struct DisconnectedTtl {
+    deadline: tokio::time::Instant,
+    id: Uuid,
+}
+
+impl PartialEq for DisconnectedTtl {
+    fn eq(&self, other: &Self) -> bool {
+        self.deadline.eq(&other.deadline) && self.id.eq(&other.id)
+    }
+}
+
+impl Eq for DisconnectedTtl {}
+
+impl PartialOrd for DisconnectedTtl {
+    fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for DisconnectedTtl {
+    fn cmp(&self, other: &Self) -> cmp::Ordering {
+        match self.deadline.cmp(&other.deadline) {
+            cmp::Ordering::Less => cmp::Ordering::Greater,
+            cmp::Ordering::Equal => self.id.cmp(&other.id),
+            cmp::Ordering::Greater => cmp::Ordering::Less,
+        }
+    }
+}
+
+pub struct RecordingManagerTask {
+    rx: RecordingMessageReceiver,
+    ongoing_recordings: HashMap<Uuid, OnGoingRecording>,
+    storage: DynRecordingStorage,
+    sessions: SessionMessageSender,
+    gateway_id: Option<Uuid>,
+    pending_events: PendingEvents,
+}
+
+impl RecordingManagerTask {
+    pub fn new(
+        rx: RecordingMessageReceiver,
+        storage: DynRecordingStorage,
+        sessions: SessionMessageSender,
+        gateway_id: Option<Uuid>,
+    ) -> Self {
+        Self {
+            rx,
+            ongoing_recordings: HashMap::new(),
+            storage,
+            sessions,
+            gateway_id,
+            pending_events: PendingEvents::default(),
+        }
+    }
+
+    /// Rebuilds the recording index from the manifests found in the storage.
+    async fn rebuild_index(&self) -> anyhow::Result<()> {
+        use futures::StreamExt as _;
+
+        const CONCURRENT_READS: usize = 16;
+
+        let ids = self.storage.list_recordings().await.context("list recordings")?;
+
+        let mut manifests = futures::stream::iter(ids)
+            .map(|id| async move { (id, self.storage.read_manifest(id).await) })
+            .buffer_unordered(CONCURRENT_READS);
+
+        while let Some((id, result)) = manifests.next().await {
+            match result {
+                Ok(Some(manifest)) => self.rx.index.insert(manifest),
+                Ok(None) => debug!(%id, "Recording without manifest"),
+                Err(e) => warn!(%id, error = format!("{e:#}"), "Failed to read manifest"),
+            }
+        }
+
+        info!(count = self.rx.index.len(), "Recording index rebuilt");
+
+        Ok(())
+    }
+
+    /// Fills the metadata missing from the manifest using the running session, if any.
+    async fn fill_metadata(&self, manifest: &mut JrecManifest) {
+        if manifest.gateway_id.is_none() {
+            manifest.gateway_id = self.gateway_id;
+        }
+
+        let session = match self.sessions.get_running_sessions().await {
+            Ok(mut sessions) => sessions.remove(&manifest.session_id),
+            Err(e) => {
+                warn!(error = format!("{e:#}"), "Failed to retrieve running sessions");
+                None
+            }
+        };
+
+        let Some(session) = session else {
+            debug!(id = %manifest.session_id, "No running session for this recording yet");
+            return;
+        };
+
+        if manifest.protocol.is_none() {
+            manifest.protocol = Some(session.application_protocol);
+        }
+
+        if manifest.destination.is_none() {
+            if let ConnectionModeDetails::Fwd { destination_host } = session.mode_details {
+                manifest.destination = Some(destination_host.as_str().to_owned());
+            }
+        }
+
+        if manifest.subject.is_none() {
+            manifest.subject = session.subject;
+        }
+
+        if manifest.source_ip.is_none() {
+            manifest.source_ip = session.client_addr.map(|addr| addr.ip());
+        }
+    }
+
+    async fn write_manifest(&self, manifest: &JrecManifest) -> anyhow::Result<()> {
+        self.storage.write_manifest(manifest.session_id, manifest).await?;
+        self.rx.index.insert(manifest.clone());
+        Ok(())
+    }
+
+    /// Appends events to the timeline of an on-going recording.
+    async fn write_events(&self, id: Uuid, records: &[EventRecord]) {
+        let Some(ongoing) = self.ongoing_recordings.get(&id) else {
+            return;
+        };
+
+        let path = self.storage.staging_path(id).join(&ongoing.events_file);
+
+        if let Err(e) = events::append(&path, records).await {
+            warn!(%id, error = format!("{e:#}"), "Failed to write session events");
+        }
+    }
+
+    /// Writes the events received before the recording started, followed by the connection of the stream.
+    async fn write_connected_event(&mut self, id: Uuid, stream: Option<String>, file_name: String, resumed: bool) {
+        let mut records = self.pending_events.take(id);
+
+        records.push(EventRecord::now(SessionEvent::RecordingConnected {
+            stream,
+            file_name,
+            resumed,
+        }));
+
+        self.write_events(id, &records).await;
+    }
+
+    async fn handle_event(&mut self, message: EventMessage) {
+        let EventMessage {
+            id,
+            recording_policy,
+            record,
+        } = message;
+
+        if self.ongoing_recordings.contains_key(&id) {
+            self.write_events(id, &[record]).await;
+        } else if matches!(record.event, SessionEvent::SessionEnded) {
+            // The session ended without being recorded.
+            self.pending_events.discard(id);
+        } else if recording_policy {
+            self.pending_events.push(id, record);
+        }
+    }
+
+    /// Hands over a complete recording file to the storage, in the background.
+    ///
+    /// The file is kept in the staging folder until stored, so it remains available in the meantime. Failed attempts
+    /// are retried a few times, then the file is left in the staging folder until the next start.
+    fn spawn_store_file(&self, id: Uuid, file_name: String) {
+        let storage = Arc::clone(&self.storage);
+
+        tokio::spawn(async move {
+            let mut retry_delay = STORE_FILE_INITIAL_RETRY_DELAY;
+
+            for attempt in 1..=STORE_FILE_MAX_ATTEMPTS {
+                match storage.store_file(id, &file_name).await {
+                    Ok(()) => return,
+                    Err(e) if attempt < STORE_FILE_MAX_ATTEMPTS => {
+                        let error = format!("{e:#}");
+                        warn!(%id, error, %file_name, attempt, "Failed to store recording file, retrying");
+                        tokio::time::sleep(retry_delay).await;
+                        retry_delay *= 2;
+                    }
+                    Err(e) => {
+                        let error = format!("{e:#}");
+                        error!(%id, error, %file_name, "Failed to store recording file, left in the staging folder");
+                    }
+                }
+            }
+        });
+    }
+
+    /// Hands over the files left in the staging folder (e.g.: failed uploads, or a crash) to the storage.
+    ///
+    /// Must be called before any recording is started, as all the staged files are assumed to be complete.
+    async fn store_leftover_files(&self) -> anyhow::Result<()> {
+        let files = self.storage.list_staged_files().await.context("list staged files")?;
+
+        if !files.is_empty() {
+            info!(
+                count = files.len(),
+                "Storing recording files left in the staging folder"
+            );
+        }
+
+        for (id, file_name) in files {
+            self.spawn_store_file(id, file_name);
+        }
+
+        Ok(())
+    }
+
+    /// Resumes the last file of a stream disconnected recently, if it has the expected type.
+    ///
+    /// Returns `None` when the stream can't be resumed, in which case a new file should be started.
+    async fn try_resume(
+        &mut self,
+        id: Uuid,
+        stream: &Option<String>,
+        file_type: RecordingFileType,
+    ) -> anyhow::Result<Option<ConnectedRecording>> {
+        let Some(ongoing) = self.ongoing_recordings.get_mut(&id) else {
+            debug!(%id, "No recent recording to resume");
+            return Ok(None);
+        };
+
+        let Some(ongoing_stream) = ongoing.streams.get_mut(stream) else {
+            debug!(%id, ?stream, "No recent stream to resume");
+            return Ok(None);
+        };
+
+        let OnGoingRecordingState::LastSeen { timestamp: last_seen } = ongoing_stream.state else {
+            return Ok(None);
+        };
+
+        let Some(current_file) = ongoing.manifest.last_file_mut(stream.as_deref()) else {
+            return Ok(None);
+        };
+
+        if !current_file.file_name.ends_with(&format!(".{file_type}")) {
+            debug!(%id, file_name = %current_file.file_name, %file_type, "File type changed; can’t resume");
+            return Ok(None);
+        }
+
+        let recording_file = self.storage.staging_path(id).join(&current_file.file_name);
+
+        let offset = match fs::metadata(&recording_file).await {
+            Ok(metadata) => metadata.len(),
+            Err(e) => {
+                debug!(%id, error = %e, path = %recording_file, "Recording file not found in staging folder; can’t resume");
+                return Ok(None);
+            }
+        };
+
+        let now = time::OffsetDateTime::now_utc().unix_timestamp();
+
+        current_file.gaps.push(JrecGap {
+            start_time: last_seen,
+            duration: now - last_seen,
+        });
+
+        ongoing_stream.state = OnGoingRecordingState::Connected;
+
+        debug!(%id, path = %recording_file, offset, "Resume recording");
+
+        self.storage
+            .write_manifest(id, &ongoing.manifest)
+            .await
+            .context("write updated manifest")?;
+
+        self.rx.index.insert(ongoing.manifest.clone());
+
+        ongoing_stream.live.send_replace(LiveRecording::File {
+            path: recording_file.clone(),
+            file_type,
+            written: offset,
+        });
+
+        let live = Arc::clone(&ongoing_stream.live);
+
+        self.rx.active_recordings.insert(id);
+
+        Ok(Some(ConnectedRecording {
+            path: recording_file,
+            offset,
+            live,
+        }))
+    }
+
+    async fn handle_connect(
+        &mut self,
+        id: Uuid,
+        stream: Option<String>,
+        file_type: RecordingFileType,
+        resume: bool,
+    ) -> anyhow::Result<ConnectedRecording> {
+        const LENGTH_WARNING_THRESHOLD: usize = 1000;
+
+        let ongoing_stream = self
+            .ongoing_recordings
+            .get(&id)
+            .and_then(|ongoing| ongoing.streams.get(&stream));
+
+        if let Some(ongoing_stream) = ongoing_stream {
+            if matches!(ongoing_stream.state, OnGoingRecordingState::Connected) {
+                anyhow::bail!("concurrent push for the same stream is not supported");
+            }
+        }
+
+        if resume {
+            if let Some(connected) = self.try_resume(id, &stream, file_type).await? {
+                let file_name = connected.path.file_name().unwrap_or_default().to_owned();
+                self.write_connected_event(id, stream, file_name, true).await;
+                return Ok(connected);
+            }
+        }
+
+        let recording_path = self.storage.staging_path(id);
+
+        // The staging folder is created even for existing recordings, as it may have been cleaned up by the storage.
+        fs::create_dir_all(&recording_path)
+            .await
+            .with_context(|| format!("failed to create recording path: {recording_path}"))?;
+
+        let start_time = time::OffsetDateTime::now_utc().unix_timestamp();
+
+        // The manifest of an on-going recording is kept up to date in memory, as other streams may be connected.
+        let mut manifest = match self.ongoing_recordings.get(&id) {
+            Some(ongoing) => {
+                // A new file is started for this stream: the previous one, if any, is complete.
+                if ongoing.streams.contains_key(&stream) {
+                    if let Some(previous_file) = ongoing.manifest.last_file(stream.as_deref()) {
+                        self.spawn_store_file(id, previous_file.file_name.clone());
+                    }
+                }
+
+                ongoing.manifest.clone()
+            }
+            None => match self.storage.read_manifest(id).await.context("read manifest")? {
+                Some(existing_manifest) => {
+                    debug!(%id, "Recording already exists");
+                    existing_manifest
+                }
+                None => {
+                    debug!(%id, "Create new recording");
+
+                    JrecManifest {
+                        session_id: id,
+                        start_time,
+                        duration: 0,
+                        files: Vec::new(),
+                        protocol: None,
+                        destination: None,
+                        subject: None,
+                        source_ip: None,
+                        gateway_id: None,
+                        events_files: Vec::new(),
+                    }
+                }
+            },
+        };
+
+        let file_name = manifest.push_file(stream.as_deref(), file_type, start_time);
+
+        // Once a recording is terminated, its events file is stored and never appended to again: a new one is
+        // started when the recording is reopened.
+        let events_file = match self.ongoing_recordings.get(&id) {
+            Some(ongoing) => ongoing.events_file.clone(),
+            None => manifest.push_events_file(),
+        };
+
+        self.fill_metadata(&mut manifest).await;
+
+        self.write_manifest(&manifest).await.context("write manifest")?;
+
+        let recording_file = recording_path.join(&file_name);
+
+        let active_recording_count = self.rx.active_recordings.insert(id);
+
+        let live_state = LiveRecording::File {
+            path: recording_file.clone(),
+            file_type,
+            written: 0,
+        };
+
+        let ongoing = match self.ongoing_recordings.entry(id) {
+            Entry::Occupied(entry) => {
+                let ongoing = entry.into_mut();
+                ongoing.manifest = manifest;
+                ongoing
+            }
+            Entry::Vacant(entry) => entry.insert(OnGoingRecording {
+                manifest,
+                streams: HashMap::new(),
+                events_file,
+            }),
+        };
+
+        let connected_stream = stream.clone();
+
+        // Live viewers subscribed before a reconnection keep following the same channel.
+        let live = match ongoing.streams.get_mut(&stream) {
+            Some(ongoing_stream) => {
+                ongoing_stream.state = OnGoingRecordingState::Connected;
+                ongoing_stream.live.send_replace(live_state);
+                Arc::clone(&ongoing_stream.live)
+            }
+            None => {
+                let live = Arc::new(watch::channel(live_state).0);
+
+                ongoing.streams.insert(
+                    stream,
+                    OnGoingStream {
+                        state: OnGoingRecordingState::Connected,
+                        live: Arc::clone(&live),
+                    },
+                );
+
+                live
+            }
+        };
+
+        let ongoing_recording_count = self.ongoing_recordings.len();
+
+        // Sanity check
+        if active_recording_count > LENGTH_WARNING_THRESHOLD || ongoing_recording_count > LENGTH_WARNING_THRESHOLD {
+            warn!(
+                active_recording_count,
+                ongoing_recording_count,
+                "length threshold exceeded (either the load is very high or the list is growing uncontrollably)"
+            );
+        }
+
+        self.write_connected_event(id, connected_stream, file_name, false).await;
+
+        Ok(ConnectedRecording {
+            path: recording_file,
+            offset: 0,
+            live,
+        })
+    }
+
+    async fn handle_disconnect(&mut self, id: Uuid, stream: Option<String>) -> anyhow::Result<()> {
+        let Some(ongoing) = self.ongoing_recordings.get_mut(&id) else {
+            anyhow::bail!("unknown recording for ID {id}");
+        };
+
+        let ongoing_stream = ongoing
+            .streams
+            .get_mut(&stream)
+            .with_context(|| format!("unknown stream {stream:?} for recording {id}"))?;
+
+        if !matches!(ongoing_stream.state, OnGoingRecordingState::Connected) {
+            anyhow::bail!("a recording not connected can’t be disconnected (there is probably a bug)");
+        }
+
+        let end_time = time::OffsetDateTime::now_utc().unix_timestamp();
+
+        ongoing_stream.state = OnGoingRecordingState::LastSeen { timestamp: end_time };
+
+        let current_file = ongoing
+            .manifest
+            .last_file_mut(stream.as_deref())
+            .context("no recording file (this is a bug)")?;
+        current_file.duration = end_time - current_file.start_time;
+
+        ongoing.manifest.duration = end_time - ongoing.manifest.start_time;
+
+        debug!(%id, ?stream, "Write updated manifest");
+
+        self.storage
+            .write_manifest(id, &ongoing.manifest)
+            .await
+            .context("write updated manifest")?;
+
+        self.rx.index.insert(ongoing.manifest.clone());
+
+        self.write_events(id, &[EventRecord::now(SessionEvent::RecordingDisconnected { stream })])
+            .await;
+
+        Ok(())
+    }
+
+    fn handle_remove(&mut self, id: Uuid) {
+        if let Some(ongoing) = self.ongoing_recordings.get(&id) {
+            let now = time::OffsetDateTime::now_utc().unix_timestamp();
+
+            match ongoing.state() {
+                // NOTE: Comparing with DISCONNECTED_TTL_SECS - 1 just in case the sleep returns faster than expected.
+                // (I don’t know if this can actually happen in practice, but it’s better to be safe than sorry.)
+                OnGoingRecordingState::LastSeen { timestamp } if now >= timestamp + DISCONNECTED_TTL_SECS - 1 => {
+                    debug!(%id, "Mark recording as terminated");
+                    ongoing.terminate();
+
+                    for stream in ongoing.streams.keys() {
+                        if let Some(last_file) = ongoing.manifest.last_file(stream.as_deref()) {
+                            self.spawn_store_file(id, last_file.file_name.clone());
+                        }
+                    }
+
+                    self.spawn_store_file(id, ongoing.events_file.clone());
+
+                    self.rx.active_recordings.remove(id);
+                    self.ongoing_recordings.remove(&id);
+
+                    // TODO(DGW-86): now is a good timing to kill sessions that _must_ be recorded
+                }
+                _ => {
+                    trace!(%id, "Recording should not be removed yet");
+                }
+            }
+        }
+    }
+
+    /// Terminates an on-going recording right away, storing the last file of each stream.
+    async fn terminate_now(&mut self, id: Uuid) {
+        let Some(ongoing) = self.ongoing_recordings.remove(&id) else {
+            return;
+        };
+
+        ongoing.terminate();
+        self.rx.active_recordings.remove(id);
+
+        let file_names = ongoing
+            .streams
+            .keys()
+            .filter_map(|stream| ongoing.manifest.last_file(stream.as_deref()))
+            .map(|file| file.file_name.as_str())
+            .chain(std::iter::once(ongoing.events_file.as_str()));
+
+        for file_name in file_names {
+            if let Err(e) = self.storage.store_file(id, file_name).await {
+                error!(%id, error = format!("{e:#}"), %file_name, "Failed to store recording file");
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Task for RecordingManagerTask {
+    type Output = anyhow::Result<()>;
+
+    const NAME: &'static str = "recording manager";
+
+    async fn run(self, shutdown_signal: ShutdownSignal) -> Self::Output {
+        recording_manager_task(self, shutdown_signal).await
+    }
+}
+
+#[instrument(skip_all)]
+async fn recording_manager_task(
+    mut manager: RecordingManagerTask,
+    mut shutdown_signal: ShutdownSignal,
+) -> anyhow::Result<()> {
+    debug!("Task started");
+
+    if let Err(e) = manager.rebuild_index().await {
+        error!(error = format!("{e:#}"), "Failed to rebuild recording index");
+    }
+
+    if let Err(e) = manager.store_leftover_files().await {
+        error!(
+            error = format!("{e:#}"),
+            "Failed to store the files left in the staging folder"
+        );
+    }
+
+    let mut disconnected = BinaryHeap::<DisconnectedTtl>::new();
+
+    let next_remove_sleep = tokio::time::sleep_until(tokio::time::Instant::now());
+    tokio::pin!(next_remove_sleep);
+
+    // Consume initial sleep
+    (&mut next_remove_sleep).await;
+
+    loop {
+        tokio::select! {
+            () = &mut next_remove_sleep, if !disconnected.is_empty() => {
+                // Will never panic since we check for non-emptiness before entering this block
+                let to_remove = disconnected.pop().unwrap();
+
+                manager.handle_remove(to_remove.id);
+
+                // Re-arm the Sleep instance with the next deadline if required
+                if let Some(next) = disconnected.peek() {
+                    next_remove_sleep.as_mut().reset(next.deadline)
+                }
+            }
+            msg = manager.rx.channel.recv() => {
+                let Some(msg) = msg else {
+                    warn!("All senders are dead");
+                    break;
+                };
+
+                debug!(?msg, "Received message");
+
+                match msg {
+                    RecordingManagerMessage::Connect { id, stream, file_type, resume, channel } => {
+                        match manager.handle_connect(id, stream, file_type, resume).await {
+                            Ok(connected) => {
+                                let _ = channel.send(connected);
+                            }
+                            Err(e) => error!(error = format!("{e:#}"), "handle_connect"),
+                        }
+                    },
+                    RecordingManagerMessage::Disconnect { id, stream } => {
+                        if let Err(e) = manager.handle_disconnect(id, stream).await {
+                            error!(error = format!("{e:#}"), "handle_disconnect");
+                        }
+
+                        let now = tokio::time::Instant::now();
+                        let deadline = now + DISCONNECTED_TTL_DURATION;
+
+                        disconnected.push(DisconnectedTtl {
+                            deadline,
+                            id,
+                        });
+
+                        // Reset the Sleep instance if the new deadline is sooner or it is already elapsed
+                        if next_remove_sleep.is_elapsed() || deadline < next_remove_sleep.deadline() {
+                            next_remove_sleep.as_mut().reset(deadline);
+                        }
+                    }
+                    RecordingManagerMessage::GetState { id, channel } => {
+                        let response = manager.ongoing_recordings.get(&id).map(OnGoingRecording::state);
+                        let _ = channel.send(response);
+                    }
+                    RecordingManagerMessage::GetCount { channel } => {
+                        let _ = channel.send(manager.ongoing_recordings.len());
+                    }
+                    RecordingManagerMessage::SubscribeLive { id, stream, channel } => {
+                        let response = manager
+                            .ongoing_recordings
+                            .get(&id)
+                            .and_then(|ongoing| ongoing.streams.get(&stream))
+                            .map(|ongoing_stream| ongoing_stream.live.subscribe());
+                        let _ = channel.send(response);
+                    }
+                }
+            }
+            Some(message) = manager.rx.events.recv() => {
+                manager.handle_event(message).await;
+            }
+            _ = shutdown_signal.wait() => {
+                break;
+            }
+        }
+    }
+
+    debug!("Task is stopping; wait for disconnect messages");
+
+    while let Some(msg) = manager.rx.channel.recv().await {
+        debug!(?msg, "Received message");
+        if let RecordingManagerMessage::Disconnect { id, stream } = msg {
+            if let Err(e) = manager.handle_disconnect(id, stream).await {
+                error!(error = format!("{e:#}"), "handle_disconnect");
+            }
+
+            let is_connected = manager
+                .ongoing_recordings
+                .get(&id)
+                .is_some_and(|ongoing| matches!(ongoing.state(), OnGoingRecordingState::Connected));
+
+            if !is_connected {
+                manager.terminate_now(id).await;
+            }
+        }
+    }
+
+    // Recordings disconnected shortly before the shutdown are not waiting for a reconnection anymore.
+    let remaining: Vec<Uuid> = manager.ongoing_recordings.keys().copied().collect();
+
+    for id in remaining {
+        manager.terminate_now(id).await;
+    }
+
+    debug!("Task terminated");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use devolutions_gateway_task::ShutdownHandle;
+    use tempfile::TempDir;
+    use tokio::io::DuplexStream;
+    use tokio::task::JoinHandle;
+
+    use super::*;
+    use crate::recording::storage::{LocalStorage, RecordingStorage};
+
+    /// Recording manager running on its own staging folder, removed once the harness is dropped.
+    struct Harness {
+        recordings: RecordingMessageSender,
+        storage: DynRecordingStorage,
+        shutdown_handle: ShutdownHandle,
+        shutdown_signal: ShutdownSignal,
+        manager: JoinHandle<anyhow::Result<()>>,
+        _root: TempDir,
+    }
+
+    fn temp_root() -> (TempDir, Utf8PathBuf) {
+        let dir = TempDir::new().unwrap();
+        let path = Utf8PathBuf::from_path_buf(dir.path().to_owned()).expect("UTF-8 temporary directory");
+        (dir, path)
+    }
+
+    impl Harness {
+        fn start() -> Self {
+            let (root, root_path) = temp_root();
+            Self::with_storage(root, Arc::new(LocalStorage::new(root_path)))
+        }
+
+        fn with_storage(root: TempDir, storage: DynRecordingStorage) -> Self {
+            let (recordings, rx) = recording_message_channel();
+
+            // Without a session manager, the metadata of the recordings is simply not filled.
+            let (sessions, _) = crate::session::session_manager_channel(&recordings);
+
+            let (shutdown_handle, shutdown_signal) = ShutdownHandle::new();
+
+            let manager = RecordingManagerTask::new(rx, Arc::clone(&storage), sessions, None);
+            let manager = tokio::spawn(manager.run(shutdown_signal.clone()));
+
+            Self {
+                recordings,
+                storage,
+                shutdown_handle,
+                shutdown_signal,
+                manager,
+                _root: root,
+            }
+        }
+
+        /// Starts pushing a WebM stream, returning the client side of the connection.
+        fn push(&self, id: Uuid, stream: Option<&str>, resume: bool) -> (DuplexStream, JoinHandle<anyhow::Result<()>>) {
+            let claims = serde_json::from_value(serde_json::json!({
+                "jet_aid": id,
+                "jet_rop": "push",
+                "exp": 0,
+                "jti": Uuid::new_v4(),
+            }))
+            .unwrap();
+
+            let (client, server) = io::duplex(64 * 1024);
+
+            let push = ClientPush::builder()
+                .recordings(self.recordings.clone())
+                .claims(claims)
+                .client_stream(server)
+                .file_type(RecordingFileType::WebM)
+                .session_id(id)
+                .stream(stream.map(str::to_owned))
+                .resume(resume)
+                .shutdown_signal(self.shutdown_signal.clone())
+                .build();
+
+            (client, tokio::spawn(push.run()))
+        }
+
+        /// Waits for the stream to be connected, and subscribes to its live state.
+        async fn subscribe_live(&self, id: Uuid, stream: Option<&str>) -> watch::Receiver<LiveRecording> {
+            loop {
+                if let Some(live) = self
+                    .recordings
+                    .subscribe_live(id, stream.map(str::to_owned))
+                    .await
+                    .unwrap()
+                {
+                    return live;
+                }
+
+                tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+            }
+        }
+
+        async fn manifest(&self, id: Uuid) -> JrecManifest {
+            // Messages are handled in order: once the state is received, the manifest is written for the previous
+            // disconnections.
+            self.recordings.get_state(id).await.unwrap();
+
+            self.storage.read_manifest(id).await.unwrap().expect("manifest")
+        }
+
+        async fn read_file(&self, id: Uuid, file_name: &str) -> Vec<u8> {
+            fs::read(self.storage.staging_path(id).join(file_name)).await.unwrap()
+        }
+
+        /// Shuts the manager down, once all the pushes are over.
+        ///
+        /// Returns the staging folder, so that it can be inspected before being removed.
+        async fn shutdown(self) -> TempDir {
+            self.shutdown_handle.signal();
+            drop(self.recordings);
+            self.manager.await.unwrap().unwrap();
+            self._root
+        }
+    }
+
+    async fn read_offset(client: &mut DuplexStream) -> u64 {
+        client.read_u64().await.unwrap()
+    }
+
+    /// Sends the bytes, and disconnects once they are all written to the recording file.
+    async fn finish_push(mut client: DuplexStream, push: JoinHandle<anyhow::Result<()>>, bytes: &[u8]) {
+        client.write_all(bytes).await.unwrap();
+        drop(client);
+        push.await.unwrap().unwrap();
+    }
+
+    async fn wait_written(live: &mut watch::Receiver<LiveRecording>, expected: u64) {
+        live.wait_for(|state| matches!(state, LiveRecording::File { written, .. } if *written == expected))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn resume_at_current_length() {
+        let harness = Harness::start();
+        let id = Uuid::new_v4();
+
+        // Nothing to resume yet: a new file is started.
+        let (mut client, push) = harness.push(id, None, true);
+        assert_eq!(read_offset(&mut client).await, 0);
+        finish_push(client, push, b"first").await;
+
+        let (mut client, push) = harness.push(id, None, true);
+        assert_eq!(read_offset(&mut client).await, 5);
+        finish_push(client, push, b" second").await;
+
+        let manifest = harness.manifest(id).await;
+        assert_eq!(manifest.files.len(), 1);
+        assert_eq!(manifest.files[0].file_name, "recording-0.webm");
+        assert_eq!(manifest.files[0].gaps.len(), 1);
+
+        assert_eq!(harness.read_file(id, "recording-0.webm").await, b"first second");
+
+        harness.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn resume_of_unknown_stream() {
+        let harness = Harness::start();
+        let id = Uuid::new_v4();
+
+        let (client, push) = harness.push(id, Some("screen"), false);
+        finish_push(client, push, b"screen").await;
+
+        // Another stream of the same recording is not resumed, but started in its own file.
+        let (mut client, push) = harness.push(id, Some("camera"), true);
+        assert_eq!(read_offset(&mut client).await, 0);
+        finish_push(client, push, b"camera").await;
+
+        let manifest = harness.manifest(id).await;
+        let file_names: Vec<_> = manifest.files.iter().map(|file| file.file_name.as_str()).collect();
+        assert_eq!(file_names, ["recording-screen-0.webm", "recording-camera-0.webm"]);
+        assert!(manifest.files.iter().all(|file| file.gaps.is_empty()));
+
+        assert_eq!(harness.read_file(id, "recording-screen-0.webm").await, b"screen");
+        assert_eq!(harness.read_file(id, "recording-camera-0.webm").await, b"camera");
+
+        harness.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn concurrent_named_streams() {
+        let harness = Harness::start();
+        let id = Uuid::new_v4();
+
+        let (mut screen, screen_push) = harness.push(id, Some("screen"), false);
+        let (mut camera, camera_push) = harness.push(id, Some("camera"), false);
+
+        let mut screen_live = harness.subscribe_live(id, Some("screen")).await;
+        let mut camera_live = harness.subscribe_live(id, Some("camera")).await;
+
+        screen.write_all(b"screen bytes").await.unwrap();
+        camera.write_all(b"camera bytes").await.unwrap();
+
+        wait_written(&mut screen_live, 12).await;
+        wait_written(&mut camera_live, 12).await;
+
+        // The recording stays connected as long as one of its streams is.
+        drop(camera);
+        camera_push.await.unwrap().unwrap();
+
+        assert!(matches!(
+            harness.recordings.get_state(id).await.unwrap(),
+            Some(OnGoingRecordingState::Connected)
+        ));
+        assert!(matches!(*camera_live.borrow(), LiveRecording::File { .. }));
+
+        // The shutdown disconnects the remaining stream, and terminates the recording.
+        let storage = Arc::clone(&harness.storage);
+        let _root = harness.shutdown().await;
+        screen_push.await.unwrap().unwrap();
+
+        assert!(matches!(*screen_live.borrow(), LiveRecording::Terminated));
+        assert!(matches!(*camera_live.borrow(), LiveRecording::Terminated));
+
+        let manifest = storage.read_manifest(id).await.unwrap().unwrap();
+        let streams: Vec<_> = manifest.files.iter().map(|file| file.stream.as_deref()).collect();
+        assert_eq!(streams.len(), 2);
+        assert!(streams.contains(&Some("screen")));
+        assert!(streams.contains(&Some("camera")));
+
+        let staging_path = storage.staging_path(id);
+        assert_eq!(
+            fs::read(staging_path.join("recording-screen-0.webm")).await.unwrap(),
+            b"screen bytes"
+        );
+        assert_eq!(
+            fs::read(staging_path.join("recording-camera-0.webm")).await.unwrap(),
+            b"camera bytes"
+        );
+
+        let events = fs::read_to_string(staging_path.join(&manifest.events_files[0]))
+            .await
+            .unwrap();
+        let event_types: Vec<serde_json::Value> = events
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap()["type"].clone())
+            .collect();
+        assert_eq!(event_types.iter().filter(|ty| *ty == "recordingConnected").count(), 2);
+        assert_eq!(
+            event_types.iter().filter(|ty| *ty == "recordingDisconnected").count(),
+            2
+        );
+    }
+
+    /// Storage with staged files left by a previous run, failing the first attempt at storing each file.
+    struct FlakyStorage {
+        inner: LocalStorage,
+        staged: Vec<(Uuid, String)>,
+        attempts: Mutex<Vec<(Uuid, String)>>,
+        stored: watch::Sender<Vec<(Uuid, String)>>,
+    }
+
+    #[async_trait]
+    impl RecordingStorage for FlakyStorage {
+        fn staging_path(&self, id: Uuid) -> Utf8PathBuf {
+            self.inner.staging_path(id)
+        }
+
+        async fn read_manifest(&self, id: Uuid) -> anyhow::Result<Option<JrecManifest>> {
+            self.inner.read_manifest(id).await
+        }
+
+        async fn write_manifest(&self, id: Uuid, manifest: &JrecManifest) -> anyhow::Result<()> {
+            self.inner.write_manifest(id, manifest).await
+        }
+
+        async fn store_file(&self, id: Uuid, file_name: &str) -> anyhow::Result<()> {
+            let file = (id, file_name.to_owned());
+
+            {
+                let mut attempts = self.attempts.lock();
+                let is_first_attempt = !attempts.contains(&file);
+                attempts.push(file.clone());
+                anyhow::ensure!(!is_first_attempt, "first attempt");
+            }
+
+            self.stored.send_modify(|stored| stored.push(file));
+
+            Ok(())
+        }
+
+        async fn list_staged_files(&self) -> anyhow::Result<Vec<(Uuid, String)>> {
+            Ok(self.staged.clone())
+        }
+
+        async fn list_recordings(&self) -> anyhow::Result<Vec<Uuid>> {
+            self.inner.list_recordings().await
+        }
+
+        async fn file_info(&self, id: Uuid, file_name: &str) -> anyhow::Result<Option<storage::RecordingFileInfo>> {
+            self.inner.file_info(id, file_name).await
+        }
+
+        async fn open_file(
+            &self,
+            id: Uuid,
+            file_name: &str,
+            range: std::ops::Range<u64>,
+        ) -> anyhow::Result<storage::RecordingFileReader> {
+            self.inner.open_file(id, file_name, range).await
+        }
+
+        async fn delete_recording(&self, id: Uuid) -> anyhow::Result<()> {
+            self.inner.delete_recording(id).await
+        }
+    }
+
+    #[tokio::test]
+    async fn leftover_staged_files_are_stored_at_startup_and_retried() {
+        let (root, root_path) = temp_root();
+
+        let leftover = (Uuid::new_v4(), "recording-0.webm".to_owned());
+        let (stored, mut stored_rx) = watch::channel(Vec::new());
+
+        let harness = Harness::with_storage(
+            root,
+            Arc::new(FlakyStorage {
+                inner: LocalStorage::new(root_path),
+                staged: vec![leftover.clone()],
+                attempts: Mutex::new(Vec::new()),
+                stored,
+            }),
+        );
+
+        // The second attempt happens after the initial retry delay.
+        let stored = tokio::time::timeout(
+            STORE_FILE_INITIAL_RETRY_DELAY * 2,
+            stored_rx.wait_for(|stored| !stored.is_empty()),
+        )
+        .await
+        .expect("file stored after a retry")
+        .unwrap()
+        .clone();
+
+        assert_eq!(stored, [leftover]);
+
+        harness.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn shadow_streams_flushed_bytes_until_the_file_is_gone() {
+        let (_root, root_path) = temp_root();
+        let path = root_path.join("recording-0.trp");
+        fs::write(&path, b"flushed, partial").await.unwrap();
+
+        let (live, live_rx) = watch::channel(LiveRecording::File {
+            path: path.clone(),
+            file_type: RecordingFileType::TRP,
+            written: 7,
+        });
+        let (_shutdown_handle, shutdown_signal) = ShutdownHandle::new();
+        let (mut client, server) = io::duplex(1024);
+
+        let shadow = ClientShadow::builder()
+            .live(live_rx)
+            .client_stream(server)
+            .shutdown_signal(shutdown_signal)
+            .build();
+        let shadow = tokio::spawn(shadow.run());
+
+        let mut received = [0; 7];
+        client.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"flushed");
+
+        notify_written(&live, 16);
+
+        let mut received = [0; 9];
+        client.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b", partial");
+
+        // Bytes not reported as written yet are never sent.
+        fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .await
+            .unwrap()
+            .write_all(b" unflushed")
+            .await
+            .unwrap();
+
+        // The next file was already stored, and removed from the staging folder.
+        live.send_replace(LiveRecording::File {
+            path: root_path.join("recording-1.trp"),
+            file_type: RecordingFileType::TRP,
+            written: 0,
+        });
+
+        let mut rest = Vec::new();
+        client.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+
+        shadow.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn only_events_of_recorded_sessions_are_kept_until_the_recording_starts() {
+        let (_root, root_path) = temp_root();
+        let (recordings, rx) = recording_message_channel();
+        let (sessions, _) = crate::session::session_manager_channel(&recordings);
+        let mut manager = RecordingManagerTask::new(rx, Arc::new(LocalStorage::new(root_path)), sessions, None);
+
+        let recorded = Uuid::new_v4();
+        let not_recorded = Uuid::new_v4();
+
+        for (id, recording_policy) in [(recorded, true), (not_recorded, false)] {
+            let record = EventRecord::now(SessionEvent::DestinationSelected {
+                destination: "tcp://localhost:3389".to_owned(),
+                address: SocketAddr::from(([127, 0, 0, 1], 3389)),
+            });
+
+            manager
+                .handle_event(EventMessage {
+                    id,
+                    recording_policy,
+                    record,
+                })
+                .await;
+        }
+
+        assert_eq!(manager.pending_events.take(recorded).len(), 1);
+        assert!(manager.pending_events.take(not_recorded).is_empty());
+    }
+
+    #[tokio::test]
+    async fn open_recording_file_discards_bytes_past_offset() {
+        let (_root, root_path) = temp_root();
+        let path = root_path.join("recording-0.webm");
+
+        fs::write(&path, b"persisted, then lost").await.unwrap();
+
+        let mut file = open_recording_file(&path, 9).await.unwrap();
+        file.write_all(b" and resumed").await.unwrap();
+        file.flush().await.unwrap();
+
+        assert_eq!(fs::read(&path).await.unwrap(), b"persisted and resumed");
+    }
+}
@@ -0,0 +1,148 @@
+use std::ops::Range;
+use std::time::UNIX_EPOCH;
+
+use anyhow::Context as _;
+use async_trait::async_trait;
+use camino::{Utf8Path, Utf8PathBuf};
+use tokio::fs;
+use tokio::io::{AsyncReadExt as _, AsyncSeekExt as _};
+use uuid::Uuid;
+
+use super::{RecordingFileInfo, RecordingFileReader, RecordingStorage, MANIFEST_FILE_NAME};
+use crate::recording::JrecManifest;
+
+/// Keeps the recordings in the recordings folder.
+///
+/// The staging folder of a recording is also its final location.
+pub struct LocalStorage {
+    root: Utf8PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(root: Utf8PathBuf) -> Self {
+        Self { root }
+    }
+}
+
+#[async_trait]
+impl RecordingStorage for LocalStorage {
+    fn staging_path(&self, id: Uuid) -> Utf8PathBuf {
+        self.root.join(id.to_string())
+    }
+
+    async fn read_manifest(&self, id: Uuid) -> anyhow::Result<Option<JrecManifest>> {
+        let manifest_path = self.staging_path(id).join(MANIFEST_FILE_NAME);
+
+        let json = match fs::read(&manifest_path).await {
+            Ok(json) => json,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(anyhow::Error::new(e).context(format!("failed to read {manifest_path}"))),
+        };
+
+        let manifest = serde_json::from_slice(&json).context("invalid manifest")?;
+
+        Ok(Some(manifest))
+    }
+
+    async fn write_manifest(&self, id: Uuid, manifest: &JrecManifest) -> anyhow::Result<()> {
+        let manifest_path = self.staging_path(id).join(MANIFEST_FILE_NAME);
+        let json = serde_json::to_string_pretty(manifest)?;
+
+        fs::write(&manifest_path, json)
+            .await
+            .with_context(|| format!("failed to write {manifest_path}"))?;
+
+        Ok(())
+    }
+
+    async fn store_file(&self, _: Uuid, _: &str) -> anyhow::Result<()> {
+        // The file is already at its final location.
+        Ok(())
+    }
+
+    async fn list_staged_files(&self) -> anyhow::Result<Vec<(Uuid, String)>> {
+        // All the files are at their final location.
+        Ok(Vec::new())
+    }
+
+    async fn list_recordings(&self) -> anyhow::Result<Vec<Uuid>> {
+        if !self.root.exists() {
+            // If the recording directory does not exist, it means that there is no recording yet
+            return Ok(Vec::new());
+        }
+
+        let mut read_dir = fs::read_dir(&self.root).await.context("couldn’t read directory")?;
+
+        let mut list = Vec::new();
+
+        while let Some(entry) = read_dir.next_entry().await.context("couldn’t read directory entry")? {
+            let Ok(file_type) = entry.file_type().await else {
+                continue;
+            };
+
+            if !file_type.is_dir() {
+                continue;
+            }
+
+            if let Some(uuid) = entry.file_name().to_str().and_then(|name| Uuid::parse_str(name).ok()) {
+                list.push(uuid);
+            }
+        }
+
+        Ok(list)
+    }
+
+    async fn file_info(&self, id: Uuid, file_name: &str) -> anyhow::Result<Option<RecordingFileInfo>> {
+        local_file_info(&self.staging_path(id).join(file_name)).await
+    }
+
+    async fn open_file(&self, id: Uuid, file_name: &str, range: Range<u64>) -> anyhow::Result<RecordingFileReader> {
+        open_local_file(&self.staging_path(id).join(file_name), range).await
+    }
+
+    async fn delete_recording(&self, id: Uuid) -> anyhow::Result<()> {
+        let recording_path = self.staging_path(id);
+
+        fs::remove_dir_all(&recording_path)
+            .await
+            .with_context(|| format!("failed to delete {recording_path}"))?;
+
+        Ok(())
+    }
+}
+
+pub(super) async fn local_file_info(path: &Utf8Path) -> anyhow::Result<Option<RecordingFileInfo>> {
+    let metadata = match fs::metadata(path).await {
+        Ok(metadata) if metadata.is_file() => metadata,
+        Ok(_) => return Ok(None),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(anyhow::Error::new(e).context(format!("failed to read metadata of {path}"))),
+    };
+
+    let size = metadata.len();
+    let last_modified = metadata.modified().ok();
+
+    // Same scheme as most web servers: the file size and modification time are enough to detect changes.
+    let modified_nanos = last_modified
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |duration| duration.as_nanos());
+    let etag = format!("\"{size:x}-{modified_nanos:x}\"");
+
+    Ok(Some(RecordingFileInfo {
+        size,
+        last_modified,
+        etag,
+    }))
+}
+
+pub(super) async fn open_local_file(path: &Utf8Path, range: Range<u64>) -> anyhow::Result<RecordingFileReader> {
+    let mut file = fs::File::open(path)
+        .await
+        .with_context(|| format!("failed to open {path}"))?;
+
+    file.seek(std::io::SeekFrom::Start(range.start))
+        .await
+        .context("failed to seek")?;
+
+    Ok(Box::pin(file.take(range.end.saturating_sub(range.start))))
+}
@@ -0,0 +1,77 @@
+//! Backends where recordings are persisted.
+//!
+//! Files of on-going recordings are always written to a local staging folder first, so that they can be shadowed
+//! live. Once a recording file is complete, it is handed over to the storage backend.
+
+mod local;
+mod s3;
+
+use std::ops::Range;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use anyhow::Context as _;
+use async_trait::async_trait;
+use camino::{Utf8Path, Utf8PathBuf};
+use tokio::io::AsyncRead;
+use uuid::Uuid;
+
+use crate::config::dto::RecordingStorageConf;
+use crate::recording::JrecManifest;
+
+pub use self::local::LocalStorage;
+pub use self::s3::S3Storage;
+
+pub const MANIFEST_FILE_NAME: &str = "recording.json";
+
+pub type DynRecordingStorage = Arc<dyn RecordingStorage>;
+
+#[async_trait]
+pub trait RecordingStorage: Send + Sync {
+    /// Returns the local folder where the files of an on-going recording are written.
+    fn staging_path(&self, id: Uuid) -> Utf8PathBuf;
+
+    /// Reads the manifest of a recording, returning `None` if there is no such recording.
+    async fn read_manifest(&self, id: Uuid) -> anyhow::Result<Option<JrecManifest>>;
+
+    async fn write_manifest(&self, id: Uuid, manifest: &JrecManifest) -> anyhow::Result<()>;
+
+    /// Persists a complete recording file from the staging folder.
+    async fn store_file(&self, id: Uuid, file_name: &str) -> anyhow::Result<()>;
+
+    /// Lists the files of the staging folder which are not persisted yet, as (recording ID, file name) pairs.
+    async fn list_staged_files(&self) -> anyhow::Result<Vec<(Uuid, String)>>;
+
+    /// Lists the IDs of all the stored recordings.
+    async fn list_recordings(&self) -> anyhow::Result<Vec<Uuid>>;
+
+    /// Retrieves the metadata of a recording file, returning `None` if there is no such file.
+    async fn file_info(&self, id: Uuid, file_name: &str) -> anyhow::Result<Option<RecordingFileInfo>>;
+
+    /// Opens a recording file for reading the given range of bytes.
+    async fn open_file(&self, id: Uuid, file_name: &str, range: Range<u64>) -> anyhow::Result<RecordingFileReader>;
+
+    /// Deletes a recording, including its manifest.
+    async fn delete_recording(&self, id: Uuid) -> anyhow::Result<()>;
+}
+
+pub type RecordingFileReader = Pin<Box<dyn AsyncRead + Send>>;
+
+#[derive(Debug, Clone)]
+pub struct RecordingFileInfo {
+    pub size: u64,
+    pub last_modified: Option<SystemTime>,
+    /// Entity tag (quoted), changing whenever the content of the file changes
+    pub etag: String,
+}
+
+pub fn from_conf(conf: &RecordingStorageConf, recording_path: &Utf8Path) -> anyhow::Result<DynRecordingStorage> {
+    match conf {
+        RecordingStorageConf::Local => Ok(Arc::new(LocalStorage::new(recording_path.to_owned()))),
+        RecordingStorageConf::S3(s3_conf) => {
+            let storage = S3Storage::new(s3_conf, recording_path.to_owned()).context("S3 storage")?;
+            Ok(Arc::new(storage))
+        }
+    }
+}
@@ -0,0 +1,493 @@
+use std::ops::Range;
+use std::time::SystemTime;
+
+use anyhow::Context as _;
+use async_trait::async_trait;
+use aws_sdk_s3::config::{BehaviorVersion, Credentials, Region};
+use aws_sdk_s3::operation::get_object::{GetObjectError, GetObjectOutput};
+use aws_sdk_s3::operation::head_object::HeadObjectError;
+use aws_sdk_s3::primitives::ByteStream;
+use aws_sdk_s3::types::{CompletedMultipartUpload, CompletedPart, Delete, ObjectIdentifier};
+use camino::{Utf8Path, Utf8PathBuf};
+use tokio::fs;
+use tokio::io::AsyncReadExt as _;
+use uuid::Uuid;
+
+use super::local::{local_file_info, open_local_file};
+use super::{RecordingFileInfo, RecordingFileReader, RecordingStorage, MANIFEST_FILE_NAME};
+use crate::config::dto::S3StorageConf;
+use crate::recording::JrecManifest;
+
+const DEFAULT_PART_SIZE_MIB: u64 = 8;
+const MIN_PART_SIZE_MIB: u64 = 5;
+
+/// Maximum number of keys accepted by a single DeleteObjects request.
+const DELETE_OBJECTS_BATCH_SIZE: usize = 1000;
+
+/// Uploads the recordings to an S3-compatible object storage.
+///
+/// Objects are stored under `<prefix><session ID>/<file name>`, mirroring the layout of the recordings folder.
+pub struct S3Storage {
+    client: aws_sdk_s3::Client,
+    bucket: String,
+    key_prefix: String,
+    part_size: u64,
+    staging_root: Utf8PathBuf,
+}
+
+impl S3Storage {
+    pub fn new(conf: &S3StorageConf, staging_root: Utf8PathBuf) -> anyhow::Result<Self> {
+        let part_size_mib = conf.part_size.unwrap_or(DEFAULT_PART_SIZE_MIB);
+
+        anyhow::ensure!(
+            part_size_mib >= MIN_PART_SIZE_MIB,
+            "part size must be at least {MIN_PART_SIZE_MIB} MiB"
+        );
+
+        let credentials = Credentials::new(
+            conf.access_key_id.clone(),
+            conf.secret_access_key.get().to_owned(),
+            None,
+            None,
+            "devolutions-gateway",
+        );
+
+        let mut config = aws_sdk_s3::config::Builder::new()
+            .behavior_version(BehaviorVersion::latest())
+            .region(Region::new(conf.region.clone()))
+            .credentials_provider(credentials)
+            .force_path_style(conf.force_path_style.unwrap_or(false));
+
+        if let Some(endpoint) = &conf.endpoint {
+            config = config.endpoint_url(endpoint);
+        }
+
+        Ok(Self {
+            client: aws_sdk_s3::Client::from_conf(config.build()),
+            bucket: conf.bucket.clone(),
+            key_prefix: conf.key_prefix.clone().unwrap_or_default(),
+            part_size: part_size_mib * 1024 * 1024,
+            staging_root,
+        })
+    }
+
+    fn recording_prefix(&self, id: Uuid) -> String {
+        format!("{}{id}/", self.key_prefix)
+    }
+
+    fn object_key(&self, id: Uuid, file_name: &str) -> String {
+        format!("{}{file_name}", self.recording_prefix(id))
+    }
+
+    /// Fetches an object, returning `None` if there is no such key.
+    async fn get_object(&self, key: &str) -> anyhow::Result<Option<GetObjectOutput>> {
+        match self.client.get_object().bucket(&self.bucket).key(key).send().await {
+            Ok(output) => Ok(Some(output)),
+            Err(e) => match e.into_service_error() {
+                GetObjectError::NoSuchKey(_) => Ok(None),
+                e => Err(anyhow::Error::new(e).context(format!("GetObject {key}"))),
+            },
+        }
+    }
+
+    async fn multipart_upload(&self, key: &str, file: &mut fs::File) -> anyhow::Result<()> {
+        let upload = self
+            .client
+            .create_multipart_upload()
+            .bucket(&self.bucket)
+            .key(key)
+            .send()
+            .await
+            .with_context(|| format!("CreateMultipartUpload {key}"))?;
+
+        let upload_id = upload.upload_id().context("missing upload ID")?;
+
+        let parts = match self.upload_parts(key, upload_id, file).await {
+            Ok(parts) => parts,
+            Err(e) => {
+                // Uploaded parts are billed until the upload is either completed or aborted.
+                if let Err(abort_error) = self
+                    .client
+                    .abort_multipart_upload()
+                    .bucket(&self.bucket)
+                    .key(key)
+                    .upload_id(upload_id)
+                    .send()
+                    .await
+                {
+                    warn!(error = format!("{abort_error:#}"), %key, "Failed to abort multipart upload");
+                }
+
+                return Err(e);
+            }
+        };
+
+        self.client
+            .complete_multipart_upload()
+            .bucket(&self.bucket)
+            .key(key)
+            .upload_id(upload_id)
+            .multipart_upload(CompletedMultipartUpload::builder().set_parts(Some(parts)).build())
+            .send()
+            .await
+            .with_context(|| format!("CompleteMultipartUpload {key}"))?;
+
+        Ok(())
+    }
+
+    async fn upload_parts(
+        &self,
+        key: &str,
+        upload_id: &str,
+        file: &mut fs::File,
+    ) -> anyhow::Result<Vec<CompletedPart>> {
+        let mut parts = Vec::new();
+        let mut part_number = 1;
+
+        loop {
+            let mut buf = Vec::new();
+
+            (&mut *file)
+                .take(self.part_size)
+                .read_to_end(&mut buf)
+                .await
+                .context("failed to read recording file")?;
+
+            if buf.is_empty() {
+                break;
+            }
+
+            trace!(%key, part_number, size = buf.len(), "Upload part");
+
+            let output = self
+                .client
+                .upload_part()
+                .bucket(&self.bucket)
+                .key(key)
+                .upload_id(upload_id)
+                .part_number(part_number)
+                .body(ByteStream::from(buf))
+                .send()
+                .await
+                .with_context(|| format!("UploadPart {part_number} for {key}"))?;
+
+            parts.push(
+                CompletedPart::builder()
+                    .set_e_tag(output.e_tag().map(str::to_owned))
+                    .part_number(part_number)
+                    .build(),
+            );
+
+            part_number += 1;
+        }
+
+        Ok(parts)
+    }
+
+    async fn list_keys(&self, prefix: &str) -> anyhow::Result<Vec<String>> {
+        let mut keys = Vec::new();
+        let mut continuation_token = None;
+
+        loop {
+            let output = self
+                .client
+                .list_objects_v2()
+                .bucket(&self.bucket)
+                .prefix(prefix)
+                .set_continuation_token(continuation_token.take())
+                .send()
+                .await
+                .with_context(|| format!("ListObjectsV2 {prefix}"))?;
+
+            keys.extend(
+                output
+                    .contents()
+                    .iter()
+                    .filter_map(|object| object.key())
+                    .map(str::to_owned),
+            );
+
+            match output.next_continuation_token() {
+                Some(token) => continuation_token = Some(token.to_owned()),
+                None => break,
+            }
+        }
+
+        Ok(keys)
+    }
+}
+
+#[async_trait]
+impl RecordingStorage for S3Storage {
+    fn staging_path(&self, id: Uuid) -> Utf8PathBuf {
+        self.staging_root.join(id.to_string())
+    }
+
+    async fn read_manifest(&self, id: Uuid) -> anyhow::Result<Option<JrecManifest>> {
+        let key = self.object_key(id, MANIFEST_FILE_NAME);
+
+        let Some(output) = self.get_object(&key).await? else {
+            return Ok(None);
+        };
+
+        let json = output
+            .body
+            .collect()
+            .await
+            .with_context(|| format!("failed to download {key}"))?
+            .into_bytes();
+
+        let manifest = serde_json::from_slice(&json).context("invalid manifest")?;
+
+        Ok(Some(manifest))
+    }
+
+    async fn write_manifest(&self, id: Uuid, manifest: &JrecManifest) -> anyhow::Result<()> {
+        let key = self.object_key(id, MANIFEST_FILE_NAME);
+        let json = serde_json::to_vec_pretty(manifest)?;
+
+        self.client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(&key)
+            .content_type("application/json")
+            .body(ByteStream::from(json))
+            .send()
+            .await
+            .with_context(|| format!("PutObject {key}"))?;
+
+        Ok(())
+    }
+
+    async fn store_file(&self, id: Uuid, file_name: &str) -> anyhow::Result<()> {
+        let path = self.staging_path(id).join(file_name);
+        let key = self.object_key(id, file_name);
+
+        let mut file = fs::File::open(&path)
+            .await
+            .with_context(|| format!("failed to open {path}"))?;
+
+        let size = file.metadata().await.context("failed to read file metadata")?.len();
+
+        debug!(%path, %key, size, "Upload recording file");
+
+        if size <= self.part_size {
+            let body = ByteStream::from_path(&path)
+                .await
+                .with_context(|| format!("failed to read {path}"))?;
+
+            self.client
+                .put_object()
+                .bucket(&self.bucket)
+                .key(&key)
+                .body(body)
+                .send()
+                .await
+                .with_context(|| format!("PutObject {key}"))?;
+        } else {
+            self.multipart_upload(&key, &mut file).await?;
+        }
+
+        drop(file);
+
+        // Live viewers already holding the file open are not affected by the removal.
+        if let Err(error) = fs::remove_file(&path).await {
+            warn!(%error, %path, "Failed to remove uploaded recording file from the staging folder");
+        }
+
+        Ok(())
+    }
+
+    async fn list_staged_files(&self) -> anyhow::Result<Vec<(Uuid, String)>> {
+        let mut read_dir = match fs::read_dir(&self.staging_root).await {
+            Ok(read_dir) => read_dir,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(anyhow::Error::new(e).context(format!("failed to read {}", self.staging_root))),
+        };
+
+        let mut files = Vec::new();
+
+        while let Some(entry) = read_dir.next_entry().await.context("couldn’t read directory entry")? {
+            let Some(id) = entry.file_name().to_str().and_then(|name| Uuid::parse_str(name).ok()) else {
+                continue;
+            };
+
+            if !entry.file_type().await.is_ok_and(|file_type| file_type.is_dir()) {
+                continue;
+            }
+
+            let staging_path = self.staging_path(id);
+            let mut recording_dir = fs::read_dir(&staging_path)
+                .await
+                .with_context(|| format!("failed to read {staging_path}"))?;
+
+            while let Some(entry) = recording_dir
+                .next_entry()
+                .await
+                .context("couldn’t read directory entry")?
+            {
+                if !entry.file_type().await.is_ok_and(|file_type| file_type.is_file()) {
+                    continue;
+                }
+
+                if let Some(file_name) = entry.file_name().to_str() {
+                    files.push((id, file_name.to_owned()));
+                }
+            }
+        }
+
+        Ok(files)
+    }
+
+    async fn list_recordings(&self) -> anyhow::Result<Vec<Uuid>> {
+        let mut list = Vec::new();
+        let mut continuation_token = None;
+
+        loop {
+            let output = self
+                .client
+                .list_objects_v2()
+                .bucket(&self.bucket)
+                .prefix(&self.key_prefix)
+                .delimiter("/")
+                .set_continuation_token(continuation_token.take())
+                .send()
+                .await
+                .context("ListObjectsV2")?;
+
+            let ids = output
+                .common_prefixes()
+                .iter()
+                .filter_map(|common_prefix| common_prefix.prefix())
+                .filter_map(|prefix| prefix.strip_prefix(self.key_prefix.as_str()))
+                .filter_map(|name| Uuid::parse_str(name.trim_end_matches('/')).ok());
+
+            list.extend(ids);
+
+            match output.next_continuation_token() {
+                Some(token) => continuation_token = Some(token.to_owned()),
+                None => break,
+            }
+        }
+
+        Ok(list)
+    }
+
+    async fn file_info(&self, id: Uuid, file_name: &str) -> anyhow::Result<Option<RecordingFileInfo>> {
+        // Files of on-going recordings are not uploaded yet.
+        let staging_file_path = self.staging_path(id).join(file_name);
+
+        if is_staged(&staging_file_path).await {
+            return local_file_info(&staging_file_path).await;
+        }
+
+        let key = self.object_key(id, file_name);
+
+        let output = match self.client.head_object().bucket(&self.bucket).key(&key).send().await {
+            Ok(output) => output,
+            Err(e) => match e.into_service_error() {
+                HeadObjectError::NotFound(_) => return Ok(None),
+                e => return Err(anyhow::Error::new(e).context(format!("HeadObject {key}"))),
+            },
+        };
+
+        let size = output
+            .content_length()
+            .and_then(|length| u64::try_from(length).ok())
+            .context("missing content length")?;
+
+        let last_modified = output
+            .last_modified()
+            .and_then(|date_time| SystemTime::try_from(*date_time).ok());
+
+        let etag = output.e_tag().context("missing ETag")?.to_owned();
+
+        Ok(Some(RecordingFileInfo {
+            size,
+            last_modified,
+            etag,
+        }))
+    }
+
+    async fn open_file(&self, id: Uuid, file_name: &str, range: Range<u64>) -> anyhow::Result<RecordingFileReader> {
+        let staging_file_path = self.staging_path(id).join(file_name);
+
+        if is_staged(&staging_file_path).await {
+            return open_local_file(&staging_file_path, range).await;
+        }
+
+        if range.is_empty() {
+            return Ok(Box::pin(tokio::io::empty()));
+        }
+
+        let key = self.object_key(id, file_name);
+
+        let output = self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(&key)
+            // The end of an HTTP byte range is inclusive.
+            .range(format!("bytes={}-{}", range.start, range.end - 1))
+            .send()
+            .await
+            .with_context(|| format!("GetObject {key}"))?;
+
+        Ok(Box::pin(output.body.into_async_read()))
+    }
+
+    async fn delete_recording(&self, id: Uuid) -> anyhow::Result<()> {
+        let keys = self.list_keys(&self.recording_prefix(id)).await?;
+
+        for batch in keys.chunks(DELETE_OBJECTS_BATCH_SIZE) {
+            let objects = batch
+                .iter()
+                .map(|key| ObjectIdentifier::builder().key(key).build())
+                .collect::<Result<Vec<_>, _>>()
+                .context("build object identifier")?;
+
+            let delete = Delete::builder()
+                .set_objects(Some(objects))
+                .quiet(true)
+                .build()
+                .context("build Delete")?;
+
+            let output = self
+                .client
+                .delete_objects()
+                .bucket(&self.bucket)
+                .delete(delete)
+                .send()
+                .await
+                .context("DeleteObjects")?;
+
+            // In quiet mode, only the keys that could not be deleted are reported, and the request still succeeds.
+            if let Some(error) = output.errors().first() {
+                anyhow::bail!(
+                    "DeleteObjects failed for {} key(s), including {}: {} ({})",
+                    output.errors().len(),
+                    error.key().unwrap_or("<unknown>"),
+                    error.message().unwrap_or("no message"),
+                    error.code().unwrap_or("no code"),
+                );
+            }
+        }
+
+        let staging_path = self.staging_path(id);
+
+        match fs::remove_dir_all(&staging_path).await {
+            Ok(()) => {}
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => {}
+            Err(error) => {
+                return Err(anyhow::Error::new(error).context(format!("failed to delete {staging_path}")));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns true when the file is in the staging folder, i.e.: not uploaded yet.
+async fn is_staged(path: &Utf8Path) -> bool {
+    fs::metadata(path).await.is_ok_and(|metadata| metadata.is_file())
+}
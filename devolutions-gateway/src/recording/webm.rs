@@ -0,0 +1,385 @@
+//! Minimal WebM (EBML) parsing, used to find where a live viewer can start playing a recording.
+
+use std::io::{self, BufReader, Read, Seek};
+
+mod ebml_id {
+    pub(super) const EBML: u32 = 0x1A45_DFA3;
+    pub(super) const SEGMENT: u32 = 0x1853_8067;
+    pub(super) const CLUSTER: u32 = 0x1F43_B675;
+
+    /// IDs of the elements found at the top level of a segment.
+    ///
+    /// A cluster of unknown size ends where one of these is found.
+    pub(super) const SEGMENT_CHILDREN: [u32; 8] = [
+        0x114D_9B74, // SeekHead
+        0x1549_A966, // Info
+        0x1654_AE6B, // Tracks
+        0x1C53_BB6B, // Cues
+        CLUSTER,
+        0x1043_A770, // Chapters
+        0x1254_C367, // Tags
+        0x1941_A469, // Attachments
+    ];
+}
+
+/// Header of an EBML element.
+#[derive(Debug, Clone, Copy)]
+struct ElementHeader {
+    id: u32,
+    /// Size of the element data, `None` when unknown (e.g.: segments and clusters written by a live encoder).
+    size: Option<u64>,
+    /// Offset of the element data in the file.
+    data_offset: u64,
+}
+
+/// Returns the offsets of the first and last complete WebM clusters.
+///
+/// Only the element headers are read: the EBML header and the segment children are walked using their sizes,
+/// so bytes inside frames are never mistaken for a cluster ID. For clusters of unknown size, as written by
+/// browsers, the blocks are walked until the next segment child is found.
+///
+/// A cluster is complete once all its data is in the file. The cluster being written is not, and when no
+/// cluster is complete yet, the first cluster is returned as the last one.
+pub(super) fn find_clusters<R>(mut reader: BufReader<R>) -> io::Result<Option<(u64, u64)>>
+where
+    R: Read + Seek,
+{
+    let reader = &mut reader;
+
+    let file_len = reader.seek(io::SeekFrom::End(0))?;
+    reader.seek(io::SeekFrom::Start(0))?;
+
+    let mut position = 0;
+
+    let Some(ebml_header) = read_element_header(reader, &mut position)? else {
+        return Ok(None);
+    };
+
+    let (ebml_id::EBML, Some(ebml_header_size)) = (ebml_header.id, ebml_header.size) else {
+        return Ok(None);
+    };
+
+    skip_to(reader, &mut position, ebml_header.data_offset + ebml_header_size)?;
+
+    let Some(segment) = read_element_header(reader, &mut position)? else {
+        return Ok(None);
+    };
+
+    if segment.id != ebml_id::SEGMENT {
+        return Ok(None);
+    }
+
+    let segment_end = segment.size.map_or(file_len, |size| segment.data_offset + size);
+
+    let mut first = None;
+    let mut last_complete = None;
+
+    'segment: while position < segment_end {
+        let element_offset = position;
+
+        let Some(element) = read_element_header(reader, &mut position)? else {
+            break;
+        };
+
+        if element.id != ebml_id::CLUSTER {
+            let Some(size) = element.size else {
+                // Elements of unknown size other than clusters can't be skipped.
+                break;
+            };
+
+            skip_to(reader, &mut position, element.data_offset + size)?;
+
+            continue;
+        }
+
+        first.get_or_insert(element_offset);
+
+        if let Some(size) = element.size {
+            let cluster_end = element.data_offset + size;
+
+            if cluster_end > file_len {
+                break;
+            }
+
+            last_complete = Some(element_offset);
+            skip_to(reader, &mut position, cluster_end)?;
+
+            continue;
+        }
+
+        // Cluster of unknown size: walk its children until the next segment child.
+        loop {
+            let child_offset = position;
+
+            let Some(child) = read_element_header(reader, &mut position)? else {
+                break 'segment;
+            };
+
+            if ebml_id::SEGMENT_CHILDREN.contains(&child.id) {
+                last_complete = Some(element_offset);
+                skip_to(reader, &mut position, child_offset)?;
+                continue 'segment;
+            }
+
+            let Some(size) = child.size else {
+                break 'segment;
+            };
+
+            let child_end = child.data_offset + size;
+
+            if child_end > file_len {
+                break 'segment;
+            }
+
+            skip_to(reader, &mut position, child_end)?;
+        }
+    }
+
+    Ok(first.map(|first| (first, last_complete.unwrap_or(first))))
+}
+
+/// Reads the header of the EBML element at `position`.
+///
+/// Returns `None` if the header is truncated or invalid.
+fn read_element_header<R>(reader: &mut R, position: &mut u64) -> io::Result<Option<ElementHeader>>
+where
+    R: Read,
+{
+    // The IDs used by WebM are at most 4 bytes long, and sizes at most 8 bytes long.
+    let Some((id, _)) = read_vint(reader, position, 4)? else {
+        return Ok(None);
+    };
+
+    let Some((size, size_len)) = read_vint(reader, position, 8)? else {
+        return Ok(None);
+    };
+
+    // The marker bit is kept in IDs, but not in sizes.
+    let size_marker = 1u64 << (7 * size_len);
+    let size = size ^ size_marker;
+    let size = if size == size_marker - 1 { None } else { Some(size) };
+
+    Ok(Some(ElementHeader {
+        id: u32::try_from(id).expect("at most 4 bytes"),
+        size,
+        data_offset: *position,
+    }))
+}
+
+/// Reads an EBML variable-size integer, returning its raw value (marker bit included) and its length.
+fn read_vint<R>(reader: &mut R, position: &mut u64, max_len: u32) -> io::Result<Option<(u64, u32)>>
+where
+    R: Read,
+{
+    let mut first = [0];
+
+    if !read_exact_or_eof(reader, &mut first)? {
+        return Ok(None);
+    }
+
+    let len = first[0].leading_zeros() + 1;
+
+    if len > max_len {
+        return Ok(None);
+    }
+
+    let mut bytes = [0; 8];
+    bytes[0] = first[0];
+
+    if !read_exact_or_eof(reader, &mut bytes[1..len as usize])? {
+        return Ok(None);
+    }
+
+    *position += u64::from(len);
+
+    let value = bytes[..len as usize]
+        .iter()
+        .fold(0u64, |value, byte| (value << 8) | u64::from(*byte));
+
+    Ok(Some((value, len)))
+}
+
+fn read_exact_or_eof<R>(reader: &mut R, buf: &mut [u8]) -> io::Result<bool>
+where
+    R: Read,
+{
+    match reader.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Moves to `target`, keeping the buffered bytes when possible.
+fn skip_to<R>(reader: &mut BufReader<R>, position: &mut u64, target: u64) -> io::Result<()>
+where
+    R: Read + Seek,
+{
+    let offset =
+        i64::try_from(target).map_err(io::Error::other)? - i64::try_from(*position).map_err(io::Error::other)?;
+    reader.seek_relative(offset)?;
+    *position = target;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UNKNOWN_SIZE: Option<usize> = None;
+
+    fn element(id: u32, size: Option<usize>, data: &[u8]) -> Vec<u8> {
+        let mut out: Vec<u8> = id.to_be_bytes().iter().copied().skip_while(|byte| *byte == 0).collect();
+
+        match size {
+            Some(size) => {
+                out.push(0x01);
+                out.extend_from_slice(&(size as u64).to_be_bytes()[1..]);
+            }
+            None => out.extend_from_slice(&[0x01, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]),
+        }
+
+        out.extend_from_slice(data);
+        out
+    }
+
+    fn known(id: u32, data: &[u8]) -> Vec<u8> {
+        element(id, Some(data.len()), data)
+    }
+
+    const SIMPLE_BLOCK: u32 = 0xA3;
+    const TIMESTAMP: u32 = 0xE7;
+    const INFO: u32 = 0x1549_A966;
+    const TRACKS: u32 = 0x1654_AE6B;
+    const CUES: u32 = 0x1C53_BB6B;
+
+    /// EBML header and segment start, followed by the segment info and tracks.
+    fn header() -> Vec<u8> {
+        let mut out = known(ebml_id::EBML, b"webm");
+        out.extend(element(ebml_id::SEGMENT, UNKNOWN_SIZE, &[]));
+        out.extend(known(INFO, b"info"));
+        out.extend(known(TRACKS, b"tracks"));
+        out
+    }
+
+    fn find(bytes: &[u8]) -> Option<(u64, u64)> {
+        find_clusters(BufReader::new(io::Cursor::new(bytes))).unwrap()
+    }
+
+    /// Reader returning at most a few bytes per read.
+    struct ShortReads(io::Cursor<Vec<u8>>);
+
+    impl Read for ShortReads {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = buf.len().min(3);
+            self.0.read(&mut buf[..len])
+        }
+    }
+
+    impl Seek for ShortReads {
+        fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+            self.0.seek(pos)
+        }
+    }
+
+    #[test]
+    fn clusters_of_known_size() {
+        let mut file = header();
+        let first = file.len() as u64;
+        file.extend(known(
+            ebml_id::CLUSTER,
+            &[known(TIMESTAMP, &[0]), known(SIMPLE_BLOCK, b"frame")].concat(),
+        ));
+        let last = file.len() as u64;
+        file.extend(known(
+            ebml_id::CLUSTER,
+            &[known(TIMESTAMP, &[1]), known(SIMPLE_BLOCK, b"frame")].concat(),
+        ));
+        file.extend(known(CUES, b"cues"));
+
+        assert_eq!(find(&file), Some((first, last)));
+    }
+
+    #[test]
+    fn clusters_of_unknown_size() {
+        let mut file = header();
+        let first = file.len() as u64;
+        file.extend(element(ebml_id::CLUSTER, UNKNOWN_SIZE, &known(TIMESTAMP, &[0])));
+        file.extend(known(SIMPLE_BLOCK, b"frame"));
+        let last = file.len() as u64;
+        file.extend(element(ebml_id::CLUSTER, UNKNOWN_SIZE, &known(TIMESTAMP, &[1])));
+        file.extend(known(SIMPLE_BLOCK, b"frame"));
+        file.extend(known(CUES, b"cues"));
+
+        assert_eq!(find(&file), Some((first, last)));
+    }
+
+    #[test]
+    fn cluster_being_written_is_skipped() {
+        let mut file = header();
+        let first = file.len() as u64;
+        file.extend(element(ebml_id::CLUSTER, UNKNOWN_SIZE, &known(TIMESTAMP, &[0])));
+        file.extend(known(SIMPLE_BLOCK, b"frame"));
+        file.extend(element(ebml_id::CLUSTER, UNKNOWN_SIZE, &known(TIMESTAMP, &[1])));
+        let mut block = known(SIMPLE_BLOCK, b"frame being written");
+        block.truncate(block.len() - 4);
+        file.extend(block);
+
+        assert_eq!(find(&file), Some((first, first)));
+    }
+
+    #[test]
+    fn only_cluster_being_written() {
+        let mut file = header();
+        let first = file.len() as u64;
+        file.extend(known(ebml_id::CLUSTER, &known(TIMESTAMP, &[0])));
+        file.truncate(file.len() - 1);
+
+        assert_eq!(find(&file), Some((first, first)));
+    }
+
+    #[test]
+    fn cluster_id_in_payload_is_ignored() {
+        let mut payload = b"frame".to_vec();
+        payload.extend_from_slice(&ebml_id::CLUSTER.to_be_bytes());
+        payload.extend_from_slice(b"frame");
+
+        let mut file = header();
+        let first = file.len() as u64;
+        file.extend(element(ebml_id::CLUSTER, UNKNOWN_SIZE, &known(TIMESTAMP, &[0])));
+        file.extend(known(SIMPLE_BLOCK, &payload));
+        let last = file.len() as u64;
+        file.extend(element(ebml_id::CLUSTER, UNKNOWN_SIZE, &known(TIMESTAMP, &[1])));
+        file.extend(known(SIMPLE_BLOCK, &payload));
+        file.extend(known(CUES, b"cues"));
+
+        assert_eq!(find(&file), Some((first, last)));
+    }
+
+    #[test]
+    fn id_spanning_two_reads() {
+        let mut file = header();
+        let first = file.len() as u64;
+        file.extend(element(ebml_id::CLUSTER, UNKNOWN_SIZE, &known(TIMESTAMP, &[0])));
+        file.extend(known(SIMPLE_BLOCK, b"frame"));
+        let last = file.len() as u64;
+        file.extend(element(ebml_id::CLUSTER, UNKNOWN_SIZE, &known(TIMESTAMP, &[1])));
+        file.extend(known(SIMPLE_BLOCK, b"frame"));
+        file.extend(known(CUES, b"cues"));
+
+        let reader = BufReader::with_capacity(5, ShortReads(io::Cursor::new(file)));
+
+        assert_eq!(find_clusters(reader).unwrap(), Some((first, last)));
+    }
+
+    #[test]
+    fn no_cluster() {
+        assert_eq!(find(&header()), None);
+    }
+
+    #[test]
+    fn not_webm() {
+        assert_eq!(find(b"not a WebM file"), None);
+        assert_eq!(find(&[]), None);
+    }
+}
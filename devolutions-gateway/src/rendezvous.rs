@@ -0,0 +1,397 @@
+//! JET rendezvous over TCP.
+//!
+//! Two peers reach the Gateway, neither being able to reach the other directly. The acceptor announces itself with
+//! a JET accept request holding an association ID and a candidate ID, and waits until a connector sends a JET
+//! connect request holding the same IDs. A connector may also present its association token in a preconnection PDU,
+//! in which case it is paired with any acceptor waiting for this association.
+//!
+//! Both peers must present an association token using the `rdv` connection mode, each peer with its own token.
+//! Peers are only paired when both tokens are for the same association.
+//!
+//! Once paired, the traffic of both peers is forwarded as a regular session.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context as _;
+use bytes::Bytes;
+use jet_proto::accept::JetAcceptRsp;
+use jet_proto::connect::JetConnectRsp;
+use jet_proto::{JetMessage, StatusCode, JET_MSG_HEADER_SIZE};
+use parking_lot::Mutex;
+use tokio::io::{AsyncRead, AsyncReadExt as _, AsyncWrite, AsyncWriteExt as _};
+use tokio::net::TcpStream;
+use tokio::sync::oneshot;
+use tracing::field;
+use transport::ErasedReadWrite;
+use uuid::Uuid;
+
+use crate::proxy::Proxy;
+use crate::rdp_pcb::validate_association_token;
+use crate::session::{ConnectionModeDetails, SessionInfo};
+use crate::token::{AssociationTokenClaims, ConnectionMode};
+use crate::DgwState;
+
+/// How long an acceptor waits for a connector
+pub const RENDEZVOUS_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Maximum number of acceptors waiting at the same time
+pub const MAX_PENDING_ACCEPTORS: usize = 1024;
+
+/// Maximum number of acceptors waiting at the same time for a given association
+pub const MAX_PENDING_ACCEPTORS_PER_ASSOCIATION: usize = 8;
+
+const JET_MESSAGE_READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Peer connecting to a waiting acceptor
+pub struct Connector {
+    pub stream: ErasedReadWrite,
+    pub addr: SocketAddr,
+    /// Bytes already received from the connector, and to be sent to the acceptor first
+    pub leftover: Bytes,
+    /// Claims of the association token presented by the connector
+    pub claims: AssociationTokenClaims,
+    /// Version of the JET connect request to answer, if any
+    pub jet_version: Option<u32>,
+}
+
+/// Acceptors waiting for a connector, by association ID and candidate ID
+#[derive(Default)]
+pub struct RendezvousState {
+    pending: Mutex<HashMap<(Uuid, Uuid), oneshot::Sender<Connector>>>,
+}
+
+impl RendezvousState {
+    /// Hands the connector over to the acceptor waiting for the association of its token, with this candidate ID.
+    ///
+    /// Without candidate ID, any acceptor waiting for this association is picked.
+    /// The connector is given back when no such acceptor is waiting.
+    pub fn pair(&self, candidate: Option<Uuid>, connector: Connector) -> Result<(), Connector> {
+        let association = connector.claims.jet_aid;
+
+        let sender = {
+            let mut pending = self.pending.lock();
+
+            let key = match candidate {
+                Some(candidate) => Some((association, candidate)),
+                None => pending.keys().find(|(id, _)| *id == association).copied(),
+            };
+
+            key.and_then(|key| pending.remove(&key))
+        };
+
+        match sender {
+            Some(sender) => sender.send(connector),
+            None => Err(connector),
+        }
+    }
+
+    fn wait(self: &Arc<Self>, association: Uuid, candidate: Uuid) -> anyhow::Result<PendingAcceptor> {
+        let (sender, receiver) = oneshot::channel();
+
+        {
+            let mut pending = self.pending.lock();
+            anyhow::ensure!(
+                !pending.contains_key(&(association, candidate)),
+                "an acceptor is already waiting for this candidate"
+            );
+            anyhow::ensure!(pending.len() < MAX_PENDING_ACCEPTORS, "too many acceptors are waiting");
+            anyhow::ensure!(
+                pending.keys().filter(|(id, _)| *id == association).count() < MAX_PENDING_ACCEPTORS_PER_ASSOCIATION,
+                "too many acceptors are waiting for this association"
+            );
+            pending.insert((association, candidate), sender);
+        }
+
+        Ok(PendingAcceptor {
+            state: Arc::clone(self),
+            key: (association, candidate),
+            receiver,
+            settled: false,
+        })
+    }
+}
+
+struct PendingAcceptor {
+    state: Arc<RendezvousState>,
+    key: (Uuid, Uuid),
+    receiver: oneshot::Receiver<Connector>,
+    /// Whether the entry was already removed from the pending acceptors
+    settled: bool,
+}
+
+impl PendingAcceptor {
+    async fn connector(mut self) -> Option<Connector> {
+        let connector = match tokio::time::timeout(RENDEZVOUS_TIMEOUT, &mut self.receiver).await {
+            Ok(connector) => connector.ok(),
+            Err(_) => {
+                // A connector may have taken the sender just before the timeout.
+                if self.state.pending.lock().remove(&self.key).is_some() {
+                    None
+                } else {
+                    (&mut self.receiver).await.ok()
+                }
+            }
+        };
+
+        self.settled = true;
+
+        connector
+    }
+}
+
+impl Drop for PendingAcceptor {
+    fn drop(&mut self) {
+        if !self.settled {
+            self.state.pending.lock().remove(&self.key);
+        }
+    }
+}
+
+/// Handles a peer starting with a JET message, either an acceptor or a connector.
+#[instrument("jet", skip_all, fields(session_id = field::Empty))]
+pub async fn handle_jet_peer(mut stream: TcpStream, peer_addr: SocketAddr, state: DgwState) -> anyhow::Result<()> {
+    let message = tokio::time::timeout(JET_MESSAGE_READ_TIMEOUT, read_jet_message(&mut stream))
+        .await
+        .context("timed out at JET message reception")??;
+
+    match message {
+        JetMessage::JetAcceptReq(req) => {
+            let span = tracing::Span::current();
+            span.record("session_id", req.association.to_string());
+
+            let claims = validate_peer_token(req.token.as_deref(), req.association, peer_addr, &state)?;
+
+            let acceptor = state.rendezvous.wait(claims.jet_aid, req.candidate)?;
+
+            let conf = state.conf_handle.get_conf();
+
+            let rsp = JetMessage::JetAcceptRsp(JetAcceptRsp {
+                status_code: StatusCode::OK,
+                version: req.version,
+                association: req.association,
+                timeout: u32::try_from(RENDEZVOUS_TIMEOUT.as_secs()).expect("small enough"),
+                instance: conf.hostname.clone(),
+            });
+            write_jet_message(&mut stream, &rsp).await?;
+
+            debug!(candidate = %req.candidate, "Waiting for connector");
+
+            let Some(mut connector) = acceptor.connector().await else {
+                anyhow::bail!("no connector showed up for candidate {}", req.candidate);
+            };
+
+            if let Some(version) = connector.jet_version {
+                let rsp = JetMessage::JetConnectRsp(JetConnectRsp {
+                    status_code: StatusCode::OK,
+                    version,
+                });
+                write_jet_message(&mut connector.stream, &rsp).await?;
+            }
+
+            let claims = &connector.claims;
+
+            let info = SessionInfo::new(claims.jet_aid, claims.jet_ap.clone(), ConnectionModeDetails::Rdv)
+                .with_ttl(claims.jet_ttl)
+                .with_filtering_policy(claims.jet_flt)
+                .with_capture_policy(claims.jet_cap)
+                .with_subject(claims.sub.clone())
+                .with_client_addr(connector.addr);
+
+            if !connector.leftover.is_empty() {
+                stream
+                    .write_all(&connector.leftover)
+                    .await
+                    .context("failed to write leftover bytes")?;
+            }
+
+            info!(connector = %connector.addr, "TCP rendezvous");
+
+            Proxy::builder()
+                .conf(conf)
+                .session_info(info)
+                .address_a(connector.addr)
+                .transport_a(connector.stream)
+                .address_b(peer_addr)
+                .transport_b(stream)
+                .sessions(state.sessions)
+                .subscriber_tx(state.subscriber_tx)
+                .build()
+                .select_dissector_and_forward()
+                .await
+                .context("encountered a failure during rendezvous traffic proxying")
+        }
+        JetMessage::JetConnectReq(req) => {
+            let span = tracing::Span::current();
+            span.record("session_id", req.association.to_string());
+
+            let claims = validate_peer_token(req.token.as_deref(), req.association, peer_addr, &state)?;
+
+            let connector = Connector {
+                stream: Box::new(stream),
+                addr: peer_addr,
+                leftover: Bytes::new(),
+                claims,
+                jet_version: Some(req.version),
+            };
+
+            if let Err(mut connector) = state.rendezvous.pair(Some(req.candidate), connector) {
+                let rsp = JetMessage::JetConnectRsp(JetConnectRsp {
+                    status_code: StatusCode::NOT_FOUND,
+                    version: req.version,
+                });
+                write_jet_message(&mut connector.stream, &rsp).await?;
+
+                anyhow::bail!("no acceptor is waiting for candidate {}", req.candidate);
+            }
+
+            debug!(candidate = %req.candidate, "Paired with acceptor");
+
+            Ok(())
+        }
+        unexpected => anyhow::bail!("unexpected JET message: {unexpected:?}"),
+    }
+}
+
+fn validate_peer_token(
+    token: Option<&str>,
+    association: Uuid,
+    peer_addr: SocketAddr,
+    state: &DgwState,
+) -> anyhow::Result<AssociationTokenClaims> {
+    let token = token.context("association token missing from JET request")?;
+
+    let conf = state.conf_handle.get_conf();
+
+    let claims = validate_association_token(
+        token,
+        peer_addr.ip(),
+        &conf,
+        &state.token_cache,
+        &state.jrl,
+        &state.recordings.active_recordings,
+    )?;
+
+    anyhow::ensure!(
+        matches!(claims.jet_cm, ConnectionMode::Rdv),
+        "association token is not for a rendezvous"
+    );
+    anyhow::ensure!(
+        claims.jet_aid == association,
+        "association token is for another association"
+    );
+    anyhow::ensure!(!claims.jet_rec, "can't meet recording policy");
+
+    Ok(claims)
+}
+
+async fn read_jet_message(stream: &mut (impl AsyncRead + Unpin)) -> anyhow::Result<JetMessage> {
+    let mut buf = vec![0; JET_MSG_HEADER_SIZE as usize];
+    stream.read_exact(&mut buf).await.context("read JET message header")?;
+
+    // The message size, header included, is a big endian u16 following the signature.
+    let msg_size = usize::from(u16::from_be_bytes([buf[4], buf[5]]));
+    anyhow::ensure!(msg_size >= buf.len(), "invalid JET message size: {msg_size}");
+
+    buf.resize(msg_size, 0);
+    stream
+        .read_exact(&mut buf[JET_MSG_HEADER_SIZE as usize..])
+        .await
+        .context("read JET message payload")?;
+
+    JetMessage::read_request(&mut buf.as_slice()).context("invalid JET message")
+}
+
+async fn write_jet_message(stream: &mut (impl AsyncWrite + Unpin), message: &JetMessage) -> anyhow::Result<()> {
+    let mut buf = Vec::new();
+    message.write_to(&mut buf).context("encode JET message")?;
+    stream.write_all(&buf).await.context("write JET message")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn connector(association: Uuid) -> Connector {
+        let (stream, _) = tokio::io::duplex(64);
+
+        let claims = serde_json::from_value(serde_json::json!({
+            "jet_aid": association,
+            "jet_ap": "rdp",
+            "jet_cm": "rdv",
+            "exp": 0,
+        }))
+        .unwrap();
+
+        Connector {
+            stream: Box::new(stream),
+            addr: "127.0.0.1:4000".parse().unwrap(),
+            leftover: Bytes::new(),
+            claims,
+            jet_version: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn pair_by_candidate() {
+        let state = Arc::new(RendezvousState::default());
+        let association = Uuid::new_v4();
+        let candidate = Uuid::new_v4();
+
+        let acceptor = state.wait(association, candidate).unwrap();
+        assert!(state.wait(association, candidate).is_err());
+
+        assert!(state.pair(Some(Uuid::new_v4()), connector(association)).is_err());
+        assert!(state.pair(Some(candidate), connector(Uuid::new_v4())).is_err());
+        assert!(state.pair(Some(candidate), connector(association)).is_ok());
+        assert!(acceptor.connector().await.is_some());
+
+        assert!(state.pair(Some(candidate), connector(association)).is_err());
+    }
+
+    #[tokio::test]
+    async fn pair_by_association() {
+        let state = Arc::new(RendezvousState::default());
+        let association = Uuid::new_v4();
+
+        let acceptor = state.wait(association, Uuid::new_v4()).unwrap();
+
+        assert!(state.pair(None, connector(Uuid::new_v4())).is_err());
+        assert!(state.pair(None, connector(association)).is_ok());
+        assert!(acceptor.connector().await.is_some());
+    }
+
+    #[test]
+    fn dropped_acceptor_is_forgotten() {
+        let state = Arc::new(RendezvousState::default());
+        let association = Uuid::new_v4();
+        let candidate = Uuid::new_v4();
+
+        drop(state.wait(association, candidate).unwrap());
+
+        assert!(state.pair(Some(candidate), connector(association)).is_err());
+        assert!(state.wait(association, candidate).is_ok());
+    }
+
+    #[test]
+    fn pending_acceptors_are_capped() {
+        let state = Arc::new(RendezvousState::default());
+        let association = Uuid::new_v4();
+
+        let mut acceptors: Vec<_> = (0..MAX_PENDING_ACCEPTORS_PER_ASSOCIATION)
+            .map(|_| state.wait(association, Uuid::new_v4()).unwrap())
+            .collect();
+        assert!(state.wait(association, Uuid::new_v4()).is_err());
+
+        while acceptors.len() < MAX_PENDING_ACCEPTORS {
+            acceptors.push(state.wait(Uuid::new_v4(), Uuid::new_v4()).unwrap());
+        }
+        assert!(state.wait(Uuid::new_v4(), Uuid::new_v4()).is_err());
+
+        acceptors.pop();
+        assert!(state.wait(Uuid::new_v4(), Uuid::new_v4()).is_ok());
+    }
+}
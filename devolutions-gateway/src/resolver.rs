@@ -0,0 +1,437 @@
+//! Name resolution of the destinations.
+//!
+//! The system resolver is used unless nameservers are configured, either globally or for the domains under a given
+//! suffix (e.g.: split-horizon DNS). Answers from configured nameservers are cached until their TTL expires.
+//!
+//! When enabled, SRV records (e.g.: `_rdp._tcp.<host>`) are looked up for the destinations specified without a port.
+//! The service name is derived from the default port of the application protocol, and the default port is used
+//! when no SRV record is found.
+
+use std::cmp::Reverse;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::Context as _;
+use hickory_resolver::config::{
+    LookupIpStrategy, NameServerConfig, NameServerConfigGroup, Protocol, ResolverConfig, ResolverOpts,
+};
+use hickory_resolver::error::ResolveErrorKind;
+use hickory_resolver::TokioAsyncResolver;
+use tap::prelude::*;
+
+use crate::config::dto;
+use crate::target_addr::TargetAddr;
+
+const DNS_DEFAULT_PORT: u16 = 53;
+const DEFAULT_CACHE_SIZE: usize = 1024;
+
+/// Resolver for the destination host names
+///
+/// Cloning is cheap, and clones share the same cache.
+#[derive(Clone)]
+pub struct Resolver {
+    conf: dto::DnsConf,
+    inner: Arc<ResolverInner>,
+}
+
+struct ResolverInner {
+    /// Nameservers for the domains not matching any suffix, the system resolver is used when unset
+    default: Option<Nameservers>,
+    /// Nameservers for the domains under a given suffix, longest suffix first
+    domains: Vec<(String, Nameservers)>,
+    /// Nameservers from the system configuration, used for SRV lookups when no default nameserver is configured
+    system: Option<Nameservers>,
+    srv_lookup: bool,
+}
+
+struct Nameservers {
+    addrs: Vec<SocketAddr>,
+    resolver: TokioAsyncResolver,
+}
+
+/// Outcome of a name resolution
+#[derive(Debug, Clone)]
+pub struct Resolution {
+    /// Resolved addresses, in order of preference
+    pub addrs: Vec<SocketAddr>,
+    /// SRV records from which the addresses were resolved, in order of preference
+    pub srv_records: Vec<SrvRecord>,
+    /// Nameservers which were queried, empty when the system resolver was used
+    pub nameservers: Vec<SocketAddr>,
+    /// Remaining time before the answer expires from the cache, unknown when the system resolver was used
+    pub ttl: Option<Duration>,
+}
+
+#[derive(Debug, Clone)]
+pub struct SrvRecord {
+    pub target: String,
+    pub port: u16,
+    pub priority: u16,
+    pub weight: u16,
+}
+
+impl Resolver {
+    pub fn from_dto(value: &dto::DnsConf) -> anyhow::Result<Self> {
+        let cache_size = value.cache_size.unwrap_or(DEFAULT_CACHE_SIZE);
+        let srv_lookup = value.srv_lookup.unwrap_or(false);
+
+        let default = if value.nameservers.is_empty() {
+            None
+        } else {
+            Some(Nameservers::new(&value.nameservers, cache_size)?)
+        };
+
+        let mut domains = value
+            .domains
+            .iter()
+            .map(|domain| {
+                let suffix = domain.suffix.trim_matches('.').to_ascii_lowercase();
+                anyhow::ensure!(!suffix.is_empty(), "empty domain suffix");
+
+                let nameservers = Nameservers::new(&domain.nameservers, cache_size)
+                    .with_context(|| format!("nameservers for {suffix}"))?;
+
+                Ok((suffix, nameservers))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        domains.sort_by_key(|(suffix, _)| Reverse(suffix.len()));
+
+        let system = if srv_lookup && default.is_none() {
+            Some(Nameservers::from_system_conf(cache_size).context("failed to read the system DNS configuration")?)
+        } else {
+            None
+        };
+
+        Ok(Self {
+            conf: value.clone(),
+            inner: Arc::new(ResolverInner {
+                default,
+                domains,
+                system,
+                srv_lookup,
+            }),
+        })
+    }
+
+    /// Resolves the destination into the addresses to connect to, in order of preference.
+    pub async fn resolve(&self, dest: &TargetAddr) -> anyhow::Result<Vec<SocketAddr>> {
+        self.resolve_detailed(dest).await.map(|resolution| resolution.addrs)
+    }
+
+    /// Same as [`Self::resolve`], but also returns how the destination was resolved.
+    pub async fn resolve_detailed(&self, dest: &TargetAddr) -> anyhow::Result<Resolution> {
+        if let Some(ip) = dest.host_ip() {
+            return Ok(Resolution {
+                addrs: vec![SocketAddr::new(ip, dest.port())],
+                srv_records: Vec::new(),
+                nameservers: Vec::new(),
+                ttl: None,
+            });
+        }
+
+        if self.inner.srv_lookup && dest.has_default_port() {
+            if let Some(srv_name) = srv_name(dest) {
+                match self.lookup_srv(dest.host(), &srv_name).await {
+                    Ok(Some(resolution)) => return Ok(resolution),
+                    Ok(None) => debug!(%srv_name, "No SRV record, the default port is used"),
+                    Err(error) => {
+                        warn!(%srv_name, error = format!("{error:#}"), "SRV lookup failed, the default port is used")
+                    }
+                }
+            }
+        }
+
+        self.lookup_addrs(dest.host(), dest.port()).await
+    }
+
+    async fn lookup_srv(&self, host: &str, srv_name: &str) -> anyhow::Result<Option<Resolution>> {
+        let Some(nameservers) = self.nameservers_for(host).or(self.inner.system.as_ref()) else {
+            return Ok(None);
+        };
+
+        let lookup = match nameservers.resolver.srv_lookup(srv_name).await {
+            Ok(lookup) => lookup,
+            Err(error) if matches!(error.kind(), ResolveErrorKind::NoRecordsFound { .. }) => return Ok(None),
+            Err(error) => return Err(anyhow::Error::new(error).context("SRV lookup")),
+        };
+
+        let mut srv_records = lookup
+            .iter()
+            .map(|srv| SrvRecord {
+                target: srv.target().to_utf8().trim_end_matches('.').to_owned(),
+                port: srv.port(),
+                priority: srv.priority(),
+                weight: srv.weight(),
+            })
+            // A target of "." means that the service is decidedly not available at this domain (RFC 2782).
+            .filter(|record| !record.target.is_empty())
+            .collect::<Vec<_>>();
+
+        if srv_records.is_empty() {
+            return Ok(None);
+        }
+
+        // Lowest priority first, then highest weight first.
+        srv_records.sort_by_key(|record| (record.priority, Reverse(record.weight)));
+
+        let mut addrs = Vec::new();
+        let mut ttl = remaining(lookup.as_lookup().valid_until());
+
+        for record in &srv_records {
+            match self.lookup_addrs(&record.target, record.port).await {
+                Ok(resolution) => {
+                    addrs.extend(resolution.addrs);
+                    ttl = resolution.ttl.map_or(ttl, |record_ttl| ttl.min(record_ttl));
+                }
+                Err(error) => {
+                    warn!(srv_target = %record.target, error = format!("{error:#}"), "Failed to resolve SRV target");
+                }
+            }
+        }
+
+        anyhow::ensure!(!addrs.is_empty(), "none of the SRV targets could be resolved");
+
+        Ok(Some(Resolution {
+            addrs,
+            srv_records,
+            nameservers: nameservers.addrs.clone(),
+            ttl: Some(ttl),
+        }))
+    }
+
+    async fn lookup_addrs(&self, host: &str, port: u16) -> anyhow::Result<Resolution> {
+        match self.nameservers_for(host) {
+            Some(nameservers) => {
+                let lookup = nameservers
+                    .resolver
+                    .lookup_ip(host)
+                    .await
+                    .with_context(|| format!("failed to resolve {host}"))?;
+
+                Ok(Resolution {
+                    addrs: lookup.iter().map(|ip| SocketAddr::new(ip, port)).collect(),
+                    srv_records: Vec::new(),
+                    nameservers: nameservers.addrs.clone(),
+                    ttl: Some(remaining(lookup.valid_until())),
+                })
+            }
+            None => {
+                let addrs = tokio::net::lookup_host((host, port))
+                    .await
+                    .with_context(|| format!("failed to resolve {host}"))?;
+
+                Ok(Resolution {
+                    addrs: addrs.collect(),
+                    srv_records: Vec::new(),
+                    nameservers: Vec::new(),
+                    ttl: None,
+                })
+            }
+        }
+    }
+
+    fn nameservers_for(&self, host: &str) -> Option<&Nameservers> {
+        let host = host.trim_end_matches('.');
+
+        self.inner
+            .domains
+            .iter()
+            .find(|(suffix, _)| is_under_suffix(host, suffix))
+            .map(|(_, nameservers)| nameservers)
+            .or(self.inner.default.as_ref())
+    }
+}
+
+impl Default for Resolver {
+    fn default() -> Self {
+        Self::from_dto(&dto::DnsConf::default()).expect("default DNS configuration is valid")
+    }
+}
+
+impl PartialEq for Resolver {
+    fn eq(&self, other: &Self) -> bool {
+        self.conf == other.conf
+    }
+}
+
+impl Eq for Resolver {}
+
+impl std::fmt::Debug for Resolver {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Resolver")
+            .field("conf", &self.conf)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Nameservers {
+    fn new(nameservers: &[String], cache_size: usize) -> anyhow::Result<Self> {
+        anyhow::ensure!(!nameservers.is_empty(), "at least one nameserver is required");
+
+        let addrs = nameservers
+            .iter()
+            .map(|nameserver| {
+                nameserver
+                    .parse::<SocketAddr>()
+                    .or_else(|_| {
+                        nameserver
+                            .parse::<IpAddr>()
+                            .map(|ip| SocketAddr::new(ip, DNS_DEFAULT_PORT))
+                    })
+                    .with_context(|| format!("invalid nameserver: {nameserver}"))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let group = addrs
+            .iter()
+            .flat_map(|addr| {
+                [
+                    NameServerConfig::new(*addr, Protocol::Udp),
+                    NameServerConfig::new(*addr, Protocol::Tcp),
+                ]
+            })
+            .collect::<Vec<_>>()
+            .pipe(NameServerConfigGroup::from);
+
+        let config = ResolverConfig::from_parts(None, Vec::new(), group);
+
+        Ok(Self {
+            addrs,
+            resolver: TokioAsyncResolver::tokio(config, resolver_opts(ResolverOpts::default(), cache_size)),
+        })
+    }
+
+    fn from_system_conf(cache_size: usize) -> anyhow::Result<Self> {
+        let (config, opts) = hickory_resolver::system_conf::read_system_conf()?;
+
+        let mut addrs = config
+            .name_servers()
+            .iter()
+            .map(|nameserver| nameserver.socket_addr)
+            .collect::<Vec<_>>();
+        addrs.dedup();
+
+        Ok(Self {
+            addrs,
+            resolver: TokioAsyncResolver::tokio(config, resolver_opts(opts, cache_size)),
+        })
+    }
+}
+
+fn resolver_opts(mut opts: ResolverOpts, cache_size: usize) -> ResolverOpts {
+    // Both address families are required for racing the connection attempts.
+    opts.ip_strategy = LookupIpStrategy::Ipv4AndIpv6;
+    opts.cache_size = cache_size;
+    opts
+}
+
+fn remaining(valid_until: Instant) -> Duration {
+    valid_until.saturating_duration_since(Instant::now())
+}
+
+fn is_under_suffix(host: &str, suffix: &str) -> bool {
+    let Some(prefix_len) = host.len().checked_sub(suffix.len()) else {
+        return false;
+    };
+
+    host[prefix_len..].eq_ignore_ascii_case(suffix) && (prefix_len == 0 || host.as_bytes()[prefix_len - 1] == b'.')
+}
+
+/// Name of the SRV record for the destination, based on the IANA service name of its default port.
+fn srv_name(dest: &TargetAddr) -> Option<String> {
+    let service = match dest.port() {
+        22 => "ssh",
+        23 => "telnet",
+        80 => "http",
+        88 => "kerberos",
+        389 => "ldap",
+        443 => "https",
+        636 => "ldaps",
+        3389 => "rdp",
+        5900 => "rfb",
+        5985 => "wsman",
+        5986 => "wsmans",
+        _ => return None,
+    };
+
+    let protocol = if dest.scheme().eq_ignore_ascii_case("udp") {
+        "udp"
+    } else {
+        "tcp"
+    };
+
+    // The trailing dot prevents the search domains from being appended.
+    Some(format!("_{service}._{protocol}.{}.", dest.host().trim_end_matches('.')))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn longest_suffix_wins() {
+        let resolver = Resolver::from_dto(&dto::DnsConf {
+            nameservers: vec!["10.0.0.53".to_owned()],
+            domains: vec![
+                dto::DnsDomainConf {
+                    suffix: "corp.local".to_owned(),
+                    nameservers: vec!["10.1.0.53:5353".to_owned()],
+                },
+                dto::DnsDomainConf {
+                    suffix: ".lab.corp.local.".to_owned(),
+                    nameservers: vec!["10.2.0.53".to_owned()],
+                },
+            ],
+            cache_size: None,
+            srv_lookup: None,
+        })
+        .unwrap();
+
+        let nameservers = |host| resolver.nameservers_for(host).unwrap().addrs[0].to_string();
+
+        assert_eq!(nameservers("rdp.lab.corp.local"), "10.2.0.53:53");
+        assert_eq!(nameservers("RDP.CORP.LOCAL."), "10.1.0.53:5353");
+        assert_eq!(nameservers("corp.local"), "10.1.0.53:5353");
+        assert_eq!(nameservers("notcorp.local"), "10.0.0.53:53");
+        assert_eq!(nameservers("example.com"), "10.0.0.53:53");
+    }
+
+    #[test]
+    fn system_resolver_by_default() {
+        let resolver = Resolver::default();
+        assert!(resolver.nameservers_for("example.com").is_none());
+    }
+
+    #[test]
+    fn invalid_nameservers_are_rejected() {
+        let conf = |nameservers: &[&str]| dto::DnsConf {
+            nameservers: nameservers.iter().map(|ns| (*ns).to_owned()).collect(),
+            ..Default::default()
+        };
+
+        assert!(Resolver::from_dto(&conf(&["dns.example.com"])).is_err());
+        assert!(Resolver::from_dto(&conf(&["10.0.0.53:dns"])).is_err());
+        assert!(Resolver::from_dto(&conf(&["[::1]:53", "10.0.0.53"])).is_ok());
+    }
+
+    #[test]
+    fn srv_name_from_default_port() {
+        let dest = TargetAddr::parse("rdp.corp.local", 3389).unwrap();
+        assert_eq!(srv_name(&dest).as_deref(), Some("_rdp._tcp.rdp.corp.local."));
+
+        let dest = TargetAddr::parse("udp://corp.local", 88).unwrap();
+        assert_eq!(srv_name(&dest).as_deref(), Some("_kerberos._udp.corp.local."));
+
+        let dest = TargetAddr::parse("corp.local", 12876).unwrap();
+        assert_eq!(srv_name(&dest), None);
+    }
+
+    #[tokio::test]
+    async fn ip_addresses_are_not_resolved() {
+        let dest = TargetAddr::parse("tcp://192.168.1.10", 3389).unwrap();
+        let addrs = Resolver::default().resolve(&dest).await.unwrap();
+        assert_eq!(addrs, vec!["192.168.1.10:3389".parse::<SocketAddr>().unwrap()]);
+    }
+}
@@ -196,9 +196,13 @@ async fn spawn_tasks(conf_handle: ConfHandle) -> anyhow::Result<Tasks> {
 
     let token_cache = devolutions_gateway::token::new_token_cache().pipe(Arc::new);
     let jrl = load_jrl_from_disk(&conf)?;
-    let (session_manager_handle, session_manager_rx) = session_manager_channel();
     let (recording_manager_handle, recording_manager_rx) = recording_message_channel();
+    let (session_manager_handle, session_manager_rx) = session_manager_channel(&recording_manager_handle);
     let (subscriber_tx, subscriber_rx) = subscriber_channel();
+    let recording_storage =
+        devolutions_gateway::recording::storage::from_conf(&conf.recording_storage, &conf.recording_path)
+            .context("failed to initialize recording storage")?;
+    let target_pools = Arc::new(devolutions_gateway::target_pool::TargetPoolState::default());
     let mut tasks = Tasks::new();
 
     let state = DgwState {
@@ -208,7 +212,10 @@ async fn spawn_tasks(conf_handle: ConfHandle) -> anyhow::Result<Tasks> {
         sessions: session_manager_handle.clone(),
         subscriber_tx: subscriber_tx.clone(),
         shutdown_signal: tasks.shutdown_signal.clone(),
-        recordings: recording_manager_handle,
+        recordings: recording_manager_handle.clone(),
+        recording_storage: recording_storage.clone(),
+        target_pools: target_pools.clone(),
+        rendezvous: Arc::new(devolutions_gateway::rendezvous::RendezvousState::default()),
     };
 
     conf.listeners
@@ -243,10 +250,15 @@ async fn spawn_tasks(conf_handle: ConfHandle) -> anyhow::Result<Tasks> {
     ));
 
     tasks.register(devolutions_gateway::subscriber::SubscriberPollingTask {
-        sessions: session_manager_handle,
+        sessions: session_manager_handle.clone(),
         subscriber: subscriber_tx,
     });
 
+    tasks.register(devolutions_gateway::target_pool::TargetPoolHealthCheckTask {
+        conf_handle: conf_handle.clone(),
+        state: target_pools,
+    });
+
     tasks.register(devolutions_gateway::subscriber::SubscriberTask {
         conf_handle,
         rx: subscriber_rx,
@@ -254,11 +266,14 @@ async fn spawn_tasks(conf_handle: ConfHandle) -> anyhow::Result<Tasks> {
 
     tasks.register(devolutions_gateway::session::SessionManagerTask::new(
         session_manager_rx,
+        recording_manager_handle,
     ));
 
     tasks.register(devolutions_gateway::recording::RecordingManagerTask::new(
         recording_manager_rx,
-        conf.recording_path.clone(),
+        recording_storage,
+        session_manager_handle,
+        conf.id,
     ));
 
     Ok(tasks)
@@ -1,3 +1,6 @@
+use crate::interceptor::pcap::SessionCapture;
+use crate::recording::events::{EventSender, KillReason, SessionEvent};
+use crate::recording::RecordingMessageSender;
 use crate::subscriber;
 use crate::target_addr::TargetAddr;
 use crate::token::{ApplicationProtocol, SessionTtl};
@@ -7,6 +10,7 @@ use core::fmt;
 use devolutions_gateway_task::{ShutdownSignal, Task};
 use std::cmp;
 use std::collections::{BinaryHeap, HashMap};
+use std::net::SocketAddr;
 use std::sync::Arc;
 use std::time::Duration;
 use tap::prelude::*;
@@ -28,11 +32,18 @@ pub struct SessionInfo {
     pub application_protocol: ApplicationProtocol,
     pub recording_policy: bool,
     pub filtering_policy: bool,
+    pub capture_policy: bool,
     #[serde(with = "time::serde::rfc3339")]
     pub start_timestamp: OffsetDateTime,
     pub time_to_live: SessionTtl,
     #[serde(flatten)]
     pub mode_details: ConnectionModeDetails,
+    /// Subject of the token used to establish the session
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subject: Option<String>,
+    /// Address of the client which established the session
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_addr: Option<SocketAddr>,
 }
 
 impl SessionInfo {
@@ -42,9 +53,12 @@ impl SessionInfo {
             application_protocol: ap,
             recording_policy: false,
             filtering_policy: false,
+            capture_policy: false,
             start_timestamp: OffsetDateTime::now_utc(),
             time_to_live: SessionTtl::Unlimited,
             mode_details,
+            subject: None,
+            client_addr: None,
         }
     }
 
@@ -58,28 +72,44 @@ impl SessionInfo {
         self
     }
 
+    pub fn with_capture_policy(mut self, value: bool) -> Self {
+        self.capture_policy = value;
+        self
+    }
+
     pub fn with_ttl(mut self, value: SessionTtl) -> Self {
         self.time_to_live = value;
         self
     }
 
+    pub fn with_subject(mut self, value: Option<String>) -> Self {
+        self.subject = value;
+        self
+    }
+
+    pub fn with_client_addr(mut self, value: SocketAddr) -> Self {
+        self.client_addr = Some(value);
+        self
+    }
+
     pub fn id(&self) -> Uuid {
         self.association_id
     }
 }
 
-#[instrument]
+#[instrument(skip(capture))]
 pub async fn add_session_in_progress(
     sessions: &SessionMessageSender,
     subscriber_tx: &subscriber::SubscriberSender,
     info: SessionInfo,
     notify_kill: Arc<Notify>,
+    capture: Option<Arc<SessionCapture>>,
 ) -> anyhow::Result<()> {
     let association_id = info.association_id;
     let start_timestamp = info.start_timestamp;
 
     sessions
-        .new_session(info, notify_kill)
+        .new_session(info, notify_kill, capture)
         .await
         .context("couldn't register new session")?;
 
@@ -128,10 +158,19 @@ pub enum KillResult {
     NotFound,
 }
 
+#[must_use]
+pub enum CaptureResult {
+    Success,
+    NotFound,
+    /// The traffic of this session can't be captured (e.g.: forwarded without being copied into userspace buffers)
+    NotSupported,
+}
+
 enum SessionManagerMessage {
     New {
         info: SessionInfo,
         notify_kill: Arc<Notify>,
+        capture: Option<Arc<SessionCapture>>,
     },
     Remove {
         id: Uuid,
@@ -141,6 +180,10 @@ enum SessionManagerMessage {
         id: Uuid,
         channel: oneshot::Sender<KillResult>,
     },
+    StartCapture {
+        id: Uuid,
+        channel: oneshot::Sender<anyhow::Result<CaptureResult>>,
+    },
     GetRunning {
         channel: oneshot::Sender<RunningSessions>,
     },
@@ -152,15 +195,20 @@ enum SessionManagerMessage {
 impl fmt::Debug for SessionManagerMessage {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            SessionManagerMessage::New { info, notify_kill: _ } => {
-                f.debug_struct("New").field("info", info).finish_non_exhaustive()
-            }
+            SessionManagerMessage::New {
+                info,
+                notify_kill: _,
+                capture: _,
+            } => f.debug_struct("New").field("info", info).finish_non_exhaustive(),
             SessionManagerMessage::Remove { id, channel: _ } => {
                 f.debug_struct("Remove").field("id", id).finish_non_exhaustive()
             }
             SessionManagerMessage::Kill { id, channel: _ } => {
                 f.debug_struct("Kill").field("id", id).finish_non_exhaustive()
             }
+            SessionManagerMessage::StartCapture { id, channel: _ } => {
+                f.debug_struct("StartCapture").field("id", id).finish_non_exhaustive()
+            }
             SessionManagerMessage::GetRunning { channel: _ } => f.debug_struct("GetRunning").finish_non_exhaustive(),
             SessionManagerMessage::GetCount { channel: _ } => f.debug_struct("GetCount").finish_non_exhaustive(),
         }
@@ -168,12 +216,21 @@ impl fmt::Debug for SessionManagerMessage {
 }
 
 #[derive(Clone, Debug)]
-pub struct SessionMessageSender(mpsc::Sender<SessionManagerMessage>);
+pub struct SessionMessageSender(mpsc::Sender<SessionManagerMessage>, EventSender);
 
 impl SessionMessageSender {
-    pub async fn new_session(&self, info: SessionInfo, notify_kill: Arc<Notify>) -> anyhow::Result<()> {
+    pub async fn new_session(
+        &self,
+        info: SessionInfo,
+        notify_kill: Arc<Notify>,
+        capture: Option<Arc<SessionCapture>>,
+    ) -> anyhow::Result<()> {
         self.0
-            .send(SessionManagerMessage::New { info, notify_kill })
+            .send(SessionManagerMessage::New {
+                info,
+                notify_kill,
+                capture,
+            })
             .await
             .ok()
             .context("couldn't send New message")
@@ -199,6 +256,16 @@ impl SessionMessageSender {
         rx.await.context("couldn't receive kill result")
     }
 
+    pub async fn start_capture(&self, id: Uuid) -> anyhow::Result<CaptureResult> {
+        let (tx, rx) = oneshot::channel();
+        self.0
+            .send(SessionManagerMessage::StartCapture { id, channel: tx })
+            .await
+            .ok()
+            .context("couldn't send StartCapture message")?;
+        rx.await.context("couldn't receive capture result")?
+    }
+
     pub async fn get_running_sessions(&self) -> anyhow::Result<RunningSessions> {
         let (tx, rx) = oneshot::channel();
         self.0
@@ -218,12 +285,21 @@ impl SessionMessageSender {
             .context("couldn't send GetRunning message")?;
         rx.await.context("couldn't receive running session count")
     }
+
+    /// Adds an event to the timeline of a session, written alongside its recording.
+    ///
+    /// `recording_policy` is the recording policy of the session: see [`RecordingMessageSender::record_event`].
+    pub fn record_event(&self, id: Uuid, recording_policy: bool, event: SessionEvent) {
+        self.1.send(id, recording_policy, event);
+    }
 }
 
 pub struct SessionMessageReceiver(mpsc::Receiver<SessionManagerMessage>);
 
-pub fn session_manager_channel() -> (SessionMessageSender, SessionMessageReceiver) {
-    mpsc::channel(64).pipe(|(tx, rx)| (SessionMessageSender(tx), SessionMessageReceiver(rx)))
+/// The session events are sent directly to the recording manager, through its own channel.
+pub fn session_manager_channel(recordings: &RecordingMessageSender) -> (SessionMessageSender, SessionMessageReceiver) {
+    let events = recordings.event_sender();
+    mpsc::channel(64).pipe(|(tx, rx)| (SessionMessageSender(tx, events), SessionMessageReceiver(rx)))
 }
 
 struct WithTtlInfo {
@@ -259,26 +335,58 @@ pub struct SessionManagerTask {
     rx: SessionMessageReceiver,
     all_running: RunningSessions,
     all_notify_kill: HashMap<Uuid, Arc<Notify>>,
+    all_captures: HashMap<Uuid, Arc<SessionCapture>>,
+    recordings: RecordingMessageSender,
 }
 
 impl SessionManagerTask {
-    pub fn new(rx: SessionMessageReceiver) -> Self {
+    pub fn new(rx: SessionMessageReceiver, recordings: RecordingMessageSender) -> Self {
         Self {
             rx,
             all_running: HashMap::new(),
             all_notify_kill: HashMap::new(),
+            all_captures: HashMap::new(),
+            recordings,
         }
     }
 
-    fn handle_new(&mut self, info: SessionInfo, notify_kill: Arc<Notify>) {
+    fn handle_new(&mut self, info: SessionInfo, notify_kill: Arc<Notify>, capture: Option<Arc<SessionCapture>>) {
         let id = info.association_id;
+
+        let destination = match &info.mode_details {
+            ConnectionModeDetails::Rdv => None,
+            ConnectionModeDetails::Fwd { destination_host } => Some(destination_host.to_string()),
+        };
+
+        self.recordings.record_event(
+            id,
+            info.recording_policy,
+            SessionEvent::SessionStarted {
+                protocol: info.application_protocol.clone(),
+                destination,
+                subject: info.subject.clone(),
+                client_addr: info.client_addr,
+            },
+        );
+
         self.all_running.insert(id, info);
         self.all_notify_kill.insert(id, notify_kill);
+
+        if let Some(capture) = capture {
+            self.all_captures.insert(id, capture);
+        }
     }
 
     fn handle_remove(&mut self, id: Uuid) -> Option<SessionInfo> {
         let removed_session = self.all_running.remove(&id);
         let _ = self.all_notify_kill.remove(&id);
+        let _ = self.all_captures.remove(&id);
+
+        if let Some(session) = &removed_session {
+            self.recordings
+                .record_event(id, session.recording_policy, SessionEvent::SessionEnded);
+        }
+
         removed_session
     }
 
@@ -291,6 +399,27 @@ impl SessionManagerTask {
             None => KillResult::NotFound,
         }
     }
+
+    fn record_killed(&self, id: Uuid, reason: KillReason) {
+        let recording_policy = self.all_running.get(&id).is_some_and(|info| info.recording_policy);
+        self.recordings
+            .record_event(id, recording_policy, SessionEvent::SessionKilled { reason });
+    }
+
+    fn handle_start_capture(&mut self, id: Uuid) -> anyhow::Result<CaptureResult> {
+        let Some(info) = self.all_running.get_mut(&id) else {
+            return Ok(CaptureResult::NotFound);
+        };
+
+        let Some(capture) = self.all_captures.get(&id) else {
+            return Ok(CaptureResult::NotSupported);
+        };
+
+        capture.start()?;
+        info.capture_policy = true;
+
+        Ok(CaptureResult::Success)
+    }
 }
 
 #[async_trait]
@@ -328,6 +457,7 @@ async fn session_manager_task(
                 match manager.handle_kill(to_kill.session_id) {
                     KillResult::Success => {
                         info!(session.id = %to_kill.session_id, "Session killed because it reached its max duration");
+                        manager.record_killed(to_kill.session_id, KillReason::Ttl);
                     }
                     KillResult::NotFound => {
                         debug!(session.id = %to_kill.session_id, "Session already ended");
@@ -348,7 +478,7 @@ async fn session_manager_task(
                 debug!(?msg, "Received message");
 
                 match msg {
-                    SessionManagerMessage::New { info, notify_kill } => {
+                    SessionManagerMessage::New { info, notify_kill, capture } => {
                         if let SessionTtl::Limited { minutes } = info.time_to_live {
                             let duration = Duration::from_secs(minutes.get() * 60);
                             let now = tokio::time::Instant::now();
@@ -366,7 +496,7 @@ async fn session_manager_task(
                             debug!(session.id = %info.id(), minutes = minutes.get(), "Limited TTL session registed");
                         }
 
-                        manager.handle_new(info, notify_kill);
+                        manager.handle_new(info, notify_kill, capture);
                     },
                     SessionManagerMessage::Remove { id, channel } => {
                         let removed_session = manager.handle_remove(id);
@@ -374,8 +504,16 @@ async fn session_manager_task(
                     }
                     SessionManagerMessage::Kill { id, channel } => {
                         let kill_result = manager.handle_kill(id);
+
+                        if let KillResult::Success = kill_result {
+                            manager.record_killed(id, KillReason::Api);
+                        }
+
                         let _ = channel.send(kill_result);
                     }
+                    SessionManagerMessage::StartCapture { id, channel } => {
+                        let _ = channel.send(manager.handle_start_capture(id));
+                    }
                     SessionManagerMessage::GetRunning { channel } => {
                         let _ = channel.send(manager.all_running.clone());
                     }
@@ -42,6 +42,8 @@ pub struct TargetAddr {
     host_end: u16,
     host_internal: HostInternal,
     port: u16,
+    // Whether the port was not specified, and the default one was used instead
+    default_port: bool,
 }
 
 #[derive(Debug, Clone)]
@@ -77,6 +79,7 @@ impl TargetAddr {
             host_end: host_end.pipe(u16::try_from).map_err(|_| BadTargetAddr::TooLong)?,
             host_internal,
             port,
+            default_port: false,
         })
     }
 
@@ -115,6 +118,11 @@ impl TargetAddr {
         self.port
     }
 
+    /// Returns true when the port was not specified, and the default one was used instead.
+    pub fn has_default_port(&self) -> bool {
+        self.default_port
+    }
+
     pub fn as_addr(&self) -> &str {
         self.h_slice_repr((self.scheme_end + 3)..)
     }
@@ -159,15 +167,15 @@ fn target_addr_parse_impl(
         rest.rfind(':').map(|idx| idx + 1)
     };
 
-    let (rest, port) = if let Some(port_start) = port_start {
+    let (rest, port, is_default_port) = if let Some(port_start) = port_start {
         let port = &rest[port_start..];
         let port = port
             .parse::<u16>()
             .map_err(|_| BadTargetAddr::BadPort { value: port.into() })?;
 
-        (&rest[..port_start - 1], port)
+        (&rest[..port_start - 1], port, false)
     } else if let Some(default_port) = default_port {
-        (rest, default_port)
+        (rest, default_port, true)
     } else {
         return Err(BadTargetAddr::PortMissing);
     };
@@ -178,7 +186,10 @@ fn target_addr_parse_impl(
         rest
     };
 
-    TargetAddr::from_components(scheme, host, port)
+    let mut addr = TargetAddr::from_components(scheme, host, port)?;
+    addr.default_port = is_default_port;
+
+    Ok(addr)
 }
 
 impl PartialEq for TargetAddr {
@@ -350,8 +361,10 @@ mod tests {
 
         if let Some(expected) = port {
             assert_eq!(addr.port(), expected);
+            assert!(!addr.has_default_port());
         } else {
             assert_eq!(addr.port(), default_port);
+            assert!(addr.has_default_port());
         }
     }
 
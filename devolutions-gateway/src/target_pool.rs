@@ -0,0 +1,422 @@
+//! Selection of the target among several candidates, and health of the targets.
+//!
+//! Without selection strategy, the targets are tried in order, and the first reachable one is used.
+//! With a selection strategy, the targets are ordered according to this strategy instead, and the targets which
+//! recently failed are tried last, until their cool-down ends.
+//!
+//! Named pools of targets are defined in the configuration, and referenced by tokens as `pool://<NAME>`.
+//! The targets of a pool are also checked in the background, when a health check interval is configured.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context as _;
+use async_trait::async_trait;
+use devolutions_gateway_task::{ShutdownSignal, Task};
+use nonempty::NonEmpty;
+use parking_lot::Mutex;
+use rand::seq::SliceRandom as _;
+use tokio::net::TcpStream;
+use tokio::time::Instant;
+
+use crate::config::{dto, Conf, ConfHandle};
+use crate::egress::EgressDenied;
+use crate::session::{ConnectionModeDetails, SessionMessageSender};
+use crate::target_addr::TargetAddr;
+use crate::utils;
+
+/// Scheme of the targets referencing a named pool (e.g.: `pool://rdp-farm`)
+pub const POOL_SCHEME: &str = "pool";
+
+/// Returns true when the target references a named pool (e.g.: `pool://rdp-farm`).
+pub fn is_pool_reference(target: &str) -> bool {
+    target
+        .get(..POOL_SCHEME.len() + 3)
+        .is_some_and(|prefix| prefix.eq_ignore_ascii_case("pool://"))
+}
+
+const DEFAULT_COOLDOWN: Duration = Duration::from_secs(30);
+
+/// Order in which the candidate targets are tried
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SelectionStrategy {
+    /// Each new session starts with the next target
+    RoundRobin,
+    /// Targets with the fewest running sessions first
+    LeastConnections,
+    /// Targets in random order
+    Random,
+}
+
+impl From<dto::TargetSelectionStrategy> for SelectionStrategy {
+    fn from(value: dto::TargetSelectionStrategy) -> Self {
+        match value {
+            dto::TargetSelectionStrategy::RoundRobin => Self::RoundRobin,
+            dto::TargetSelectionStrategy::LeastConnections => Self::LeastConnections,
+            dto::TargetSelectionStrategy::Random => Self::Random,
+        }
+    }
+}
+
+/// Named pool of targets
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct TargetPool {
+    pub name: String,
+    pub targets: Vec<TargetAddr>,
+    pub strategy: SelectionStrategy,
+    /// Interval between two background health checks, no background health check when unset
+    pub health_check_interval: Option<Duration>,
+    /// Duration during which a failed target is taken out of rotation
+    pub cooldown: Duration,
+}
+
+impl TargetPool {
+    pub fn from_dto(value: &dto::TargetPoolConf) -> anyhow::Result<Self> {
+        anyhow::ensure!(!value.name.is_empty(), "empty pool name");
+        anyhow::ensure!(!value.targets.is_empty(), "at least one target is required");
+
+        let targets = value
+            .targets
+            .iter()
+            .map(|target| TargetAddr::parse(target, None).with_context(|| format!("invalid target: {target}")))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        anyhow::ensure!(
+            targets
+                .iter()
+                .all(|target| !target.scheme().eq_ignore_ascii_case(POOL_SCHEME)),
+            "pools can’t be nested"
+        );
+
+        Ok(Self {
+            name: value.name.clone(),
+            targets,
+            strategy: value
+                .strategy
+                .map(SelectionStrategy::from)
+                .unwrap_or(SelectionStrategy::RoundRobin),
+            health_check_interval: value.health_check_interval.map(Duration::from_secs),
+            cooldown: value.cooldown.map(Duration::from_secs).unwrap_or(DEFAULT_COOLDOWN),
+        })
+    }
+}
+
+/// State shared by all the sessions (round-robin positions, health of the targets)
+#[derive(Debug, Default)]
+pub struct TargetPoolState {
+    round_robin: Mutex<HashMap<String, usize>>,
+    health: Mutex<HashMap<String, TargetHealth>>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TargetHealth {
+    /// End of the cool-down, when the target recently failed
+    pub unhealthy_until: Option<Instant>,
+    /// Error of the last failed connection or health check
+    pub last_error: Option<String>,
+    /// Time of the last successful connection or health check
+    pub last_success: Option<time::OffsetDateTime>,
+}
+
+impl TargetHealth {
+    pub fn is_healthy(&self) -> bool {
+        self.unhealthy_until.map_or(true, |until| until <= Instant::now())
+    }
+}
+
+impl TargetPoolState {
+    pub fn health(&self, target: &TargetAddr) -> TargetHealth {
+        self.health.lock().get(target.as_str()).cloned().unwrap_or_default()
+    }
+
+    fn is_healthy(&self, target: &TargetAddr) -> bool {
+        self.health
+            .lock()
+            .get(target.as_str())
+            .map_or(true, TargetHealth::is_healthy)
+    }
+
+    fn mark_failed(&self, target: &TargetAddr, cooldown: Duration, error: &anyhow::Error) {
+        let mut health = self.health.lock();
+        let entry = health.entry(target.as_str().to_owned()).or_default();
+
+        if entry.is_healthy() {
+            warn!(%target, ?cooldown, "Target taken out of rotation");
+        }
+
+        entry.unhealthy_until = Some(Instant::now() + cooldown);
+        entry.last_error = Some(format!("{error:#}"));
+    }
+
+    fn mark_succeeded(&self, target: &TargetAddr) {
+        let mut health = self.health.lock();
+        let entry = health.entry(target.as_str().to_owned()).or_default();
+        entry.last_success = Some(time::OffsetDateTime::now_utc());
+    }
+
+    /// Orders the candidates according to the strategy, the targets in cool-down coming last.
+    fn order(
+        &self,
+        key: &str,
+        candidates: Vec<TargetAddr>,
+        strategy: SelectionStrategy,
+        session_counts: &HashMap<String, usize>,
+    ) -> Vec<TargetAddr> {
+        let (mut healthy, unhealthy): (Vec<_>, Vec<_>) =
+            candidates.into_iter().partition(|target| self.is_healthy(target));
+
+        match strategy {
+            SelectionStrategy::RoundRobin => {
+                let mut round_robin = self.round_robin.lock();
+                let position = round_robin.entry(key.to_owned()).or_default();
+
+                if !healthy.is_empty() {
+                    let len = healthy.len();
+                    healthy.rotate_left(*position % len);
+                }
+
+                *position = position.wrapping_add(1);
+            }
+            SelectionStrategy::LeastConnections => {
+                healthy.sort_by_key(|target| session_counts.get(target.as_str()).copied().unwrap_or(0));
+            }
+            SelectionStrategy::Random => {
+                healthy.shuffle(&mut rand::thread_rng());
+            }
+        }
+
+        healthy.extend(unhealthy);
+        healthy
+    }
+}
+
+/// Connects to one of the targets, trying them in the order given by the selection strategy.
+///
+/// The targets referencing a pool are replaced by the targets of this pool, and the strategy of the pool is used
+/// unless one is specified.
+pub async fn connect_to_target(
+    targets: &NonEmpty<TargetAddr>,
+    strategy: Option<SelectionStrategy>,
+    conf: &Conf,
+    state: &TargetPoolState,
+    sessions: &SessionMessageSender,
+) -> anyhow::Result<((TcpStream, SocketAddr), TargetAddr)> {
+    let mut candidates = Vec::with_capacity(targets.len());
+    let mut strategy = strategy;
+    let mut cooldown = DEFAULT_COOLDOWN;
+    let mut key = None;
+
+    for target in targets.iter() {
+        if target.scheme().eq_ignore_ascii_case(POOL_SCHEME) {
+            let pool = conf
+                .target_pools
+                .iter()
+                .find(|pool| pool.name.eq_ignore_ascii_case(target.host()))
+                .with_context(|| format!("unknown target pool: {}", target.host()))?;
+
+            candidates.extend(pool.targets.iter().cloned());
+            strategy = strategy.or(Some(pool.strategy));
+            cooldown = pool.cooldown;
+            key.get_or_insert_with(|| format!("{POOL_SCHEME}://{}", pool.name));
+        } else {
+            candidates.push(target.clone());
+        }
+    }
+
+    let Some(strategy) = strategy else {
+        let (connection, target) =
+            utils::successive_try(&candidates, |target| utils::tcp_connect(target, &conf.outbound)).await?;
+        return Ok((connection, target.clone()));
+    };
+
+    let key = key.unwrap_or_else(|| candidates.iter().map(TargetAddr::as_str).collect::<Vec<_>>().join(","));
+
+    let session_counts = if strategy == SelectionStrategy::LeastConnections {
+        count_sessions_by_target(sessions).await?
+    } else {
+        HashMap::new()
+    };
+
+    let ordered = state.order(&key, candidates, strategy, &session_counts);
+
+    trace!(?strategy, targets = ?ordered.iter().map(TargetAddr::as_str).collect::<Vec<_>>(), "Targets ordered");
+
+    let (connection, target) = utils::successive_try(&ordered, |target| async move {
+        let result = utils::tcp_connect(target, &conf.outbound).await;
+
+        match &result {
+            Ok(_) => state.mark_succeeded(target),
+            // The target is not at fault.
+            Err(error) if EgressDenied::is_cause_of(error) => {}
+            Err(error) => state.mark_failed(target, cooldown, error),
+        }
+
+        result
+    })
+    .await?;
+
+    Ok((connection, target.clone()))
+}
+
+/// Counts the running sessions for each target.
+pub async fn count_sessions_by_target(sessions: &SessionMessageSender) -> anyhow::Result<HashMap<String, usize>> {
+    let running = sessions
+        .get_running_sessions()
+        .await
+        .context("couldn’t retrieve running sessions")?;
+
+    let mut counts = HashMap::new();
+
+    for session in running.values() {
+        if let ConnectionModeDetails::Fwd { destination_host } = &session.mode_details {
+            *counts.entry(destination_host.as_str().to_owned()).or_insert(0) += 1;
+        }
+    }
+
+    Ok(counts)
+}
+
+pub struct TargetPoolHealthCheckTask {
+    pub conf_handle: ConfHandle,
+    pub state: Arc<TargetPoolState>,
+}
+
+#[async_trait]
+impl Task for TargetPoolHealthCheckTask {
+    type Output = anyhow::Result<()>;
+
+    const NAME: &'static str = "target pool health check";
+
+    async fn run(self, shutdown_signal: ShutdownSignal) -> Self::Output {
+        health_check_task(self.conf_handle, self.state, shutdown_signal).await;
+        Ok(())
+    }
+}
+
+#[instrument(skip_all)]
+async fn health_check_task(conf_handle: ConfHandle, state: Arc<TargetPoolState>, mut shutdown_signal: ShutdownSignal) {
+    const TICK_INTERVAL: Duration = Duration::from_secs(1);
+
+    debug!("Task started");
+
+    // Pools are identified by name, so that the schedule survives configuration changes.
+    let mut next_checks = HashMap::<String, Instant>::new();
+
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(TICK_INTERVAL) => {}
+            _ = shutdown_signal.wait() => {
+                break;
+            }
+        }
+
+        let conf = conf_handle.get_conf();
+        let now = Instant::now();
+
+        next_checks.retain(|name, _| conf.target_pools.iter().any(|pool| &pool.name == name));
+
+        let due_pools = conf.target_pools.iter().filter_map(|pool| {
+            let interval = pool.health_check_interval?;
+            let next_check = next_checks.entry(pool.name.clone()).or_insert(now);
+
+            if *next_check <= now {
+                *next_check = now + interval;
+                Some(pool)
+            } else {
+                None
+            }
+        });
+
+        let checks = due_pools
+            .flat_map(|pool| pool.targets.iter().map(move |target| (pool, target)))
+            .map(|(pool, target)| {
+                let conf = &conf;
+                let state = &state;
+
+                async move {
+                    match utils::tcp_connect(target, &conf.outbound).await {
+                        Ok(_) => {
+                            trace!(pool = %pool.name, %target, "Health check succeeded");
+                            state.mark_succeeded(target);
+                        }
+                        Err(error) => {
+                            debug!(pool = %pool.name, %target, error = format!("{error:#}"), "Health check failed");
+                            state.mark_failed(target, pool.cooldown, &error);
+                        }
+                    }
+                }
+            })
+            .collect::<Vec<_>>();
+
+        futures::future::join_all(checks).await;
+    }
+
+    debug!("Task terminated");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn targets(targets: &[&str]) -> Vec<TargetAddr> {
+        targets
+            .iter()
+            .map(|target| TargetAddr::parse(target, None).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn round_robin_rotates_the_targets() {
+        let state = TargetPoolState::default();
+        let candidates = targets(&["a:3389", "b:3389", "c:3389"]);
+
+        let first_targets = (0..4)
+            .map(|_| {
+                state.order(
+                    "key",
+                    candidates.clone(),
+                    SelectionStrategy::RoundRobin,
+                    &HashMap::new(),
+                )[0]
+                .clone()
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(first_targets, targets(&["a:3389", "b:3389", "c:3389", "a:3389"]));
+    }
+
+    #[test]
+    fn least_connections_prefers_idle_targets() {
+        let state = TargetPoolState::default();
+        let candidates = targets(&["a:3389", "b:3389", "c:3389"]);
+        let session_counts = HashMap::from([("tcp://a:3389".to_owned(), 2), ("tcp://b:3389".to_owned(), 1)]);
+
+        let ordered = state.order("key", candidates, SelectionStrategy::LeastConnections, &session_counts);
+
+        assert_eq!(ordered, targets(&["c:3389", "b:3389", "a:3389"]));
+    }
+
+    #[test]
+    fn failed_targets_come_last_until_cooldown_ends() {
+        let state = TargetPoolState::default();
+        let candidates = targets(&["a:3389", "b:3389"]);
+
+        state.mark_failed(
+            &candidates[0],
+            Duration::from_secs(30),
+            &anyhow::anyhow!("connection refused"),
+        );
+
+        let ordered = state.order("key", candidates.clone(), SelectionStrategy::Random, &HashMap::new());
+        assert_eq!(ordered, targets(&["b:3389", "a:3389"]));
+
+        state.mark_failed(&candidates[1], Duration::ZERO, &anyhow::anyhow!("connection refused"));
+
+        let ordered = state.order("key", candidates, SelectionStrategy::LeastConnections, &HashMap::new());
+        assert_eq!(ordered, targets(&["b:3389", "a:3389"]));
+    }
+}
@@ -1,4 +1,6 @@
+use core::fmt;
 use std::io;
+use std::net::SocketAddr;
 use std::sync::Arc;
 
 use anyhow::Context as _;
@@ -6,40 +8,299 @@ use tokio::net::TcpStream;
 use tokio_rustls::client::TlsStream;
 use tokio_rustls::rustls;
 
-lazy_static::lazy_static! {
-    // rustls doc says:
-    //
-    // > Making one of these can be expensive, and should be once per process rather than once per connection.
+use crate::config::dto;
+use crate::egress::DestinationRule;
+use crate::target_addr::TargetAddr;
+use crate::token::AssociationTokenClaims;
+
+fn build_connector(
+    verifier: Arc<dyn rustls::client::ServerCertVerifier>,
+    identity: Option<&ClientIdentity>,
+) -> anyhow::Result<tokio_rustls::TlsConnector> {
+    let builder = rustls::client::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(verifier);
+
+    let mut tls_client_config = match identity {
+        Some(identity) => builder
+            .with_client_auth_cert(identity.certificates.clone(), identity.private_key.clone())
+            .with_context(|| {
+                format!(
+                    "invalid certificate or private key for client identity {}",
+                    identity.name
+                )
+            })?,
+        None => builder.with_no_client_auth(),
+    };
+
+    // Disable TLS resumption because it’s not supported by some services such as CredSSP.
     //
-    // source: https://docs.rs/rustls/0.21.1/rustls/client/struct.ClientConfig.html
+    // > The CredSSP Protocol does not extend the TLS wire protocol. TLS session resumption is not supported.
     //
-    // We’ll reuse the same TLS client config for all proxy-based TLS connections.
-    // (TlsConnector is just a wrapper around the config providing the `connect` method.)
-    static ref TLS_CONNECTOR: tokio_rustls::TlsConnector = {
-        let mut tls_client_config = rustls::client::ClientConfig::builder()
-            .with_safe_defaults()
-            .with_custom_certificate_verifier(Arc::new(danger::NoCertificateVerification))
-            .with_no_client_auth();
-
-        // Disable TLS resumption because it’s not supported by some services such as CredSSP.
-        //
-        // > The CredSSP Protocol does not extend the TLS wire protocol. TLS session resumption is not supported.
-        //
-        // source: https://learn.microsoft.com/en-us/openspecs/windows_protocols/ms-cssp/385a7489-d46b-464c-b224-f7340e308a5c
-        tls_client_config.resumption = tokio_rustls::rustls::client::Resumption::disabled();
-
-        tokio_rustls::TlsConnector::from(Arc::new(tls_client_config))
-    };
+    // source: https://learn.microsoft.com/en-us/openspecs/windows_protocols/ms-cssp/385a7489-d46b-464c-b224-f7340e308a5c
+    tls_client_config.resumption = tokio_rustls::rustls::client::Resumption::disabled();
+
+    Ok(tokio_rustls::TlsConnector::from(Arc::new(tls_client_config)))
+}
+
+/// How the certificate presented by a target is verified
+#[derive(Debug, Clone, Copy)]
+pub enum ServerCertVerification<'a> {
+    /// The certificate chain and the name are verified using the configured trust anchors
+    TrustAnchors,
+    /// The certificate must match one of these thumbprints, the certificate chain and the name are not verified
+    Pinned(&'a [CertThumbprint]),
+    /// The certificate is not verified at all
+    Insecure,
+}
+
+impl<'a> ServerCertVerification<'a> {
+    /// Policy requested by an association token.
+    ///
+    /// Pinned thumbprints take precedence over the insecure opt-out.
+    pub fn from_claims(claims: &'a AssociationTokenClaims) -> Self {
+        if !claims.jet_tls_pin.is_empty() {
+            Self::Pinned(&claims.jet_tls_pin)
+        } else if claims.jet_tls_insecure {
+            Self::Insecure
+        } else {
+            Self::TrustAnchors
+        }
+    }
+}
+
+// rustls doc says:
+//
+// > Making one of these can be expensive, and should be once per process rather than once per connection.
+//
+// source: https://docs.rs/rustls/0.21.1/rustls/client/struct.ClientConfig.html
+//
+// We’ll reuse the same TLS client configs for all the proxy-based TLS connections presenting the same client
+// identity, except when pinning certificates, since the pinned thumbprints are specific to each token.
+// (TlsConnector is just a wrapper around the config providing the `connect` method.)
+#[derive(Clone)]
+struct Connectors {
+    trust_anchors: tokio_rustls::TlsConnector,
+    insecure: tokio_rustls::TlsConnector,
+}
+
+impl Connectors {
+    fn new(
+        trust_anchors: &Arc<rustls::client::WebPkiVerifier>,
+        identity: Option<&ClientIdentity>,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            trust_anchors: build_connector(trust_anchors.clone(), identity)?,
+            insecure: build_connector(Arc::new(danger::NoCertificateVerification), identity)?,
+        })
+    }
+}
+
+/// Certificate and private key presented to the targets requesting client authentication
+struct ClientIdentity {
+    name: String,
+    certificates: Vec<rustls::Certificate>,
+    private_key: rustls::PrivateKey,
+    /// Destinations to which this identity is presented, unless the token selects another one
+    destinations: Vec<DestinationRule>,
+}
+
+impl ClientIdentity {
+    fn from_dto(conf: &dto::ClientIdentityConf) -> anyhow::Result<Self> {
+        let (certificates, private_key) = crate::config::read_client_identity(conf)?;
+
+        let destinations = conf
+            .destinations
+            .iter()
+            .enumerate()
+            .map(|(idx, rule)| DestinationRule::from_dto(rule).with_context(|| format!("rule #{idx}")))
+            .collect::<anyhow::Result<Vec<_>>>()
+            .context("invalid destination rule")?;
+
+        Ok(Self {
+            name: conf.name.clone(),
+            certificates,
+            private_key,
+            destinations,
+        })
+    }
+}
+
+/// Trust anchors used to verify the certificates of the targets, and client identities presented to them
+#[derive(Clone)]
+pub struct TargetTls {
+    conf: dto::TargetTlsConf,
+    inner: Arc<TargetTlsInner>,
+}
+
+struct TargetTlsInner {
+    /// Connectors used when no client identity is presented
+    anonymous: Connectors,
+    /// Client identities, along with their connectors
+    identities: Vec<(ClientIdentity, Connectors)>,
+}
+
+impl PartialEq for TargetTls {
+    fn eq(&self, other: &Self) -> bool {
+        self.conf == other.conf
+    }
+}
+
+impl Eq for TargetTls {}
+
+impl fmt::Debug for TargetTls {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TargetTls")
+            .field("conf", &self.conf)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for TargetTls {
+    fn default() -> Self {
+        Self::from_dto(&dto::TargetTlsConf::default()).expect("default target TLS configuration is valid")
+    }
+}
+
+impl TargetTls {
+    pub fn from_dto(conf: &dto::TargetTlsConf) -> anyhow::Result<Self> {
+        let mut root_store = rustls::RootCertStore::empty();
+
+        match conf.ca_source.unwrap_or_default() {
+            dto::TargetCaSource::System => {
+                anyhow::ensure!(
+                    conf.ca_bundle_file.is_none(),
+                    "CaBundleFile is only used when CaSource is Custom"
+                );
+
+                match rustls_native_certs::load_native_certs() {
+                    Ok(certificates) => {
+                        let (added, ignored) = root_store.add_parsable_certificates(&certificates);
+                        debug!(added, ignored, "Loaded system trust anchors");
+                    }
+                    // Targets are then only reachable using pinning or the insecure opt-out.
+                    Err(error) => warn!(%error, "Couldn’t load system trust anchors"),
+                }
+            }
+            dto::TargetCaSource::Custom => {
+                let path = conf
+                    .ca_bundle_file
+                    .as_deref()
+                    .context("CaBundleFile is required when CaSource is Custom")?;
+
+                let certificates = crate::config::read_rustls_certificate_file(path).context("invalid CA bundle")?;
+
+                for certificate in certificates {
+                    root_store
+                        .add(&certificate)
+                        .with_context(|| format!("invalid CA certificate in {path}"))?;
+                }
+            }
+        }
+
+        if conf.default_policy == Some(dto::TargetTlsPolicy::Insecure) {
+            warn!(
+                "Certificates of the targets are not verified unless pinned by the token (Outbound.Tls.DefaultPolicy)"
+            );
+        }
+
+        let trust_anchors = Arc::new(rustls::client::WebPkiVerifier::new(root_store, None));
+
+        let anonymous = Connectors::new(&trust_anchors, None)?;
+
+        let mut identities = Vec::with_capacity(conf.client_identities.len());
+
+        for identity_conf in &conf.client_identities {
+            anyhow::ensure!(
+                identities
+                    .iter()
+                    .all(|(identity, _): &(ClientIdentity, _)| identity.name != identity_conf.name),
+                "duplicated client identity name: {}",
+                identity_conf.name
+            );
+
+            let identity = ClientIdentity::from_dto(identity_conf)
+                .with_context(|| format!("client identity {}", identity_conf.name))?;
+            let connectors = Connectors::new(&trust_anchors, Some(&identity))?;
+
+            identities.push((identity, connectors));
+        }
+
+        Ok(Self {
+            conf: conf.clone(),
+            inner: Arc::new(TargetTlsInner { anonymous, identities }),
+        })
+    }
+
+    /// Returns how the certificate of a target is verified, according to the token and to the default policy.
+    pub fn verification<'a>(&self, claims: &'a AssociationTokenClaims) -> ServerCertVerification<'a> {
+        match ServerCertVerification::from_claims(claims) {
+            ServerCertVerification::TrustAnchors
+                if self.conf.default_policy.unwrap_or_default() == dto::TargetTlsPolicy::Insecure =>
+            {
+                ServerCertVerification::Insecure
+            }
+            verification => verification,
+        }
+    }
+
+    /// Returns the connector to use for a target, according to the token and to the configuration.
+    ///
+    /// The client identity is the one selected by the token if any, or the first one whose destination rules
+    /// match the target.
+    pub fn connector(
+        &self,
+        claims: &AssociationTokenClaims,
+        target: &TargetAddr,
+        server_addr: SocketAddr,
+    ) -> anyhow::Result<tokio_rustls::TlsConnector> {
+        let identity = match &claims.jet_tls_identity {
+            Some(name) => self
+                .inner
+                .identities
+                .iter()
+                .find(|(identity, _)| identity.name == *name)
+                .with_context(|| format!("unknown client identity: {name}"))
+                .map(Some)?,
+            None => self.inner.identities.iter().find(|(identity, _)| {
+                identity
+                    .destinations
+                    .iter()
+                    .any(|rule| rule.matches(target.host(), Some(server_addr.ip()), target.port()))
+            }),
+        };
+
+        if let Some((identity, _)) = identity {
+            debug!(identity = %identity.name, "Presenting client certificate");
+        }
+
+        let connectors = identity.map_or(&self.inner.anonymous, |(_, connectors)| connectors);
+
+        match self.verification(claims) {
+            ServerCertVerification::TrustAnchors => Ok(connectors.trust_anchors.clone()),
+            ServerCertVerification::Insecure => Ok(connectors.insecure.clone()),
+            ServerCertVerification::Pinned(thumbprints) => build_connector(
+                Arc::new(PinnedCertificateVerifier {
+                    thumbprints: thumbprints.to_vec(),
+                }),
+                identity.map(|(identity, _)| identity),
+            ),
+        }
+    }
 }
 
-pub async fn connect(dns_name: &str, stream: TcpStream) -> io::Result<TlsStream<TcpStream>> {
+pub async fn connect(
+    dns_name: &str,
+    stream: TcpStream,
+    connector: &tokio_rustls::TlsConnector,
+) -> io::Result<TlsStream<TcpStream>> {
     use tokio::io::AsyncWriteExt as _;
 
     let dns_name = dns_name
         .try_into()
         .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
 
-    let mut tls_stream = TLS_CONNECTOR.connect(dns_name, stream).await?;
+    let mut tls_stream = connector.connect(dns_name, stream).await?;
 
     // > To keep it simple and correct, [TlsStream] will behave like `BufWriter`.
     // > For `TlsStream<TcpStream>`, this means that data written by `poll_write`
@@ -52,6 +313,103 @@ pub async fn connect(dns_name: &str, stream: TcpStream) -> io::Result<TlsStream<
     Ok(tls_stream)
 }
 
+/// Returns the reason why the certificate of the server was rejected, if this is why the TLS handshake failed.
+pub fn rejected_certificate_error(error: &io::Error) -> Option<&rustls::CertificateError> {
+    match error.get_ref()?.downcast_ref::<rustls::Error>()? {
+        rustls::Error::InvalidCertificate(certificate_error) => Some(certificate_error),
+        _ => None,
+    }
+}
+
+/// SHA-256 thumbprint of a DER-encoded certificate
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CertThumbprint([u8; 32]);
+
+impl CertThumbprint {
+    pub fn of(certificate: &rustls::Certificate) -> Self {
+        use sha2::Digest as _;
+
+        Self(sha2::Sha256::digest(&certificate.0).into())
+    }
+}
+
+impl fmt::Display for CertThumbprint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.iter().try_for_each(|byte| write!(f, "{byte:02x}"))
+    }
+}
+
+impl core::str::FromStr for CertThumbprint {
+    type Err = anyhow::Error;
+
+    /// Parses an hexadecimal thumbprint, optionally separated by colons (e.g.: `AB:CD:…`).
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let digits = s.bytes().filter(|byte| *byte != b':').collect::<Vec<u8>>();
+
+        anyhow::ensure!(digits.iter().all(u8::is_ascii_hexdigit), "invalid hexadecimal digit");
+        anyhow::ensure!(
+            digits.len() == 64,
+            "expected 64 hexadecimal digits, got {}",
+            digits.len()
+        );
+
+        let mut thumbprint = [0; 32];
+
+        for (byte, pair) in thumbprint.iter_mut().zip(digits.chunks_exact(2)) {
+            let pair = core::str::from_utf8(pair).expect("ASCII hexadecimal digits");
+            *byte = u8::from_str_radix(pair, 16).expect("two hexadecimal digits");
+        }
+
+        Ok(Self(thumbprint))
+    }
+}
+
+impl serde::Serialize for CertThumbprint {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for CertThumbprint {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s = <std::borrow::Cow<'de, str>>::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+struct PinnedCertificateVerifier {
+    thumbprints: Vec<CertThumbprint>,
+}
+
+impl rustls::client::ServerCertVerifier for PinnedCertificateVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &rustls::Certificate,
+        _intermediates: &[rustls::Certificate],
+        _server_name: &rustls::ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: std::time::SystemTime,
+    ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
+        let thumbprint = CertThumbprint::of(end_entity);
+
+        if self.thumbprints.contains(&thumbprint) {
+            Ok(rustls::client::ServerCertVerified::assertion())
+        } else {
+            debug!(%thumbprint, "Server certificate doesn’t match any of the pinned thumbprints");
+            Err(rustls::Error::InvalidCertificate(
+                rustls::CertificateError::ApplicationVerificationFailure,
+            ))
+        }
+    }
+}
+
 pub enum CertificateSource {
     External {
         certificates: Vec<rustls::Certificate>,
@@ -266,3 +624,132 @@ mod danger {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use rustls::client::ServerCertVerifier as _;
+
+    use super::*;
+
+    fn claims(jet_tls_pin: &[CertThumbprint], jet_tls_insecure: bool) -> AssociationTokenClaims {
+        serde_json::from_value(serde_json::json!({
+            "jet_aid": "4c8f409a-c1a2-4cae-bda2-84c590fed618",
+            "jet_ap": "rdp",
+            "jet_cm": "fwd",
+            "dst_hst": "tcp://rdp.example.com:3389",
+            "jet_tls_pin": jet_tls_pin,
+            "jet_tls_insecure": jet_tls_insecure,
+            "exp": 0,
+        }))
+        .unwrap()
+    }
+
+    fn verify(verifier: &PinnedCertificateVerifier, certificate: &rustls::Certificate) -> Result<(), rustls::Error> {
+        let server_name = rustls::ServerName::try_from("rdp.example.com").unwrap();
+
+        verifier
+            .verify_server_cert(
+                certificate,
+                &[],
+                &server_name,
+                &mut std::iter::empty(),
+                &[],
+                std::time::SystemTime::now(),
+            )
+            .map(|_| ())
+    }
+
+    #[test]
+    fn pinned_certificate_is_accepted() {
+        let certificate = rustls::Certificate(b"pinned certificate".to_vec());
+        let verifier = PinnedCertificateVerifier {
+            thumbprints: vec![
+                CertThumbprint::of(&rustls::Certificate(b"other".to_vec())),
+                CertThumbprint::of(&certificate),
+            ],
+        };
+
+        verify(&verifier, &certificate).unwrap();
+    }
+
+    #[test]
+    fn certificate_not_pinned_is_rejected() {
+        let verifier = PinnedCertificateVerifier {
+            thumbprints: vec![CertThumbprint::of(&rustls::Certificate(b"pinned certificate".to_vec()))],
+        };
+
+        let error = verify(&verifier, &rustls::Certificate(b"other certificate".to_vec())).unwrap_err();
+
+        assert_eq!(
+            error,
+            rustls::Error::InvalidCertificate(rustls::CertificateError::ApplicationVerificationFailure)
+        );
+    }
+
+    #[test]
+    fn pinned_thumbprints_take_precedence_over_insecure() {
+        let thumbprint = CertThumbprint::of(&rustls::Certificate(b"pinned certificate".to_vec()));
+        let claims = claims(&[thumbprint], true);
+
+        assert!(matches!(
+            ServerCertVerification::from_claims(&claims),
+            ServerCertVerification::Pinned([pinned]) if *pinned == thumbprint
+        ));
+    }
+
+    #[test]
+    fn insecure_default_policy() {
+        let conf = dto::TargetTlsConf {
+            default_policy: Some(dto::TargetTlsPolicy::Insecure),
+            ..Default::default()
+        };
+        let target_tls = TargetTls::from_dto(&conf).unwrap();
+
+        assert!(matches!(
+            target_tls.verification(&claims(&[], false)),
+            ServerCertVerification::Insecure
+        ));
+
+        // Tokens pinning the certificate are still verified.
+        let thumbprint = CertThumbprint::of(&rustls::Certificate(b"pinned certificate".to_vec()));
+        assert!(matches!(
+            target_tls.verification(&claims(&[thumbprint], false)),
+            ServerCertVerification::Pinned(_)
+        ));
+    }
+
+    #[test]
+    fn verified_by_default() {
+        let target_tls = TargetTls::default();
+
+        assert!(matches!(
+            target_tls.verification(&claims(&[], false)),
+            ServerCertVerification::TrustAnchors
+        ));
+        assert!(matches!(
+            target_tls.verification(&claims(&[], true)),
+            ServerCertVerification::Insecure
+        ));
+    }
+
+    #[test]
+    fn rejected_certificate_error_only_matches_invalid_certificates() {
+        let rejected = io::Error::new(
+            io::ErrorKind::InvalidData,
+            rustls::Error::InvalidCertificate(rustls::CertificateError::UnknownIssuer),
+        );
+        assert_eq!(
+            rejected_certificate_error(&rejected),
+            Some(&rustls::CertificateError::UnknownIssuer)
+        );
+
+        let alert = io::Error::new(
+            io::ErrorKind::InvalidData,
+            rustls::Error::AlertReceived(rustls::AlertDescription::HandshakeFailure),
+        );
+        assert_eq!(rejected_certificate_error(&alert), None);
+
+        let refused = io::Error::from(io::ErrorKind::ConnectionRefused);
+        assert_eq!(rejected_certificate_error(&refused), None);
+    }
+}
@@ -17,6 +17,8 @@ use zeroize::Zeroize;
 
 use crate::recording::ActiveRecordings;
 use crate::target_addr::TargetAddr;
+use crate::target_pool::SelectionStrategy;
+use crate::tls::CertThumbprint;
 
 pub const MAX_SUBKEY_TOKEN_VALIDITY_DURATION_SECS: i64 = 60 * 60 * 2; // 2 hours
 
@@ -364,9 +366,46 @@ pub struct AssociationTokenClaims {
     /// Filtering Policy
     pub jet_flt: bool,
 
+    /// Capture Policy
+    ///
+    /// When true, the traffic of the session is captured in a PCAPNG file.
+    pub jet_cap: bool,
+
+    /// PROXY protocol Policy
+    ///
+    /// When true, a PROXY protocol header advertising the client address is sent to the target.
+    pub jet_pp: bool,
+
+    /// Target selection strategy
+    ///
+    /// When set, the targets are not tried strictly in order, and the targets which recently failed are tried last.
+    pub jet_lb: Option<SelectionStrategy>,
+
+    /// Pinned certificates of the target
+    ///
+    /// When not empty, the certificate presented by the target must match one of these SHA-256 thumbprints, and the
+    /// certificate chain is not verified.
+    pub jet_tls_pin: Vec<CertThumbprint>,
+
+    /// Certificate verification opt-out
+    ///
+    /// When true, the certificate presented by the target is not verified.
+    pub jet_tls_insecure: bool,
+
+    /// Client identity presented to the target
+    ///
+    /// Name of one of the client identities configured for the outbound TLS connections, overriding the one selected
+    /// by the destination rules.
+    pub jet_tls_identity: Option<String>,
+
     /// Max session duration
     pub jet_ttl: SessionTtl,
 
+    /// JWT "Subject" claim.
+    ///
+    /// Identifies the user on whose behalf the session is established, if known.
+    pub sub: Option<String>,
+
     /// JWT expiration time claim.
     ///
     /// We need this to build our token invalidation cache.
@@ -396,6 +435,8 @@ pub enum AccessScope {
     SessionsRead,
     #[serde(rename = "gateway.session.terminate")]
     SessionTerminate,
+    #[serde(rename = "gateway.session.capture")]
+    SessionCapture,
     #[serde(rename = "gateway.associations.read")]
     AssociationsRead,
     #[serde(rename = "gateway.diagnostics.read")]
@@ -454,6 +495,16 @@ pub struct JmuxTokenClaims {
     /// Max duration
     pub jet_ttl: SessionTtl,
 
+    /// Capture Policy
+    ///
+    /// When true, the traffic of the session is captured in a PCAPNG file.
+    pub jet_cap: bool,
+
+    /// JWT "Subject" claim.
+    ///
+    /// Identifies the user on whose behalf the session is established, if known.
+    pub sub: Option<String>,
+
     /// JWT expiration time claim.
     pub exp: i64,
 
@@ -1163,7 +1214,21 @@ mod serde_impl {
         #[serde(default)]
         jet_flt: bool,
         #[serde(default)]
+        jet_cap: bool,
+        #[serde(default)]
+        jet_pp: bool,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        jet_lb: Option<SelectionStrategy>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        jet_tls_pin: Vec<CertThumbprint>,
+        #[serde(default)]
+        jet_tls_insecure: bool,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        jet_tls_identity: Option<String>,
+        #[serde(default)]
         jet_ttl: SessionTtl,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        sub: Option<String>,
         exp: i64,
         jti: Option<Uuid>, // DVLS up to 2022.1.9 do not generate this claim.
     }
@@ -1180,6 +1245,10 @@ mod serde_impl {
         jet_aid: Uuid,
         #[serde(default)]
         jet_ttl: SessionTtl,
+        #[serde(default)]
+        jet_cap: bool,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        sub: Option<String>,
         exp: i64,
         jti: Uuid,
     }
@@ -1233,7 +1302,14 @@ mod serde_impl {
                 },
                 jet_rec: self.jet_rec,
                 jet_flt: self.jet_flt,
+                jet_cap: self.jet_cap,
+                jet_pp: self.jet_pp,
+                jet_lb: self.jet_lb,
+                jet_tls_pin: self.jet_tls_pin.clone(),
+                jet_tls_insecure: self.jet_tls_insecure,
+                jet_tls_identity: self.jet_tls_identity.clone(),
                 jet_ttl: self.jet_ttl,
+                sub: self.sub.clone(),
                 exp: self.exp,
                 jti: self.jti,
             }
@@ -1255,8 +1331,17 @@ mod serde_impl {
                     dst_alt,
                     creds,
                 } => {
-                    let primary_target =
-                        TargetAddr::parse(&dst_hst, claims.jet_ap.known_default_port()).map_err(de::Error::custom)?;
+                    let parse_target = |target: &str| {
+                        // The port of a target pool reference is not used, and is therefore optional.
+                        let default_port = claims
+                            .jet_ap
+                            .known_default_port()
+                            .or_else(|| crate::target_pool::is_pool_reference(target).then_some(0));
+
+                        TargetAddr::parse(target, default_port).map_err(de::Error::custom)
+                    };
+
+                    let primary_target = parse_target(&dst_hst)?;
 
                     let mut targets = NonEmpty {
                         head: primary_target,
@@ -1264,8 +1349,7 @@ mod serde_impl {
                     };
 
                     for alt in dst_alt {
-                        let alt =
-                            TargetAddr::parse(&alt, claims.jet_ap.known_default_port()).map_err(de::Error::custom)?;
+                        let alt = parse_target(&alt)?;
                         targets.push(alt);
                     }
 
@@ -1279,7 +1363,14 @@ mod serde_impl {
                 jet_cm,
                 jet_rec: claims.jet_rec,
                 jet_flt: claims.jet_flt,
+                jet_cap: claims.jet_cap,
+                jet_pp: claims.jet_pp,
+                jet_lb: claims.jet_lb,
+                jet_tls_pin: claims.jet_tls_pin,
+                jet_tls_insecure: claims.jet_tls_insecure,
+                jet_tls_identity: claims.jet_tls_identity,
                 jet_ttl: claims.jet_ttl,
+                sub: claims.sub,
                 exp: claims.exp,
                 jti: claims.jti,
             })
@@ -1302,6 +1393,8 @@ mod serde_impl {
                 jet_ap: self.jet_ap.clone(),
                 jet_aid: self.jet_aid,
                 jet_ttl: self.jet_ttl,
+                jet_cap: self.jet_cap,
+                sub: self.sub.clone(),
                 exp: self.exp,
                 jti: self.jti,
             }
@@ -1337,6 +1430,8 @@ mod serde_impl {
                 hosts,
                 jet_ap,
                 jet_ttl: claims.jet_ttl,
+                jet_cap: claims.jet_cap,
+                sub: claims.sub,
                 exp: claims.exp,
                 jti: claims.jti,
             });
@@ -0,0 +1,239 @@
+//! Forwarding of UDP datagrams over WebSocket.
+//!
+//! Each WebSocket binary message carries exactly one datagram, in both directions. A socket is bound for each
+//! session, and only the datagrams coming from the target are relayed back to the client. The session ends when
+//! no datagram is exchanged for a while.
+
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::pin;
+use std::time::Duration;
+
+use anyhow::Context as _;
+use axum::extract::ws;
+use futures::{Sink, SinkExt as _, Stream, StreamExt as _};
+use nonempty::NonEmpty;
+use tokio::net::UdpSocket;
+use tokio::time::Instant;
+
+use crate::config::OutboundConf;
+use crate::target_addr::TargetAddr;
+use crate::utils;
+
+/// Scheme of the targets reached over UDP (e.g.: `udp://10.0.0.53:53`)
+pub const UDP_SCHEME: &str = "udp";
+
+/// Largest payload of a UDP datagram over IPv4
+const MAX_DATAGRAM_SIZE: usize = 65507;
+
+/// Binds a socket for the first target whose address is allowed by the egress policy.
+///
+/// The resolved addresses of a target are tried in order, until a socket can be bound for the address family.
+///
+/// Unlike TCP connections, datagrams are never sent through the upstream proxies.
+pub async fn bind_for_target<'a>(
+    targets: &'a NonEmpty<TargetAddr>,
+    conf: &OutboundConf,
+) -> anyhow::Result<(UdpSocket, SocketAddr, &'a TargetAddr)> {
+    let mut last_error = None;
+
+    for target in targets.iter() {
+        match bind_for(target, conf).await {
+            Ok((socket, target_addr)) => return Ok((socket, target_addr, target)),
+            Err(error) => {
+                debug!(%target, error = format!("{error:#}"), "Target discarded");
+                last_error = Some(error.context(format!("{target} failed")));
+            }
+        }
+    }
+
+    Err(last_error.expect("at least one target"))
+}
+
+async fn bind_for(target: &TargetAddr, conf: &OutboundConf) -> anyhow::Result<(UdpSocket, SocketAddr)> {
+    anyhow::ensure!(
+        target.scheme().eq_ignore_ascii_case(UDP_SCHEME),
+        "unexpected scheme: {}",
+        target.scheme()
+    );
+
+    let addrs = tokio::time::timeout(conf.connect_timeout, utils::resolve_allowed(target, conf))
+        .await
+        .with_context(|| format!("name resolution timed out after {:?}", conf.connect_timeout))??;
+
+    let mut last_error = None;
+
+    for target_addr in addrs {
+        // The resolved addresses were filtered already, but the address actually used is checked once more.
+        if let Err(denied) = conf.egress.check(target.host(), target_addr) {
+            last_error = Some(anyhow::Error::new(denied));
+            continue;
+        }
+
+        let bind_addr = if target_addr.is_ipv4() {
+            SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))
+        } else {
+            SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0))
+        };
+
+        match UdpSocket::bind(bind_addr).await {
+            Ok(socket) => return Ok((socket, target_addr)),
+            Err(error) => {
+                debug!(%target_addr, %error, "Failed to bind UDP socket for this address");
+                last_error = Some(anyhow::Error::new(error).context("failed to bind UDP socket"));
+            }
+        }
+    }
+
+    Err(last_error.unwrap_or_else(|| anyhow::anyhow!("no address resolved")))
+}
+
+/// Relays the datagrams between the client and the target.
+///
+/// Returns when the client closes the WebSocket, or when no datagram was exchanged for `idle_timeout`.
+pub async fn relay<St, Si, E>(
+    client_rx: St,
+    mut client_tx: Si,
+    socket: &UdpSocket,
+    target_addr: SocketAddr,
+    idle_timeout: Duration,
+) -> anyhow::Result<()>
+where
+    St: Stream<Item = Result<ws::Message, E>>,
+    E: std::error::Error + Send + Sync + 'static,
+    Si: Sink<ws::Message> + Unpin,
+    Si::Error: std::error::Error + Send + Sync + 'static,
+{
+    let mut client_rx = pin!(client_rx);
+    let mut idle = pin!(tokio::time::sleep(idle_timeout));
+    let mut buf = vec![0; usize::from(u16::MAX)];
+
+    loop {
+        tokio::select! {
+            message = client_rx.next() => {
+                let datagram = match message.transpose().context("WebSocket error")? {
+                    Some(ws::Message::Binary(datagram)) => datagram,
+                    Some(ws::Message::Close(_)) | None => {
+                        debug!("Client left");
+                        return Ok(());
+                    }
+                    Some(ws::Message::Text(_)) => {
+                        debug!("Discarded a text message");
+                        continue;
+                    }
+                    Some(ws::Message::Ping(_) | ws::Message::Pong(_)) => continue,
+                };
+
+                if datagram.len() > MAX_DATAGRAM_SIZE {
+                    warn!(size = datagram.len(), "Discarded an oversized datagram");
+                    continue;
+                }
+
+                socket
+                    .send_to(&datagram, target_addr)
+                    .await
+                    .context("failed to send datagram to the target")?;
+
+                idle.as_mut().reset(Instant::now() + idle_timeout);
+            }
+            result = socket.recv_from(&mut buf) => {
+                let (len, source_addr) = match result {
+                    Ok(received) => received,
+                    // On Windows, an ICMP "port unreachable" message is reported by the next receive operation.
+                    Err(error) if error.kind() == io::ErrorKind::ConnectionReset => {
+                        debug!(%error, "Target unreachable");
+                        continue;
+                    }
+                    Err(error) => return Err(anyhow::Error::new(error).context("failed to receive datagram")),
+                };
+
+                if source_addr != target_addr {
+                    debug!(%source_addr, "Discarded a datagram from an unexpected source");
+                    continue;
+                }
+
+                client_tx
+                    .send(ws::Message::Binary(buf[..len].to_vec()))
+                    .await
+                    .context("failed to send datagram to the client")?;
+
+                idle.as_mut().reset(Instant::now() + idle_timeout);
+            }
+            () = &mut idle => {
+                debug!("No datagram exchanged for {idle_timeout:?}");
+
+                let _ = client_tx
+                    .send(ws::Message::Close(Some(ws::CloseFrame {
+                        code: ws::close_code::NORMAL,
+                        reason: "idle timeout".into(),
+                    })))
+                    .await;
+
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::channel::mpsc;
+
+    fn binary(payload: &[u8]) -> Result<ws::Message, axum::Error> {
+        Ok(ws::Message::Binary(payload.to_vec()))
+    }
+
+    #[tokio::test]
+    async fn relay_datagrams() {
+        let target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        let intruder = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let socket_addr = socket.local_addr().unwrap();
+
+        let (client_tx, mut gateway_rx) = mpsc::unbounded();
+        let client_rx = futures::stream::iter([binary(b"ping")]).chain(futures::stream::pending());
+
+        let relay = relay(client_rx, client_tx, &socket, target_addr, Duration::from_secs(5));
+
+        let target_side = async {
+            let mut buf = [0; 16];
+            let (len, gateway_addr) = target.recv_from(&mut buf).await.unwrap();
+            assert_eq!(&buf[..len], b"ping");
+
+            intruder.send_to(b"intruder", socket_addr).await.unwrap();
+            target.send_to(b"pong", gateway_addr).await.unwrap();
+
+            match gateway_rx.next().await {
+                Some(ws::Message::Binary(datagram)) => assert_eq!(datagram, b"pong"),
+                unexpected => panic!("unexpected message: {unexpected:?}"),
+            }
+        };
+
+        tokio::select! {
+            result = relay => panic!("relay ended early: {result:?}"),
+            () = target_side => {}
+        }
+    }
+
+    #[tokio::test]
+    async fn idle_timeout() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (client_tx, mut gateway_rx) = mpsc::unbounded();
+        let client_rx = futures::stream::pending::<Result<ws::Message, axum::Error>>();
+
+        relay(
+            client_rx,
+            client_tx,
+            &socket,
+            "127.0.0.1:9".parse().unwrap(),
+            Duration::from_millis(50),
+        )
+        .await
+        .unwrap();
+
+        assert!(matches!(gateway_rx.next().await, Some(ws::Message::Close(_))));
+    }
+}
@@ -0,0 +1,338 @@
+//! Upstream proxies (SOCKS5 or HTTP CONNECT) through which some destinations are reached.
+//!
+//! Rules are evaluated in order, and the first matching one wins. Host rules are matched before name
+//! resolution, and the destination is then resolved by the proxy itself. Network rules are matched against
+//! the address of the destination, which is resolved by the Gateway when required.
+
+use std::io;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use anyhow::Context as _;
+use ipnet::IpNet;
+use proxy_types::DestAddr;
+use tap::prelude::*;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use url::Url;
+
+use crate::config::dto;
+
+const SOCKS5_DEFAULT_PORT: u16 = 1080;
+
+/// Ordered list of the rules selecting an upstream proxy for a destination.
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+pub struct UpstreamProxyRules {
+    rules: Vec<UpstreamProxyRule>,
+}
+
+impl UpstreamProxyRules {
+    pub fn from_dto(value: &[dto::UpstreamProxyConf]) -> anyhow::Result<Self> {
+        let rules = value
+            .iter()
+            .enumerate()
+            .map(|(idx, rule)| UpstreamProxyRule::from_dto(rule).with_context(|| format!("rule #{idx}")))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        Ok(Self { rules })
+    }
+
+    /// Finds the upstream proxy for a destination which is not resolved yet.
+    ///
+    /// Only host rules are considered, network rules can’t be evaluated before name resolution.
+    pub fn find_by_host(&self, host: &str) -> Option<&UpstreamProxy> {
+        self.rules
+            .iter()
+            .find(|rule| match &rule.destination {
+                ProxiedDestination::Host(pattern) => crate::utils::wildcard_host_match(pattern, host),
+                ProxiedDestination::Network(_) => false,
+            })
+            .map(|rule| &rule.proxy)
+    }
+
+    /// Finds the upstream proxy for a resolved destination.
+    ///
+    /// Only network rules are considered, host rules are expected to be evaluated before name resolution.
+    pub fn find_by_ip(&self, ip: IpAddr) -> Option<&UpstreamProxy> {
+        let ip = crate::egress::canonical_ip(ip);
+
+        self.rules
+            .iter()
+            .find(|rule| match &rule.destination {
+                ProxiedDestination::Network(network) => network.contains(&ip),
+                ProxiedDestination::Host(_) => false,
+            })
+            .map(|rule| &rule.proxy)
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+struct UpstreamProxyRule {
+    destination: ProxiedDestination,
+    proxy: UpstreamProxy,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+enum ProxiedDestination {
+    Network(IpNet),
+    /// Host name, possibly containing wildcards (e.g.: `*.example.com`)
+    Host(String),
+}
+
+impl UpstreamProxyRule {
+    fn from_dto(value: &dto::UpstreamProxyConf) -> anyhow::Result<Self> {
+        let destination = match (&value.cidr, &value.host) {
+            (Some(_), Some(_)) => anyhow::bail!("Cidr and Host can’t be both specified"),
+            (None, None) => anyhow::bail!("either Cidr or Host must be specified"),
+            (Some(cidr), None) => cidr
+                .parse::<IpNet>()
+                .or_else(|_| cidr.parse::<IpAddr>().map(IpNet::from))
+                .with_context(|| format!("invalid CIDR: {cidr}"))?
+                .pipe(ProxiedDestination::Network),
+            (None, Some(host)) => ProxiedDestination::Host(host.clone()),
+        };
+
+        let proxy = UpstreamProxy::from_dto(value)?;
+
+        Ok(Self { destination, proxy })
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum UpstreamProxyKind {
+    Socks5,
+    Http,
+}
+
+/// An upstream proxy, and the credentials to authenticate with
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct UpstreamProxy {
+    pub kind: UpstreamProxyKind,
+    pub host: String,
+    pub port: u16,
+    credentials: Option<(String, dto::Password)>,
+}
+
+impl UpstreamProxy {
+    fn from_dto(value: &dto::UpstreamProxyConf) -> anyhow::Result<Self> {
+        let url = Url::parse(&value.url).with_context(|| format!("invalid proxy URL: {}", value.url))?;
+
+        let (kind, default_port) = match url.scheme() {
+            "socks" | "socks5" => (UpstreamProxyKind::Socks5, SOCKS5_DEFAULT_PORT),
+            "http" => (UpstreamProxyKind::Http, 80),
+            scheme => anyhow::bail!("unsupported proxy scheme: {scheme}"),
+        };
+
+        let host = url
+            .host_str()
+            .context("proxy URL is missing the host")?
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_owned();
+
+        let port = url.port().unwrap_or(default_port);
+
+        let credentials = match (&value.username, &value.password) {
+            (Some(username), Some(password)) => Some((username.clone(), password.clone())),
+            (None, None) => None,
+            _ => anyhow::bail!("Username and Password must be both specified"),
+        };
+
+        Ok(Self {
+            kind,
+            host,
+            port,
+            credentials,
+        })
+    }
+
+    /// Connects to `dest` through this proxy, returning the tunneled stream.
+    pub async fn connect(&self, dest: DestAddr, attempt_timeout: Duration) -> anyhow::Result<TcpStream> {
+        let fut = async {
+            let stream = TcpStream::connect((self.host.as_str(), self.port))
+                .await
+                .context("failed to connect to the upstream proxy")?;
+
+            match self.kind {
+                UpstreamProxyKind::Socks5 => {
+                    let stream = match &self.credentials {
+                        Some((username, password)) => {
+                            proxy_socks::Socks5Stream::connect_with_password(
+                                stream,
+                                dest,
+                                username.as_str(),
+                                password.get(),
+                            )
+                            .await
+                        }
+                        None => proxy_socks::Socks5Stream::connect(stream, dest).await,
+                    }
+                    .context("SOCKS5 handshake failed")?;
+
+                    Ok(stream.into_inner())
+                }
+                UpstreamProxyKind::Http => {
+                    // The target may speak first (e.g.: SSH banner), and its data must not be consumed along the
+                    // CONNECT response, since only the raw TCP stream is handed over.
+                    let stream = ByteByByteReader(stream);
+
+                    let stream = match &self.credentials {
+                        Some((username, password)) => {
+                            proxy_http::ProxyStream::connect_with_basic_auth(stream, dest, username, password.get())
+                                .await
+                        }
+                        None => proxy_http::ProxyStream::connect(stream, dest).await,
+                    }
+                    .context("HTTP CONNECT request failed")?;
+
+                    let (stream, leftover) = stream.into_parts();
+                    debug_assert!(leftover.is_empty());
+
+                    Ok(stream.0)
+                }
+            }
+        };
+
+        tokio::time::timeout(attempt_timeout, fut)
+            .await
+            .context("connection through the upstream proxy timed out")?
+    }
+}
+
+/// Reads at most one byte at a time, so that nothing past the CONNECT response is read.
+struct ByteByByteReader(TcpStream);
+
+impl AsyncRead for ByteByByteReader {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        if buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+
+        let mut byte = [0u8; 1];
+        let mut one_byte_buf = ReadBuf::new(&mut byte);
+
+        ready!(Pin::new(&mut self.0).poll_read(cx, &mut one_byte_buf))?;
+        buf.put_slice(one_byte_buf.filled());
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for ByteByByteReader {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+
+impl std::fmt::Display for UpstreamProxy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let scheme = match self.kind {
+            UpstreamProxyKind::Socks5 => "socks5",
+            UpstreamProxyKind::Http => "http",
+        };
+
+        if self.host.contains(':') {
+            write!(f, "{scheme}://[{}]:{}", self.host, self.port)
+        } else {
+            write!(f, "{scheme}://{}:{}", self.host, self.port)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(cidr: Option<&str>, host: Option<&str>, url: &str) -> dto::UpstreamProxyConf {
+        dto::UpstreamProxyConf {
+            cidr: cidr.map(str::to_owned),
+            host: host.map(str::to_owned),
+            url: url.to_owned(),
+            username: None,
+            password: None,
+        }
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let rules = UpstreamProxyRules::from_dto(&[
+            rule(Some("10.1.0.0/16"), None, "http://proxy-a.corp.local:3128"),
+            rule(Some("10.0.0.0/8"), None, "socks5://proxy-b.corp.local"),
+            rule(None, Some("*.corp.local"), "socks5://proxy-c.corp.local:1081"),
+        ])
+        .unwrap();
+
+        let proxy = rules.find_by_ip("10.1.2.3".parse().unwrap()).unwrap();
+        assert_eq!(proxy.kind, UpstreamProxyKind::Http);
+        assert_eq!(proxy.host, "proxy-a.corp.local");
+        assert_eq!(proxy.port, 3128);
+
+        let proxy = rules.find_by_ip("::ffff:10.2.0.1".parse().unwrap()).unwrap();
+        assert_eq!(proxy.kind, UpstreamProxyKind::Socks5);
+        assert_eq!(proxy.port, SOCKS5_DEFAULT_PORT);
+
+        let proxy = rules.find_by_host("rdp.corp.local").unwrap();
+        assert_eq!(proxy.host, "proxy-c.corp.local");
+
+        assert!(rules.find_by_ip("192.168.1.1".parse().unwrap()).is_none());
+        assert!(rules.find_by_host("rdp.example.com").is_none());
+    }
+
+    #[test]
+    fn invalid_rules_are_rejected() {
+        assert!(UpstreamProxyRule::from_dto(&rule(None, None, "socks5://proxy")).is_err());
+        assert!(UpstreamProxyRule::from_dto(&rule(Some("10.0.0.0/8"), Some("host"), "socks5://proxy")).is_err());
+        assert!(UpstreamProxyRule::from_dto(&rule(Some("10.0.0.0/8"), None, "https://proxy")).is_err());
+
+        let mut missing_password = rule(Some("10.0.0.0/8"), None, "socks5://proxy");
+        missing_password.username = Some("user".to_owned());
+        assert!(UpstreamProxyRule::from_dto(&missing_password).is_err());
+    }
+
+    #[tokio::test]
+    async fn denied_destination_is_refused_before_dialing_the_proxy() {
+        use crate::egress::{EgressDenied, EgressPolicy};
+        use crate::target_addr::TargetAddr;
+
+        let proxy_listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = proxy_listener.local_addr().unwrap();
+
+        let conf = crate::config::OutboundConf {
+            egress: EgressPolicy::from_dto(&dto::EgressPolicyConf {
+                allow: Vec::new(),
+                deny: vec![dto::DestinationRuleConf {
+                    cidr: Some("0.0.0.0/0".to_owned()),
+                    host: None,
+                    ports: Some("22".to_owned()),
+                }],
+            })
+            .unwrap(),
+            proxies: UpstreamProxyRules::from_dto(&[rule(
+                None,
+                Some("*.unresolvable.invalid"),
+                &format!("http://{proxy_addr}"),
+            )])
+            .unwrap(),
+            ..Default::default()
+        };
+
+        let dest = TargetAddr::parse("tcp://ssh.unresolvable.invalid", 22).unwrap();
+
+        let error = crate::utils::tcp_connect(&dest, &conf).await.unwrap_err();
+        assert!(EgressDenied::is_cause_of(&error));
+
+        // No connection was ever opened to the proxy.
+        let accepted = tokio::time::timeout(Duration::from_millis(100), proxy_listener.accept()).await;
+        assert!(accepted.is_err());
+    }
+}
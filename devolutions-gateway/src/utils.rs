@@ -1,37 +1,191 @@
 use anyhow::Context as _;
-use std::fmt;
+use futures::stream::{FuturesUnordered, StreamExt as _};
+use proxy_types::DestAddr;
 use std::net::SocketAddr;
-use tokio::net::{lookup_host, TcpStream};
+use std::{fmt, io};
+use tokio::net::TcpStream;
 use url::Url;
 
+use crate::config::OutboundConf;
 use crate::target_addr::TargetAddr;
+use crate::upstream_proxy::UpstreamProxy;
 
-pub async fn tcp_connect(dest: &TargetAddr) -> anyhow::Result<(TcpStream, SocketAddr)> {
-    const CONNECTION_TIMEOUT: tokio::time::Duration = tokio::time::Duration::from_secs(10);
+/// Resolves the destination, keeping only the addresses allowed by the egress policy.
+///
+/// An [`EgressDenied`](crate::egress::EgressDenied) error is returned when all the resolved addresses are denied.
+pub async fn resolve_allowed(dest: &TargetAddr, conf: &OutboundConf) -> anyhow::Result<Vec<SocketAddr>> {
+    let addrs = conf
+        .resolver
+        .resolve(dest)
+        .await
+        .context("failed to lookup destination address")?;
 
+    let mut allowed = Vec::new();
+    let mut first_denial = None;
+
+    for addr in addrs {
+        match conf.egress.check(dest.host(), addr) {
+            Ok(()) => allowed.push(addr),
+            Err(denied) => {
+                warn!(resolved = %addr, destination = %dest, "Resolved address denied by the egress policy");
+                first_denial.get_or_insert(denied);
+            }
+        }
+    }
+
+    match first_denial {
+        Some(denied) if allowed.is_empty() => Err(anyhow::Error::new(denied)),
+        _ => Ok(allowed),
+    }
+}
+
+/// Connects to the destination, racing the resolved addresses as described in RFC 8305 (Happy Eyeballs).
+///
+/// Address families are interleaved, starting with IPv6, and a new attempt is started each time the previous one
+/// fails or when the attempt delay elapses. The first established connection wins and the pending attempts are
+/// cancelled. The winning address and the number of failed attempts are recorded in the `resolved` and
+/// `nb_failed_attempts` fields of the current span, when it declares them.
+///
+/// Addresses denied by the egress policy are never attempted.
+///
+/// Destinations matching an upstream proxy rule are reached through that proxy. When the proxy is selected by
+/// host name, the destination is resolved by the proxy itself, and the returned address is the one of the proxy.
+pub async fn tcp_connect(dest: &TargetAddr, conf: &OutboundConf) -> anyhow::Result<(TcpStream, SocketAddr)> {
     let fut = async move {
-        let addrs = lookup_host(dest.as_addr())
-            .await
-            .context("failed to lookup destination address")?;
+        if dest.host_ip().is_none() {
+            if let Some(proxy) = conf.proxies.find_by_host(dest.host()) {
+                return connect_through_proxy_unresolved(dest, proxy, conf).await;
+            }
+        }
+
+        let addrs = resolve_allowed(dest, conf).await?;
+        race_connections(dest, interleave_address_families(addrs), conf).await
+    };
+
+    let result = tokio::time::timeout(conf.connect_timeout, fut)
+        .await
+        .with_context(|| format!("connection timed out after {:?}", conf.connect_timeout))??;
+
+    Ok(result)
+}
+
+async fn connect_through_proxy_unresolved(
+    dest: &TargetAddr,
+    proxy: &UpstreamProxy,
+    conf: &OutboundConf,
+) -> anyhow::Result<(TcpStream, SocketAddr)> {
+    // Checked before dialing the proxy. Since the address is unknown, any network deny rule matching the port applies.
+    conf.egress.check_unresolved(dest.host(), dest.port())?;
+
+    debug!(destination = %dest, %proxy, "Connect through an upstream proxy");
 
-        let mut last_err = None;
+    let stream = proxy
+        .connect(
+            DestAddr::Domain(dest.host().to_owned(), dest.port()),
+            conf.attempt_timeout,
+        )
+        .await
+        .with_context(|| format!("failed to connect through the upstream proxy {proxy}"))?;
 
-        for addr in addrs {
-            match TcpStream::connect(addr).await {
-                Ok(stream) => return Ok((stream, addr)),
+    let proxy_addr = stream
+        .peer_addr()
+        .context("failed to retrieve the upstream proxy address")?;
+
+    Ok((stream, proxy_addr))
+}
+
+async fn race_connections(
+    dest: &TargetAddr,
+    addrs: Vec<SocketAddr>,
+    conf: &OutboundConf,
+) -> anyhow::Result<(TcpStream, SocketAddr)> {
+    let connect = |addr: SocketAddr| {
+        let attempt_timeout = conf.attempt_timeout;
+        let proxy = conf.proxies.find_by_ip(addr.ip());
+
+        async move {
+            let result = match proxy {
+                Some(proxy) => proxy
+                    .connect(DestAddr::Ip(addr), attempt_timeout)
+                    .await
+                    .with_context(|| format!("failed to connect through the upstream proxy {proxy}")),
+                None => match tokio::time::timeout(attempt_timeout, TcpStream::connect(addr)).await {
+                    Ok(result) => result.context("TcpStream::connect"),
+                    Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "connection attempt timed out"))
+                        .context("TcpStream::connect"),
+                },
+            };
+
+            (addr, result)
+        }
+    };
+
+    let mut remaining = addrs.into_iter();
+    let mut attempts = FuturesUnordered::new();
+    let mut nb_failed_attempts = 0;
+    let mut last_err = None;
+
+    loop {
+        if attempts.is_empty() {
+            match remaining.next() {
+                Some(addr) => attempts.push(connect(addr)),
+                None => break,
+            }
+        }
+
+        let attempt_delay = tokio::time::sleep(conf.attempt_delay);
+
+        tokio::select! {
+            Some((addr, result)) = attempts.next() => match result {
+                Ok(stream) => {
+                    debug!(resolved = %addr, destination = %dest, nb_failed_attempts, "Connected to a resolved address");
+                    tracing::Span::current()
+                        .record("resolved", addr.to_string())
+                        .record("nb_failed_attempts", nb_failed_attempts);
+                    return Ok((stream, addr));
+                }
                 Err(error) => {
-                    warn!(%error, resolved = %addr, destination = %dest, "Failed to connect to a resolved address");
-                    last_err = Some(anyhow::Error::new(error).context("TcpStream::connect"))
+                    warn!(error = format!("{error:#}"), resolved = %addr, destination = %dest, "Failed to connect to a resolved address");
+                    nb_failed_attempts += 1;
+                    tracing::Span::current().record("nb_failed_attempts", nb_failed_attempts);
+                    last_err = Some(error);
+
+                    // The failure of an attempt immediately starts the next one.
+                    if let Some(addr) = remaining.next() {
+                        attempts.push(connect(addr));
+                    }
+                }
+            },
+            () = attempt_delay, if remaining.len() > 0 => {
+                if let Some(addr) = remaining.next() {
+                    trace!(resolved = %addr, destination = %dest, "Start a concurrent connection attempt");
+                    attempts.push(connect(addr));
                 }
             }
         }
+    }
 
-        Err::<_, anyhow::Error>(last_err.unwrap_or_else(|| anyhow::format_err!("could not resolve to any address")))
-    };
+    Err(last_err.unwrap_or_else(|| anyhow::format_err!("could not resolve to any address")))
+}
 
-    let result = tokio::time::timeout(CONNECTION_TIMEOUT, fut).await??;
+/// Orders the addresses by alternating between address families, starting with IPv6 when available (RFC 8305).
+///
+/// The order of the addresses of a given family is preserved.
+fn interleave_address_families(addrs: impl IntoIterator<Item = SocketAddr>) -> Vec<SocketAddr> {
+    let (preferred, other): (Vec<_>, Vec<_>) = addrs.into_iter().partition(SocketAddr::is_ipv6);
 
-    Ok(result)
+    let mut preferred = preferred.into_iter();
+    let mut other = other.into_iter();
+    let mut interleaved = Vec::with_capacity(preferred.len() + other.len());
+
+    loop {
+        match (preferred.next(), other.next()) {
+            (None, None) => break,
+            (a, b) => interleaved.extend(a.into_iter().chain(b)),
+        }
+    }
+
+    interleaved
 }
 
 pub async fn successive_try<'a, F, Fut, In, Out>(
@@ -82,3 +236,104 @@ pub fn wildcard_host_match(wildcard_host: &str, actual_host: &str) -> bool {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn address_families_are_interleaved() {
+        let addrs: Vec<SocketAddr> = ["[::1]:80", "[::2]:80", "[::3]:80", "127.0.0.1:80", "127.0.0.2:80"]
+            .into_iter()
+            .map(|addr| addr.parse().unwrap())
+            .collect();
+
+        let expected: Vec<SocketAddr> = ["[::1]:80", "127.0.0.1:80", "[::2]:80", "127.0.0.2:80", "[::3]:80"]
+            .into_iter()
+            .map(|addr| addr.parse().unwrap())
+            .collect();
+
+        assert_eq!(interleave_address_families(addrs), expected);
+    }
+
+    #[test]
+    fn ipv6_addresses_are_attempted_first() {
+        let addrs: Vec<SocketAddr> = ["127.0.0.1:80", "127.0.0.2:80", "[::1]:80"]
+            .into_iter()
+            .map(|addr| addr.parse().unwrap())
+            .collect();
+
+        let expected: Vec<SocketAddr> = ["[::1]:80", "127.0.0.1:80", "127.0.0.2:80"]
+            .into_iter()
+            .map(|addr| addr.parse().unwrap())
+            .collect();
+
+        assert_eq!(interleave_address_families(addrs), expected);
+    }
+
+    #[tokio::test]
+    async fn failed_attempt_falls_back_to_next_address() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let listening_addr = listener.local_addr().unwrap();
+
+        // Bind then drop to get an address on which connections are refused.
+        let refusing_addr = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let dest = TargetAddr::parse("tcp://localhost", 80).unwrap();
+
+        let (_, addr) = race_connections(&dest, vec![refusing_addr, listening_addr], &OutboundConf::default())
+            .await
+            .unwrap();
+
+        assert_eq!(addr, listening_addr);
+    }
+
+    #[tokio::test]
+    async fn host_rule_connects_through_upstream_proxy() {
+        use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+        let proxy_listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = proxy_listener.local_addr().unwrap();
+
+        let proxy_task = tokio::spawn(async move {
+            let (mut stream, _) = proxy_listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            while !request.ends_with(b"\r\n\r\n") {
+                let mut buf = [0; 256];
+                let n = stream.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+            }
+            stream
+                .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                .await
+                .unwrap();
+            String::from_utf8(request).unwrap()
+        });
+
+        let proxies = crate::upstream_proxy::UpstreamProxyRules::from_dto(&[crate::config::dto::UpstreamProxyConf {
+            cidr: None,
+            host: Some("*.unresolvable.invalid".to_owned()),
+            url: format!("http://{proxy_addr}"),
+            username: None,
+            password: None,
+        }])
+        .unwrap();
+
+        let conf = OutboundConf {
+            proxies,
+            ..Default::default()
+        };
+
+        let dest = TargetAddr::parse("tcp://rdp.unresolvable.invalid", 3389).unwrap();
+
+        let (_, addr) = tcp_connect(&dest, &conf).await.unwrap();
+        assert_eq!(addr, proxy_addr);
+
+        let request = proxy_task.await.unwrap();
+        assert!(request.starts_with("CONNECT rdp.unresolvable.invalid:3389 HTTP/1.1\r\n"));
+    }
+}
@@ -81,6 +81,11 @@ fn hub_sample() -> Sample {
             jrl_file: None,
             plugins: None,
             recording_path: None,
+            recording_storage: None,
+            capture_path: None,
+            outbound: None,
+            proxy_protocol: None,
+            target_pools: vec![],
             sogar: None,
             ngrok: None,
             verbosity_profile: Some(VerbosityProfile::Tls),
@@ -123,6 +128,11 @@ fn legacy_sample() -> Sample {
             jrl_file: None,
             plugins: None,
             recording_path: None,
+            recording_storage: None,
+            capture_path: None,
+            outbound: None,
+            proxy_protocol: None,
+            target_pools: vec![],
             sogar: None,
             ngrok: None,
             verbosity_profile: None,
@@ -164,6 +174,11 @@ fn system_store_sample() -> Sample {
             jrl_file: None,
             plugins: None,
             recording_path: None,
+            recording_storage: None,
+            capture_path: None,
+            outbound: None,
+            proxy_protocol: None,
+            target_pools: vec![],
             sogar: None,
             ngrok: None,
             verbosity_profile: None,
@@ -230,6 +245,11 @@ fn standalone_custom_auth_sample() -> Sample {
             jrl_file: None,
             plugins: None,
             recording_path: None,
+            recording_storage: None,
+            capture_path: None,
+            outbound: None,
+            proxy_protocol: None,
+            target_pools: vec![],
             sogar: None,
             ngrok: None,
             verbosity_profile: None,
@@ -303,6 +323,11 @@ fn standalone_no_auth_sample() -> Sample {
             jrl_file: None,
             plugins: None,
             recording_path: None,
+            recording_storage: None,
+            capture_path: None,
+            outbound: None,
+            proxy_protocol: None,
+            target_pools: vec![],
             sogar: None,
             ngrok: None,
             verbosity_profile: None,
@@ -320,12 +345,560 @@ fn standalone_no_auth_sample() -> Sample {
     }
 }
 
+fn s3_recording_storage_sample() -> Sample {
+    Sample {
+        json_repr: r#"{
+            "ProvisionerPublicKeyFile": "provisioner.pem",
+            "Listeners": [],
+            "RecordingPath": "/path/to/staging",
+            "RecordingStorage": {
+                "Kind": "S3",
+                "Bucket": "recordings",
+                "Region": "us-east-1",
+                "Endpoint": "http://localhost:9000",
+                "AccessKeyId": "minioadmin",
+                "SecretAccessKey": "minioadmin",
+                "KeyPrefix": "gateway/",
+                "ForcePathStyle": true
+            }
+        }"#,
+        file_conf: ConfFile {
+            id: None,
+            hostname: None,
+            provisioner_public_key_file: Some("provisioner.pem".into()),
+            provisioner_public_key_data: None,
+            provisioner_private_key_file: None,
+            provisioner_private_key_data: None,
+            sub_provisioner_public_key: None,
+            delegation_private_key_file: None,
+            delegation_private_key_data: None,
+            tls_certificate_source: None,
+            tls_certificate_file: None,
+            tls_private_key_file: None,
+            tls_private_key_password: None,
+            tls_certificate_subject_name: None,
+            tls_certificate_store_location: None,
+            tls_certificate_store_name: None,
+            listeners: vec![],
+            subscriber: None,
+            log_file: None,
+            jrl_file: None,
+            plugins: None,
+            recording_path: Some("/path/to/staging".into()),
+            recording_storage: Some(RecordingStorageConf::S3(S3StorageConf {
+                bucket: "recordings".to_owned(),
+                region: "us-east-1".to_owned(),
+                endpoint: Some("http://localhost:9000".to_owned()),
+                access_key_id: "minioadmin".to_owned(),
+                secret_access_key: Password::from("minioadmin"),
+                key_prefix: Some("gateway/".to_owned()),
+                force_path_style: Some(true),
+                part_size: None,
+            })),
+            capture_path: None,
+            outbound: None,
+            proxy_protocol: None,
+            target_pools: vec![],
+            sogar: None,
+            ngrok: None,
+            verbosity_profile: None,
+            web_app: None,
+            debug: None,
+            rest: Default::default(),
+        },
+    }
+}
+
+fn outbound_egress_sample() -> Sample {
+    Sample {
+        json_repr: r#"{
+            "ProvisionerPublicKeyFile": "provisioner.pem",
+            "Listeners": [],
+            "Outbound": {
+                "ConnectTimeout": 15,
+                "AttemptDelay": 300,
+                "Egress": {
+                    "Allow": [
+                        { "Host": "*.corp.local", "Ports": "1-1024" }
+                    ],
+                    "Deny": [
+                        { "Cidr": "127.0.0.0/8" },
+                        { "Cidr": "169.254.169.254" }
+                    ]
+                }
+            }
+        }"#,
+        file_conf: ConfFile {
+            id: None,
+            hostname: None,
+            provisioner_public_key_file: Some("provisioner.pem".into()),
+            provisioner_public_key_data: None,
+            provisioner_private_key_file: None,
+            provisioner_private_key_data: None,
+            sub_provisioner_public_key: None,
+            delegation_private_key_file: None,
+            delegation_private_key_data: None,
+            tls_certificate_source: None,
+            tls_certificate_file: None,
+            tls_private_key_file: None,
+            tls_private_key_password: None,
+            tls_certificate_subject_name: None,
+            tls_certificate_store_location: None,
+            tls_certificate_store_name: None,
+            listeners: vec![],
+            subscriber: None,
+            log_file: None,
+            jrl_file: None,
+            plugins: None,
+            recording_path: None,
+            recording_storage: None,
+            capture_path: None,
+            outbound: Some(OutboundConf {
+                connect_timeout: Some(15),
+                attempt_timeout: None,
+                attempt_delay: Some(300),
+                udp_idle_timeout: None,
+                egress: Some(EgressPolicyConf {
+                    allow: vec![DestinationRuleConf {
+                        cidr: None,
+                        host: Some("*.corp.local".to_owned()),
+                        ports: Some("1-1024".to_owned()),
+                    }],
+                    deny: vec![
+                        DestinationRuleConf {
+                            cidr: Some("127.0.0.0/8".to_owned()),
+                            host: None,
+                            ports: None,
+                        },
+                        DestinationRuleConf {
+                            cidr: Some("169.254.169.254".to_owned()),
+                            host: None,
+                            ports: None,
+                        },
+                    ],
+                }),
+                proxies: vec![],
+                send_proxy_protocol: vec![],
+                dns: None,
+                tls: None,
+                zero_copy: None,
+            }),
+            proxy_protocol: None,
+            target_pools: vec![],
+            sogar: None,
+            ngrok: None,
+            verbosity_profile: None,
+            web_app: None,
+            debug: None,
+            rest: Default::default(),
+        },
+    }
+}
+
+fn outbound_proxies_sample() -> Sample {
+    Sample {
+        json_repr: r#"{
+            "ProvisionerPublicKeyFile": "provisioner.pem",
+            "Listeners": [],
+            "Outbound": {
+                "Proxies": [
+                    { "Host": "*.corp.local", "Url": "http://proxy.corp.local:3128" },
+                    {
+                        "Cidr": "10.10.0.0/16",
+                        "Url": "socks5://jump.corp.local:1080",
+                        "Username": "gateway",
+                        "Password": "secret"
+                    }
+                ],
+                "SendProxyProtocol": [
+                    { "Host": "bastion.corp.local", "Ports": "22" }
+                ]
+            }
+        }"#,
+        file_conf: ConfFile {
+            id: None,
+            hostname: None,
+            provisioner_public_key_file: Some("provisioner.pem".into()),
+            provisioner_public_key_data: None,
+            provisioner_private_key_file: None,
+            provisioner_private_key_data: None,
+            sub_provisioner_public_key: None,
+            delegation_private_key_file: None,
+            delegation_private_key_data: None,
+            tls_certificate_source: None,
+            tls_certificate_file: None,
+            tls_private_key_file: None,
+            tls_private_key_password: None,
+            tls_certificate_subject_name: None,
+            tls_certificate_store_location: None,
+            tls_certificate_store_name: None,
+            listeners: vec![],
+            subscriber: None,
+            log_file: None,
+            jrl_file: None,
+            plugins: None,
+            recording_path: None,
+            recording_storage: None,
+            capture_path: None,
+            outbound: Some(OutboundConf {
+                connect_timeout: None,
+                attempt_timeout: None,
+                attempt_delay: None,
+                udp_idle_timeout: None,
+                egress: None,
+                proxies: vec![
+                    UpstreamProxyConf {
+                        cidr: None,
+                        host: Some("*.corp.local".to_owned()),
+                        url: "http://proxy.corp.local:3128".to_owned(),
+                        username: None,
+                        password: None,
+                    },
+                    UpstreamProxyConf {
+                        cidr: Some("10.10.0.0/16".to_owned()),
+                        host: None,
+                        url: "socks5://jump.corp.local:1080".to_owned(),
+                        username: Some("gateway".to_owned()),
+                        password: Some(Password::from("secret")),
+                    },
+                ],
+                send_proxy_protocol: vec![DestinationRuleConf {
+                    cidr: None,
+                    host: Some("bastion.corp.local".to_owned()),
+                    ports: Some("22".to_owned()),
+                }],
+                dns: None,
+                tls: None,
+                zero_copy: None,
+            }),
+            proxy_protocol: None,
+            target_pools: vec![],
+            sogar: None,
+            ngrok: None,
+            verbosity_profile: None,
+            web_app: None,
+            debug: None,
+            rest: Default::default(),
+        },
+    }
+}
+
+fn outbound_dns_sample() -> Sample {
+    Sample {
+        json_repr: r#"{
+            "ProvisionerPublicKeyFile": "provisioner.pem",
+            "Listeners": [],
+            "Outbound": {
+                "Dns": {
+                    "Nameservers": ["10.0.0.53"],
+                    "Domains": [
+                        { "Suffix": "corp.local", "Nameservers": ["10.10.0.53", "10.10.1.53:5353"] }
+                    ],
+                    "CacheSize": 256,
+                    "SrvLookup": true
+                }
+            }
+        }"#,
+        file_conf: ConfFile {
+            id: None,
+            hostname: None,
+            provisioner_public_key_file: Some("provisioner.pem".into()),
+            provisioner_public_key_data: None,
+            provisioner_private_key_file: None,
+            provisioner_private_key_data: None,
+            sub_provisioner_public_key: None,
+            delegation_private_key_file: None,
+            delegation_private_key_data: None,
+            tls_certificate_source: None,
+            tls_certificate_file: None,
+            tls_private_key_file: None,
+            tls_private_key_password: None,
+            tls_certificate_subject_name: None,
+            tls_certificate_store_location: None,
+            tls_certificate_store_name: None,
+            listeners: vec![],
+            subscriber: None,
+            log_file: None,
+            jrl_file: None,
+            plugins: None,
+            recording_path: None,
+            recording_storage: None,
+            capture_path: None,
+            outbound: Some(OutboundConf {
+                connect_timeout: None,
+                attempt_timeout: None,
+                attempt_delay: None,
+                udp_idle_timeout: None,
+                egress: None,
+                proxies: vec![],
+                send_proxy_protocol: vec![],
+                dns: Some(DnsConf {
+                    nameservers: vec!["10.0.0.53".to_owned()],
+                    domains: vec![DnsDomainConf {
+                        suffix: "corp.local".to_owned(),
+                        nameservers: vec!["10.10.0.53".to_owned(), "10.10.1.53:5353".to_owned()],
+                    }],
+                    cache_size: Some(256),
+                    srv_lookup: Some(true),
+                }),
+                tls: None,
+                zero_copy: None,
+            }),
+            proxy_protocol: None,
+            target_pools: vec![],
+            sogar: None,
+            ngrok: None,
+            verbosity_profile: None,
+            web_app: None,
+            debug: None,
+            rest: Default::default(),
+        },
+    }
+}
+
+fn outbound_tls_sample() -> Sample {
+    Sample {
+        json_repr: r#"{
+            "ProvisionerPublicKeyFile": "provisioner.pem",
+            "Listeners": [],
+            "Outbound": {
+                "Tls": {
+                    "CaSource": "Custom",
+                    "CaBundleFile": "/path/to/ca-bundle.pem",
+                    "ClientIdentities": [
+                        {
+                            "Name": "ldaps",
+                            "CertificateFile": "/path/to/client.pem",
+                            "PrivateKeyFile": "/path/to/client.key",
+                            "Destinations": [
+                                {
+                                    "Host": "*.ad.contoso.local",
+                                    "Ports": "636"
+                                }
+                            ]
+                        }
+                    ],
+                    "DefaultPolicy": "Insecure"
+                }
+            }
+        }"#,
+        file_conf: ConfFile {
+            id: None,
+            hostname: None,
+            provisioner_public_key_file: Some("provisioner.pem".into()),
+            provisioner_public_key_data: None,
+            provisioner_private_key_file: None,
+            provisioner_private_key_data: None,
+            sub_provisioner_public_key: None,
+            delegation_private_key_file: None,
+            delegation_private_key_data: None,
+            tls_certificate_source: None,
+            tls_certificate_file: None,
+            tls_private_key_file: None,
+            tls_private_key_password: None,
+            tls_certificate_subject_name: None,
+            tls_certificate_store_location: None,
+            tls_certificate_store_name: None,
+            listeners: vec![],
+            subscriber: None,
+            log_file: None,
+            jrl_file: None,
+            plugins: None,
+            recording_path: None,
+            recording_storage: None,
+            capture_path: None,
+            outbound: Some(OutboundConf {
+                connect_timeout: None,
+                attempt_timeout: None,
+                attempt_delay: None,
+                udp_idle_timeout: None,
+                egress: None,
+                proxies: vec![],
+                send_proxy_protocol: vec![],
+                dns: None,
+                tls: Some(TargetTlsConf {
+                    ca_source: Some(TargetCaSource::Custom),
+                    ca_bundle_file: Some("/path/to/ca-bundle.pem".into()),
+                    client_identities: vec![ClientIdentityConf {
+                        name: "ldaps".to_owned(),
+                        certificate_file: "/path/to/client.pem".into(),
+                        private_key_file: Some("/path/to/client.key".into()),
+                        private_key_password: None,
+                        destinations: vec![DestinationRuleConf {
+                            cidr: None,
+                            host: Some("*.ad.contoso.local".to_owned()),
+                            ports: Some("636".to_owned()),
+                        }],
+                    }],
+                    default_policy: Some(TargetTlsPolicy::Insecure),
+                }),
+                zero_copy: None,
+            }),
+            proxy_protocol: None,
+            target_pools: vec![],
+            sogar: None,
+            ngrok: None,
+            verbosity_profile: None,
+            web_app: None,
+            debug: None,
+            rest: Default::default(),
+        },
+    }
+}
+
+fn proxy_protocol_sample() -> Sample {
+    Sample {
+        json_repr: r#"{
+            "ProvisionerPublicKeyFile": "provisioner.pem",
+            "Listeners": [
+                {
+                    "InternalUrl": "tcp://*:8181",
+                    "ExternalUrl": "tcp://*:8181"
+                }
+            ],
+            "ProxyProtocol": {
+                "TrustedSources": ["10.0.0.0/24", "192.168.1.10"]
+            }
+        }"#,
+        file_conf: ConfFile {
+            id: None,
+            hostname: None,
+            provisioner_public_key_file: Some("provisioner.pem".into()),
+            provisioner_public_key_data: None,
+            provisioner_private_key_file: None,
+            provisioner_private_key_data: None,
+            sub_provisioner_public_key: None,
+            delegation_private_key_file: None,
+            delegation_private_key_data: None,
+            tls_certificate_source: None,
+            tls_certificate_file: None,
+            tls_private_key_file: None,
+            tls_private_key_password: None,
+            tls_certificate_subject_name: None,
+            tls_certificate_store_location: None,
+            tls_certificate_store_name: None,
+            listeners: vec![ListenerConf {
+                internal_url: "tcp://*:8181".to_owned(),
+                external_url: "tcp://*:8181".to_owned(),
+            }],
+            subscriber: None,
+            log_file: None,
+            jrl_file: None,
+            plugins: None,
+            recording_path: None,
+            recording_storage: None,
+            capture_path: None,
+            outbound: None,
+            proxy_protocol: Some(ProxyProtocolConf {
+                trusted_sources: vec!["10.0.0.0/24".to_owned(), "192.168.1.10".to_owned()],
+            }),
+            target_pools: vec![],
+            sogar: None,
+            ngrok: None,
+            verbosity_profile: None,
+            web_app: None,
+            debug: None,
+            rest: Default::default(),
+        },
+    }
+}
+
+fn target_pools_sample() -> Sample {
+    Sample {
+        json_repr: r#"{
+            "ProvisionerPublicKeyFile": "provisioner.pem",
+            "Listeners": [
+                {
+                    "InternalUrl": "tcp://*:8181",
+                    "ExternalUrl": "tcp://*:8181"
+                }
+            ],
+            "TargetPools": [
+                {
+                    "Name": "rdp-farm",
+                    "Targets": ["rdp-01.example.com:3389", "rdp-02.example.com:3389"],
+                    "Strategy": "LeastConnections",
+                    "HealthCheckInterval": 10,
+                    "Cooldown": 60
+                },
+                {
+                    "Name": "ssh",
+                    "Targets": ["ssh-01.example.com:22"]
+                }
+            ]
+        }"#,
+        file_conf: ConfFile {
+            id: None,
+            hostname: None,
+            provisioner_public_key_file: Some("provisioner.pem".into()),
+            provisioner_public_key_data: None,
+            provisioner_private_key_file: None,
+            provisioner_private_key_data: None,
+            sub_provisioner_public_key: None,
+            delegation_private_key_file: None,
+            delegation_private_key_data: None,
+            tls_certificate_source: None,
+            tls_certificate_file: None,
+            tls_private_key_file: None,
+            tls_private_key_password: None,
+            tls_certificate_subject_name: None,
+            tls_certificate_store_location: None,
+            tls_certificate_store_name: None,
+            listeners: vec![ListenerConf {
+                internal_url: "tcp://*:8181".to_owned(),
+                external_url: "tcp://*:8181".to_owned(),
+            }],
+            subscriber: None,
+            log_file: None,
+            jrl_file: None,
+            plugins: None,
+            recording_path: None,
+            recording_storage: None,
+            capture_path: None,
+            outbound: None,
+            proxy_protocol: None,
+            target_pools: vec![
+                TargetPoolConf {
+                    name: "rdp-farm".to_owned(),
+                    targets: vec![
+                        "rdp-01.example.com:3389".to_owned(),
+                        "rdp-02.example.com:3389".to_owned(),
+                    ],
+                    strategy: Some(TargetSelectionStrategy::LeastConnections),
+                    health_check_interval: Some(10),
+                    cooldown: Some(60),
+                },
+                TargetPoolConf {
+                    name: "ssh".to_owned(),
+                    targets: vec!["ssh-01.example.com:22".to_owned()],
+                    strategy: None,
+                    health_check_interval: None,
+                    cooldown: None,
+                },
+            ],
+            sogar: None,
+            ngrok: None,
+            verbosity_profile: None,
+            web_app: None,
+            debug: None,
+            rest: Default::default(),
+        },
+    }
+}
+
 #[rstest]
 #[case(hub_sample())]
 #[case(legacy_sample())]
 #[case(system_store_sample())]
 #[case(standalone_custom_auth_sample())]
 #[case(standalone_no_auth_sample())]
+#[case(s3_recording_storage_sample())]
+#[case(outbound_egress_sample())]
+#[case(outbound_proxies_sample())]
+#[case(outbound_dns_sample())]
+#[case(outbound_tls_sample())]
+#[case(proxy_protocol_sample())]
+#[case(target_pools_sample())]
 fn sample_parsing(#[case] sample: Sample) {
     let from_json = serde_json::from_str::<ConfFile>(sample.json_repr)
         .unwrap()
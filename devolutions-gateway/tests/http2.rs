@@ -0,0 +1,124 @@
+use std::net::SocketAddr;
+
+use devolutions_gateway::DgwState;
+use futures::{SinkExt as _, StreamExt as _};
+use http_body_util::Empty;
+use hyper::body::Bytes;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use picky::jose::jws::JwsAlg;
+use picky::jose::jwt::CheckedJwtSig;
+use serde_json::json;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_tungstenite::tungstenite::protocol::Role;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+use uuid::Uuid;
+
+const PROVISIONER_PUBLIC_KEY: &str = "mMIIBIjANBgkqhkiG9w0BAQEFAAOCAQ8AMIIBCgKCAQEA4vuqLOkl1pWobt6su1XO9VskgCAwevEGs6kkNjJQBwkGnPKYLmNF1E/af1yCocfVn/OnPf9e4x+lXVyZ6LMDJxFxu+axdgOq3Ld392J1iAEbfvwlyRFnEXFOJNyylqg3bY6LvnWHL/XZczVdMD9xYfq2sO9bg3xjRW4s7r9EEYOFjqVT3VFznH9iWJVtcSEKukmS/3uKoO6lGhacvu0HhjXXdgq0R8zvR4XRJ9Fcnf0f9Ypoc+i6L80NVjrRCeVOH+Ld/2fA9bocpfLarcVqG3RjS+qgOtpyCc0jWVFF4zaGQ7LUDFkEIYILkICeMMn2ll29hmZNzsJzZJ9s6NocgQIDAQAB";
+
+const PROVISIONER_PRIVATE_KEY: &str = "mMIIEvAIBADANBgkqhkiG9w0BAQEFAASCBKYwggSiAgEAAoIBAQDi+6os6SXWlahu3qy7Vc71WySAIDB68QazqSQ2MlAHCQac8pguY0XUT9p/XIKhx9Wf86c9/17jH6VdXJnoswMnEXG75rF2A6rct3f3YnWIARt+/CXJEWcRcU4k3LKWqDdtjou+dYcv9dlzNV0wP3Fh+raw71uDfGNFbizuv0QRg4WOpVPdUXOcf2JYlW1xIQq6SZL/e4qg7qUaFpy+7QeGNdd2CrRHzO9HhdEn0Vyd/R/1imhz6LovzQ1WOtEJ5U4f4t3/Z8D1uhyl8tqtxWobdGNL6qA62nIJzSNZUUXjNoZDstQMWQQhgguQgJ4wyfaWXb2GZk3OwnNkn2zo2hyBAgMBAAECggEBAKCO0GOQUDmoB0rVrG2fVxPrcrhHDMQKNmljnb/Qexde5RSj7c3yXvS9v5sTvzvc9Vl9qrGKMH6MZhbSZ/RYnERIbKEzoBgQpA4YoX2WYfjgf6ilh7zg2H1YHqSokJNNTlfq2yLQU94zE6wQ9WgpmHRsOkqSJbOuizITqyj+lpGjl8dBAeOCD9HsnOGQiwsQD+joZ3yDRdFKSaBBtbklTYDyAmPvmp2G5A00UIo7KeOcNv59MPHnFBxMj0/z+QPKlqLQMsjL8vQX5DU2t/K4jdFHWGL8NZcz7KsCfh2Aa0vWEnroRzPPhKuBSBtaykbvfTcGrvRioesPq3EUdUqjQSECgYEA52UlMYeRYiTWsGq69lFWSlBjlRKhEMpg0Tp05z7J/A9X+ytB+6dZ37hk5asq84adRp7pnCEHV3SbczGq5ULFQBEqtFWPlD348zB8xxdBpAw3NAkVVDpAXBREhxXOnQm7MMmaXLH6d4Gv4kc6jKTC62w7cUUSlkIhlWSw5pSuVh0CgYEA+x5rJ4MQ6A/OKh058QY3ydRJw/sV54oxIFIIuJDw4I4eMsJ5Ht7MW5Pl1VQj+XuJRgMeqgZMQIIAcf5JNXqcesswVwdXy4awtw3TZV1Hi47Or7qHrFA/DtG4lNeDtyaWNuOtNnGw+LuqEmuu8BsWhB7yTHWJW7z+k6qO90CnArUCgYEA5ew66NwsObkhGmrzG432kCEQ0i+Qm358dWoAf0aErVERuyFgjw3a39H5b7yFETXRUTrWJa0r/lp/nBbeGLAgD2j/ZfEemc56cCrd0XXqY3c/4xSjfO3kxZnd/dxNUP06Y1/vYev3VIgonE7qfpW4mPUSm5pmvac4d5l1rahPEoECgYBUvAToRj+ULpEggNAmVjTI88sYSEcx492DzGqI7M961jm2Ywy/r+pBFHy/KS8iZd8CMtdMA+gC9Fr2HBnT49WdUaa0FxQ25vIGMrIcSAd2Pe/cOBLDwCgm9flUsAwP5wNU7ipqbp6Kr7hJkvBqsJk+Z7rWteptfC5i4XBwWe6A6QJ/Ddv+9vZe89uMdq+PThhELBHK+twZKawpKXYvzKlvPfMVisY+m9m37t7wK8PJexWOI9loVif6+ZIdWpXXntwrz94hYld/6+qK+sSt8EGmcJpAAI3zkp/ZMXhio0fy27sPaTlKlS6GNx/gPXRj6NHg/nu6lMmQ/EpLi1lyExPc8Q";
+
+fn fwd_token(state: &DgwState, session_id: Uuid, destination: &str) -> anyhow::Result<String> {
+    let conf = state.conf_handle.get_conf();
+    let provisioner_key = conf.provisioner_private_key.as_ref().expect("set below");
+
+    let now = time::OffsetDateTime::now_utc().unix_timestamp();
+
+    let claims = json!({
+        "dst_hst": destination,
+        "jet_ap": "unknown",
+        "jet_aid": session_id,
+        "jet_cm": "fwd",
+        "iat": now,
+        "nbf": now,
+        "exp": now + 60,
+        "jti": Uuid::new_v4(),
+    });
+
+    let token = CheckedJwtSig::new_with_cty(JwsAlg::RS256, "ASSOCIATION", claims).encode(provisioner_key)?;
+
+    Ok(token)
+}
+
+/// Echoes everything received on the first accepted connection.
+async fn echo_target() -> anyhow::Result<SocketAddr> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let (mut reader, mut writer) = stream.split();
+        let _ = tokio::io::copy(&mut reader, &mut writer).await;
+    });
+
+    Ok(addr)
+}
+
+#[tokio::test]
+async fn websocket_forwarding_over_http2() -> anyhow::Result<()> {
+    let config = json!({
+        "ProvisionerPublicKeyData": { "Value": PROVISIONER_PUBLIC_KEY },
+        "ProvisionerPrivateKeyData": { "Value": PROVISIONER_PRIVATE_KEY },
+        "Listeners": [
+            {
+                "InternalUrl": "tcp://*:8080",
+                "ExternalUrl": "tcp://*:8080"
+            },
+            {
+                "InternalUrl": "http://*:7171",
+                "ExternalUrl": "https://*:7171"
+            }
+        ],
+    });
+
+    // The handles are kept alive so that the session can be registered.
+    let (state, _handles) = DgwState::mock(&config.to_string())?;
+
+    let target_addr = echo_target().await?;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let server_addr = listener.local_addr()?;
+    let server_state = state.clone();
+    let server_task = tokio::spawn(async move {
+        let (stream, peer_addr) = listener.accept().await?;
+        devolutions_gateway::listener::handle_http_peer(stream, server_state, peer_addr, server_addr).await
+    });
+
+    // HTTP/2 with prior knowledge, as negotiated with ALPN on the HTTPS listeners.
+    let stream = TcpStream::connect(server_addr).await?;
+    let (mut send_request, connection) =
+        hyper::client::conn::http2::handshake(TokioExecutor::new(), TokioIo::new(stream)).await?;
+    tokio::spawn(connection);
+
+    // Extended CONNECT can only be used once the server advertised it in its SETTINGS frame.
+    let request = hyper::Request::get(format!("http://{server_addr}/jet/health")).body(Empty::<Bytes>::new())?;
+    let response = send_request.send_request(request).await?;
+    assert!(response.status().is_success());
+
+    let session_id = Uuid::new_v4();
+    let token = fwd_token(&state, session_id, &format!("tcp://{target_addr}"))?;
+
+    let request = hyper::Request::builder()
+        .method(hyper::Method::CONNECT)
+        .uri(format!("http://{server_addr}/jet/fwd/tcp/{session_id}?token={token}"))
+        .header("sec-websocket-version", "13")
+        .extension(hyper::ext::Protocol::from_static("websocket"))
+        .body(Empty::<Bytes>::new())?;
+    let response = send_request.send_request(request).await?;
+    assert_eq!(response.status(), hyper::StatusCode::OK);
+    assert_eq!(response.version(), hyper::Version::HTTP_2);
+
+    let upgraded = hyper::upgrade::on(response).await?;
+    let mut ws = WebSocketStream::from_raw_socket(TokioIo::new(upgraded), Role::Client, None).await;
+
+    ws.send(Message::Binary(b"hello over HTTP/2".to_vec())).await?;
+
+    let echoed = tokio::time::timeout(std::time::Duration::from_secs(10), ws.next())
+        .await?
+        .expect("stream closed")?;
+    assert_eq!(echoed, Message::Binary(b"hello over HTTP/2".to_vec()));
+
+    server_task.abort();
+
+    Ok(())
+}
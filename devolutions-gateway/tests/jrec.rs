@@ -0,0 +1,318 @@
+use std::net::SocketAddr;
+
+use axum::body::Body;
+use axum::extract::connect_info::MockConnectInfo;
+use axum::http::{self, Request, StatusCode};
+use axum::Router;
+use http_body_util::BodyExt as _;
+use picky::jose::jws::JwsAlg;
+use picky::jose::jwt::CheckedJwtSig;
+use serde_json::json;
+use tower::ServiceExt as _;
+use uuid::Uuid;
+
+const PROVISIONER_PUBLIC_KEY: &str = "mMIIBIjANBgkqhkiG9w0BAQEFAAOCAQ8AMIIBCgKCAQEA4vuqLOkl1pWobt6su1XO9VskgCAwevEGs6kkNjJQBwkGnPKYLmNF1E/af1yCocfVn/OnPf9e4x+lXVyZ6LMDJxFxu+axdgOq3Ld392J1iAEbfvwlyRFnEXFOJNyylqg3bY6LvnWHL/XZczVdMD9xYfq2sO9bg3xjRW4s7r9EEYOFjqVT3VFznH9iWJVtcSEKukmS/3uKoO6lGhacvu0HhjXXdgq0R8zvR4XRJ9Fcnf0f9Ypoc+i6L80NVjrRCeVOH+Ld/2fA9bocpfLarcVqG3RjS+qgOtpyCc0jWVFF4zaGQ7LUDFkEIYILkICeMMn2ll29hmZNzsJzZJ9s6NocgQIDAQAB";
+
+const PROVISIONER_PRIVATE_KEY: &str = "mMIIEvAIBADANBgkqhkiG9w0BAQEFAASCBKYwggSiAgEAAoIBAQDi+6os6SXWlahu3qy7Vc71WySAIDB68QazqSQ2MlAHCQac8pguY0XUT9p/XIKhx9Wf86c9/17jH6VdXJnoswMnEXG75rF2A6rct3f3YnWIARt+/CXJEWcRcU4k3LKWqDdtjou+dYcv9dlzNV0wP3Fh+raw71uDfGNFbizuv0QRg4WOpVPdUXOcf2JYlW1xIQq6SZL/e4qg7qUaFpy+7QeGNdd2CrRHzO9HhdEn0Vyd/R/1imhz6LovzQ1WOtEJ5U4f4t3/Z8D1uhyl8tqtxWobdGNL6qA62nIJzSNZUUXjNoZDstQMWQQhgguQgJ4wyfaWXb2GZk3OwnNkn2zo2hyBAgMBAAECggEBAKCO0GOQUDmoB0rVrG2fVxPrcrhHDMQKNmljnb/Qexde5RSj7c3yXvS9v5sTvzvc9Vl9qrGKMH6MZhbSZ/RYnERIbKEzoBgQpA4YoX2WYfjgf6ilh7zg2H1YHqSokJNNTlfq2yLQU94zE6wQ9WgpmHRsOkqSJbOuizITqyj+lpGjl8dBAeOCD9HsnOGQiwsQD+joZ3yDRdFKSaBBtbklTYDyAmPvmp2G5A00UIo7KeOcNv59MPHnFBxMj0/z+QPKlqLQMsjL8vQX5DU2t/K4jdFHWGL8NZcz7KsCfh2Aa0vWEnroRzPPhKuBSBtaykbvfTcGrvRioesPq3EUdUqjQSECgYEA52UlMYeRYiTWsGq69lFWSlBjlRKhEMpg0Tp05z7J/A9X+ytB+6dZ37hk5asq84adRp7pnCEHV3SbczGq5ULFQBEqtFWPlD348zB8xxdBpAw3NAkVVDpAXBREhxXOnQm7MMmaXLH6d4Gv4kc6jKTC62w7cUUSlkIhlWSw5pSuVh0CgYEA+x5rJ4MQ6A/OKh058QY3ydRJw/sV54oxIFIIuJDw4I4eMsJ5Ht7MW5Pl1VQj+XuJRgMeqgZMQIIAcf5JNXqcesswVwdXy4awtw3TZV1Hi47Or7qHrFA/DtG4lNeDtyaWNuOtNnGw+LuqEmuu8BsWhB7yTHWJW7z+k6qO90CnArUCgYEA5ew66NwsObkhGmrzG432kCEQ0i+Qm358dWoAf0aErVERuyFgjw3a39H5b7yFETXRUTrWJa0r/lp/nBbeGLAgD2j/ZfEemc56cCrd0XXqY3c/4xSjfO3kxZnd/dxNUP06Y1/vYev3VIgonE7qfpW4mPUSm5pmvac4d5l1rahPEoECgYBUvAToRj+ULpEggNAmVjTI88sYSEcx492DzGqI7M961jm2Ywy/r+pBFHy/KS8iZd8CMtdMA+gC9Fr2HBnT49WdUaa0FxQ25vIGMrIcSAd2Pe/cOBLDwCgm9flUsAwP5wNU7ipqbp6Kr7hJkvBqsJk+Z7rWteptfC5i4XBwWe6A6QJ/Ddv+9vZe89uMdq+PThhELBHK+twZKawpKXYvzKlvPfMVisY+m9m37t7wK8PJexWOI9loVif6+ZIdWpXXntwrz94hYld/6+qK+sSt8EGmcJpAAI3zkp/ZMXhio0fy27sPaTlKlS6GNx/gPXRj6NHg/nu6lMmQ/EpLi1lyExPc8Q";
+
+const FILE_NAME: &str = "recording-0.webm";
+
+/// Router serving a single recording file, along with a JREC pull token for it.
+struct Fixture {
+    app: Router,
+    token: String,
+    id: Uuid,
+    contents: Vec<u8>,
+}
+
+impl Fixture {
+    fn new() -> anyhow::Result<Self> {
+        let recording_path = format!("{}/jrec-{}", env!("CARGO_TARGET_TMPDIR"), Uuid::new_v4());
+
+        let config = json!({
+            "ProvisionerPublicKeyData": { "Value": PROVISIONER_PUBLIC_KEY },
+            "ProvisionerPrivateKeyData": { "Value": PROVISIONER_PRIVATE_KEY },
+            "Listeners": [
+                {
+                    "InternalUrl": "tcp://*:8080",
+                    "ExternalUrl": "tcp://*:8080"
+                },
+                {
+                    "InternalUrl": "http://*:7171",
+                    "ExternalUrl": "https://*:7171"
+                }
+            ],
+            "RecordingPath": recording_path,
+        });
+
+        let (state, _handles) = devolutions_gateway::DgwState::mock(&config.to_string())?;
+
+        let id = Uuid::new_v4();
+        let contents: Vec<u8> = (0..=255).cycle().take(1000).collect();
+
+        let recording_folder = format!("{recording_path}/{id}");
+        std::fs::create_dir_all(&recording_folder)?;
+        std::fs::write(format!("{recording_folder}/{FILE_NAME}"), &contents)?;
+
+        let token = {
+            let conf = state.conf_handle.get_conf();
+            let provisioner_key = conf.provisioner_private_key.as_ref().expect("set above");
+
+            let now = time::OffsetDateTime::now_utc().unix_timestamp();
+
+            let claims = json!({
+                "jet_aid": id,
+                "jet_rop": "pull",
+                "iat": now,
+                "nbf": now,
+                "exp": now + 60,
+                "jti": Uuid::new_v4(),
+            });
+
+            CheckedJwtSig::new_with_cty(JwsAlg::RS256, "JREC", claims).encode(provisioner_key)?
+        };
+
+        let app = devolutions_gateway::make_http_service(state)
+            .layer(MockConnectInfo(SocketAddr::from(([0, 0, 0, 0], 3000))));
+
+        Ok(Self {
+            app,
+            token,
+            id,
+            contents,
+        })
+    }
+
+    fn request(&self, method: http::Method) -> http::request::Builder {
+        Request::builder()
+            .method(method)
+            .uri(format!("/jet/jrec/pull/{}/{FILE_NAME}", self.id))
+            .header(http::header::AUTHORIZATION, format!("Bearer {}", self.token))
+    }
+
+    async fn send(&self, request: http::request::Builder) -> anyhow::Result<(http::response::Parts, Vec<u8>)> {
+        let response = self.app.clone().oneshot(request.body(Body::empty())?).await?;
+        let (parts, body) = response.into_parts();
+        let body = body.collect().await?.to_bytes();
+        Ok((parts, Vec::from(body)))
+    }
+
+    async fn etag(&self) -> anyhow::Result<String> {
+        let (parts, _) = self.send(self.request(http::Method::HEAD)).await?;
+        Ok(header(&parts, http::header::ETAG).to_owned())
+    }
+}
+
+fn header(parts: &http::response::Parts, name: http::header::HeaderName) -> &str {
+    parts.headers.get(name).unwrap().to_str().unwrap()
+}
+
+#[tokio::test]
+async fn full_file() -> anyhow::Result<()> {
+    let fixture = Fixture::new()?;
+
+    let (parts, body) = fixture.send(fixture.request(http::Method::GET)).await?;
+
+    assert_eq!(parts.status, StatusCode::OK);
+    assert_eq!(header(&parts, http::header::CONTENT_LENGTH), "1000");
+    assert_eq!(header(&parts, http::header::ACCEPT_RANGES), "bytes");
+    assert_eq!(header(&parts, http::header::CONTENT_TYPE), "video/webm");
+    assert!(parts.headers.get(http::header::CONTENT_RANGE).is_none());
+    assert_eq!(body, fixture.contents);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn single_range() -> anyhow::Result<()> {
+    let fixture = Fixture::new()?;
+
+    let (parts, body) = fixture
+        .send(
+            fixture
+                .request(http::Method::GET)
+                .header(http::header::RANGE, "bytes=100-199"),
+        )
+        .await?;
+
+    assert_eq!(parts.status, StatusCode::PARTIAL_CONTENT);
+    assert_eq!(header(&parts, http::header::CONTENT_RANGE), "bytes 100-199/1000");
+    assert_eq!(header(&parts, http::header::CONTENT_LENGTH), "100");
+    assert_eq!(body, fixture.contents[100..200]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn suffix_range() -> anyhow::Result<()> {
+    let fixture = Fixture::new()?;
+
+    let (parts, body) = fixture
+        .send(
+            fixture
+                .request(http::Method::GET)
+                .header(http::header::RANGE, "bytes=-10"),
+        )
+        .await?;
+
+    assert_eq!(parts.status, StatusCode::PARTIAL_CONTENT);
+    assert_eq!(header(&parts, http::header::CONTENT_RANGE), "bytes 990-999/1000");
+    assert_eq!(body, fixture.contents[990..]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn open_ended_range_past_the_end() -> anyhow::Result<()> {
+    let fixture = Fixture::new()?;
+
+    let (parts, body) = fixture
+        .send(
+            fixture
+                .request(http::Method::GET)
+                .header(http::header::RANGE, "bytes=900-5000"),
+        )
+        .await?;
+
+    assert_eq!(parts.status, StatusCode::PARTIAL_CONTENT);
+    assert_eq!(header(&parts, http::header::CONTENT_RANGE), "bytes 900-999/1000");
+    assert_eq!(body, fixture.contents[900..]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn unsatisfiable_range() -> anyhow::Result<()> {
+    let fixture = Fixture::new()?;
+
+    let (parts, body) = fixture
+        .send(
+            fixture
+                .request(http::Method::GET)
+                .header(http::header::RANGE, "bytes=1000-1100"),
+        )
+        .await?;
+
+    assert_eq!(parts.status, StatusCode::RANGE_NOT_SATISFIABLE);
+    assert_eq!(header(&parts, http::header::CONTENT_RANGE), "bytes */1000");
+    assert!(body.is_empty());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn multiple_ranges_serve_the_whole_file() -> anyhow::Result<()> {
+    let fixture = Fixture::new()?;
+
+    let (parts, body) = fixture
+        .send(
+            fixture
+                .request(http::Method::GET)
+                .header(http::header::RANGE, "bytes=0-9,100-109"),
+        )
+        .await?;
+
+    assert_eq!(parts.status, StatusCode::OK);
+    assert!(parts.headers.get(http::header::CONTENT_RANGE).is_none());
+    assert_eq!(body, fixture.contents);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn if_range_match() -> anyhow::Result<()> {
+    let fixture = Fixture::new()?;
+    let etag = fixture.etag().await?;
+
+    let (parts, body) = fixture
+        .send(
+            fixture
+                .request(http::Method::GET)
+                .header(http::header::RANGE, "bytes=0-9")
+                .header(http::header::IF_RANGE, etag),
+        )
+        .await?;
+
+    assert_eq!(parts.status, StatusCode::PARTIAL_CONTENT);
+    assert_eq!(body, fixture.contents[..10]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn if_range_mismatch_serves_the_whole_file() -> anyhow::Result<()> {
+    let fixture = Fixture::new()?;
+
+    let (parts, body) = fixture
+        .send(
+            fixture
+                .request(http::Method::GET)
+                .header(http::header::RANGE, "bytes=0-9")
+                .header(http::header::IF_RANGE, "\"outdated\""),
+        )
+        .await?;
+
+    assert_eq!(parts.status, StatusCode::OK);
+    assert!(parts.headers.get(http::header::CONTENT_RANGE).is_none());
+    assert_eq!(body, fixture.contents);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn if_none_match() -> anyhow::Result<()> {
+    let fixture = Fixture::new()?;
+    let etag = fixture.etag().await?;
+
+    let (parts, body) = fixture
+        .send(
+            fixture
+                .request(http::Method::GET)
+                .header(http::header::IF_NONE_MATCH, etag.as_str()),
+        )
+        .await?;
+
+    assert_eq!(parts.status, StatusCode::NOT_MODIFIED);
+    assert_eq!(header(&parts, http::header::ETAG), etag);
+    assert!(body.is_empty());
+
+    // A different entity tag does not match, and the file is served.
+    let (parts, body) = fixture
+        .send(
+            fixture
+                .request(http::Method::GET)
+                .header(http::header::IF_NONE_MATCH, "\"outdated\""),
+        )
+        .await?;
+
+    assert_eq!(parts.status, StatusCode::OK);
+    assert_eq!(body, fixture.contents);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn head() -> anyhow::Result<()> {
+    let fixture = Fixture::new()?;
+
+    let (parts, body) = fixture.send(fixture.request(http::Method::HEAD)).await?;
+
+    assert_eq!(parts.status, StatusCode::OK);
+    assert_eq!(header(&parts, http::header::CONTENT_LENGTH), "1000");
+    assert!(parts.headers.get(http::header::ETAG).is_some());
+    assert!(body.is_empty());
+
+    let (parts, body) = fixture
+        .send(
+            fixture
+                .request(http::Method::HEAD)
+                .header(http::header::RANGE, "bytes=0-9"),
+        )
+        .await?;
+
+    assert_eq!(parts.status, StatusCode::PARTIAL_CONTENT);
+    assert_eq!(header(&parts, http::header::CONTENT_LENGTH), "10");
+    assert!(body.is_empty());
+
+    Ok(())
+}
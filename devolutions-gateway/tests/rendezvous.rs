@@ -0,0 +1,157 @@
+use std::net::SocketAddr;
+
+use devolutions_gateway::rendezvous::handle_jet_peer;
+use devolutions_gateway::DgwState;
+use jet_proto::accept::JetAcceptReq;
+use jet_proto::connect::JetConnectReq;
+use jet_proto::{JetMessage, StatusCode};
+use picky::jose::jws::JwsAlg;
+use picky::jose::jwt::CheckedJwtSig;
+use serde_json::json;
+use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+use tokio::net::{TcpListener, TcpStream};
+use uuid::Uuid;
+
+const PROVISIONER_PUBLIC_KEY: &str = "mMIIBIjANBgkqhkiG9w0BAQEFAAOCAQ8AMIIBCgKCAQEA4vuqLOkl1pWobt6su1XO9VskgCAwevEGs6kkNjJQBwkGnPKYLmNF1E/af1yCocfVn/OnPf9e4x+lXVyZ6LMDJxFxu+axdgOq3Ld392J1iAEbfvwlyRFnEXFOJNyylqg3bY6LvnWHL/XZczVdMD9xYfq2sO9bg3xjRW4s7r9EEYOFjqVT3VFznH9iWJVtcSEKukmS/3uKoO6lGhacvu0HhjXXdgq0R8zvR4XRJ9Fcnf0f9Ypoc+i6L80NVjrRCeVOH+Ld/2fA9bocpfLarcVqG3RjS+qgOtpyCc0jWVFF4zaGQ7LUDFkEIYILkICeMMn2ll29hmZNzsJzZJ9s6NocgQIDAQAB";
+
+const PROVISIONER_PRIVATE_KEY: &str = "mMIIEvAIBADANBgkqhkiG9w0BAQEFAASCBKYwggSiAgEAAoIBAQDi+6os6SXWlahu3qy7Vc71WySAIDB68QazqSQ2MlAHCQac8pguY0XUT9p/XIKhx9Wf86c9/17jH6VdXJnoswMnEXG75rF2A6rct3f3YnWIARt+/CXJEWcRcU4k3LKWqDdtjou+dYcv9dlzNV0wP3Fh+raw71uDfGNFbizuv0QRg4WOpVPdUXOcf2JYlW1xIQq6SZL/e4qg7qUaFpy+7QeGNdd2CrRHzO9HhdEn0Vyd/R/1imhz6LovzQ1WOtEJ5U4f4t3/Z8D1uhyl8tqtxWobdGNL6qA62nIJzSNZUUXjNoZDstQMWQQhgguQgJ4wyfaWXb2GZk3OwnNkn2zo2hyBAgMBAAECggEBAKCO0GOQUDmoB0rVrG2fVxPrcrhHDMQKNmljnb/Qexde5RSj7c3yXvS9v5sTvzvc9Vl9qrGKMH6MZhbSZ/RYnERIbKEzoBgQpA4YoX2WYfjgf6ilh7zg2H1YHqSokJNNTlfq2yLQU94zE6wQ9WgpmHRsOkqSJbOuizITqyj+lpGjl8dBAeOCD9HsnOGQiwsQD+joZ3yDRdFKSaBBtbklTYDyAmPvmp2G5A00UIo7KeOcNv59MPHnFBxMj0/z+QPKlqLQMsjL8vQX5DU2t/K4jdFHWGL8NZcz7KsCfh2Aa0vWEnroRzPPhKuBSBtaykbvfTcGrvRioesPq3EUdUqjQSECgYEA52UlMYeRYiTWsGq69lFWSlBjlRKhEMpg0Tp05z7J/A9X+ytB+6dZ37hk5asq84adRp7pnCEHV3SbczGq5ULFQBEqtFWPlD348zB8xxdBpAw3NAkVVDpAXBREhxXOnQm7MMmaXLH6d4Gv4kc6jKTC62w7cUUSlkIhlWSw5pSuVh0CgYEA+x5rJ4MQ6A/OKh058QY3ydRJw/sV54oxIFIIuJDw4I4eMsJ5Ht7MW5Pl1VQj+XuJRgMeqgZMQIIAcf5JNXqcesswVwdXy4awtw3TZV1Hi47Or7qHrFA/DtG4lNeDtyaWNuOtNnGw+LuqEmuu8BsWhB7yTHWJW7z+k6qO90CnArUCgYEA5ew66NwsObkhGmrzG432kCEQ0i+Qm358dWoAf0aErVERuyFgjw3a39H5b7yFETXRUTrWJa0r/lp/nBbeGLAgD2j/ZfEemc56cCrd0XXqY3c/4xSjfO3kxZnd/dxNUP06Y1/vYev3VIgonE7qfpW4mPUSm5pmvac4d5l1rahPEoECgYBUvAToRj+ULpEggNAmVjTI88sYSEcx492DzGqI7M961jm2Ywy/r+pBFHy/KS8iZd8CMtdMA+gC9Fr2HBnT49WdUaa0FxQ25vIGMrIcSAd2Pe/cOBLDwCgm9flUsAwP5wNU7ipqbp6Kr7hJkvBqsJk+Z7rWteptfC5i4XBwWe6A6QJ/Ddv+9vZe89uMdq+PThhELBHK+twZKawpKXYvzKlvPfMVisY+m9m37t7wK8PJexWOI9loVif6+ZIdWpXXntwrz94hYld/6+qK+sSt8EGmcJpAAI3zkp/ZMXhio0fy27sPaTlKlS6GNx/gPXRj6NHg/nu6lMmQ/EpLi1lyExPc8Q";
+
+fn state() -> anyhow::Result<DgwState> {
+    let config = json!({
+        "ProvisionerPublicKeyData": { "Value": PROVISIONER_PUBLIC_KEY },
+        "ProvisionerPrivateKeyData": { "Value": PROVISIONER_PRIVATE_KEY },
+        "Listeners": [
+            {
+                "InternalUrl": "tcp://*:8080",
+                "ExternalUrl": "tcp://*:8080"
+            },
+            {
+                "InternalUrl": "http://*:7171",
+                "ExternalUrl": "https://*:7171"
+            }
+        ],
+    });
+
+    let (state, _handles) = DgwState::mock(&config.to_string())?;
+
+    Ok(state)
+}
+
+/// Signs a rendezvous association token for the given association.
+fn rdv_token(state: &DgwState, association: Uuid) -> anyhow::Result<String> {
+    let conf = state.conf_handle.get_conf();
+    let provisioner_key = conf.provisioner_private_key.as_ref().expect("set above");
+
+    let now = time::OffsetDateTime::now_utc().unix_timestamp();
+
+    let claims = json!({
+        "jet_aid": association,
+        "jet_ap": "rdp",
+        "jet_cm": "rdv",
+        "iat": now,
+        "nbf": now,
+        "exp": now + 60,
+        "jti": Uuid::new_v4(),
+    });
+
+    let token = CheckedJwtSig::new_with_cty(JwsAlg::RS256, "ASSOCIATION", claims).encode(provisioner_key)?;
+
+    Ok(token)
+}
+
+/// Connects a peer to a task handling it as a JET peer, and sends the given JET message.
+async fn jet_peer(
+    state: &DgwState,
+    message: JetMessage,
+) -> anyhow::Result<(TcpStream, tokio::task::JoinHandle<anyhow::Result<()>>)> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let mut peer = TcpStream::connect(listener.local_addr()?).await?;
+    let (stream, peer_addr): (TcpStream, SocketAddr) = listener.accept().await?;
+
+    let task = tokio::spawn(handle_jet_peer(stream, peer_addr, state.clone()));
+
+    let mut buf = Vec::new();
+    message.write_to(&mut buf)?;
+    peer.write_all(&buf).await?;
+
+    Ok((peer, task))
+}
+
+async fn read_status(peer: &mut TcpStream) -> anyhow::Result<StatusCode> {
+    let mut buf = [0; 1024];
+    let n = peer.read(&mut buf).await?;
+    anyhow::ensure!(n > 0, "connection closed");
+
+    // Accept and connect responses only hold a status code with JET version 2.
+    match JetMessage::read_connect_response(&mut &buf[..n])? {
+        JetMessage::JetConnectRsp(rsp) => Ok(rsp.status_code),
+        unexpected => anyhow::bail!("unexpected response: {unexpected:?}"),
+    }
+}
+
+fn accept_request(association: Uuid, candidate: Uuid, token: Option<String>) -> JetMessage {
+    JetMessage::JetAcceptReq(JetAcceptReq {
+        version: 2,
+        host: "jetsocat".to_owned(),
+        association,
+        candidate,
+        token,
+    })
+}
+
+fn connect_request(association: Uuid, candidate: Uuid, token: Option<String>) -> JetMessage {
+    JetMessage::JetConnectReq(JetConnectReq {
+        version: 2,
+        host: "jetsocat".to_owned(),
+        association,
+        candidate,
+        token,
+    })
+}
+
+#[tokio::test]
+async fn anonymous_acceptor_is_rejected() -> anyhow::Result<()> {
+    let state = state()?;
+    let association = Uuid::new_v4();
+    let candidate = Uuid::new_v4();
+
+    let (mut peer, task) = jet_peer(&state, accept_request(association, candidate, None)).await?;
+
+    let error = task.await?.unwrap_err();
+    assert!(format!("{error:#}").contains("association token missing"));
+
+    // The acceptor is not waiting: the connection is closed without any response.
+    let mut buf = [0; 1];
+    assert_eq!(peer.read(&mut buf).await?, 0);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn connector_is_only_paired_within_its_association() -> anyhow::Result<()> {
+    let state = state()?;
+    let association = Uuid::new_v4();
+    let other_association = Uuid::new_v4();
+    let candidate = Uuid::new_v4();
+
+    let token = rdv_token(&state, association)?;
+    let (mut acceptor, acceptor_task) = jet_peer(&state, accept_request(association, candidate, Some(token))).await?;
+
+    assert_eq!(read_status(&mut acceptor).await?, StatusCode::OK);
+
+    // A token for another association can't be used to reach the waiting acceptor.
+    let token = rdv_token(&state, other_association)?;
+    let (_connector, task) = jet_peer(&state, connect_request(association, candidate, Some(token))).await?;
+    let error = task.await?.unwrap_err();
+    assert!(format!("{error:#}").contains("another association"));
+
+    // Nor is a connector of another association paired with it.
+    let token = rdv_token(&state, other_association)?;
+    let (mut connector, task) = jet_peer(&state, connect_request(other_association, candidate, Some(token))).await?;
+    assert_eq!(read_status(&mut connector).await?, StatusCode::NOT_FOUND);
+    assert!(task.await?.is_err());
+
+    acceptor_task.abort();
+
+    Ok(())
+}
@@ -0,0 +1,182 @@
+//! Tests of the S3 recording storage against an S3-compatible service.
+//!
+//! These tests are ignored by default. To run them against a local MinIO server:
+//!
+//! ```shell
+//! docker run --rm -p 9000:9000 minio/minio server /data
+//! cargo test -p devolutions-gateway --test s3_storage -- --ignored
+//! ```
+//!
+//! The service is reached at `http://localhost:9000` with the `minioadmin` credentials, using the `dgw-test`
+//! bucket (created if missing). Override with `DGW_TEST_S3_ENDPOINT`, `DGW_TEST_S3_ACCESS_KEY_ID`,
+//! `DGW_TEST_S3_SECRET_ACCESS_KEY` and `DGW_TEST_S3_BUCKET`.
+
+use anyhow::Context as _;
+use camino::Utf8PathBuf;
+use devolutions_gateway::config::dto::{Password, S3StorageConf};
+use devolutions_gateway::recording::storage::{RecordingStorage, S3Storage};
+use devolutions_gateway::recording::JrecManifest;
+use tokio::io::AsyncReadExt as _;
+use uuid::Uuid;
+
+const REGION: &str = "us-east-1";
+
+fn env_or(name: &str, default: &str) -> String {
+    std::env::var(name).unwrap_or_else(|_| default.to_owned())
+}
+
+fn s3_conf(part_size: Option<u64>) -> S3StorageConf {
+    S3StorageConf {
+        bucket: env_or("DGW_TEST_S3_BUCKET", "dgw-test"),
+        region: REGION.to_owned(),
+        endpoint: Some(env_or("DGW_TEST_S3_ENDPOINT", "http://localhost:9000")),
+        access_key_id: env_or("DGW_TEST_S3_ACCESS_KEY_ID", "minioadmin"),
+        secret_access_key: Password::from(env_or("DGW_TEST_S3_SECRET_ACCESS_KEY", "minioadmin")),
+        // Each run uses its own prefix, so that recordings of previous runs are not listed.
+        key_prefix: Some(format!("{}/", Uuid::new_v4())),
+        force_path_style: Some(true),
+        part_size,
+    }
+}
+
+async fn create_bucket(conf: &S3StorageConf) -> anyhow::Result<()> {
+    use aws_sdk_s3::config::{BehaviorVersion, Credentials, Region};
+
+    let credentials = Credentials::new(
+        conf.access_key_id.clone(),
+        conf.secret_access_key.get().to_owned(),
+        None,
+        None,
+        "devolutions-gateway-tests",
+    );
+
+    let config = aws_sdk_s3::config::Builder::new()
+        .behavior_version(BehaviorVersion::latest())
+        .region(Region::new(conf.region.clone()))
+        .credentials_provider(credentials)
+        .force_path_style(true)
+        .endpoint_url(conf.endpoint.clone().expect("set above"))
+        .build();
+
+    let client = aws_sdk_s3::Client::from_conf(config);
+
+    if client.head_bucket().bucket(&conf.bucket).send().await.is_err() {
+        client
+            .create_bucket()
+            .bucket(&conf.bucket)
+            .send()
+            .await
+            .context("CreateBucket")?;
+    }
+
+    Ok(())
+}
+
+fn staging_root() -> Utf8PathBuf {
+    Utf8PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(format!("s3-staging-{}", Uuid::new_v4()))
+}
+
+fn manifest(id: Uuid, file_name: &str) -> JrecManifest {
+    serde_json::from_value(serde_json::json!({
+        "sessionId": id,
+        "startTime": 1700000000,
+        "duration": 60,
+        "files": [{ "fileName": file_name, "startTime": 1700000000, "duration": 60 }],
+    }))
+    .unwrap()
+}
+
+async fn stage_file(storage: &S3Storage, id: Uuid, file_name: &str, contents: &[u8]) -> Utf8PathBuf {
+    let staging_path = storage.staging_path(id);
+    tokio::fs::create_dir_all(&staging_path).await.unwrap();
+    let path = staging_path.join(file_name);
+    tokio::fs::write(&path, contents).await.unwrap();
+    path
+}
+
+async fn read_range(storage: &S3Storage, id: Uuid, file_name: &str, range: std::ops::Range<u64>) -> Vec<u8> {
+    let mut reader = storage.open_file(id, file_name, range).await.unwrap();
+    let mut out = Vec::new();
+    reader.read_to_end(&mut out).await.unwrap();
+    out
+}
+
+#[tokio::test]
+#[ignore = "requires an S3-compatible service (see module documentation)"]
+async fn put_list_read_delete() {
+    let conf = s3_conf(None);
+    create_bucket(&conf).await.unwrap();
+    let storage = S3Storage::new(&conf, staging_root()).unwrap();
+
+    let id = Uuid::new_v4();
+    let file_name = "recording-0.webm";
+    let contents: Vec<u8> = (0..=255).cycle().take(10_000).collect();
+
+    storage.write_manifest(id, &manifest(id, file_name)).await.unwrap();
+    let staged = stage_file(&storage, id, file_name, &contents).await;
+    storage.store_file(id, file_name).await.unwrap();
+
+    // The file is removed from the staging folder once uploaded, so it is now read from the bucket.
+    assert!(!staged.exists());
+
+    assert_eq!(storage.list_recordings().await.unwrap(), vec![id]);
+
+    let manifest = storage.read_manifest(id).await.unwrap().unwrap();
+    assert_eq!(manifest.session_id, id);
+    assert_eq!(manifest.files[0].file_name, file_name);
+
+    let info = storage.file_info(id, file_name).await.unwrap().unwrap();
+    assert_eq!(info.size, contents.len() as u64);
+    assert!(info.etag.starts_with('"'));
+
+    assert_eq!(read_range(&storage, id, file_name, 0..10_000).await, contents);
+    assert_eq!(read_range(&storage, id, file_name, 100..356).await, contents[100..356]);
+    assert_eq!(
+        read_range(&storage, id, file_name, 9_990..10_000).await,
+        contents[9_990..]
+    );
+    assert!(read_range(&storage, id, file_name, 42..42).await.is_empty());
+
+    assert!(storage.file_info(id, "missing.webm").await.unwrap().is_none());
+
+    storage.delete_recording(id).await.unwrap();
+
+    assert!(storage.list_recordings().await.unwrap().is_empty());
+    assert!(storage.read_manifest(id).await.unwrap().is_none());
+    assert!(storage.file_info(id, file_name).await.unwrap().is_none());
+}
+
+#[tokio::test]
+#[ignore = "requires an S3-compatible service (see module documentation)"]
+async fn multipart_upload() {
+    const PART_SIZE_MIB: u64 = 5;
+
+    let conf = s3_conf(Some(PART_SIZE_MIB));
+    create_bucket(&conf).await.unwrap();
+    let storage = S3Storage::new(&conf, staging_root()).unwrap();
+
+    let id = Uuid::new_v4();
+    let file_name = "recording-0.trp";
+
+    // Two full parts and a partial one.
+    let size = usize::try_from(PART_SIZE_MIB * 1024 * 1024 * 2 + 1234).unwrap();
+    let contents: Vec<u8> = (0..=250).cycle().take(size).collect();
+
+    stage_file(&storage, id, file_name, &contents).await;
+    storage.store_file(id, file_name).await.unwrap();
+
+    let info = storage.file_info(id, file_name).await.unwrap().unwrap();
+    assert_eq!(info.size, contents.len() as u64);
+
+    // Range spanning the boundary between the first two parts.
+    let boundary = PART_SIZE_MIB * 1024 * 1024;
+    let range = boundary - 10..boundary + 10;
+    assert_eq!(
+        read_range(&storage, id, file_name, range.clone()).await,
+        contents[usize::try_from(range.start).unwrap()..usize::try_from(range.end).unwrap()]
+    );
+
+    storage.delete_recording(id).await.unwrap();
+
+    assert!(storage.file_info(id, file_name).await.unwrap().is_none());
+}
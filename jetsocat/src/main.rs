@@ -90,8 +90,8 @@ const PIPE_FORMATS: &str = r#"Pipe formats:
     `read-file://<PATH>`: Open specified file in read mode
     `tcp://<ADDRESS>`: Plain TCP stream
     `tcp-listen://<BINDING ADDRESS>`: TCP listener
-    `jet-tcp-connect://<ADDRESS>/<ASSOCIATION ID>/<CANDIDATE ID>`: TCP stream over JET protocol as client
-    `jet-tcp-accept://<ADDRESS>/<ASSOCIATION ID>/<CANDIDATE ID>`: TCP stream over JET protocol as server
+    `jet-tcp-connect://<ADDRESS>/<ASSOCIATION ID>/<CANDIDATE ID>[/<TOKEN>]`: TCP stream over JET protocol as client
+    `jet-tcp-accept://<ADDRESS>/<ASSOCIATION ID>/<CANDIDATE ID>[/<TOKEN>]`: TCP stream over JET protocol as server
     `ws://<URL>`: WebSocket
     `wss://<URL>`: WebSocket Secure
     `ws-listen://<BINDING ADDRESS>`: WebSocket listener"#;
@@ -451,7 +451,7 @@ fn parse_pipe_mode(arg: String) -> anyhow::Result<PipeMode> {
     let scheme = &arg[..scheme_end_idx];
     let value = &arg[scheme_end_idx + SCHEME_SEPARATOR.len()..];
 
-    fn parse_jet_pipe_format(value: &str) -> anyhow::Result<(String, Uuid, Uuid)> {
+    fn parse_jet_pipe_format(value: &str) -> anyhow::Result<(String, Uuid, Uuid, Option<String>)> {
         let mut it = value.split('/');
         let addr = it.next().context("address is missing")?;
 
@@ -461,7 +461,9 @@ fn parse_pipe_mode(arg: String) -> anyhow::Result<PipeMode> {
         let candidate_id_str = it.next().context("candidate ID is missing")?;
         let candidate_id = Uuid::parse_str(candidate_id_str).context("bad candidate ID")?;
 
-        Ok((addr.to_owned(), association_id, candidate_id))
+        let token = it.next().filter(|token| !token.is_empty()).map(str::to_owned);
+
+        Ok((addr.to_owned(), association_id, candidate_id, token))
     }
 
     match scheme {
@@ -479,19 +481,21 @@ fn parse_pipe_mode(arg: String) -> anyhow::Result<PipeMode> {
         }),
         "tcp" => Ok(PipeMode::Tcp { addr: value.to_owned() }),
         "jet-tcp-connect" => {
-            let (addr, association_id, candidate_id) = parse_jet_pipe_format(value)?;
+            let (addr, association_id, candidate_id, token) = parse_jet_pipe_format(value)?;
             Ok(PipeMode::JetTcpConnect {
                 addr,
                 association_id,
                 candidate_id,
+                token,
             })
         }
         "jet-tcp-accept" => {
-            let (addr, association_id, candidate_id) = parse_jet_pipe_format(value)?;
+            let (addr, association_id, candidate_id, token) = parse_jet_pipe_format(value)?;
             Ok(PipeMode::JetTcpAccept {
                 addr,
                 association_id,
                 candidate_id,
+                token,
             })
         }
         "ws" | "wss" => Ok(PipeMode::WebSocket { url: arg }),
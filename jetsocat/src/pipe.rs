@@ -27,11 +27,13 @@ pub enum PipeMode {
         addr: String,
         association_id: Uuid,
         candidate_id: Uuid,
+        token: Option<String>,
     },
     JetTcpConnect {
         addr: String,
         association_id: Uuid,
         candidate_id: Uuid,
+        token: Option<String>,
     },
     WebSocket {
         url: String,
@@ -179,6 +181,7 @@ pub async fn open_pipe(mode: PipeMode, proxy_cfg: Option<ProxyConfig>) -> Result
             addr,
             association_id,
             candidate_id,
+            token,
         } => {
             use crate::jet::{read_jet_accept_response, write_jet_accept_request};
             use crate::utils::tcp_connect;
@@ -194,7 +197,7 @@ pub async fn open_pipe(mode: PipeMode, proxy_cfg: Option<ProxyConfig>) -> Result
                 .with_context(|| "TCP connect failed")?;
 
             debug!("Sending JET accept request…");
-            write_jet_accept_request(&mut write, association_id, candidate_id).await?;
+            write_jet_accept_request(&mut write, association_id, candidate_id, token).await?;
             debug!("JET accept request sent, waiting for response…");
             read_jet_accept_response(&mut read).await?;
             debug!("JET accept response received and processed successfully!");
@@ -212,6 +215,7 @@ pub async fn open_pipe(mode: PipeMode, proxy_cfg: Option<ProxyConfig>) -> Result
             addr,
             association_id,
             candidate_id,
+            token,
         } => {
             use crate::jet::{read_jet_connect_response, write_jet_connect_request};
             use crate::utils::tcp_connect;
@@ -226,7 +230,7 @@ pub async fn open_pipe(mode: PipeMode, proxy_cfg: Option<ProxyConfig>) -> Result
                 .with_context(|| "TCP connect failed")?;
 
             debug!("Sending JET connect request…");
-            write_jet_connect_request(&mut write, association_id, candidate_id).await?;
+            write_jet_connect_request(&mut write, association_id, candidate_id, token).await?;
             debug!("JET connect request sent, waiting for response…");
             read_jet_connect_response(&mut read).await?;
             debug!("JET connect response received and processed successfully!");
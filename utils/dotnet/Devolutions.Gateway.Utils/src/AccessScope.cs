@@ -15,6 +15,7 @@ public struct AccessScope
     public static AccessScope Star = new AccessScope("*");
     public static AccessScope GatewaySessionsRead = new AccessScope("gateway.sessions.read");
     public static AccessScope GatewaySessionTerminate = new AccessScope("gateway.session.terminate");
+    public static AccessScope GatewaySessionCapture = new AccessScope("gateway.session.capture");
     public static AccessScope GatewayAssociationsRead = new AccessScope("gateway.associations.read");
     public static AccessScope GatewayDiagnosticsRead = new AccessScope("gateway.diagnostics.read");
     public static AccessScope GatewayJrlRead = new AccessScope("gateway.jrl.read");